@@ -0,0 +1,409 @@
+// BP Core Library implementing LNP/BP specifications & standards related to
+// bitcoin protocol
+//
+// Written in 2020-2021 by
+//     Dr. Maxim Orlovsky <orlovsky@pandoracore.com>
+//
+// To the extent possible under law, the author(s) have dedicated all
+// copyright and related and neighboring rights to this software to
+// the public domain worldwide. This software is distributed without
+// any warranty.
+//
+// You should have received a copy of the Apache 2.0 License
+// along with this software.
+// If not, see <https://opensource.org/licenses/Apache-2.0>.
+
+//! Anchors binding transaction, which contains deterministic bitcoin
+//! commitment, to the proof required for the commitment verification.
+
+use amplify::Wrapper;
+use bitcoin::hashes::{sha256, sha256t, Hash, HashEngine};
+use bitcoin::util::merkleblock::PartialMerkleTree;
+use bitcoin::{BlockHeader, Transaction, Txid};
+use strict_encoding::StrictEncode;
+
+use crate::{Error, Proof, ProtocolTag, TxoutCommitment};
+
+/// Tag used for [`AnchorId`] hash type
+pub struct AnchorIdTag;
+
+/// Name of the BIP-340-style tag used in [`AnchorId`] computation
+pub const ANCHOR_ID_TAG: &str = "bp:dbc:anchor:v1";
+
+impl sha256t::Tag for AnchorIdTag {
+    #[inline]
+    fn engine() -> sha256::HashEngine {
+        let tag = sha256::Hash::hash(ANCHOR_ID_TAG.as_bytes());
+        let mut engine = sha256::HashEngine::default();
+        engine.input(&tag[..]);
+        engine.input(&tag[..]);
+        engine
+    }
+}
+
+/// Unique anchor identifier, computed as BIP-340-style tagged hash (with
+/// [`ANCHOR_ID_TAG`]) of the strict-encoded [`Anchor`] data. The identifier
+/// is displayed and parsed as a hexadecimal string in the reverse byte order,
+/// like bitcoin transaction ids.
+///
+/// NB: The identifiers are used as database keys, so all implementations must
+/// compute them identically. The preimage layout must never change without
+/// changing the version in the tag.
+#[derive(
+    Wrapper, Copy, Clone, PartialEq, Eq, PartialOrd, Ord, Hash, Default,
+    Display, From
+)]
+#[wrapper(
+    Debug, LowerHex, FromStr, Index, IndexRange, IndexFrom, IndexTo, IndexFull
+)]
+#[display(LowerHex)]
+pub struct AnchorId(sha256t::Hash<AnchorIdTag>);
+
+impl strict_encoding::Strategy for AnchorId {
+    type Strategy = strict_encoding::strategies::Wrapped;
+}
+
+/// Anchor proving that transaction with id `txid` contains commitment for the
+/// protocol with `protocol_tag` in its output number `commitment_output`.
+///
+/// The anchor does not include the message itself, which must be known to the
+/// verifier from the client-side data.
+#[derive(Clone, PartialEq, Eq, Hash, Debug, StrictEncode, StrictDecode)]
+#[cfg_attr(
+    feature = "serde",
+    derive(Serialize, Deserialize),
+    serde(crate = "serde_crate")
+)]
+pub struct Anchor {
+    /// Id of the transaction containing the commitment
+    pub txid: Txid,
+
+    /// Proof required to reconstruct commitment container from the output
+    pub proof: Proof,
+
+    /// Protocol-specific tag used in the commitment
+    pub protocol_tag: ProtocolTag,
+
+    /// Index of the transaction output containing the commitment
+    pub commitment_output: u16,
+}
+
+impl Anchor {
+    /// Computes unique anchor identifier
+    pub fn anchor_id(&self) -> AnchorId {
+        let mut engine = sha256t::Hash::<AnchorIdTag>::engine();
+        self.strict_encode(&mut engine)
+            .expect("memory encoders do not fail");
+        AnchorId::from_inner(sha256t::Hash::from_engine(engine))
+    }
+
+    /// Verifies that the transaction `tx` is the one referenced by the anchor
+    /// and that its output `commitment_output` commits to the message `msg`.
+    /// The commitment container is reconstructed from the output and the
+    /// anchor proof, as described in
+    /// [`TxoutCommitment::verify_txout_script_only`]: the anchor does not
+    /// keep the output value, so the value is not checked.
+    ///
+    /// Returns `Ok(false)` if the transaction id does not match the anchor or
+    /// the output does not commit to the message. Fails with
+    /// [`Error::OutputNotFound`] if the transaction has no output with the
+    /// index specified by the anchor, or with other errors if the proof is
+    /// structurally incompatible with the output `scriptPubkey`.
+    pub fn verify(
+        &self,
+        tx: &Transaction,
+        msg: &impl AsRef<[u8]>,
+    ) -> Result<bool, Error> {
+        if tx.txid() != self.txid {
+            return Ok(false);
+        }
+        let txout = tx
+            .output
+            .get(self.commitment_output as usize)
+            .ok_or(Error::OutputNotFound(self.commitment_output as u32))?;
+        TxoutCommitment::verify_txout_script_only(
+            txout,
+            &self.proof,
+            &self.protocol_tag,
+            msg,
+        )
+    }
+
+    /// Verifies the anchor with [`Anchor::verify`] after checking that the
+    /// anchor transaction is included into the block with the given
+    /// `header`, as it is done by SPV clients using BIP-37 partial merkle
+    /// trees. The validity of the header itself (proof of work and its
+    /// presence in the best chain) must be checked by the caller.
+    ///
+    /// # Errors
+    ///
+    /// Fails with [`Error::InvalidMerkleProof`] if `merkle_proof` is
+    /// malformed, with [`Error::MerkleRootMismatch`] if its root differs from
+    /// the header merkle root and with [`Error::TxNotIncluded`] if it does not
+    /// include the anchor transaction id. The rest of errors are the same as
+    /// for [`Anchor::verify`].
+    pub fn verify_spv(
+        &self,
+        header: &BlockHeader,
+        merkle_proof: &PartialMerkleTree,
+        tx: &Transaction,
+        msg: &impl AsRef<[u8]>,
+    ) -> Result<bool, Error> {
+        let mut matches = vec![];
+        let mut indexes = vec![];
+        let merkle_root = merkle_proof
+            .extract_matches(&mut matches, &mut indexes)
+            .map_err(|_| Error::InvalidMerkleProof)?;
+        if merkle_root != header.merkle_root {
+            return Err(Error::MerkleRootMismatch);
+        }
+        if !matches.contains(&self.txid) {
+            return Err(Error::TxNotIncluded);
+        }
+        self.verify(tx, msg)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::str::FromStr;
+
+    use bitcoin::consensus::deserialize;
+    use bitcoin::hashes::hex::{FromHex, ToHex};
+    use bitcoin::hashes::sha256t::Tag;
+    use bitcoin::util::merkleblock::MerkleBlock;
+    use bitcoin::{secp256k1, OutPoint, TxIn, TxOut};
+    use commit_verify::EmbedCommitVerify;
+    use strict_encoding::StrictDecode;
+
+    use super::*;
+    use crate::{
+        Container, ScriptEncodeData, ScriptEncodeMethod, TxoutContainer,
+    };
+
+    fn gen_anchor(tag: &ProtocolTag) -> (Anchor, Transaction) {
+        let pubkey = secp256k1::PublicKey::from_str(
+            "0218845781f631c48f1c9709e23092067d06837f30aa0cd0544ac887fe91ddd166",
+        )
+        .unwrap();
+        let mut tx = Transaction {
+            version: 2,
+            lock_time: 0,
+            input: vec![TxIn {
+                previous_output: OutPoint::default(),
+                script_sig: Default::default(),
+                sequence: 0,
+                witness: vec![],
+            }],
+            output: vec![TxOut {
+                value: 5000,
+                script_pubkey: Default::default(),
+            }],
+        };
+        let mut proof = None;
+        for msg in &["message", "other message"] {
+            let mut container = TxoutContainer::construct(
+                tag,
+                1000,
+                pubkey,
+                ScriptEncodeData::SinglePubkey,
+                ScriptEncodeMethod::WPubkeyHash,
+            );
+            let commitment =
+                TxoutCommitment::embed_commit(&mut container, msg).unwrap();
+            tx.output.push(commitment.into_inner());
+            proof.get_or_insert(container.into_proof());
+        }
+        let anchor = Anchor {
+            txid: tx.txid(),
+            proof: proof.unwrap(),
+            protocol_tag: *tag,
+            commitment_output: 1,
+        };
+        (anchor, tx)
+    }
+
+    #[test]
+    fn test_anchor_id_tag() {
+        let tag = sha256::Hash::hash(b"bp:dbc:anchor:v1");
+        let mut engine = sha256::HashEngine::default();
+        engine.input(&tag[..]);
+        engine.input(&tag[..]);
+        assert_eq!(AnchorIdTag::engine().midstate(), engine.midstate());
+    }
+
+    fn golden_anchor() -> Anchor {
+        Anchor {
+            txid: Txid::from_hex(
+                "4a5e1e4baab89f3a32518a88c31bc87f618f76673e2cc77ab2127b7afdeda33b",
+            )
+            .unwrap(),
+            proof: Proof::from_pubkey_only(
+                secp256k1::PublicKey::from_str(
+                    "0218845781f631c48f1c9709e23092067d06837f30aa0cd0544ac887fe91ddd166",
+                )
+                .unwrap(),
+            ),
+            protocol_tag: ProtocolTag::new("TEST_TAG"),
+            commitment_output: 1,
+        }
+    }
+
+    #[test]
+    fn test_anchor_id_golden() {
+        // Any change of these values breaks compatibility of the identifiers
+        // and requires change of the tag version
+        let anchor = golden_anchor();
+        assert_eq!(
+            anchor.strict_serialize().unwrap().to_hex(),
+            "3ba3edfd7a7b12b27ac72c3e67768f617fc81bc3888a51323a9fb8aa4b1e5e4a\
+             0218845781f631c48f1c9709e23092067d06837f30aa0cd0544ac887fe91ddd166\
+             00\
+             013113300fd425c4880faf45e5d3196a833e7b36ea5222310388f4f0c43f9c59\
+             0100"
+        );
+        let id = anchor.anchor_id();
+        assert_eq!(
+            id.as_inner().into_inner().to_hex(),
+            "31f092e68b6c723612b621ddfcf8d58677492eb6f3a211a1ff3461242213cb31"
+        );
+        assert_eq!(
+            id.to_string(),
+            "31cb1322246134ffa111a2f3b62e497786d5f8fcdd21b61236726c8be692f031"
+        );
+        assert_eq!(AnchorId::from_str(&id.to_string()), Ok(id));
+        assert_eq!(
+            AnchorId::strict_deserialize(id.strict_serialize().unwrap()),
+            Ok(id)
+        );
+        assert_eq!(id.strict_serialize().unwrap(), id.as_inner().to_vec());
+    }
+
+    #[test]
+    fn test_anchor_verify() {
+        let tag = ProtocolTag::new("TEST_TAG");
+        let (anchor, tx) = gen_anchor(&tag);
+        assert_eq!(anchor.verify(&tx, &"message"), Ok(true));
+        assert_eq!(anchor.verify(&tx, &"other message"), Ok(false));
+
+        // Other protocol
+        let mut other = anchor.clone();
+        other.protocol_tag = ProtocolTag::new("OTHER_TAG");
+        assert_eq!(other.verify(&tx, &"message"), Ok(false));
+
+        // Wrong output
+        let mut wrong = anchor.clone();
+        wrong.commitment_output = 2;
+        assert_eq!(wrong.verify(&tx, &"message"), Ok(false));
+        assert_eq!(wrong.verify(&tx, &"other message"), Ok(true));
+        wrong.commitment_output = 3;
+        assert_eq!(
+            wrong.verify(&tx, &"message"),
+            Err(Error::OutputNotFound(3))
+        );
+
+        // Txid mismatch
+        let mut modified = tx.clone();
+        modified.lock_time = 1;
+        assert_eq!(anchor.verify(&modified, &"message"), Ok(false));
+        let mut wrong = anchor;
+        wrong.txid = modified.txid();
+        assert_eq!(wrong.verify(&tx, &"message"), Ok(false));
+        assert_eq!(wrong.verify(&modified, &"message"), Ok(true));
+    }
+
+    #[test]
+    fn test_anchor_encoding() {
+        let tag = ProtocolTag::new("TEST_TAG");
+        let (anchor, _) = gen_anchor(&tag);
+        let data = anchor.strict_serialize().unwrap();
+        assert_eq!(Anchor::strict_deserialize(&data), Ok(anchor.clone()));
+
+        let id = anchor.anchor_id();
+        assert_eq!(id, anchor.clone().anchor_id());
+        assert_eq!(id.into_inner(), sha256t::Hash::<AnchorIdTag>::hash(&data));
+        assert_eq!(format!("{}", id), format!("{:x}", id));
+
+        let mut other = anchor;
+        other.commitment_output = 2;
+        assert_ne!(other.anchor_id(), id);
+    }
+
+    #[test]
+    fn test_anchor_verify_spv() {
+        // Testnet block
+        // 0000000000013b8ab2cd513b0261a14096412195a72a0c4827d229dcc7e0f7af
+        // with a proof for transaction
+        // 220ebc64e21abece964927322cba69180ed853bb187fbc6923bac7d010b9d87a
+        let merkle_block: MerkleBlock = deserialize(
+            &Vec::from_hex(
+                "0100000090f0a9f110702f808219ebea1173056042a714bad51b916cb6800000000000005275289558f51c\
+                9966699404ae2294730c3c9f9bda53523ce50e9b95e558da2fdb261b4d4c86041b1ab1bf930900000005fac\
+                7708a6e81b2a986dea60db2663840ed141130848162eb1bd1dee54f309a1b2ee1e12587e497ada70d9bd10d\
+                31e83f0a924825b96cb8d04e8936d793fb60db7ad8b910d0c7ba2369bc7f18bb53d80e1869ba2c32274996c\
+                ebe1ae264bc0e2289189ff0316cdc10511da71da757e553cada9f3b5b1434f3923673adb57d83caac392c38\
+                af156d6fc30b55fad4112df2b95531e68114e9ad10011e72f7b7cfdb025700",
+            )
+            .unwrap(),
+        )
+        .unwrap();
+        let header = merkle_block.header;
+        assert_eq!(
+            header.block_hash().to_string(),
+            "0000000000013b8ab2cd513b0261a14096412195a72a0c4827d229dcc7e0f7af"
+        );
+        let included = Txid::from_hex(
+            "220ebc64e21abece964927322cba69180ed853bb187fbc6923bac7d010b9d87a",
+        )
+        .unwrap();
+
+        let tag = ProtocolTag::new("TEST_TAG");
+        let (anchor, tx) = gen_anchor(&tag);
+
+        // Real block proof does not include the anchor transaction
+        assert_eq!(
+            anchor.verify_spv(&header, &merkle_block.txn, &tx, &"message"),
+            Err(Error::TxNotIncluded)
+        );
+
+        // Proof for the tree with the anchor transaction, which does not
+        // match the block
+        let txids = [included, anchor.txid, Txid::hash(b"other")];
+        let merkle_proof =
+            PartialMerkleTree::from_txids(&txids, &[false, true, false]);
+        assert_eq!(
+            anchor.verify_spv(&header, &merkle_proof, &tx, &"message"),
+            Err(Error::MerkleRootMismatch)
+        );
+
+        // Header of the block containing the anchor transaction
+        let mut header = header;
+        header.merkle_root = merkle_proof
+            .extract_matches(&mut vec![], &mut vec![])
+            .unwrap();
+        assert_eq!(
+            anchor.verify_spv(&header, &merkle_proof, &tx, &"message"),
+            Ok(true)
+        );
+        assert_eq!(
+            anchor.verify_spv(&header, &merkle_proof, &tx, &"other message"),
+            Ok(false)
+        );
+
+        // The same tree proving inclusion of another transaction only
+        let other_proof =
+            PartialMerkleTree::from_txids(&txids, &[true, false, false]);
+        assert_eq!(
+            anchor.verify_spv(&header, &other_proof, &tx, &"message"),
+            Err(Error::TxNotIncluded)
+        );
+
+        // Malformed tree with no transactions
+        let malformed: PartialMerkleTree =
+            deserialize(&[0u8, 0, 0, 0, 0, 0]).unwrap();
+        assert_eq!(
+            anchor.verify_spv(&header, &malformed, &tx, &"message"),
+            Err(Error::InvalidMerkleProof)
+        );
+    }
+}
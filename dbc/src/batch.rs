@@ -0,0 +1,361 @@
+// BP Core Library implementing LNP/BP specifications & standards related to
+// bitcoin protocol
+//
+// Written in 2020-2021 by
+//     Dr. Maxim Orlovsky <orlovsky@pandoracore.com>
+//
+// To the extent possible under law, the author(s) have dedicated all
+// copyright and related and neighboring rights to this software to
+// the public domain worldwide. This software is distributed without
+// any warranty.
+//
+// You should have received a copy of the Apache 2.0 License
+// along with this software.
+// If not, see <https://opensource.org/licenses/Apache-2.0>.
+
+//! Batching of commitments for multiple independent protocols into a single
+//! transaction through LNPBP-4 multi-protocol commitment (MPC) tree.
+//!
+//! Only the root of the tree is committed to by the transaction (in the form
+//! of [`MpcRoot::to_commitment_message`]), and each protocol receives
+//! [`BatchAnchor`] with the merkle path to its slot, which is verified
+//! without knowledge of the messages of the other protocols.
+
+use std::collections::BTreeMap;
+use std::convert::TryFrom;
+
+use amplify::Wrapper;
+use bitcoin::hashes::sha256;
+use bitcoin::Transaction;
+use commit_verify::CommitConceal;
+
+use crate::lnpbp4::{MpcProof, MpcRoot, MultiCommitBlock, MultiSource};
+use crate::{Anchor, Error, FinalizedTx, ProtocolTag, TxTemplate};
+
+/// Name of the protocol tag used by the transaction-level commitment to the
+/// MPC tree root
+pub const MPC_PROTOCOL_TAG: &str = "bp:dbc:mpc";
+
+/// Returns protocol tag of the transaction-level commitment to the MPC tree
+/// root
+#[inline]
+pub fn mpc_protocol_tag() -> ProtocolTag { ProtocolTag::new(MPC_PROTOCOL_TAG) }
+
+/// Anchor for a single protocol from the commitment batch: the anchor of the
+/// transaction-level commitment to the MPC tree root together with the merkle
+/// path from the protocol slot to the root
+#[derive(Clone, PartialEq, Eq, Hash, Debug, StrictEncode, StrictDecode)]
+#[cfg_attr(
+    feature = "serde",
+    derive(Serialize, Deserialize),
+    serde(crate = "serde_crate")
+)]
+pub struct BatchAnchor {
+    /// Anchor of the commitment to the MPC tree root, which always uses
+    /// [`mpc_protocol_tag`]
+    pub anchor: Anchor,
+
+    /// Tag of the protocol which message is anchored
+    pub protocol_tag: ProtocolTag,
+
+    /// Merkle path from the protocol slot to the MPC tree root
+    pub mpc_proof: MpcProof,
+}
+
+impl BatchAnchor {
+    /// Verifies that the transaction `tx` commits to the MPC tree with the
+    /// `message` placed into the slot of the anchored protocol.
+    ///
+    /// Returns `Ok(false)` if the anchor is not an MPC anchor, if the proof
+    /// slot does not belong to the anchored protocol or if the transaction
+    /// does not commit to the message. The errors are the same as for
+    /// [`MpcProof::merkle_root`] and [`Anchor::verify`].
+    pub fn verify(
+        &self,
+        tx: &Transaction,
+        message: &sha256::Hash,
+    ) -> Result<bool, Error> {
+        if self.anchor.protocol_tag != mpc_protocol_tag()
+            || !self.mpc_proof.matches_slot(&self.protocol_tag)
+        {
+            return Ok(false);
+        }
+        let root = MpcRoot::from_inner(
+            self.mpc_proof.merkle_root(&self.protocol_tag, message)?,
+        );
+        self.anchor.verify(tx, &root.to_commitment_message())
+    }
+}
+
+/// Batch of messages from multiple protocols committed to by a single
+/// transaction, at most one message per protocol.
+#[derive(Clone, PartialEq, Eq, Debug, Default)]
+pub struct AnchorBatch(MultiSource);
+
+impl AnchorBatch {
+    /// Constructs empty batch with random entropy
+    #[inline]
+    pub fn new() -> Self { AnchorBatch(MultiSource::new()) }
+
+    /// Constructs empty batch with the given entropy used to fill unoccupied
+    /// slots of the MPC tree. The entropy must be random, otherwise the
+    /// anchor holders may learn presence of the other protocols in the batch
+    /// (see [`crate::lnpbp4`]).
+    #[inline]
+    pub fn with_entropy(entropy: u64) -> Self {
+        AnchorBatch(MultiSource::with_entropy(entropy))
+    }
+
+    /// Adds `message` for the protocol with the given tag, replacing the
+    /// previously added message for the same protocol
+    pub fn add(
+        &mut self,
+        protocol_tag: ProtocolTag,
+        message: sha256::Hash,
+    ) -> &mut Self {
+        self.0.insert(protocol_tag, message);
+        self
+    }
+
+    /// Returns number of protocols in the batch
+    #[inline]
+    pub fn len(&self) -> usize { self.0.messages.len() }
+
+    /// Detects whether the batch contains no messages
+    #[inline]
+    pub fn is_empty(&self) -> bool { self.0.messages.is_empty() }
+
+    /// Constructs the MPC tree, finalizes transaction from the `template`
+    /// committing to the tree root and produces anchor for each of the
+    /// protocols in the batch.
+    ///
+    /// The message of the template is not used, and the protocol tag of the
+    /// template commitment container is replaced with [`mpc_protocol_tag`].
+    /// Since the anchors reference id of the unsigned transaction, the
+    /// template must spend only segwit outputs, which signatures do not
+    /// change the transaction id.
+    ///
+    /// Fails with [`Error::EmptyBatch`] if the batch has no messages, with
+    /// [`crate::lnpbp4::Error::TreeOverflow`] if the protocols can't be placed
+    /// into distinct tree slots and with the errors of
+    /// [`TxTemplate::finalize`].
+    pub fn finalize<MSG>(
+        &self,
+        template: &TxTemplate<MSG>,
+    ) -> Result<(FinalizedTx, BTreeMap<ProtocolTag, BatchAnchor>), Error> {
+        if self.is_empty() {
+            return Err(Error::EmptyBatch);
+        }
+        let block = MultiCommitBlock::with(self.0.clone())?;
+        let root = block.commit_conceal();
+
+        let mut container = template.container.clone();
+        container.script_container.tag = mpc_protocol_tag();
+        let finalized = TxTemplate {
+            version: template.version,
+            lock_time: template.lock_time,
+            inputs: template.inputs.clone(),
+            outputs: template.outputs.clone(),
+            fee_rate: template.fee_rate,
+            reserved: template.reserved.clone(),
+            protocol_factor: template.protocol_factor,
+            container,
+            msg: root.to_commitment_message(),
+        }
+        .finalize()?;
+
+        let commitment_output =
+            u16::try_from(finalized.output_index).map_err(|_| {
+                Error::OutputIndexOutOfRange {
+                    index: finalized.output_index,
+                    outputs: u16::MAX as usize + 1,
+                }
+            })?;
+        let anchor = Anchor {
+            txid: finalized.tx.txid(),
+            proof: finalized.proof.clone(),
+            protocol_tag: mpc_protocol_tag(),
+            commitment_output,
+        };
+        let anchors = self
+            .0
+            .messages
+            .keys()
+            .map(|tag| {
+                (*tag, BatchAnchor {
+                    anchor: anchor.clone(),
+                    protocol_tag: *tag,
+                    mpc_proof: block
+                        .proof_for(tag)
+                        .expect("the tree contains all batch protocols"),
+                })
+            })
+            .collect();
+
+        Ok((finalized, anchors))
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use bitcoin::hashes::Hash;
+    use bitcoin::{OutPoint, TxIn, TxOut, Txid};
+    use bitcoin_scripts::{Category, ToPubkeyScript};
+
+    use super::*;
+    use crate::lnpbp1::test_helpers::*;
+    use crate::{lnpbp4, ScriptEncodeData, ScriptEncodeMethod};
+
+    fn gen_template() -> TxTemplate<&'static str> {
+        let keys = gen_secp_pubkeys(2);
+        let mut template = TxTemplate::new(
+            &ProtocolTag::new("TEST_TAG"),
+            5,
+            keys[0],
+            ScriptEncodeData::SinglePubkey,
+            ScriptEncodeMethod::WPubkeyHash,
+            "ignored message",
+        );
+        template
+            .add_input(
+                TxIn {
+                    previous_output: OutPoint::new(Txid::hash(&[0]), 0),
+                    ..Default::default()
+                },
+                50_000,
+                110,
+            )
+            .add_output(TxOut {
+                value: 20_000,
+                script_pubkey: bitcoin::PublicKey {
+                    compressed: true,
+                    key: keys[1],
+                }
+                .to_pubkey_script(Category::SegWit)
+                .into_inner(),
+            });
+        template
+    }
+
+    fn gen_batch(count: usize) -> AnchorBatch {
+        let mut batch = AnchorBatch::with_entropy(0xdead_beef);
+        for no in 0..count {
+            batch.add(
+                ProtocolTag::new(&format!("protocol #{}", no)),
+                sha256::Hash::hash(format!("message #{}", no).as_bytes()),
+            );
+        }
+        batch
+    }
+
+    #[test]
+    fn test_batch_anchors() {
+        let template = gen_template();
+        let batch = gen_batch(8);
+        let (finalized, anchors) = batch.finalize(&template).unwrap();
+        assert_eq!(anchors.len(), 8);
+
+        let width = anchors.values().next().unwrap().mpc_proof.width;
+        assert!(width >= 8);
+        let mut slots = anchors
+            .keys()
+            .map(|tag| lnpbp4::protocol_slot(tag, width))
+            .collect::<Vec<_>>();
+        slots.sort_unstable();
+        slots.dedup();
+        assert_eq!(slots.len(), 8);
+
+        for (tag, message) in &batch.0.messages {
+            let anchor = &anchors[tag];
+            assert_eq!(anchor.protocol_tag, *tag);
+            assert_eq!(anchor.mpc_proof.width, width);
+            assert_eq!(anchor.anchor.txid, finalized.tx.txid());
+            assert_eq!(
+                anchor.anchor.commitment_output as usize,
+                finalized.output_index
+            );
+            assert_eq!(anchor.verify(&finalized.tx, message), Ok(true));
+            // The template message is not committed to
+            assert_eq!(
+                anchor.anchor.verify(&finalized.tx, &template.msg),
+                Ok(false)
+            );
+        }
+
+        // Anchors of the other protocols do not verify the message
+        for (tag, message) in &batch.0.messages {
+            for (other, anchor) in &anchors {
+                if other == tag {
+                    continue;
+                }
+                assert_eq!(anchor.verify(&finalized.tx, message), Ok(false));
+
+                let mut forged = anchor.clone();
+                forged.protocol_tag = *tag;
+                assert_eq!(forged.verify(&finalized.tx, message), Ok(false));
+            }
+        }
+
+        // Protocol absent from the batch can't be verified
+        let outsider = ProtocolTag::new("outsider");
+        let message = batch.0.messages.values().next().unwrap();
+        let mut forged = anchors.values().next().unwrap().clone();
+        forged.protocol_tag = outsider;
+        assert_eq!(forged.verify(&finalized.tx, message), Ok(false));
+
+        // Anchor must reference MPC commitment
+        let mut forged = anchors.values().next().unwrap().clone();
+        forged.anchor.protocol_tag = outsider;
+        assert_eq!(forged.verify(&finalized.tx, message), Ok(false));
+    }
+
+    #[test]
+    fn test_batch_failures() {
+        let template = gen_template();
+        assert_eq!(
+            AnchorBatch::new().finalize(&template),
+            Err(Error::EmptyBatch)
+        );
+
+        let batch = gen_batch(3);
+        let (finalized, anchors) = batch.finalize(&template).unwrap();
+        let (tag, anchor) = anchors.into_iter().next().unwrap();
+        let message = batch.0.messages[&tag];
+
+        let mut malformed = anchor.clone();
+        malformed.mpc_proof.path.pop();
+        assert_eq!(
+            malformed.verify(&finalized.tx, &message),
+            Err(Error::Lnpbp4(lnpbp4::Error::InvalidProof))
+        );
+        malformed.mpc_proof.width = 0;
+        malformed.mpc_proof.path = vec![];
+        assert_eq!(malformed.verify(&finalized.tx, &message), Ok(false));
+    }
+
+    #[test]
+    fn test_batch_entropy() {
+        let template = gen_template();
+        let batch = gen_batch(5);
+        let (_, anchors) = batch.finalize(&template).unwrap();
+
+        // Single protocol batch has a single-leaf tree with no path
+        let (tx, single) = gen_batch(1).finalize(&template).unwrap();
+        let anchor = single.values().next().unwrap();
+        assert_eq!(anchor.mpc_proof.width, 1);
+        assert!(anchor.mpc_proof.path.is_empty());
+        assert_eq!(
+            anchor.verify(
+                &tx.tx,
+                gen_batch(1).0.messages.values().next().unwrap()
+            ),
+            Ok(true)
+        );
+
+        // Unoccupied slots depend on the entropy
+        let mut other = batch.clone();
+        other.0.entropy = 0;
+        let (_, other_anchors) = other.finalize(&template).unwrap();
+        assert_ne!(anchors, other_anchors);
+    }
+}
@@ -0,0 +1,253 @@
+// BP Core Library implementing LNP/BP specifications & standards related to
+// bitcoin protocol
+//
+// Written in 2020-2021 by
+//     Dr. Maxim Orlovsky <orlovsky@pandoracore.com>
+//
+// To the extent possible under law, the author(s) have dedicated all
+// copyright and related and neighboring rights to this software to
+// the public domain worldwide. This software is distributed without
+// any warranty.
+//
+// You should have received a copy of the Apache 2.0 License
+// along with this software.
+// If not, see <https://opensource.org/licenses/Apache-2.0>.
+
+//! Bundles of deterministic bitcoin commitments made to multiple outputs of
+//! a single transaction.
+
+use std::io;
+
+use amplify::Wrapper;
+use bitcoin::Transaction;
+#[cfg(feature = "parallel")]
+use rayon::prelude::*;
+use strict_encoding::{StrictDecode, StrictEncode};
+
+use crate::{Error, Proof, ProtocolTag, TxoutCommitment};
+
+/// Set of commitments made to different outputs of the same transaction
+#[derive(Clone, PartialEq, Eq, Debug, Default)]
+pub struct CommitmentBundle {
+    /// Commitments with the indexes of the transaction outputs containing
+    /// them and proofs required for their verification
+    pub commitments: Vec<(u32, TxoutCommitment, Proof)>,
+}
+
+impl CommitmentBundle {
+    /// Verifies all commitments from the bundle against the transaction
+    /// outputs, returning verification status for each of the commitments.
+    /// Messages must be provided in the same order as the commitments.
+    /// Commitments are verified in parallel if the crate is compiled with
+    /// `parallel` feature.
+    ///
+    /// Commitment fails verification if the transaction output does not match
+    /// the output stored in the bundle, or if the output does not commit to
+    /// the message (see [`TxoutCommitment::verify_txout`]).
+    ///
+    /// Fails with [`Error::MessageCountMismatch`] if the number of messages
+    /// differs from the number of commitments, with [`Error::OutputNotFound`]
+    /// if the transaction has no output referenced by the bundle, or if some
+    /// of the proofs are structurally incompatible with the outputs.
+    pub fn verify_all<MSG>(
+        &self,
+        tx: &Transaction,
+        tag: &ProtocolTag,
+        messages: &[MSG],
+    ) -> Result<Vec<bool>, Error>
+    where
+        MSG: AsRef<[u8]> + Sync,
+    {
+        if messages.len() != self.commitments.len() {
+            return Err(Error::MessageCountMismatch {
+                commitments: self.commitments.len(),
+                messages: messages.len(),
+            });
+        }
+
+        #[cfg(not(feature = "parallel"))]
+        let items = self.commitments.iter().zip(messages.iter());
+        #[cfg(feature = "parallel")]
+        let items = self.commitments.par_iter().zip(messages.par_iter());
+
+        items
+            .map(|((vout, commitment, proof), msg)| {
+                let txout = tx
+                    .output
+                    .get(*vout as usize)
+                    .ok_or(Error::OutputNotFound(*vout))?;
+                if txout != commitment.as_inner() {
+                    return Ok(false);
+                }
+                TxoutCommitment::verify_txout(
+                    txout,
+                    commitment.as_inner().value,
+                    proof,
+                    tag,
+                    msg,
+                )
+            })
+            .collect()
+    }
+}
+
+impl StrictEncode for CommitmentBundle {
+    fn strict_encode<E: io::Write>(
+        &self,
+        mut e: E,
+    ) -> Result<usize, strict_encoding::Error> {
+        // Same encoding as for `Vec<(u32, (TxoutCommitment, Proof))>`, used
+        // by the decoder, but without copying the commitments and proofs
+        let mut len = self.commitments.len().strict_encode(&mut e)?;
+        for (vout, commitment, proof) in &self.commitments {
+            len += vout.strict_encode(&mut e)?;
+            len += commitment.strict_encode(&mut e)?;
+            len += proof.strict_encode(&mut e)?;
+        }
+        Ok(len)
+    }
+}
+
+impl StrictDecode for CommitmentBundle {
+    fn strict_decode<D: io::Read>(
+        d: D,
+    ) -> Result<Self, strict_encoding::Error> {
+        let commitments =
+            Vec::<(u32, (TxoutCommitment, Proof))>::strict_decode(d)?
+                .into_iter()
+                .map(|(vout, (commitment, proof))| (vout, commitment, proof))
+                .collect();
+        Ok(CommitmentBundle { commitments })
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::str::FromStr;
+
+    use bitcoin::hashes::{sha256, Hash};
+    use bitcoin::{secp256k1, OutPoint, TxIn, TxOut};
+    use commit_verify::EmbedCommitVerify;
+
+    use super::*;
+    use crate::{
+        Container, ScriptEncodeData, ScriptEncodeMethod, TxoutContainer,
+    };
+
+    fn gen_bundle(tag: &ProtocolTag) -> (CommitmentBundle, Transaction) {
+        let pubkey = secp256k1::PublicKey::from_str(
+            "0218845781f631c48f1c9709e23092067d06837f30aa0cd0544ac887fe91ddd166",
+        )
+        .unwrap();
+        let cases = vec![
+            (
+                ScriptEncodeData::SinglePubkey,
+                ScriptEncodeMethod::WPubkeyHash,
+            ),
+            (
+                ScriptEncodeData::Taproot(sha256::Hash::hash(b"root")),
+                ScriptEncodeMethod::Taproot,
+            ),
+            (
+                ScriptEncodeData::SinglePubkey,
+                ScriptEncodeMethod::PubkeyHash,
+            ),
+        ];
+        let mut tx = Transaction {
+            version: 2,
+            lock_time: 0,
+            input: vec![TxIn {
+                previous_output: OutPoint::default(),
+                script_sig: Default::default(),
+                sequence: 0,
+                witness: vec![],
+            }],
+            output: vec![TxOut {
+                value: 5000,
+                script_pubkey: Default::default(),
+            }],
+        };
+        let mut bundle = CommitmentBundle::default();
+        for (no, (source, method)) in cases.into_iter().enumerate() {
+            let mut container =
+                TxoutContainer::construct(tag, 1000, pubkey, source, method);
+            let commitment = TxoutCommitment::embed_commit(
+                &mut container,
+                &format!("message {}", no),
+            )
+            .unwrap();
+            tx.output.push(commitment.as_inner().clone());
+            bundle.commitments.push((
+                no as u32 + 1,
+                commitment,
+                container.to_proof(),
+            ));
+        }
+        (bundle, tx)
+    }
+
+    #[test]
+    fn test_verify_all() {
+        let tag = ProtocolTag::new("TEST_TAG");
+        let (bundle, tx) = gen_bundle(&tag);
+        let messages = ["message 0", "message 1", "message 2"];
+        assert_eq!(
+            bundle.verify_all(&tx, &tag, &messages),
+            Ok(vec![true, true, true])
+        );
+        assert_eq!(
+            bundle.verify_all(&tx, &tag, &["message 0", "other", "message 2"]),
+            Ok(vec![true, false, true])
+        );
+        assert_eq!(
+            bundle.verify_all(&tx, &tag, &messages[..2]),
+            Err(Error::MessageCountMismatch {
+                commitments: 3,
+                messages: 2
+            })
+        );
+
+        // Outputs are swapped in the transaction
+        let mut swapped = tx.clone();
+        swapped.output.swap(1, 3);
+        assert_eq!(
+            bundle.verify_all(&swapped, &tag, &messages),
+            Ok(vec![false, true, false])
+        );
+
+        let mut truncated = tx;
+        truncated.output.pop();
+        assert_eq!(
+            bundle.verify_all(&truncated, &tag, &messages),
+            Err(Error::OutputNotFound(3))
+        );
+    }
+
+    #[test]
+    fn test_strict_encoding() {
+        let tag = ProtocolTag::new("TEST_TAG");
+        let (bundle, _) = gen_bundle(&tag);
+        let data = bundle.strict_serialize().unwrap();
+        assert_eq!(
+            CommitmentBundle::strict_deserialize(&data).unwrap(),
+            bundle
+        );
+        assert_eq!(
+            data,
+            bundle
+                .commitments
+                .iter()
+                .map(|(vout, commitment, proof)| {
+                    (*vout, (commitment.clone(), proof.clone()))
+                })
+                .collect::<Vec<_>>()
+                .strict_serialize()
+                .unwrap()
+        );
+
+        let empty = CommitmentBundle::default();
+        let data = empty.strict_serialize().unwrap();
+        assert_eq!(data, vec![0, 0]);
+        assert_eq!(CommitmentBundle::strict_deserialize(&data).unwrap(), empty);
+    }
+}
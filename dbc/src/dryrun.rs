@@ -0,0 +1,178 @@
+// BP Core Library implementing LNP/BP specifications & standards related to
+// bitcoin protocol
+//
+// Written in 2020-2021 by
+//     Dr. Maxim Orlovsky <orlovsky@pandoracore.com>
+//
+// To the extent possible under law, the author(s) have dedicated all
+// copyright and related and neighboring rights to this software to
+// the public domain worldwide. This software is distributed without
+// any warranty.
+//
+// You should have received a copy of the Apache 2.0 License
+// along with this software.
+// If not, see <https://opensource.org/licenses/Apache-2.0>.
+
+//! Simulation of the commitment procedure, which allows to check whether a
+//! commitment would succeed (and what it will be) before it is actually
+//! embedded into a container.
+
+use commit_verify::EmbedCommitVerify;
+
+use crate::{Container, Error, Proof};
+
+/// Wrapper around any [`Container`] for which the commitment procedure is
+/// only simulated: embedding commitment with [`DryRunCommitment`] runs on a
+/// copy of the inner container, leaving the wrapped container (including its
+/// tweaking factor) unchanged.
+#[derive(Clone, PartialEq, Eq, Hash, Debug)]
+pub struct DryRunContainer<C: Container>(C);
+
+impl<C: Container> DryRunContainer<C> {
+    /// Wraps the container for the simulated commitments
+    #[inline]
+    pub fn new(container: C) -> Self { DryRunContainer(container) }
+
+    /// Returns reference to the wrapped container
+    #[inline]
+    pub fn as_inner(&self) -> &C { &self.0 }
+
+    /// Returns the wrapped container
+    #[inline]
+    pub fn into_inner(self) -> C { self.0 }
+}
+
+impl<C: Container> From<C> for DryRunContainer<C> {
+    #[inline]
+    fn from(container: C) -> Self { DryRunContainer(container) }
+}
+
+impl<C: Container> Container for DryRunContainer<C> {
+    type Supplement = C::Supplement;
+    type Host = C::Host;
+
+    fn reconstruct(
+        proof: &Proof,
+        supplement: &Self::Supplement,
+        host: &Self::Host,
+    ) -> Result<Self, Error> {
+        C::reconstruct(proof, supplement, host).map(DryRunContainer)
+    }
+
+    #[inline]
+    fn deconstruct(self) -> (Proof, Self::Supplement) { self.0.deconstruct() }
+
+    #[inline]
+    fn supplement(&self) -> &Self::Supplement { self.0.supplement() }
+
+    #[inline]
+    fn to_proof(&self) -> Proof { self.0.to_proof() }
+
+    #[inline]
+    fn into_proof(self) -> Proof { self.0.into_proof() }
+}
+
+/// Commitment produced by the simulated commitment procedure over
+/// [`DryRunContainer`]; it is equal to the commitment which would be produced
+/// by the real procedure over the wrapped container.
+#[derive(Clone, PartialEq, Eq, Hash, Debug)]
+pub struct DryRunCommitment<T>(T);
+
+impl<T> DryRunCommitment<T> {
+    /// Returns reference to the commitment
+    #[inline]
+    pub fn as_inner(&self) -> &T { &self.0 }
+
+    /// Returns the commitment
+    #[inline]
+    pub fn into_inner(self) -> T { self.0 }
+}
+
+impl<MSG, T> EmbedCommitVerify<MSG> for DryRunCommitment<T>
+where
+    T: EmbedCommitVerify<MSG>,
+    T::Container: Container,
+{
+    type Container = DryRunContainer<T::Container>;
+    type Error = T::Error;
+
+    fn embed_commit(
+        container: &mut Self::Container,
+        msg: &MSG,
+    ) -> Result<Self, Self::Error> {
+        let mut container = container.as_inner().clone();
+        T::embed_commit(&mut container, msg).map(DryRunCommitment)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::lnpbp1::test_helpers::*;
+    use crate::{
+        ProtocolTag, PubkeyCommitment, PubkeyContainer, ScriptEncodeData,
+        ScriptEncodeMethod, SpkCommitment, SpkContainer, TxoutCommitment,
+        TxoutContainer,
+    };
+
+    #[test]
+    fn test_dry_run() {
+        let tag = ProtocolTag::new("TEST_TAG");
+        for pubkey in gen_secp_pubkeys(3) {
+            let container = PubkeyContainer::with_tag_name(pubkey, "TEST_TAG");
+            let mut dry_run = DryRunContainer::new(container.clone());
+            let simulated = DryRunCommitment::<PubkeyCommitment>::embed_commit(
+                &mut dry_run,
+                &"message",
+            )
+            .unwrap();
+            assert_eq!(dry_run.as_inner(), &container);
+            assert_eq!(dry_run.as_inner().tweaking_factor, None);
+            let mut real = container.clone();
+            let commitment =
+                PubkeyCommitment::embed_commit(&mut real, &"message").unwrap();
+            assert_eq!(simulated.as_inner(), &commitment);
+            assert_ne!(real, container);
+            assert_eq!(simulated.verify(&dry_run, &"message"), Ok(true));
+            assert_eq!(simulated.verify(&dry_run, &"other"), Ok(false));
+
+            let container = TxoutContainer::construct(
+                &tag,
+                1000,
+                pubkey,
+                ScriptEncodeData::SinglePubkey,
+                ScriptEncodeMethod::WPubkeyHash,
+            );
+            let mut dry_run = DryRunContainer::from(container.clone());
+            let simulated = DryRunCommitment::<TxoutCommitment>::embed_commit(
+                &mut dry_run,
+                &"message",
+            )
+            .unwrap();
+            assert_eq!(dry_run.clone().into_inner(), container);
+            let commitment = TxoutCommitment::embed_commit(
+                &mut container.clone(),
+                &"message",
+            )
+            .unwrap();
+            assert_eq!(simulated.into_inner(), commitment);
+            assert_eq!(dry_run.to_proof(), container.to_proof());
+        }
+
+        // Failures are reported in the same way as by the real procedure
+        let container = SpkContainer::construct(
+            &tag,
+            gen_secp_pubkeys(1)[0],
+            ScriptEncodeData::SinglePubkey,
+            ScriptEncodeMethod::Bare,
+        );
+        assert_eq!(
+            DryRunCommitment::<SpkCommitment>::embed_commit(
+                &mut DryRunContainer::new(container.clone()),
+                &"message",
+            )
+            .map(DryRunCommitment::into_inner),
+            SpkCommitment::embed_commit(&mut container.clone(), &"message")
+        );
+    }
+}
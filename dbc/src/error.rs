@@ -13,7 +13,7 @@
 // along with this software.
 // If not, see <https://opensource.org/licenses/Apache-2.0>.
 
-use crate::lnpbp1;
+use crate::{lnpbp1, lnpbp4, ProtocolTag};
 
 /// Different error types which may happen during deterministic bitcoin
 /// commitment generation procedures
@@ -24,6 +24,12 @@ pub enum Error {
     #[from]
     Lnpbp1Commitment(lnpbp1::Error),
 
+    /// Indicates failure of multi-protocol commitment tree construction or
+    /// verification
+    #[from]
+    #[display(inner)]
+    Lnpbp4(lnpbp4::Error),
+
     /// Unable to verify commitment due to an incorrect proof data structure
     InvalidProofStructure,
 
@@ -58,6 +64,16 @@ pub enum Error {
     /// any of the script branches
     LockscriptKeyNotFound,
 
+    /// Multisig script can't require {m} signatures out of {n} keys; the
+    /// number of required signatures must be non-zero and must not exceed the
+    /// number of keys, which must not exceed 16
+    InvalidMultisig {
+        /// Number of required signatures
+        m: usize,
+        /// Number of keys
+        n: usize,
+    },
+
     /// Policy compilation error
     #[from]
     #[display(inner)]
@@ -65,6 +81,169 @@ pub enum Error {
 
     /// Deterministic bitcoin commitments require use of compressed public keys
     UncompressedKey,
+
+    /// Descriptor does not contain the public key which must hold the
+    /// commitment
+    DescriptorKeyNotFound,
+
+    /// BIP-341 taproot tweak results in an invalid output key; you may try
+    /// with a different script tree or a different message
+    InvalidTaprootTweak,
+
+    /// Only P2WSH `scriptPubkey` can be wrapped into P2SH
+    NotWScriptHash,
+
+    /// Container does not contain tweaking factor: the commitment must be
+    /// embedded first
+    NoTweakingFactor,
+
+    /// PSBT output does not contain a valid taproot internal key
+    PsbtNoTapInternalKey,
+
+    /// PSBT output does not contain commitment proof for the protocol
+    PsbtNoProof,
+
+    /// Taproot script tree is absent or has invalid structure
+    InvalidTapTree,
+
+    /// Taproot script tree does not contain the requested leaf
+    TapLeafNotFound,
+
+    /// Taproot control block has invalid length, leaf version or internal key
+    InvalidControlBlock,
+
+    /// Merkle root of the taproot script tree computed from the control block
+    /// does not match the script root in the commitment proof
+    ScriptRootMismatch,
+
+    /// Taproot internal key from the control block does not match the key
+    /// produced by the commitment procedure from the proof and the message
+    InternalKeyMismatch,
+
+    /// Output value {value} is below the dust limit of {required} satoshis
+    DustOutput {
+        /// Output value
+        value: u64,
+        /// Dust limit for the output script type
+        required: u64,
+    },
+
+    /// Transaction inputs provide {available} satoshis, which is less than
+    /// {required} satoshis required to pay the outputs and the fee
+    InsufficientFunds {
+        /// Total value of the transaction inputs
+        available: u64,
+        /// Total value of the outputs together with the fee
+        required: u64,
+    },
+
+    /// All {0} transaction outputs are reserved, so none of them may contain
+    /// the commitment
+    AllOutputsReserved(usize),
+
+    /// transaction template can't place the commitment into OP_RETURN output,
+    /// since the commitment output receives the change
+    OpReturnChange,
+
+    /// OP_RETURN output has non-zero value of {0} satoshis, which is not
+    /// standard and would not be relayed by the network
+    NonZeroOpReturnValue(u64),
+
+    /// Transaction has no output #{0} referenced by the commitment bundle
+    OutputNotFound(u32),
+
+    /// Number of messages ({messages}) does not match the number of
+    /// commitments ({commitments}) in the bundle
+    MessageCountMismatch {
+        /// Number of commitments in the bundle
+        commitments: usize,
+        /// Number of provided messages
+        messages: usize,
+    },
+
+    /// Commitment is found in multiple transaction outputs (#{first} and
+    /// #{second}), which indicates malformed transaction
+    MultipleMatches {
+        /// Index of the first output containing the commitment
+        first: usize,
+        /// Index of the second output containing the commitment
+        second: usize,
+    },
+
+    /// Commitments of protocols {tag_a} and {tag_b} are designated to the
+    /// same transaction output #{index}
+    OutputCollision {
+        /// Tag of the first protocol
+        tag_a: ProtocolTag,
+        /// Tag of the second protocol
+        tag_b: ProtocolTag,
+        /// Index of the output designated to both protocols
+        index: usize,
+    },
+
+    /// Output `scriptPubkey` does not match the provided public key or script
+    /// data
+    ScriptMismatch,
+
+    /// Commitment data are not a valid strict encoding of the commitment
+    InvalidCommitmentEncoding,
+
+    /// Output #{index} designated for the commitment is out of range of
+    /// {outputs} transaction outputs
+    OutputIndexOutOfRange {
+        /// Index of the designated output
+        index: usize,
+        /// Number of the transaction outputs
+        outputs: usize,
+    },
+
+    /// Address does not match the provided public key or script data
+    AddressMismatch,
+
+    /// Data are not a valid consensus serialization of a transaction
+    TxDecode,
+
+    /// Serialized transaction size of {size} bytes exceeds the limit of
+    /// {max_size} bytes
+    TxTooLarge {
+        /// Size of the serialized transaction
+        size: usize,
+        /// Maximal allowed size
+        max_size: usize,
+    },
+
+    /// Coinbase transaction has no fee, so LNPBP-3 commitment output must be
+    /// selected with zero fee instead of {0} satoshis
+    CoinbaseFee(u64),
+
+    /// Transaction is not a coinbase transaction
+    NotCoinbase,
+
+    /// Partial merkle tree is malformed
+    InvalidMerkleProof,
+
+    /// Merkle root of the partial merkle tree does not match the merkle root
+    /// of the block header
+    MerkleRootMismatch,
+
+    /// Transaction is not included into the partial merkle tree
+    TxNotIncluded,
+
+    /// `scriptPubkey` matches multiple commitment encoding methods, so the
+    /// method can't be detected automatically and must be provided explicitly
+    AmbiguousScriptType,
+
+    /// Commitment batch contains no messages
+    EmptyBatch,
+
+    /// Address belongs to {actual} network while {expected} network was
+    /// expected
+    NetworkMismatch {
+        /// Network the address was expected to belong to
+        expected: bitcoin::Network,
+        /// Actual address network
+        actual: bitcoin::Network,
+    },
 }
 
 impl From<descriptors::Error> for Error {
@@ -22,7 +22,7 @@ use bitcoin::secp256k1;
 use commit_verify::EmbedCommitVerify;
 use miniscript::Segwitv0;
 
-use super::{Container, Error, Proof, ScriptEncodeData};
+use super::{Container, Error, Proof, ProtocolTag, ScriptEncodeData};
 use crate::lnpbp1;
 
 /// Container for LNPBP-1 commitments. In order to be constructed, commitment
@@ -37,7 +37,7 @@ pub struct KeysetContainer {
     /// Other keys that will participate the commitment procedure
     pub keyset: BTreeSet<secp256k1::PublicKey>,
     /// Single SHA256 hash of the protocol-specific tag
-    pub tag: sha256::Hash,
+    pub tag: ProtocolTag,
     /// Tweaking factor stored after [`KeysetCommitment::embed_commit`]
     /// procedure
     pub tweaking_factor: Option<Hmac<sha256::Hash>>,
@@ -45,7 +45,7 @@ pub struct KeysetContainer {
 
 impl Container for KeysetContainer {
     /// Out supplement is a protocol-specific tag in its hashed form
-    type Supplement = sha256::Hash;
+    type Supplement = ProtocolTag;
 
     /// Proof contains both original public key and all participating keys
     /// (inside it's script), so we don't need host here
@@ -56,7 +56,9 @@ impl Container for KeysetContainer {
         supplement: &Self::Supplement,
         _: &Self::Host,
     ) -> Result<Self, Error> {
-        if let ScriptEncodeData::LockScript(ref script) = proof.source {
+        if let ScriptEncodeData::LockScript(ref script)
+        | ScriptEncodeData::NestedLockScript(ref script) = proof.source
+        {
             Ok(Self {
                 pubkey: proof.pubkey,
                 keyset: script
@@ -81,6 +83,8 @@ impl Container for KeysetContainer {
     /// support proof generation, use more advanced structures like LockScript
     /// container to generate the proof
     #[inline]
+    fn supplement(&self) -> &Self::Supplement { &self.tag }
+
     fn to_proof(&self) -> Proof {
         panic!("KeysetContainer does not support proof generation")
     }
@@ -121,7 +125,7 @@ where
         let tweaking_factor = lnpbp1::commit(
             &mut keyset,
             &mut pubkey,
-            &keyset_container.tag,
+            &keyset_container.tag.to_hash(),
             msg,
         )?;
 
@@ -139,7 +143,6 @@ mod test {
 
     use amplify::hex::ToHex;
     use amplify::Wrapper;
-    use bitcoin::hashes::{sha256, Hash};
     use bitcoin::secp256k1;
 
     use super::*;
@@ -148,7 +151,7 @@ mod test {
 
     #[test]
     fn test_lnpbp1_vs_lnpbp2() {
-        let tag = sha256::Hash::hash(b"TEST_TAG2");
+        let tag = ProtocolTag::new("TEST_TAG2");
         let msg = "test message";
         gen_secp_pubkeys(9).into_iter().for_each(|pubkey| {
             let lnpbp1_commitment = PubkeyCommitment::embed_commit(
@@ -180,7 +183,7 @@ mod test {
 
     #[test]
     fn test_keyset_commitment() {
-        let tag = sha256::Hash::hash(b"TEST_TAG2");
+        let tag = ProtocolTag::new("TEST_TAG2");
         let pubkey = secp256k1::PublicKey::from_str(
             "0218845781f631c48f1c9709e23092067d06837f30aa0cd0544ac887fe91ddd166",
         )
@@ -200,7 +203,7 @@ mod test {
 
     #[test]
     fn test_keyset_tweaking_results() {
-        let tag = sha256::Hash::hash(b"TEST_TAG2");
+        let tag = ProtocolTag::new("TEST_TAG2");
         let msg = "test message";
         let pubkey = secp256k1::PublicKey::from_str(
             "0218845781f631c48f1c9709e23092067d06837f30aa0cd0544ac887fe91ddd166",
@@ -25,25 +25,57 @@ extern crate strict_encoding;
 #[macro_use]
 extern crate serde_crate as serde;
 
+pub mod anchor;
+pub mod batch;
+pub mod bundle;
+pub mod dryrun;
 mod error;
 pub mod keyset;
 pub mod lnpbp1;
+pub mod lnpbp2;
+pub mod lnpbp3;
+pub mod lnpbp4;
 pub mod lockscript;
 pub mod pubkey;
 pub mod spk;
 pub mod taproot;
+pub mod taptree;
+pub mod template;
+#[cfg(test)]
+pub mod test_helpers;
 pub mod tx;
 pub mod txout;
 pub mod types;
 
+pub use anchor::{Anchor, AnchorId, AnchorIdTag, ANCHOR_ID_TAG};
+pub use batch::{AnchorBatch, BatchAnchor};
+pub use bundle::CommitmentBundle;
+pub use dryrun::{DryRunCommitment, DryRunContainer};
 pub use error::Error;
 pub use keyset::{KeysetCommitment, KeysetContainer};
-pub use lockscript::{LockscriptCommitment, LockscriptContainer};
+pub use lockscript::{
+    LockscriptCommitment, LockscriptContainer, LockscriptError, TryFromScript,
+};
 pub use pubkey::{PubkeyCommitment, PubkeyContainer};
 pub use spk::{
-    ScriptEncodeData, ScriptEncodeMethod, SpkCommitment, SpkContainer,
+    AddressType, ScriptEncodeData, ScriptEncodeMethod, SpkCommitment,
+    SpkContainer,
+};
+pub use taproot::{
+    combined_tweak, lift_xonly_key, Parity, SpendTweak, TapleafCommitment,
+    TaprootCommitment, TaprootContainer, TweakChain,
+};
+pub use taptree::{ControlBlock, TapLeaf, TapTree};
+pub use template::{FinalizedTx, TxTemplate};
+pub use tx::{
+    find_commitment_output, tx_fee, FeeError, TxCommitment, TxContainer,
+    TxMultiCommitment, TxSupplement,
+};
+pub use txout::{
+    DustError, DustPolicy, TxoutCommitment, TxoutContainer, ValueSource,
+    MAX_MONEY,
+};
+pub use types::{
+    Container, Proof, ProofId, ProofIdTag, ProofParseError, ProtocolTag,
+    VerifyEncoded, LNPBP1_TAG, LNPBP2_TAG, PROOF_ID_TAG,
 };
-pub use taproot::{TaprootCommitment, TaprootContainer};
-pub use tx::{TxCommitment, TxContainer, TxSupplement};
-pub use txout::{TxoutCommitment, TxoutContainer};
-pub use types::{Container, Proof};
@@ -13,10 +13,17 @@
 // along with this software.
 // If not, see <https://opensource.org/licenses/Apache-2.0>.
 
+use std::cell::Cell;
 use std::collections::BTreeSet;
 
-use bitcoin::hashes::{sha256, Hash, HashEngine, Hmac, HmacEngine};
+use bitcoin::hashes::{hash160, sha256, Hash, HashEngine, Hmac, HmacEngine};
 use bitcoin::secp256k1;
+#[cfg(feature = "musig2")]
+use bitcoin::secp256k1::schnorrsig;
+use bitcoin::util::bip32::ExtendedPubKey;
+
+#[cfg(feature = "musig2")]
+use crate::taproot::lift_xonly_key;
 
 /// Single SHA256 hash of "LNPBP1" string according to LNPBP-1 acting as a
 /// prefix to the message in computing tweaking factor
@@ -25,28 +32,146 @@ pub static LNPBP1_HASHED_TAG: [u8; 32] = [
     212, 149, 78, 46, 201, 252, 82, 171, 140, 204, 209, 41, 17, 12, 0, 64, 175,
 ];
 
+thread_local! {
+    /// Secp256k1 context provided with [`with_secp_context`] for the current
+    /// thread
+    static SECP_CONTEXT: Cell<Option<*const secp256k1::Secp256k1<secp256k1::All>>> =
+        Cell::new(None);
+}
+
+#[cfg(not(feature = "global-context"))]
+thread_local! {
+    /// Secp256k1 context used by the current thread when no context is
+    /// provided with [`with_secp_context`]. Context creation is expensive, so
+    /// it is created once per thread on the first use.
+    static SECP_FALLBACK: secp256k1::Secp256k1<secp256k1::All> =
+        secp256k1::Secp256k1::new();
+}
+
+/// Runs `f` with all of the elliptic curve operations of this crate in the
+/// current thread using the provided secp256k1 context instead of the global
+/// one (or a thread-local one, if the crate is compiled without
+/// `global-context` feature). The calls may be nested; the previous context is
+/// restored once `f` returns or panics.
+pub fn with_secp_context<F, R>(
+    ctx: &secp256k1::Secp256k1<secp256k1::All>,
+    f: F,
+) -> R
+where
+    F: FnOnce() -> R,
+{
+    struct Restore(Option<*const secp256k1::Secp256k1<secp256k1::All>>);
+    impl Drop for Restore {
+        fn drop(&mut self) { SECP_CONTEXT.with(|cell| cell.set(self.0)); }
+    }
+
+    let _restore =
+        Restore(SECP_CONTEXT.with(|cell| cell.replace(Some(ctx as *const _))));
+    f()
+}
+
+/// Runs `f` with the secp256k1 context provided with [`with_secp_context`],
+/// falling back to the global context (or to the thread-local one, if the
+/// crate is compiled without `global-context` feature)
+pub(crate) fn with_secp<R>(
+    f: impl FnOnce(&secp256k1::Secp256k1<secp256k1::All>) -> R,
+) -> R {
+    match SECP_CONTEXT.with(Cell::get) {
+        // Safety: the pointer is set only by `with_secp_context` for the
+        // duration of the call borrowing the context, and is cleared before
+        // the borrow ends
+        Some(ctx) => f(unsafe { &*ctx }),
+        #[cfg(feature = "global-context")]
+        None => f(secp256k1::SECP256K1),
+        #[cfg(not(feature = "global-context"))]
+        None => SECP_FALLBACK.with(f),
+    }
+}
+
 /// Deterministically-organized set of all public keys used by this mod
 /// internally
 type Keyset = BTreeSet<secp256k1::PublicKey>;
 
+/// Tweaking factor produced by the LNPBP-1 commitment procedure: HMAC-SHA256
+/// value which is added to the target public key
+pub type TweakingFactor = Hmac<sha256::Hash>;
+
+/// Version of the LNPBP-1 commitment algorithm implemented by this module,
+/// which prefixes versioned serialization of the tweaking factor (see
+/// [`VersionedTweakingFactor`])
+pub const LNPBP1_VERSION: u8 = 1;
+
+/// Serialization of the [`TweakingFactor`] prefixed with the version of the
+/// commitment algorithm which has produced it, allowing verifiers to detect
+/// factors produced by future revisions of LNPBP-1. The 32 bytes following
+/// the version byte are the same as in the unversioned serialization.
+pub trait VersionedTweakingFactor: Sized {
+    /// Serializes tweaking factor prefixed with [`LNPBP1_VERSION`]
+    fn serialize_versioned(&self) -> [u8; 33];
+
+    /// Deserializes tweaking factor produced by [`serialize_versioned`],
+    /// failing with [`Error::UnsupportedVersion`] for the versions other than
+    /// [`LNPBP1_VERSION`].
+    ///
+    /// [`serialize_versioned`]: VersionedTweakingFactor::serialize_versioned
+    fn deserialize_versioned(bytes: &[u8; 33]) -> Result<Self, Error>;
+}
+
+impl VersionedTweakingFactor for TweakingFactor {
+    fn serialize_versioned(&self) -> [u8; 33] {
+        let mut bytes = [0u8; 33];
+        bytes[0] = LNPBP1_VERSION;
+        bytes[1..].copy_from_slice(&self[..]);
+        bytes
+    }
+
+    fn deserialize_versioned(bytes: &[u8; 33]) -> Result<Self, Error> {
+        if bytes[0] != LNPBP1_VERSION {
+            return Err(Error::UnsupportedVersion(bytes[0]));
+        }
+        Ok(TweakingFactor::from_slice(&bytes[1..])
+            .expect("32-byte slice is always a valid HMAC-SHA256 value"))
+    }
+}
+
 /// Errors that may happen during LNPBP-1 commitment procedure or because of
 /// incorrect arguments provided to [`commit()`] function.
 #[derive(Clone, Copy, PartialEq, Eq, Debug, Display, Error, From)]
 #[display(doc_comments)]
 pub enum Error {
-    /// Keyset must include target public key, but no target key found it
-    /// the provided set.
+    /// target public key is not a member of the keyset; add the target key
+    /// to the keyset before committing
     NotKeysetMember,
 
-    /// Elliptic curve point addition resulted in point in infinity; you
-    /// must select different source public keys
+    /// keyset sum produced the point at infinity (some of the keys negate
+    /// the others); remove one of the keys or change the keyset composition
     SumInfiniteResult,
 
-    /// LNPBP-1 commitment either is outside of Secp256k1 order `n` (this event
-    /// has negligible probability <~2^-64), or, when added to the provided
-    /// keyset, results in point at infinity. You may try with a different
-    /// source message or public keys.
+    /// commitment tweak is outside of Secp256k1 order `n` (this event has
+    /// negligible probability <~2^-64), or, when added to the target public
+    /// key, results in the point at infinity; try committing with a different
+    /// message or public keys
     InvalidTweak,
+
+    /// tweaking factor is produced by unsupported version {0} of LNPBP-1
+    /// commitment algorithm
+    UnsupportedVersion(u8),
+
+    /// aggregated public key does not match MuSig2 aggregation of the
+    /// participant keys
+    #[cfg(feature = "musig2")]
+    AggregatedKeyMismatch,
+}
+
+impl From<Error> for bitcoin::util::key::Error {
+    /// Maps [`Error::InvalidTweak`] to the secp256k1 tweak error; the rest of
+    /// the errors are reported as an invalid public key
+    fn from(err: Error) -> Self {
+        bitcoin::util::key::Error::Secp256k1(match err {
+            Error::InvalidTweak => secp256k1::Error::InvalidTweak,
+            _ => secp256k1::Error::InvalidPublicKey,
+        })
+    }
 }
 
 /// Function performs commitment procedure according to LNPBP-1.
@@ -97,20 +222,299 @@ pub fn commit(
     target_pubkey: &mut secp256k1::PublicKey,
     protocol_tag: &sha256::Hash,
     message: &impl AsRef<[u8]>,
-) -> Result<Hmac<sha256::Hash>, Error> {
+) -> Result<TweakingFactor, Error> {
+    if !keyset.remove(target_pubkey) {
+        return Err(Error::NotKeysetMember);
+    }
+
+    let pubkey_sum = pubkey_sum(keyset, target_pubkey)?;
+
+    let tweaking_factor =
+        tweak(pubkey_sum, target_pubkey, protocol_tag, message)?;
+
+    keyset.insert(*target_pubkey);
+
+    // Returning tweaked public key
+    Ok(tweaking_factor)
+}
+
+/// Intermediate values produced during LNPBP-1 commitment procedure, returned
+/// by [`commit_traced()`] for debugging purposes
+#[cfg(feature = "diagnostics")]
+#[derive(Clone, PartialEq, Eq, Debug)]
+pub struct CommitmentTrace {
+    /// Sum of all keyset public keys, used as HMAC key
+    pub pubkey_sum: secp256k1::PublicKey,
+    /// Data consumed by HMAC engine: LNPBP-1 tag hash, protocol-specific tag
+    /// hash and message hash
+    pub hmac_input: Vec<u8>,
+    /// Tweaking factor, i.e. HMAC value
+    pub tweaking_factor: TweakingFactor,
+    /// Target public key with the tweaking factor applied
+    pub tweaked_pubkey: secp256k1::PublicKey,
+}
+
+/// Function performs commitment procedure according to LNPBP-1 in the same
+/// way as [`commit()`], additionally returning all intermediate values
+/// computed during the procedure as [`CommitmentTrace`].
+///
+/// Parameters and errors are the same as for [`commit()`].
+#[cfg(feature = "diagnostics")]
+pub fn commit_traced(
+    keyset: &mut Keyset,
+    target_pubkey: &mut secp256k1::PublicKey,
+    protocol_tag: &sha256::Hash,
+    message: &impl AsRef<[u8]>,
+) -> Result<(TweakingFactor, CommitmentTrace), Error> {
     if !keyset.remove(target_pubkey) {
         return Err(Error::NotKeysetMember);
     }
 
+    let pubkey_sum = pubkey_sum(keyset, target_pubkey)?;
+    let tweaking_factor =
+        tweak(pubkey_sum, target_pubkey, protocol_tag, message)?;
+    keyset.insert(*target_pubkey);
+
+    // This must match the data consumed by HMAC engine in `tweak`
+    let mut hmac_input = Vec::with_capacity(96);
+    hmac_input.extend(&LNPBP1_HASHED_TAG[..]);
+    hmac_input.extend(&protocol_tag[..]);
+    hmac_input.extend(&sha256::Hash::hash(message.as_ref())[..]);
+
+    Ok((tweaking_factor, CommitmentTrace {
+        pubkey_sum,
+        hmac_input,
+        tweaking_factor,
+        tweaked_pubkey: *target_pubkey,
+    }))
+}
+
+/// Sums target public key with the rest of the keyset keys (not including the
+/// target key)
+fn pubkey_sum(
+    keyset: &Keyset,
+    target_pubkey: &secp256k1::PublicKey,
+) -> Result<secp256k1::PublicKey, Error> {
     // ! [CONSENSUS-CRITICAL]:
     // ! [STANDARD-CRITICAL]: We commit to the sum of all public keys,
     //                        not a single pubkey. For single key the set
     //                        is represented by itself
-    let pubkey_sum = keyset
+    keyset
         .iter()
         .try_fold(*target_pubkey, |sum, pubkey| sum.combine(pubkey))
-        .map_err(|_| Error::SumInfiniteResult)?;
+        .map_err(|_| Error::SumInfiniteResult)
+}
+
+/// Function performs commitment procedure according to LNPBP-1 taking
+/// protocol-specific tag in form of a raw 32-byte array (for instance, coming
+/// from FFI or deserialized data). The tag must be already hashed; the
+/// function is equivalent to [`commit()`] with the same tag wrapped into
+/// [`sha256::Hash`].
+///
+/// Parameters, return value and errors are the same as for [`commit()`].
+pub fn commit_raw_tag(
+    keyset: &mut Keyset,
+    target_pubkey: &mut secp256k1::PublicKey,
+    protocol_tag: &[u8; 32],
+    message: &impl AsRef<[u8]>,
+) -> Result<TweakingFactor, Error> {
+    commit(
+        keyset,
+        target_pubkey,
+        &sha256::Hash::from_inner(*protocol_tag),
+        message,
+    )
+}
+
+/// Function performs commitment procedure according to LNPBP-1 over keys
+/// provided by an iterator, avoiding allocation of the [`BTreeSet`] required
+/// by [`commit()`].
+///
+/// The iterator MUST yield unique keys in ascending order, i.e. in the same
+/// order as they would be stored in a [`BTreeSet`]; this is checked with a
+/// debug assertion only. The iterator must include `target_pubkey`.
+///
+/// Unlike [`commit()`], the function mutates only `target_pubkey`; the rest
+/// of parameters, return value and errors are the same as for [`commit()`].
+pub fn commit_sorted_iter(
+    sorted_keys: impl Iterator<Item = secp256k1::PublicKey>,
+    target_pubkey: &mut secp256k1::PublicKey,
+    protocol_tag: &sha256::Hash,
+    message: &impl AsRef<[u8]>,
+) -> Result<TweakingFactor, Error> {
+    let mut found = false;
+    let mut prev: Option<secp256k1::PublicKey> = None;
+    let mut pubkey_sum = Ok(*target_pubkey);
+    for pubkey in sorted_keys {
+        debug_assert!(
+            prev.map(|prev| prev < pubkey).unwrap_or(true),
+            "keys provided to `commit_sorted_iter` must be sorted and unique"
+        );
+        prev = Some(pubkey);
+        if !found && pubkey == *target_pubkey {
+            found = true;
+            continue;
+        }
+        // ! [CONSENSUS-CRITICAL]:
+        // ! [STANDARD-CRITICAL]: Keys are summed in the same order as in
+        //                        `commit`: target key first, followed by
+        //                        the rest of the keys in ascending order
+        pubkey_sum = pubkey_sum.and_then(|sum| sum.combine(&pubkey));
+    }
+    if !found {
+        return Err(Error::NotKeysetMember);
+    }
+    let pubkey_sum = pubkey_sum.map_err(|_| Error::SumInfiniteResult)?;
+
+    tweak(pubkey_sum, target_pubkey, protocol_tag, message)
+}
+
+/// Function performs commitment procedure according to LNPBP-1 to the public
+/// key of BIP-32 extended public key, which is considered to be a keyset
+/// consisting of a single key. The tweaked key replaces
+/// [`ExtendedPubKey::public_key`] in-place, while the chain code and the rest
+/// of the extended key fields are left untouched, so the child keys may be
+/// derived from the tweaked extended key in a usual way.
+///
+/// NB: Since the extended key fingerprint and identifier depend on the public
+/// key, the tweaked key has a different fingerprint, and the children
+/// derived from it will not match the children of the original key.
+///
+/// Return value and errors are the same as for [`commit()`]; if the function
+/// fails, `xpub` is not modified.
+pub fn commit_xpub(
+    xpub: &mut ExtendedPubKey,
+    protocol_tag: &sha256::Hash,
+    message: &impl AsRef<[u8]>,
+) -> Result<TweakingFactor, Error> {
+    let mut target_pubkey = xpub.public_key.key;
+    let tweaking_factor =
+        tweak(target_pubkey, &mut target_pubkey, protocol_tag, message)?;
+    xpub.public_key.key = target_pubkey;
+    Ok(tweaking_factor)
+}
+
+/// Function performs commitment procedure according to LNPBP-1 to the MuSig2
+/// aggregated x-only public key (like the ones used in Taproot key spending
+/// path). Unlike [`commit()`], which uses a simple sum of the keyset keys, the
+/// aggregated key is computed from the `participants` keys with MuSig2 key
+/// aggregation coefficients (see [`musig2_key_agg()`]); it is checked to
+/// match the provided `aggregated_key` and is used both as the HMAC key and as
+/// the tweaked key. The tweaked key replaces `aggregated_key` in-place.
+///
+/// **EXPERIMENTAL**: MuSig2 specification is still evolving, so the
+/// commitments produced by this function may become incompatible with the
+/// future versions of the specification and of this library.
+///
+/// # Errors
+///
+/// In addition to the errors returned by [`commit()`], fails with
+/// [`Error::AggregatedKeyMismatch`] if `aggregated_key` is not the MuSig2
+/// aggregation of `participants`. If the function fails, `aggregated_key` is
+/// not modified.
+#[cfg(feature = "musig2")]
+pub fn commit_musig2(
+    aggregated_key: &mut schnorrsig::PublicKey,
+    participants: &[schnorrsig::PublicKey],
+    protocol_tag: &sha256::Hash,
+    message: &impl AsRef<[u8]>,
+) -> Result<TweakingFactor, Error> {
+    if musig2_key_agg(participants)? != *aggregated_key {
+        return Err(Error::AggregatedKeyMismatch);
+    }
+    let mut target_pubkey = lift_xonly_key(*aggregated_key);
+    let tweaking_factor =
+        tweak(target_pubkey, &mut target_pubkey, protocol_tag, message)?;
+    *aggregated_key = schnorrsig::PublicKey::from(target_pubkey);
+    Ok(tweaking_factor)
+}
+
+/// Computes MuSig2 aggregated x-only public key from the list of participant
+/// keys. Each of the keys is multiplied by its key aggregation coefficient,
+/// `hash_KeyAggCoef(hash_KeyAggList(pk_1 || ... || pk_n) || pk_i) mod n`,
+/// except the second distinct key in the list, which has coefficient of 1.
+/// Thus, the order of the participant keys matters.
+///
+/// **EXPERIMENTAL**: this follows the x-only version of MuSig2 key
+/// aggregation draft, which may change in the future.
+///
+/// # Errors
+///
+/// Fails with [`Error::SumInfiniteResult`] if the list of participants is
+/// empty or the aggregation produces the point at infinity.
+#[cfg(feature = "musig2")]
+pub fn musig2_key_agg(
+    participants: &[schnorrsig::PublicKey],
+) -> Result<schnorrsig::PublicKey, Error> {
+    let mut engine = musig2_tagged_engine(b"KeyAgg list");
+    for pubkey in participants {
+        engine.input(&pubkey.serialize());
+    }
+    let list_hash = sha256::Hash::from_engine(engine);
+    let second = participants
+        .iter()
+        .skip(1)
+        .find(|pubkey| Some(*pubkey) != participants.first());
+
+    let mut aggregated: Option<secp256k1::PublicKey> = None;
+    for pubkey in participants {
+        let mut point = lift_xonly_key(*pubkey);
+        if Some(pubkey) != second {
+            let mut engine = musig2_tagged_engine(b"KeyAgg coefficient");
+            engine.input(&list_hash[..]);
+            engine.input(&pubkey.serialize());
+            let coefficient =
+                reduce_scalar(sha256::Hash::from_engine(engine).into_inner());
+            with_secp(|secp| point.mul_assign(secp, &coefficient))
+                .map_err(|_| Error::SumInfiniteResult)?;
+        }
+        aggregated = Some(match aggregated {
+            None => point,
+            Some(sum) => {
+                sum.combine(&point).map_err(|_| Error::SumInfiniteResult)?
+            }
+        });
+    }
+    aggregated
+        .map(schnorrsig::PublicKey::from)
+        .ok_or(Error::SumInfiniteResult)
+}
+
+/// Creates SHA256 engine for BIP-340-style tagged hash with the given tag
+#[cfg(feature = "musig2")]
+fn musig2_tagged_engine(tag: &[u8]) -> sha256::HashEngine {
+    let tag_hash = sha256::Hash::hash(tag);
+    let mut engine = sha256::Hash::engine();
+    engine.input(&tag_hash[..]);
+    engine.input(&tag_hash[..]);
+    engine
+}
+
+/// Reduces 256-bit big-endian number modulo Secp256k1 curve order `n`.
+/// Since `2^256 < 2n`, a single subtraction is sufficient.
+#[cfg(feature = "musig2")]
+fn reduce_scalar(mut scalar: [u8; 32]) -> [u8; 32] {
+    let order = secp256k1::constants::CURVE_ORDER;
+    if scalar < order {
+        return scalar;
+    }
+    let mut borrow = 0u16;
+    for (byte, order) in scalar.iter_mut().zip(order.iter()).rev() {
+        let subtrahend = *order as u16 + borrow;
+        borrow = (subtrahend > *byte as u16) as u16;
+        *byte = (*byte as u16 + (borrow << 8) - subtrahend) as u8;
+    }
+    scalar
+}
 
+/// Computes LNPBP-1 tweaking factor for the sum of the keyset public keys
+/// and applies it to the target public key
+fn tweak(
+    pubkey_sum: secp256k1::PublicKey,
+    target_pubkey: &mut secp256k1::PublicKey,
+    protocol_tag: &sha256::Hash,
+    message: &impl AsRef<[u8]>,
+) -> Result<TweakingFactor, Error> {
     // ! [CONSENSUS-CRITICAL]:
     // ! [STANDARD-CRITICAL]: HMAC engine is based on sha256 hash
     let mut hmac_engine =
@@ -142,13 +546,9 @@ pub fn commit(
     let tweaking_factor = Hmac::from_engine(hmac_engine);
 
     // Applying tweaking factor to public key
-    target_pubkey
-        .add_exp_assign(secp256k1::SECP256K1, &tweaking_factor[..])
+    with_secp(|secp| target_pubkey.add_exp_assign(secp, &tweaking_factor[..]))
         .map_err(|_| Error::InvalidTweak)?;
 
-    keyset.insert(*target_pubkey);
-
-    // Returning tweaked public key
     Ok(tweaking_factor)
 }
 
@@ -213,6 +613,46 @@ pub fn verify(
     }
 }
 
+/// Function verifies LNPBP-1 commitment when the tweaking factor is already
+/// known (for instance, it was stored alongside the commitment), avoiding the
+/// need for the original keyset, protocol tag and message.
+///
+/// The verification applies `tweaking_factor` to the `original_pubkey` and
+/// compares the result with the `verified_pubkey`, which requires a single
+/// elliptic curve operation. Returns `false` if the tweaking factor can't be
+/// applied to the original key.
+///
+/// NB: This function does not check that the tweaking factor was produced by
+/// LNPBP-1 commitment to a specific message; use [`verify()`] for the full
+/// verification.
+pub fn verify_with_factor(
+    verified_pubkey: secp256k1::PublicKey,
+    mut original_pubkey: secp256k1::PublicKey,
+    tweaking_factor: &TweakingFactor,
+) -> bool {
+    with_secp(|secp| original_pubkey.add_exp_assign(secp, &tweaking_factor[..]))
+        .map(|_| original_pubkey == verified_pubkey)
+        .unwrap_or(false)
+}
+
+/// Computes short fingerprint of the keyset, which may be used for logging
+/// and debugging of the multi-key commitments. Similarly to BIP-32 key
+/// fingerprints, it is the first 4 bytes of HASH160 of the serialized keys;
+/// the keys are serialized in the compressed form and concatenated in the
+/// keyset order (i.e. sorted).
+///
+/// NB: The fingerprint is not a part of LNPBP-1 and must not be used for
+/// any consensus-critical purposes, since 4-byte values are easy to collide.
+pub fn keyset_fingerprint(keyset: &Keyset) -> [u8; 4] {
+    let mut engine = hash160::Hash::engine();
+    for pubkey in keyset {
+        engine.input(&pubkey.serialize());
+    }
+    let mut fingerprint = [0u8; 4];
+    fingerprint.copy_from_slice(&hash160::Hash::from_engine(engine)[..4]);
+    fingerprint
+}
+
 /// Helpers for writing test functions working with commit-verify scheme
 #[cfg(test)]
 pub mod test_helpers {
@@ -264,10 +704,10 @@ pub mod test_helpers {
             sk[1] = (i >> 8) as u8;
             sk[2] = (i >> 16) as u8;
 
-            ret.push(secp256k1::PublicKey::from_secret_key(
-                &secp256k1::SECP256K1,
-                &secp256k1::SecretKey::from_slice(&sk[..]).unwrap(),
-            ));
+            let sk = secp256k1::SecretKey::from_slice(&sk[..]).unwrap();
+            ret.push(with_secp(|secp| {
+                secp256k1::PublicKey::from_secret_key(secp, &sk)
+            }));
         }
         ret
     }
@@ -406,9 +846,10 @@ mod test {
                 let hmac = Hmac::from_engine(engine);
                 let tweaking_factor = *hmac.as_inner();
                 let mut altkey = original;
-                altkey
-                    .add_exp_assign(&secp256k1::SECP256K1, &tweaking_factor[..])
-                    .unwrap();
+                with_secp(|secp| {
+                    altkey.add_exp_assign(secp, &tweaking_factor[..])
+                })
+                .unwrap();
                 assert_eq!(altkey, pk);
 
                 // Now try commitment with a different key, but the same data
@@ -517,9 +958,10 @@ mod test {
                 let hmac = Hmac::from_engine(engine);
                 let tweaking_factor = *hmac.as_inner();
                 let mut altkey = original;
-                altkey
-                    .add_exp_assign(&secp256k1::SECP256K1, &tweaking_factor[..])
-                    .unwrap();
+                with_secp(|secp| {
+                    altkey.add_exp_assign(secp, &tweaking_factor[..])
+                })
+                .unwrap();
                 // It must not match because done with a single key, not
                 // their sum
                 assert_ne!(altkey, pk);
@@ -595,6 +1037,460 @@ mod test {
         }
     }
 
+    #[test]
+    fn test_raw_tag() {
+        let tag = sha256::Hash::hash(b"ProtoTag");
+        let all_keys = gen_secp_pubkeys(6);
+        for msg in gen_messages() {
+            let mut pk = all_keys[0];
+            let mut pk_raw = all_keys[0];
+            let mut keyset: BTreeSet<_> = all_keys.iter().copied().collect();
+            let mut keyset_raw = keyset.clone();
+            let factor = commit(&mut keyset, &mut pk, &tag, &msg).unwrap();
+            let factor_raw = commit_raw_tag(
+                &mut keyset_raw,
+                &mut pk_raw,
+                &tag.into_inner(),
+                &msg,
+            )
+            .unwrap();
+            assert_eq!(factor, factor_raw);
+            assert_eq!(pk, pk_raw);
+            assert_eq!(keyset, keyset_raw);
+        }
+    }
+
+    #[test]
+    fn test_secp_context_override() {
+        let tag = sha256::Hash::hash(b"ProtoTag");
+        let all_keys = gen_secp_pubkeys(6);
+        let secp = secp256k1::Secp256k1::new();
+        let other = secp256k1::Secp256k1::new();
+        for msg in gen_messages() {
+            let mut pk = all_keys[0];
+            let mut pk_ctx = all_keys[0];
+            let mut keyset: BTreeSet<_> = all_keys.iter().copied().collect();
+            let mut keyset_ctx = keyset.clone();
+            let factor = commit(&mut keyset, &mut pk, &tag, &msg).unwrap();
+            let factor_ctx = with_secp_context(&secp, || {
+                with_secp(|ctx| assert!(std::ptr::eq(ctx, &secp)));
+                // Nested overrides are restored on return
+                with_secp_context(&other, || {
+                    with_secp(|ctx| assert!(std::ptr::eq(ctx, &other)));
+                });
+                with_secp(|ctx| assert!(std::ptr::eq(ctx, &secp)));
+                commit(&mut keyset_ctx, &mut pk_ctx, &tag, &msg).unwrap()
+            });
+            assert_eq!(factor, factor_ctx);
+            assert_eq!(pk, pk_ctx);
+            assert_eq!(keyset, keyset_ctx);
+        }
+        with_secp(|ctx| assert!(!std::ptr::eq(ctx, &secp)));
+    }
+
+    #[test]
+    #[cfg(feature = "diagnostics")]
+    fn test_commit_traced() {
+        let tag = sha256::Hash::hash(b"ProtoTag");
+        let all_keys = gen_secp_pubkeys(6);
+        for msg in gen_messages() {
+            let mut pk = all_keys[0];
+            let mut pk_traced = all_keys[0];
+            let mut keyset: BTreeSet<_> = all_keys.iter().copied().collect();
+            let mut keyset_traced = keyset.clone();
+            let factor = commit(&mut keyset, &mut pk, &tag, &msg).unwrap();
+            let (factor_traced, trace) =
+                commit_traced(&mut keyset_traced, &mut pk_traced, &tag, &msg)
+                    .unwrap();
+            assert_eq!(factor, factor_traced);
+            assert_eq!(pk, pk_traced);
+            assert_eq!(keyset, keyset_traced);
+
+            assert_eq!(trace.tweaking_factor, factor);
+            assert_eq!(trace.tweaked_pubkey, pk);
+            assert_eq!(
+                trace.pubkey_sum,
+                secp256k1::PublicKey::combine_keys(
+                    &all_keys.iter().collect::<Vec<_>>()
+                )
+                .unwrap()
+            );
+            assert_eq!(trace.hmac_input.len(), 96);
+            let mut engine =
+                HmacEngine::<sha256::Hash>::new(&trace.pubkey_sum.serialize());
+            engine.input(&trace.hmac_input);
+            assert_eq!(Hmac::from_engine(engine), factor);
+        }
+
+        let mut pk = all_keys[0];
+        let mut keyset: BTreeSet<_> = all_keys[1..].iter().copied().collect();
+        assert_eq!(
+            commit_traced(&mut keyset, &mut pk, &tag, b"Message"),
+            Err(Error::NotKeysetMember)
+        );
+    }
+
+    #[test]
+    fn test_commit_xpub() {
+        use bitcoin::util::bip32::{ChildNumber, DerivationPath};
+
+        let secp = secp256k1::Secp256k1::verification_only();
+        let tag = sha256::Hash::hash(b"ProtoTag");
+        // BIP-32 test vector 1, chain m
+        let original = ExtendedPubKey::from_str(
+            "xpub661MyMwAqRbcFtXgS5sYJABqqG9YLmC4Q1Rdap9gSE8NqtwybGhePY2gZ29ES\
+             FjqJoCu1Rupje8YtGqsefD265TMg7usUDFdp6W1EGMcet8",
+        )
+        .unwrap();
+
+        let mut xpub = original;
+        let factor = commit_xpub(&mut xpub, &tag, b"Message").unwrap();
+        assert_eq!(xpub.chain_code, original.chain_code);
+        assert_eq!(xpub.depth, original.depth);
+        assert_eq!(xpub.child_number, original.child_number);
+        assert_eq!(xpub.parent_fingerprint, original.parent_fingerprint);
+        assert_eq!(xpub.network, original.network);
+        assert!(xpub.public_key.compressed);
+        assert_ne!(xpub.public_key, original.public_key);
+
+        // Commitment to an extended key matches commitment to its public key
+        let mut pk = original.public_key.key;
+        let mut keyset = bset![pk];
+        assert_eq!(commit(&mut keyset, &mut pk, &tag, b"Message"), Ok(factor));
+        assert_eq!(xpub.public_key.key, pk);
+        assert!(verify(
+            xpub.public_key.key,
+            &bset![original.public_key.key],
+            original.public_key.key,
+            &tag,
+            b"Message"
+        ));
+        assert!(verify_with_factor(
+            xpub.public_key.key,
+            original.public_key.key,
+            &factor
+        ));
+
+        // Child keys are derived from the tweaked key and the original chain
+        // code
+        let path = DerivationPath::from_str("m/0/1/2").unwrap();
+        let child = xpub.derive_pub(&secp, &path).unwrap();
+        let original_child = original.derive_pub(&secp, &path).unwrap();
+        assert_eq!(child.depth, 3);
+        assert_eq!(
+            child.child_number,
+            ChildNumber::from_normal_idx(2).unwrap()
+        );
+        assert_ne!(child.public_key, original_child.public_key);
+        assert_ne!(child.chain_code, original_child.chain_code);
+        let step = xpub
+            .ckd_pub(&secp, ChildNumber::from_normal_idx(0).unwrap())
+            .unwrap();
+        assert_eq!(step.parent_fingerprint, xpub.fingerprint());
+        assert_eq!(
+            step.derive_pub(&secp, &DerivationPath::from_str("m/1/2").unwrap())
+                .unwrap(),
+            child
+        );
+
+        // Different message produces different key with the same chain code
+        let mut other = original;
+        commit_xpub(&mut other, &tag, b"Other message").unwrap();
+        assert_eq!(other.chain_code, xpub.chain_code);
+        assert_ne!(other.public_key, xpub.public_key);
+    }
+
+    #[cfg(feature = "musig2")]
+    #[test]
+    fn test_musig2_reduce_scalar() {
+        use amplify::hex::ToHex;
+
+        let order = secp256k1::constants::CURVE_ORDER;
+        assert_eq!(reduce_scalar([0u8; 32]), [0u8; 32]);
+        assert_eq!(reduce_scalar(order), [0u8; 32]);
+        let mut below = order;
+        below[31] -= 1;
+        assert_eq!(reduce_scalar(below), below);
+        let mut above = order;
+        above[31] += 1;
+        let mut one = [0u8; 32];
+        one[31] = 1;
+        assert_eq!(reduce_scalar(above), one);
+        assert_eq!(
+            reduce_scalar([0xFF; 32]).to_hex(),
+            "000000000000000000000000000000014551231950b75fc4402da1732fc9bebe"
+        );
+    }
+
+    #[cfg(feature = "musig2")]
+    #[test]
+    fn test_commit_musig2() {
+        use amplify::hex::ToHex;
+
+        let tag = sha256::Hash::hash(b"ProtoTag");
+        let participants = gen_secp_pubkeys(3)
+            .into_iter()
+            .map(schnorrsig::PublicKey::from)
+            .collect::<Vec<_>>();
+
+        // Values are computed with an independent implementation of MuSig2
+        // x-only key aggregation
+        let aggregated = musig2_key_agg(&participants).unwrap();
+        assert_eq!(
+            aggregated.serialize().to_hex(),
+            "809a13f30e9f4cb041c91017e85f2bd4e5486d92a380cc712867fe4efc78e278"
+        );
+        assert_eq!(
+            musig2_key_agg(&participants[..1])
+                .unwrap()
+                .serialize()
+                .to_hex(),
+            "ad22adbab079f89e31dfa56d20d83d6f96213acb90fbe9f79645cb4cb845abe5"
+        );
+        assert_eq!(musig2_key_agg(&[]), Err(Error::SumInfiniteResult));
+
+        // Aggregation depends on the order of the keys
+        let mut reordered = participants.clone();
+        reordered.swap(0, 1);
+        assert_ne!(musig2_key_agg(&reordered).unwrap(), aggregated);
+
+        let mut key = aggregated;
+        let factor =
+            commit_musig2(&mut key, &participants, &tag, b"Message").unwrap();
+        assert_eq!(
+            factor.to_hex(),
+            "cfb89369e041f552ec416b16536c5365af4e597727785787bd5687524c5a1e51"
+        );
+        assert_eq!(
+            key.serialize().to_hex(),
+            "b7d4b73dd5ee57bf581f54013dfe8565e2469801e686f568ff03209e87fae6ed"
+        );
+        let mut tweaked = aggregated;
+        assert!(
+            with_secp(|secp| tweaked.tweak_add_assign(secp, &factor[..]))
+                .is_ok()
+        );
+        assert_eq!(tweaked, key);
+
+        // MuSig2 commitment differs from the commitment to the plain key sum
+        let mut keyset: Keyset = gen_secp_pubkeys(3).into_iter().collect();
+        let mut target = gen_secp_pubkeys(1)[0];
+        commit(&mut keyset, &mut target, &tag, b"Message").unwrap();
+        assert_ne!(schnorrsig::PublicKey::from(target), key);
+
+        // Aggregated key must match participants
+        let mut key = aggregated;
+        assert_eq!(
+            commit_musig2(&mut key, &reordered, &tag, b"Message"),
+            Err(Error::AggregatedKeyMismatch)
+        );
+        assert_eq!(key, aggregated);
+        assert_eq!(
+            commit_musig2(&mut key, &[], &tag, b"Message"),
+            Err(Error::SumInfiniteResult)
+        );
+    }
+
+    #[test]
+    fn test_error_trait() {
+        use std::error::Error as StdError;
+
+        for err in &[
+            Error::NotKeysetMember,
+            Error::SumInfiniteResult,
+            Error::InvalidTweak,
+        ] {
+            let boxed: Box<dyn StdError> = Box::new(*err);
+            assert!(boxed.source().is_none());
+            assert_eq!(boxed.to_string(), err.to_string());
+        }
+    }
+
+    #[test]
+    fn test_keyset_fingerprint() {
+        let keys = gen_secp_pubkeys(9);
+        let mut fingerprints = BTreeSet::new();
+        for len in 1..=keys.len() {
+            let keyset: Keyset = keys[..len].iter().copied().collect();
+            assert!(fingerprints.insert(keyset_fingerprint(&keyset)));
+
+            // Fingerprint does not depend on the order of key insertion
+            let reversed: Keyset = keys[..len].iter().rev().copied().collect();
+            assert_eq!(
+                keyset_fingerprint(&reversed),
+                keyset_fingerprint(&keyset)
+            );
+        }
+        for key in &keys[1..] {
+            assert!(fingerprints.insert(keyset_fingerprint(&bset![*key])));
+        }
+
+        // Single-key keyset has the same fingerprint as BIP-32 extended key
+        let xpub = ExtendedPubKey::from_str(
+            "xpub661MyMwAqRbcFtXgS5sYJABqqG9YLmC4Q1Rdap9gSE8NqtwybGhePY2gZ29ES\
+             FjqJoCu1Rupje8YtGqsefD265TMg7usUDFdp6W1EGMcet8",
+        )
+        .unwrap();
+        assert_eq!(
+            keyset_fingerprint(&bset![xpub.public_key.key]),
+            xpub.fingerprint()[..]
+        );
+    }
+
+    #[test]
+    fn test_verify_with_factor() {
+        let tag = sha256::Hash::hash(b"ProtoTag");
+        let all_keys = gen_secp_pubkeys(3);
+        for msg in gen_messages() {
+            let original = all_keys[0];
+            let mut pk = original;
+            let mut keyset: BTreeSet<_> = all_keys.iter().copied().collect();
+            let factor = commit(&mut keyset, &mut pk, &tag, &msg).unwrap();
+            assert!(verify_with_factor(pk, original, &factor));
+            assert!(!verify_with_factor(original, original, &factor));
+            assert!(!verify_with_factor(pk, all_keys[1], &factor));
+
+            let mut other = original;
+            let other_factor = commit(
+                &mut all_keys.iter().copied().collect(),
+                &mut other,
+                &tag,
+                b"other message",
+            )
+            .unwrap();
+            assert!(!verify_with_factor(pk, original, &other_factor));
+        }
+    }
+
+    #[test]
+    fn test_versioned_tweaking_factor() {
+        let tag = sha256::Hash::hash(b"ProtoTag");
+        let all_keys = gen_secp_pubkeys(3);
+        for msg in gen_messages() {
+            let mut pk = all_keys[0];
+            let mut keyset: BTreeSet<_> = all_keys.iter().copied().collect();
+            let factor = commit(&mut keyset, &mut pk, &tag, &msg).unwrap();
+
+            // Versioned serialization extends the existing one
+            let bytes = factor.serialize_versioned();
+            assert_eq!(bytes[0], LNPBP1_VERSION);
+            assert_eq!(bytes[1..], factor.into_inner()[..]);
+            assert_eq!(bytes[1..], factor[..]);
+            assert_eq!(
+                TweakingFactor::deserialize_versioned(&bytes),
+                Ok(factor)
+            );
+
+            for version in [0u8, 2, 0xFF].iter() {
+                let mut bytes = bytes;
+                bytes[0] = *version;
+                assert_eq!(
+                    TweakingFactor::deserialize_versioned(&bytes),
+                    Err(Error::UnsupportedVersion(*version))
+                );
+            }
+        }
+    }
+
+    #[test]
+    fn test_error_display() {
+        for err in &[
+            Error::NotKeysetMember,
+            Error::SumInfiniteResult,
+            Error::InvalidTweak,
+        ] {
+            let msg = err.to_string();
+            assert!(msg.contains("key") || msg.contains("commitment"));
+        }
+    }
+
+    #[test]
+    fn test_key_error_conversion() {
+        use bitcoin::util::key;
+
+        assert_eq!(
+            key::Error::from(Error::InvalidTweak),
+            key::Error::Secp256k1(secp256k1::Error::InvalidTweak)
+        );
+        for &err in &[
+            Error::NotKeysetMember,
+            Error::SumInfiniteResult,
+            Error::UnsupportedVersion(2),
+        ] {
+            assert_eq!(
+                key::Error::from(err),
+                key::Error::Secp256k1(secp256k1::Error::InvalidPublicKey)
+            );
+        }
+
+        fn tweak(tweak: [u8; 32]) -> Result<secp256k1::SecretKey, key::Error> {
+            let mut key = secp256k1::SecretKey::from_slice(&[1u8; 32])?;
+            key.add_assign(&tweak).map_err(|_| Error::InvalidTweak)?;
+            Ok(key)
+        }
+        assert!(tweak([2u8; 32]).is_ok());
+        assert_eq!(
+            tweak([0xFF; 32]),
+            Err(key::Error::Secp256k1(secp256k1::Error::InvalidTweak))
+        );
+    }
+
+    #[test]
+    fn test_sorted_iter() {
+        let tag = sha256::Hash::hash(b"ProtoTag");
+        let all_keys = gen_secp_pubkeys(6);
+        for msg in gen_messages() {
+            for len in 1..=all_keys.len() {
+                let keyset: BTreeSet<_> =
+                    all_keys[..len].iter().copied().collect();
+                for target in &keyset {
+                    let mut pk = *target;
+                    let mut pk_iter = *target;
+                    let mut keyset_tweaked = keyset.clone();
+                    let factor =
+                        commit(&mut keyset_tweaked, &mut pk, &tag, &msg)
+                            .unwrap();
+                    let factor_iter = commit_sorted_iter(
+                        keyset.iter().copied(),
+                        &mut pk_iter,
+                        &tag,
+                        &msg,
+                    )
+                    .unwrap();
+                    assert_eq!(factor, factor_iter);
+                    assert_eq!(pk, pk_iter);
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn test_sorted_iter_not_in_keyset() {
+        let tag = sha256::Hash::hash(b"ProtoTag");
+        let all_keys = gen_secp_pubkeys(6);
+        let mut pk = all_keys[0];
+        let keyset: BTreeSet<_> = all_keys[1..].iter().copied().collect();
+        assert_eq!(
+            commit_sorted_iter(keyset.into_iter(), &mut pk, &tag, b"Message"),
+            Err(Error::NotKeysetMember)
+        );
+    }
+
+    #[test]
+    #[cfg(debug_assertions)]
+    #[should_panic(expected = "must be sorted and unique")]
+    fn test_sorted_iter_unsorted() {
+        let tag = sha256::Hash::hash(b"ProtoTag");
+        let keyset: BTreeSet<_> = gen_secp_pubkeys(6).into_iter().collect();
+        let mut pk = *keyset.iter().next().unwrap();
+        let _ = commit_sorted_iter(
+            keyset.into_iter().rev(),
+            &mut pk,
+            &tag,
+            b"Message",
+        );
+    }
+
     #[test]
     #[should_panic(expected = "NotKeysetMember")]
     fn test_failure_not_in_keyset() {
@@ -0,0 +1,149 @@
+// BP Core Library implementing LNP/BP specifications & standards related to
+// bitcoin protocol
+//
+// Written in 2020-2021 by
+//     Dr. Maxim Orlovsky <orlovsky@pandoracore.com>
+//
+// To the extent possible under law, the author(s) have dedicated all
+// copyright and related and neighboring rights to this software to
+// the public domain worldwide. This software is distributed without
+// any warranty.
+//
+// You should have received a copy of the Apache 2.0 License
+// along with this software.
+// If not, see <https://opensource.org/licenses/Apache-2.0>.
+
+//! Constants and verification function of LNPBP-2 standard: deterministic
+//! embedding of LNPBP-1 commitments into bitcoin scripts. The commitment
+//! procedure itself is implemented by [`LockscriptCommitment`].
+
+use amplify::Wrapper;
+use bitcoin::secp256k1;
+use bitcoin_scripts::LockScript;
+use commit_verify::EmbedCommitVerify;
+
+use crate::{LockscriptCommitment, LockscriptContainer, ProtocolTag};
+
+/// Single SHA256 hash of "LNPBP2" string according to LNPBP-2, which is used
+/// as the default protocol tag for the lockscript commitments (see
+/// [`LockscriptContainer::with_lnpbp2_tag`])
+pub static LNPBP2_HASHED_TAG: [u8; 32] = [
+    99, 153, 92, 193, 123, 137, 108, 224, 26, 233, 162, 48, 3, 199, 79, 172,
+    103, 145, 147, 152, 96, 7, 79, 160, 111, 66, 18, 22, 32, 76, 224, 104,
+];
+
+/// Function verifies commitment created according to LNPBP-2.
+///
+/// # Parameters
+///
+/// - `verified_script`: lockscript containing LNPBP-2 commitment
+/// - `original_script`: lockscript before the commitment
+/// - `target_pubkey`: public key from the `original_script` which was tweaked
+///   with the commitment
+/// - `protocol_tag`: protocol-specific tag
+/// - `message`: message to commit to, which must be representable as a byte
+///   slice using [`AsRef::as_ref()`]
+///
+/// # Returns
+///
+/// - `true`, if verification succeeds,
+/// - `false`, if verification fails, indicating that the provided
+///   `verified_script` is not committed to the data given in the rest of
+///   function parameters.
+///
+/// Like [`crate::lnpbp1::verify`], the function runs the commitment procedure
+/// once again and compares the result to the `verified_script`; failure of
+/// the commitment procedure means that the verification has not passed.
+pub fn verify_lnpbp2(
+    verified_script: &LockScript,
+    original_script: &LockScript,
+    target_pubkey: secp256k1::PublicKey,
+    protocol_tag: &ProtocolTag,
+    message: &impl AsRef<[u8]>,
+) -> bool {
+    let mut container = LockscriptContainer {
+        script: original_script.clone(),
+        pubkey: target_pubkey,
+        tag: *protocol_tag,
+        tweaking_factor: None,
+    };
+    match LockscriptCommitment::embed_commit(&mut container, message) {
+        Err(_) => false,
+        Ok(commitment) => commitment.as_inner() == verified_script,
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use bitcoin::blockdata::opcodes::all::*;
+    use bitcoin::blockdata::script::Builder;
+    use bitcoin::hashes::{sha256, Hash};
+
+    use super::*;
+    use crate::lnpbp1::test_helpers::*;
+    use crate::LNPBP2_TAG;
+
+    #[test]
+    fn test_lnpbp2_tag() {
+        assert_eq!(
+            sha256::Hash::hash(b"LNPBP2").into_inner(),
+            LNPBP2_HASHED_TAG
+        );
+        assert_eq!(LNPBP2_TAG.to_raw(), LNPBP2_HASHED_TAG);
+    }
+
+    #[test]
+    fn test_verify_lnpbp2() {
+        let keys = gen_secp_pubkeys(3);
+        let script = LockScript::from(
+            Builder::new()
+                .push_opcode(OP_PUSHNUM_2)
+                .push_slice(&keys[0].serialize())
+                .push_slice(&keys[1].serialize())
+                .push_slice(&keys[2].serialize())
+                .push_opcode(OP_PUSHNUM_3)
+                .push_opcode(OP_CHECKMULTISIG)
+                .into_script(),
+        );
+        let tag = ProtocolTag::new("TEST_TAG");
+
+        for msg in gen_messages() {
+            let mut container =
+                LockscriptContainer::with_lnpbp2_tag(script.clone(), keys[1]);
+            assert_eq!(container.tag, LNPBP2_TAG);
+            let commitment =
+                LockscriptCommitment::embed_commit(&mut container, &msg)
+                    .unwrap()
+                    .into_inner();
+            assert!(verify_lnpbp2(
+                &commitment,
+                &script,
+                keys[1],
+                &LNPBP2_TAG,
+                &msg
+            ));
+            assert!(!verify_lnpbp2(&commitment, &script, keys[1], &tag, &msg));
+            assert!(!verify_lnpbp2(
+                &commitment,
+                &script,
+                keys[0],
+                &LNPBP2_TAG,
+                &msg
+            ));
+            assert!(!verify_lnpbp2(
+                &commitment,
+                &script,
+                keys[1],
+                &LNPBP2_TAG,
+                b"other message"
+            ));
+            assert!(!verify_lnpbp2(
+                &script,
+                &script,
+                keys[1],
+                &LNPBP2_TAG,
+                &msg
+            ));
+        }
+    }
+}
@@ -0,0 +1,100 @@
+// BP Core Library implementing LNP/BP specifications & standards related to
+// bitcoin protocol
+//
+// Written in 2020-2021 by
+//     Dr. Maxim Orlovsky <orlovsky@pandoracore.com>
+//
+// To the extent possible under law, the author(s) have dedicated all
+// copyright and related and neighboring rights to this software to
+// the public domain worldwide. This software is distributed without
+// any warranty.
+//
+// You should have received a copy of the Apache 2.0 License
+// along with this software.
+// If not, see <https://opensource.org/licenses/Apache-2.0>.
+
+//! Deterministic selection of the transaction output containing commitment
+//! according to LNPBP-3 standard. The commitment procedure itself is
+//! implemented by [`TxCommitment`](crate::TxCommitment).
+
+/// Computes index of the transaction output which must contain the
+/// commitment according to LNPBP-3: the sum of the transaction `fee` and
+/// protocol-specific `entropy` (the protocol factor) modulo the number of
+/// the transaction outputs. Returns `None` if the transaction has no
+/// outputs.
+///
+/// The sum is computed without overflow, so the function is defined for any
+/// fee value.
+///
+/// Coinbase transactions do not pay fees, so zero fee must be used for them
+/// (see [`TxContainer::for_coinbase`](crate::TxContainer::for_coinbase)).
+///
+/// # Protocol:
+///
+/// Please refer to the original document for the verification:
+/// <https://github.com/LNP-BP/LNPBPs/blob/master/lnpbp-0003.md>
+// #[consensus_critical("RGB")]
+// #[standard_critical("LNPBP-3")]
+pub fn commitment_output_index(
+    fee: u64,
+    entropy: u32,
+    n_outputs: usize,
+) -> Option<usize> {
+    if n_outputs == 0 {
+        return None;
+    }
+    // ! [CONSENSUS-CRITICAL]:
+    // ! [STANDARD-CRITICAL]: Output index is the sum of the fee and the
+    //                        protocol factor modulo the number of outputs
+    let index = (fee as u128 + entropy as u128) % n_outputs as u128;
+    Some(index as usize)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_commitment_output_index() {
+        let cases: &[(u64, u32, usize, Option<usize>)] = &[
+            (0, 0, 0, None),
+            (1000, 7, 0, None),
+            (0, 0, 1, Some(0)),
+            (1000, 7, 1, Some(0)),
+            (u64::MAX, u32::MAX, 1, Some(0)),
+            (0, 0, 2, Some(0)),
+            (1, 0, 2, Some(1)),
+            (0, 1, 2, Some(1)),
+            (1, 1, 2, Some(0)),
+            (0, 3, 5, Some(3)),
+            (1, 3, 5, Some(4)),
+            (2, 3, 5, Some(0)),
+            (11, 3, 5, Some(4)),
+            (1000, 0, 3, Some(1)),
+            (1000, 2, 3, Some(0)),
+            (2500, 17, 7, Some(4)),
+            // 2^64 - 1 = 3 * 5 * 17 * 257 * 641 * 65537 * 6700417
+            (u64::MAX, 0, 3, Some(0)),
+            (u64::MAX, 0, 2, Some(1)),
+            (u64::MAX, 1, 2, Some(0)),
+            (u64::MAX, 1, 3, Some(1)),
+            // Sum exceeding u64 range
+            (u64::MAX, u32::MAX, 2, Some(0)),
+            (u64::MAX, u32::MAX, 7, Some(4)),
+            (u64::MAX, u32::MAX, 10, Some(0)),
+            // Number of outputs exceeding u16
+            (0, u32::MAX, 1 << 16, Some(0xFFFF)),
+            (1, u32::MAX, (1 << 16) + 1, Some(1)),
+        ];
+        for (fee, entropy, n_outputs, index) in cases {
+            assert_eq!(
+                commitment_output_index(*fee, *entropy, *n_outputs),
+                *index,
+                "fee {}, entropy {}, outputs {}",
+                fee,
+                entropy,
+                n_outputs
+            );
+        }
+    }
+}
@@ -0,0 +1,818 @@
+// BP Core Library implementing LNP/BP specifications & standards related to
+// bitcoin protocol
+//
+// Written in 2020-2021 by
+//     Dr. Maxim Orlovsky <orlovsky@pandoracore.com>
+//
+// To the extent possible under law, the author(s) have dedicated all
+// copyright and related and neighboring rights to this software to
+// the public domain worldwide. This software is distributed without
+// any warranty.
+//
+// You should have received a copy of the Apache 2.0 License
+// along with this software.
+// If not, see <https://opensource.org/licenses/Apache-2.0>.
+
+//! Multi-protocol commitments according to LNPBP-4 standard: messages of
+//! multiple protocols are placed into the slots of a merkle tree, and only
+//! the tree root is committed to with LNPBP-1 or other commitment procedures.
+//!
+//! Each protocol message is placed into the slot defined by the protocol id
+//! and the tree width; the rest of the slots are filled with hashes of the
+//! entropy, so a [`MpcProof`] for one of the protocols reveals nothing
+//! about the messages of the other protocols.
+//!
+//! The data are provided on two levels:
+//! - reveal level: [`MultiCommitBlock`] with the full tree and the entropy,
+//!   which is kept by the committing party only;
+//! - conceal level: [`MpcRoot`], produced from the block with
+//!   [`CommitConceal::commit_conceal`], which is committed to and may be
+//!   published. The entropy can't be derived from the root, so an observer
+//!   can't test presence of a protocol by brute-forcing the tree layouts.
+//!
+//! Holders of the inclusion proofs learn hashes of the unused slots; these
+//! are tagged hashes of the 64-bit entropy, so the entropy must be random.
+
+use std::collections::{BTreeMap, BTreeSet};
+use std::convert::TryFrom;
+use std::io;
+
+use amplify::Wrapper;
+use bitcoin::hashes::{sha256, Hash, HashEngine};
+use bitcoin::secp256k1::rand::{thread_rng, RngCore};
+use commit_verify::CommitConceal;
+use strict_encoding::{StrictDecode, StrictEncode};
+
+use crate::ProtocolTag;
+
+/// Single SHA256 hash of "LNPBP4" string according to LNPBP-4 acting as a
+/// prefix to the tree root in the message committed to by LNPBP-1 and the
+/// other commitment procedures (see [`MpcRoot::to_commitment_message`])
+pub static LNPBP4_HASHED_TAG: [u8; 32] = [
+    210, 36, 127, 118, 211, 119, 177, 138, 33, 185, 152, 250, 222, 3, 230, 26,
+    66, 146, 144, 166, 208, 205, 205, 223, 171, 234, 198, 171, 201, 76, 140,
+    33,
+];
+
+/// Name of the BIP-340-style tag used for the tree leaves containing protocol
+/// messages
+pub const LNPBP4_LEAF_TAG: &str = "bp:dbc:mpc:leaf";
+
+/// Name of the BIP-340-style tag used for the tree leaves of the unoccupied
+/// slots
+pub const LNPBP4_ENTROPY_TAG: &str = "bp:dbc:mpc:entropy";
+
+/// Name of the BIP-340-style tag used for the tree branches
+pub const LNPBP4_BRANCH_TAG: &str = "bp:dbc:mpc:branch";
+
+/// Maximal width of the tree; the width is serialized as a 16-bit number
+pub const MAX_WIDTH: u16 = u16::MAX;
+
+/// Protocol identifier used for the slot selection; the same as the hashed
+/// protocol tag used by the other commitment procedures
+pub type ProtocolId = ProtocolTag;
+
+/// Errors happening during construction and verification of the
+/// multi-protocol commitment tree
+#[derive(Clone, Copy, PartialEq, Eq, Debug, Display, Error)]
+#[display(doc_comments)]
+pub enum Error {
+    /// {0} protocols can't be placed into distinct slots of the
+    /// multi-protocol commitment tree with the width not exceeding the
+    /// maximum
+    TreeOverflow(usize),
+
+    /// multi-protocol commitment tree must have non-zero width
+    ZeroWidth,
+
+    /// protocols {first} and {second} are placed into the same slot {slot}
+    /// of the multi-protocol commitment tree
+    SlotCollision {
+        /// Id of the first protocol
+        first: ProtocolId,
+        /// Id of the second protocol
+        second: ProtocolId,
+        /// Tree slot of both protocols
+        slot: u16,
+    },
+
+    /// multi-protocol commitment proof does not match the protocol slot or
+    /// the tree width
+    InvalidProof,
+
+    /// protocol {0} is not a part of the multi-protocol commitment tree
+    UnknownProtocol(ProtocolId),
+}
+
+/// Creates SHA256 engine for BIP-340-style tagged hash with the given tag
+fn lnpbp4_tagged_engine(tag: &str) -> sha256::HashEngine {
+    let tag_hash = sha256::Hash::hash(tag.as_bytes());
+    let mut engine = sha256::Hash::engine();
+    engine.input(&tag_hash[..]);
+    engine.input(&tag_hash[..]);
+    engine
+}
+
+fn leaf_hash(protocol_id: &ProtocolId, message: &sha256::Hash) -> sha256::Hash {
+    let mut engine = lnpbp4_tagged_engine(LNPBP4_LEAF_TAG);
+    engine.input(&protocol_id.to_raw());
+    engine.input(&message[..]);
+    sha256::Hash::from_engine(engine)
+}
+
+fn entropy_hash(entropy: u64, slot: u16) -> sha256::Hash {
+    let mut engine = lnpbp4_tagged_engine(LNPBP4_ENTROPY_TAG);
+    engine.input(&entropy.to_le_bytes());
+    engine.input(&slot.to_le_bytes());
+    sha256::Hash::from_engine(engine)
+}
+
+fn branch_hash(left: &sha256::Hash, right: &sha256::Hash) -> sha256::Hash {
+    let mut engine = lnpbp4_tagged_engine(LNPBP4_BRANCH_TAG);
+    engine.input(&left[..]);
+    engine.input(&right[..]);
+    sha256::Hash::from_engine(engine)
+}
+
+/// Computes slot of the protocol in the tree of the given `width` as the
+/// protocol id, interpreted as a little-endian 256-bit number, modulo the
+/// tree width.
+///
+/// # Protocol:
+///
+/// Please refer to the original document for the verification:
+/// <https://github.com/LNP-BP/LNPBPs/blob/master/lnpbp-0004.md>
+// #[consensus_critical("RGB")]
+// #[standard_critical("LNPBP-4")]
+pub fn protocol_slot(protocol_id: &ProtocolId, width: u16) -> u16 {
+    let width = width as u32;
+    protocol_id
+        .to_raw()
+        .iter()
+        .rev()
+        .fold(0u32, |rem, byte| ((rem << 8) | *byte as u32) % width) as u16
+}
+
+/// Depth of the merkle tree containing `width` slots
+fn tree_depth(width: u16) -> usize {
+    (width as u32).next_power_of_two().trailing_zeros() as usize
+}
+
+/// Returns the first pair of protocols placed into the same slot of the tree
+/// with the given non-zero `width` together with the slot number
+fn find_collision<'ids>(
+    protocol_ids: impl IntoIterator<Item = &'ids ProtocolId>,
+    width: u16,
+) -> Option<(ProtocolId, ProtocolId, u16)> {
+    let mut slots = BTreeMap::<u16, ProtocolId>::new();
+    protocol_ids.into_iter().find_map(|id| {
+        let slot = protocol_slot(id, width);
+        match slots.insert(slot, *id) {
+            Some(other) if other != *id => Some((other, *id, slot)),
+            _ => None,
+        }
+    })
+}
+
+/// Finds the minimal tree width, not smaller than the number of the
+/// protocols, at which each of the protocols is placed into a distinct slot
+/// (see [`protocol_slot`]). Repeated protocol ids are counted once, and the
+/// order of the ids does not matter.
+///
+/// Fails with [`Error::TreeOverflow`] if there is no such width not
+/// exceeding [`MAX_WIDTH`].
+///
+/// # Protocol:
+///
+/// Please refer to the original document for the verification:
+/// <https://github.com/LNP-BP/LNPBPs/blob/master/lnpbp-0004.md>
+// #[consensus_critical("RGB")]
+// #[standard_critical("LNPBP-4")]
+pub fn optimal_width(protocol_ids: &[ProtocolId]) -> Result<u16, Error> {
+    let ids = protocol_ids.iter().collect::<BTreeSet<_>>();
+    let count = ids.len();
+    let min_width =
+        u16::try_from(count.max(1)).map_err(|_| Error::TreeOverflow(count))?;
+    (min_width..=MAX_WIDTH)
+        .find(|width| find_collision(ids.iter().copied(), *width).is_none())
+        .ok_or(Error::TreeOverflow(count))
+}
+
+/// Source data for the multi-protocol commitment: messages of the protocols,
+/// at most one per protocol, and entropy for the unoccupied slots.
+///
+/// Random entropy must be used if the number of protocols must not be
+/// revealed to the holders of the merkle proofs.
+#[derive(Clone, PartialEq, Eq, Hash, Debug, StrictEncode, StrictDecode)]
+#[cfg_attr(
+    feature = "serde",
+    derive(Serialize, Deserialize),
+    serde(crate = "serde_crate")
+)]
+pub struct MultiSource {
+    /// Messages ordered by the protocol ids
+    pub messages: BTreeMap<ProtocolId, sha256::Hash>,
+
+    /// Entropy used to fill unoccupied tree slots
+    pub entropy: u64,
+}
+
+impl Default for MultiSource {
+    #[inline]
+    fn default() -> Self { MultiSource::new() }
+}
+
+impl MultiSource {
+    /// Constructs source with no messages and random entropy
+    #[inline]
+    pub fn new() -> Self { MultiSource::with_entropy(thread_rng().next_u64()) }
+
+    /// Constructs source with no messages and the given `entropy`, which
+    /// must be random unless the tree is reconstructed from the existing
+    /// data
+    #[inline]
+    pub fn with_entropy(entropy: u64) -> Self {
+        MultiSource {
+            messages: empty!(),
+            entropy,
+        }
+    }
+
+    /// Adds `message` for the protocol with the given id, returning the
+    /// previously added message for the same protocol
+    #[inline]
+    pub fn insert(
+        &mut self,
+        protocol_id: ProtocolId,
+        message: sha256::Hash,
+    ) -> Option<sha256::Hash> {
+        self.messages.insert(protocol_id, message)
+    }
+}
+
+/// Multi-protocol commitment tree constructed from [`MultiSource`].
+///
+/// The tree width is the minimal number of slots not smaller than the number
+/// of the protocols at which each of the protocols gets a distinct slot. The
+/// slots are padded with entropy leaves up to the next power of two, making
+/// a perfect binary merkle tree.
+///
+/// The block can be constructed only with [`MultiCommitBlock::with`] and
+/// [`MultiCommitBlock::with_width`], which guarantee that the width is
+/// non-zero and that the protocols do not collide; strict decoding performs
+/// the same checks.
+#[derive(Clone, PartialEq, Eq, Hash, Debug)]
+#[cfg_attr(
+    feature = "serde",
+    derive(Serialize, Deserialize),
+    serde(crate = "serde_crate")
+)]
+pub struct MultiCommitBlock {
+    /// Number of the tree slots
+    width: u16,
+
+    /// Source data of the tree
+    source: MultiSource,
+}
+
+impl MultiCommitBlock {
+    /// Constructs tree with the width selected by [`optimal_width`].
+    ///
+    /// Fails with [`Error::TreeOverflow`] if no width up to [`MAX_WIDTH`]
+    /// places the protocols into distinct slots.
+    pub fn with(source: MultiSource) -> Result<Self, Error> {
+        let width = optimal_width(
+            &source.messages.keys().copied().collect::<Vec<_>>(),
+        )?;
+        Ok(MultiCommitBlock { width, source })
+    }
+
+    /// Constructs tree with the explicitly provided `width`, which is
+    /// required for the verification of the historic commitments, which
+    /// width might have been selected by a different procedure.
+    ///
+    /// Fails with [`Error::ZeroWidth`] for the zero `width` and with
+    /// [`Error::SlotCollision`] if some of the protocols are placed into the
+    /// same slot.
+    pub fn with_width(source: MultiSource, width: u16) -> Result<Self, Error> {
+        if width == 0 {
+            return Err(Error::ZeroWidth);
+        }
+        if let Some((first, second, slot)) =
+            find_collision(source.messages.keys(), width)
+        {
+            return Err(Error::SlotCollision {
+                first,
+                second,
+                slot,
+            });
+        }
+        Ok(MultiCommitBlock { width, source })
+    }
+
+    /// Returns number of the tree slots
+    #[inline]
+    pub fn width(&self) -> u16 { self.width }
+
+    /// Returns source data of the tree
+    #[inline]
+    pub fn source(&self) -> &MultiSource { &self.source }
+
+    /// Computes all levels of the tree, from the leaves to the root
+    fn levels(&self) -> Vec<Vec<sha256::Hash>> {
+        let mut leaves = (0..1usize << tree_depth(self.width))
+            .map(|slot| entropy_hash(self.source.entropy, slot as u16))
+            .collect::<Vec<_>>();
+        for (id, message) in &self.source.messages {
+            leaves[protocol_slot(id, self.width) as usize] =
+                leaf_hash(id, message);
+        }
+        let mut levels = vec![leaves];
+        while levels[levels.len() - 1].len() > 1 {
+            let level = levels[levels.len() - 1]
+                .chunks(2)
+                .map(|pair| branch_hash(&pair[0], &pair[1]))
+                .collect();
+            levels.push(level);
+        }
+        levels
+    }
+
+    /// Computes root of the tree, which has to be committed to
+    pub fn commit(&self) -> sha256::Hash {
+        let levels = self.levels();
+        levels[levels.len() - 1][0]
+    }
+
+    /// Extracts inclusion proof for the protocol with the given id, which
+    /// reveals neither the messages of the other protocols nor the entropy.
+    ///
+    /// Fails with [`Error::UnknownProtocol`] if the tree has no message for
+    /// the protocol.
+    pub fn proof_for(
+        &self,
+        protocol_id: &ProtocolId,
+    ) -> Result<MpcProof, Error> {
+        if !self.source.messages.contains_key(protocol_id) {
+            return Err(Error::UnknownProtocol(*protocol_id));
+        }
+        let slot = protocol_slot(protocol_id, self.width);
+        let levels = self.levels();
+        let path = levels[..levels.len() - 1]
+            .iter()
+            .enumerate()
+            .map(|(depth, level)| level[(slot as usize >> depth) ^ 1])
+            .collect();
+        Ok(MpcProof {
+            slot,
+            width: self.width,
+            path,
+        })
+    }
+}
+
+impl StrictEncode for MultiCommitBlock {
+    fn strict_encode<E: io::Write>(
+        &self,
+        mut e: E,
+    ) -> Result<usize, strict_encoding::Error> {
+        Ok(self.width.strict_encode(&mut e)?
+            + self.source.strict_encode(&mut e)?)
+    }
+}
+
+impl StrictDecode for MultiCommitBlock {
+    fn strict_decode<D: io::Read>(
+        mut d: D,
+    ) -> Result<Self, strict_encoding::Error> {
+        let width = u16::strict_decode(&mut d)?;
+        let source = MultiSource::strict_decode(&mut d)?;
+        MultiCommitBlock::with_width(source, width).map_err(|err| {
+            strict_encoding::Error::DataIntegrityError(err.to_string())
+        })
+    }
+}
+
+impl CommitConceal for MultiCommitBlock {
+    type ConcealedCommitment = MpcRoot;
+
+    #[inline]
+    fn commit_conceal(&self) -> Self::ConcealedCommitment {
+        MpcRoot::from_inner(self.commit())
+    }
+}
+
+/// Root of the multi-protocol commitment tree: the concealed form of
+/// [`MultiCommitBlock`], which reveals neither the protocols, nor their
+/// messages, nor the entropy
+#[derive(
+    Wrapper, Copy, Clone, PartialEq, Eq, PartialOrd, Ord, Hash, Default,
+    Display, From
+)]
+#[wrapper(
+    Debug, LowerHex, FromStr, Index, IndexRange, IndexFrom, IndexTo, IndexFull
+)]
+#[display(LowerHex)]
+pub struct MpcRoot(sha256::Hash);
+
+impl strict_encoding::Strategy for MpcRoot {
+    type Strategy = strict_encoding::strategies::Wrapped;
+}
+
+impl MpcRoot {
+    /// Returns canonical message committed to by the commitment procedures
+    /// when they are used with the tree: [`LNPBP4_HASHED_TAG`] followed by
+    /// the root hash
+    pub fn to_commitment_message(&self) -> [u8; 64] {
+        let mut msg = [0u8; 64];
+        msg[..32].copy_from_slice(&LNPBP4_HASHED_TAG);
+        msg[32..].copy_from_slice(&self.0[..]);
+        msg
+    }
+}
+
+/// Inclusion proof for a protocol message in the multi-protocol commitment
+/// tree: the protocol slot and the merkle path from it to the tree root. The
+/// size of the proof is logarithmic in the tree width.
+#[derive(Clone, PartialEq, Eq, Hash, Debug, StrictEncode, StrictDecode)]
+#[cfg_attr(
+    feature = "serde",
+    derive(Serialize, Deserialize),
+    serde(crate = "serde_crate")
+)]
+pub struct MpcProof {
+    /// Slot of the protocol in the tree
+    pub slot: u16,
+
+    /// Number of the tree slots
+    pub width: u16,
+
+    /// Hashes of the sibling nodes, ordered from the leaf to the root
+    pub path: Vec<sha256::Hash>,
+}
+
+impl MpcProof {
+    /// Checks that the proof slot is the one defined by LNPBP-4 for the
+    /// protocol with `protocol_id`, so the proof can't be used to place the
+    /// message into a slot of the other protocol
+    pub fn matches_slot(&self, protocol_id: &ProtocolId) -> bool {
+        self.width != 0 && protocol_slot(protocol_id, self.width) == self.slot
+    }
+
+    /// Computes root of the tree with the `message` placed into the slot of
+    /// the protocol with `protocol_id`.
+    ///
+    /// Fails with [`Error::InvalidProof`] if the tree width is zero, if the
+    /// slot does not match the protocol (see [`MpcProof::matches_slot`]) or
+    /// if the path length does not match the tree depth.
+    pub fn merkle_root(
+        &self,
+        protocol_id: &ProtocolId,
+        message: &sha256::Hash,
+    ) -> Result<sha256::Hash, Error> {
+        if !self.matches_slot(protocol_id)
+            || self.path.len() != tree_depth(self.width)
+        {
+            return Err(Error::InvalidProof);
+        }
+        let root = self.path.iter().enumerate().fold(
+            leaf_hash(protocol_id, message),
+            |node, (depth, sibling)| {
+                if (self.slot >> depth) & 1 == 0 {
+                    branch_hash(&node, sibling)
+                } else {
+                    branch_hash(sibling, &node)
+                }
+            },
+        );
+        Ok(root)
+    }
+
+    /// Verifies that the tree with the given `root` contains the `message`
+    /// in the slot of the protocol with `protocol_id`. Malformed proofs (see
+    /// [`MpcProof::merkle_root`]) are never valid.
+    #[inline]
+    pub fn verify(
+        &self,
+        root: &sha256::Hash,
+        protocol_id: &ProtocolId,
+        message: &sha256::Hash,
+    ) -> bool {
+        self.merkle_root(protocol_id, message) == Ok(*root)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn protocol_id(no: u8) -> ProtocolId {
+        let mut raw = [0u8; 32];
+        raw[0] = no;
+        ProtocolId::from_raw(raw)
+    }
+
+    fn gen_source(ids: &[u8]) -> MultiSource {
+        let mut source = MultiSource::with_entropy(0xdead_beef);
+        for no in ids {
+            source.insert(protocol_id(*no), sha256::Hash::hash(&[*no]));
+        }
+        source
+    }
+
+    #[test]
+    fn test_lnpbp4_tag() {
+        assert_eq!(
+            sha256::Hash::hash(b"LNPBP4").into_inner(),
+            LNPBP4_HASHED_TAG
+        );
+    }
+
+    #[test]
+    fn test_protocol_slot() {
+        let id = ProtocolId::from_raw([0xFF; 32]);
+        // 2^256 - 1 is divisible by 3, 5 and 17
+        assert_eq!(protocol_slot(&id, 1), 0);
+        assert_eq!(protocol_slot(&id, 3), 0);
+        assert_eq!(protocol_slot(&id, 5), 0);
+        assert_eq!(protocol_slot(&id, 17), 0);
+        assert_eq!(protocol_slot(&id, 256), 255);
+
+        let mut raw = [0u8; 32];
+        raw[0] = 10;
+        raw[1] = 1;
+        assert_eq!(protocol_slot(&ProtocolId::from_raw(raw), 1000), 266);
+    }
+
+    #[test]
+    fn test_width() {
+        let block = MultiCommitBlock::with(gen_source(&[])).unwrap();
+        assert_eq!(block.width(), 1);
+        let block = MultiCommitBlock::with(gen_source(&[7])).unwrap();
+        assert_eq!(block.width(), 1);
+        let block = MultiCommitBlock::with(gen_source(&[0, 1, 2])).unwrap();
+        assert_eq!(block.width(), 3);
+
+        // 0 and 4 collide at widths 1, 2 and 4
+        let block = MultiCommitBlock::with(gen_source(&[0, 4])).unwrap();
+        assert_eq!(block.width(), 3);
+        // 0, 6 and 12 collide at widths 3 and 4
+        let block = MultiCommitBlock::with(gen_source(&[0, 6, 12])).unwrap();
+        assert_eq!(block.width(), 5);
+        // 0, 60 and 120 collide at widths 3 to 6
+        let block = MultiCommitBlock::with(gen_source(&[0, 60, 120])).unwrap();
+        assert_eq!(block.width(), 7);
+    }
+
+    #[test]
+    fn test_optimal_width_properties() {
+        for seed in 0u8..32 {
+            let ids = (0..seed % 24 + 1)
+                .map(|no| ProtocolId::from(sha256::Hash::hash(&[seed, no])))
+                .collect::<Vec<_>>();
+            let width = optimal_width(&ids).unwrap();
+
+            // Determinism: neither order nor repetition of the ids matter
+            let mut reordered = ids.iter().rev().copied().collect::<Vec<_>>();
+            assert_eq!(optimal_width(&reordered), Ok(width));
+            reordered.extend(ids.iter().take(3));
+            assert_eq!(optimal_width(&reordered), Ok(width));
+
+            // Minimality
+            assert!(width as usize >= ids.len());
+            assert_eq!(find_collision(&ids, width), None);
+            for smaller in ids.len() as u16..width {
+                assert!(find_collision(&ids, smaller).is_some());
+            }
+
+            // Verification with the recorded width always succeeds
+            let mut source = MultiSource::with_entropy(seed as u64);
+            for id in &ids {
+                source.insert(*id, id.to_hash());
+            }
+            let block = MultiCommitBlock::with(source.clone()).unwrap();
+            assert_eq!(block.width(), width);
+            let root = block.commit();
+            let recorded =
+                MultiCommitBlock::with_width(source.clone(), width).unwrap();
+            assert_eq!(recorded, block);
+            for id in &ids {
+                let proof = recorded.proof_for(id).unwrap();
+                assert!(proof.verify(&root, id, &id.to_hash()));
+            }
+
+            // Historic commitments may use wider trees
+            let wider = (width + 1..)
+                .find(|wider| find_collision(&ids, *wider).is_none())
+                .unwrap();
+            let historic = MultiCommitBlock::with_width(source, wider).unwrap();
+            let root = historic.commit();
+            for id in &ids {
+                let proof = historic.proof_for(id).unwrap();
+                assert_eq!(proof.width, wider);
+                assert!(proof.verify(&root, id, &id.to_hash()));
+            }
+        }
+    }
+
+    #[test]
+    fn test_width_failures() {
+        let source = gen_source(&[0, 4]);
+        assert_eq!(
+            MultiCommitBlock::with_width(source.clone(), 0),
+            Err(Error::ZeroWidth)
+        );
+        assert_eq!(
+            MultiCommitBlock::with_width(source.clone(), 2),
+            Err(Error::SlotCollision {
+                first: protocol_id(0),
+                second: protocol_id(4),
+                slot: 0
+            })
+        );
+        assert_eq!(
+            MultiCommitBlock::with_width(source, 3).map(|block| block.width()),
+            Ok(3)
+        );
+
+        let ids = (0..=MAX_WIDTH as u32)
+            .map(|no| ProtocolId::from(sha256::Hash::hash(&no.to_le_bytes())))
+            .collect::<Vec<_>>();
+        assert_eq!(
+            optimal_width(&ids),
+            Err(Error::TreeOverflow(MAX_WIDTH as usize + 1))
+        );
+    }
+
+    #[test]
+    fn test_merkle_proofs() {
+        let ids = [0u8, 4, 6, 12, 18, 60, 120, 200, 255];
+        let block = MultiCommitBlock::with(gen_source(&ids)).unwrap();
+        let root = block.commit();
+        let mut slots = ids
+            .iter()
+            .map(|no| protocol_slot(&protocol_id(*no), block.width()))
+            .collect::<Vec<_>>();
+        slots.sort_unstable();
+        slots.dedup();
+        assert_eq!(slots.len(), ids.len());
+
+        for &no in &ids {
+            let id = protocol_id(no);
+            let message = sha256::Hash::hash(&[no]);
+            let proof = block.proof_for(&id).unwrap();
+            assert_eq!(proof.width, block.width());
+            assert_eq!(proof.path.len(), tree_depth(block.width()));
+            assert_eq!(proof.merkle_root(&id, &message), Ok(root));
+            assert!(proof.verify(&root, &id, &message));
+            assert!(!proof.verify(&root, &id, &sha256::Hash::hash(&[no, 1])));
+
+            // Proof of one protocol is not valid for the others, even for
+            // colliding ones
+            for other in ids.iter().filter(|other| **other != no) {
+                assert!(!proof.verify(&root, &protocol_id(*other), &message));
+            }
+        }
+        assert_eq!(
+            block.proof_for(&protocol_id(1)),
+            Err(Error::UnknownProtocol(protocol_id(1)))
+        );
+
+        // Unoccupied slots depend on the entropy
+        let mut source = block.source().clone();
+        source.entropy = 0;
+        let other = MultiCommitBlock::with(source).unwrap();
+        assert_eq!(other.width(), block.width());
+        assert_ne!(other.commit(), root);
+    }
+
+    #[test]
+    fn test_malformed_proof() {
+        let block = MultiCommitBlock::with(gen_source(&[0, 4, 6])).unwrap();
+        let id = protocol_id(4);
+        let message = sha256::Hash::hash(&[4]);
+        let mut proof = block.proof_for(&id).unwrap();
+        proof.path.pop();
+        assert_eq!(proof.merkle_root(&id, &message), Err(Error::InvalidProof));
+        proof.width = 0;
+        proof.path = vec![];
+        assert_eq!(proof.merkle_root(&id, &message), Err(Error::InvalidProof));
+    }
+
+    #[test]
+    fn test_proof_slots() {
+        // Protocols are placed into the slots equal to their numbers
+        let block =
+            MultiCommitBlock::with(gen_source(&[0, 1, 2, 3, 4])).unwrap();
+        assert_eq!(block.width(), 5);
+        let root = block.commit();
+
+        // Leftmost, middle and rightmost slots
+        for &no in &[0u8, 2, 4] {
+            let id = protocol_id(no);
+            let message = sha256::Hash::hash(&[no]);
+            let proof = block.proof_for(&id).unwrap();
+            assert_eq!(proof.slot, no as u16);
+            assert_eq!(proof.width, 5);
+            // Eight leaves
+            assert_eq!(proof.path.len(), 3);
+            assert!(proof.verify(&root, &id, &message));
+
+            // Tampered sibling
+            for depth in 0..proof.path.len() {
+                let mut tampered = proof.clone();
+                tampered.path[depth] = sha256::Hash::hash(&[0xFF]);
+                assert!(!tampered.verify(&root, &id, &message));
+            }
+
+            // Proof for the slot of the other protocol
+            let mut misplaced = proof.clone();
+            misplaced.slot = (no as u16 + 1) % 5;
+            assert!(!misplaced.matches_slot(&id));
+            assert_eq!(
+                misplaced.merkle_root(&id, &message),
+                Err(Error::InvalidProof)
+            );
+            assert!(!misplaced.verify(&root, &id, &message));
+        }
+
+        // The unused slots can't be claimed by the protocol, even if it
+        // knows the entropy
+        let outsider = protocol_id(7);
+        let message = sha256::Hash::hash(&[7]);
+        let mut proof = block.proof_for(&protocol_id(2)).unwrap();
+        proof.slot = 7;
+        assert!(!proof.matches_slot(&outsider));
+        assert!(!proof.verify(&root, &outsider, &message));
+    }
+
+    #[test]
+    fn test_entropy() {
+        assert_ne!(MultiSource::new().entropy, MultiSource::new().entropy);
+
+        let source = gen_source(&[0, 4, 6]);
+        let mut other_source = source.clone();
+        other_source.entropy = MultiSource::new().entropy;
+        let block = MultiCommitBlock::with(source).unwrap();
+        let other = MultiCommitBlock::with(other_source).unwrap();
+        assert_eq!(block.width(), other.width());
+
+        let root = block.commit_conceal();
+        let other_root = other.commit_conceal();
+        assert_eq!(root.into_inner(), block.commit());
+        assert_ne!(root, other_root);
+
+        for &no in &[0u8, 4, 6] {
+            let id = protocol_id(no);
+            let message = sha256::Hash::hash(&[no]);
+            let proof = block.proof_for(&id).unwrap();
+            let other_proof = other.proof_for(&id).unwrap();
+            assert!(proof.verify(root.as_inner(), &id, &message));
+            assert!(other_proof.verify(other_root.as_inner(), &id, &message));
+            assert!(!proof.verify(other_root.as_inner(), &id, &message));
+            assert!(!other_proof.verify(root.as_inner(), &id, &message));
+        }
+
+        // Concealed data contain just the root
+        let data = root.strict_serialize().unwrap();
+        assert_eq!(data, block.commit().to_vec());
+        assert_eq!(MpcRoot::strict_deserialize(&data).unwrap(), root);
+        assert_eq!(root.to_string().parse::<MpcRoot>(), Ok(root));
+    }
+
+    #[test]
+    fn test_strict_encoding() {
+        let block = MultiCommitBlock::with(gen_source(&[0, 4, 6])).unwrap();
+        let data = block.strict_serialize().unwrap();
+        // width, entropy, message count and three entries
+        assert_eq!(data.len(), 2 + 8 + 2 + 3 * 64);
+        assert_eq!(MultiCommitBlock::strict_deserialize(&data).unwrap(), block);
+
+        // Decoding performs the same checks as the construction
+        let source = block.source().clone();
+        let data = (0u16, source.clone()).strict_serialize().unwrap();
+        assert_eq!(
+            MultiCommitBlock::strict_deserialize(&data),
+            Err(strict_encoding::Error::DataIntegrityError(
+                Error::ZeroWidth.to_string()
+            ))
+        );
+        let data = (2u16, source).strict_serialize().unwrap();
+        assert_eq!(
+            MultiCommitBlock::strict_deserialize(&data),
+            Err(strict_encoding::Error::DataIntegrityError(
+                Error::SlotCollision {
+                    first: protocol_id(0),
+                    second: protocol_id(4),
+                    slot: 0
+                }
+                .to_string()
+            ))
+        );
+
+        let proof = block.proof_for(&protocol_id(6)).unwrap();
+        let data = proof.strict_serialize().unwrap();
+        assert_eq!(data.len(), 2 + 2 + 2 + 32 * proof.path.len());
+        assert_eq!(MpcProof::strict_deserialize(&data).unwrap(), proof);
+    }
+}
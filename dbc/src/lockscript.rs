@@ -28,30 +28,131 @@
 use core::cell::RefCell;
 use std::collections::{BTreeSet, HashSet};
 
+use bitcoin::blockdata::opcodes::all::*;
+use bitcoin::blockdata::opcodes::{self};
+use bitcoin::blockdata::script::{Builder, Instruction, Script};
 use bitcoin::hashes::{hash160, sha256, Hmac};
 use bitcoin::{secp256k1, PubkeyHash};
 use bitcoin_scripts::LockScript;
 use commit_verify::EmbedCommitVerify;
 use miniscript::Segwitv0;
 
-use super::{Container, Error, KeysetCommitment, Proof, ScriptEncodeData};
+use super::{
+    Container, Error, KeysetCommitment, Proof, ProtocolTag, ScriptEncodeData,
+    LNPBP2_TAG,
+};
 use crate::KeysetContainer;
 
+/// Errors happening during validation of a script as a [`LockScript`]
+#[derive(Clone, Copy, PartialEq, Eq, Debug, Display, Error)]
+#[display(doc_comments)]
+pub enum LockscriptError {
+    /// script contains opcode {0:?} which can't be used in a lockscript
+    InvalidOpcode(opcodes::All),
+
+    /// script ends in the middle of a data push
+    EarlyEndOfScript,
+}
+
+/// Construction of a [`LockScript`] from an arbitrary [`Script`] with
+/// validation that the script may actually be used as a lockscript, i.e. it
+/// does not represent a provably unspendable `OP_RETURN` output and contains
+/// only defined opcodes.
+///
+/// This is a replacement for `TryFrom<Script>`, which can't be implemented
+/// for [`LockScript`] outside of `bitcoin_scripts` crate.
+pub trait TryFromScript: Sized {
+    /// Validates the script and wraps it into the lockscript type
+    fn try_from_script(script: Script) -> Result<Self, LockscriptError>;
+}
+
+impl TryFromScript for LockScript {
+    fn try_from_script(script: Script) -> Result<Self, LockscriptError> {
+        for (no, instruction) in script.instructions().enumerate() {
+            match instruction {
+                Err(_) => return Err(LockscriptError::EarlyEndOfScript),
+                Ok(Instruction::Op(OP_RETURN)) if no == 0 => {
+                    return Err(LockscriptError::InvalidOpcode(OP_RETURN))
+                }
+                Ok(Instruction::Op(op))
+                    if op.into_u8() >= OP_RETURN_186.into_u8() =>
+                {
+                    return Err(LockscriptError::InvalidOpcode(op))
+                }
+                Ok(_) => {}
+            }
+        }
+        Ok(LockScript::from(script))
+    }
+}
+
 #[derive(Clone, PartialEq, Eq, Hash, Debug, Display)]
 #[display(Debug)]
 pub struct LockscriptContainer {
     pub script: LockScript,
     pub pubkey: secp256k1::PublicKey,
     /// Single SHA256 hash of the protocol-specific tag
-    pub tag: sha256::Hash,
+    pub tag: ProtocolTag,
     /// Tweaking factor stored after [`LockscriptCommitment::embed_commit`]
     /// procedure
     pub tweaking_factor: Option<Hmac<sha256::Hash>>,
 }
 
+impl LockscriptContainer {
+    /// Constructs container using LNPBP-2 protocol tag ([`LNPBP2_TAG`]),
+    /// which is the default tag for commitments which do not define their
+    /// own protocol-specific tag
+    pub fn with_lnpbp2_tag(
+        script: LockScript,
+        pubkey: secp256k1::PublicKey,
+    ) -> Self {
+        Self {
+            script,
+            pubkey,
+            tag: LNPBP2_TAG,
+            tweaking_factor: None,
+        }
+    }
+
+    /// Constructs container for the standard bare m-of-n multisig lockscript
+    /// `OP_m <key_1> ... <key_n> OP_n OP_CHECKMULTISIG`, with the keys put in
+    /// the provided order, committing into `keys[target_index]`.
+    ///
+    /// Fails with [`Error::InvalidMultisig`] unless `1 <= m <= n <= 16` and
+    /// with [`Error::LockscriptKeyNotFound`] if there is no key with
+    /// `target_index`.
+    pub fn multisig_keyset(
+        m: usize,
+        keys: &[secp256k1::PublicKey],
+        target_index: usize,
+        tag: ProtocolTag,
+    ) -> Result<Self, Error> {
+        let n = keys.len();
+        if m == 0 || m > n || n > 16 {
+            return Err(Error::InvalidMultisig { m, n });
+        }
+        let pubkey =
+            *keys.get(target_index).ok_or(Error::LockscriptKeyNotFound)?;
+        let script = keys
+            .iter()
+            .fold(Builder::new().push_int(m as i64), |builder, key| {
+                builder.push_slice(&key.serialize())
+            })
+            .push_int(n as i64)
+            .push_opcode(OP_CHECKMULTISIG)
+            .into_script();
+        Ok(Self {
+            script: script.into(),
+            pubkey,
+            tag,
+            tweaking_factor: None,
+        })
+    }
+}
+
 impl Container for LockscriptContainer {
     /// Out supplement is a protocol-specific tag in its hashed form
-    type Supplement = sha256::Hash;
+    type Supplement = ProtocolTag;
 
     type Host = Option<()>;
 
@@ -60,7 +161,9 @@ impl Container for LockscriptContainer {
         supplement: &Self::Supplement,
         _: &Self::Host,
     ) -> Result<Self, Error> {
-        if let ScriptEncodeData::LockScript(ref script) = proof.source {
+        if let ScriptEncodeData::LockScript(ref script)
+        | ScriptEncodeData::NestedLockScript(ref script) = proof.source
+        {
             Ok(Self {
                 pubkey: proof.pubkey,
                 script: script.clone(),
@@ -74,30 +177,21 @@ impl Container for LockscriptContainer {
 
     #[inline]
     fn deconstruct(self) -> (Proof, Self::Supplement) {
-        (
-            Proof {
-                source: ScriptEncodeData::LockScript(self.script),
-                pubkey: self.pubkey,
-            },
-            self.tag,
-        )
+        (Proof::from_lockscript(self.pubkey, self.script), self.tag)
     }
 
     #[inline]
     fn to_proof(&self) -> Proof {
-        Proof {
-            source: ScriptEncodeData::LockScript(self.script.clone()),
-            pubkey: self.pubkey,
-        }
+        Proof::from_lockscript(self.pubkey, self.script.clone())
     }
 
     #[inline]
     fn into_proof(self) -> Proof {
-        Proof {
-            source: ScriptEncodeData::LockScript(self.script),
-            pubkey: self.pubkey,
-        }
+        Proof::from_lockscript(self.pubkey, self.script)
     }
+
+    #[inline]
+    fn supplement(&self) -> &Self::Supplement { &self.tag }
 }
 
 /// [`LockScript`] containing public keys which sum is commit to some message
@@ -254,12 +348,12 @@ mod test {
             sk[1] = (i >> 8) as u8;
             sk[2] = (i >> 16) as u8;
 
+            let sk =
+                secp256k1::SecretKey::from_slice(&sk[..]).expect("secret key");
             let pk = bitcoin::PublicKey {
-                key: secp256k1::PublicKey::from_secret_key(
-                    &secp256k1::SECP256K1,
-                    &secp256k1::SecretKey::from_slice(&sk[..])
-                        .expect("secret key"),
-                ),
+                key: crate::lnpbp1::with_secp(|secp| {
+                    secp256k1::PublicKey::from_secret_key(secp, &sk)
+                }),
                 compressed: true,
             };
             ret.push(pk);
@@ -280,7 +374,7 @@ mod test {
 
     #[test]
     fn test_no_keys_and_hashes() {
-        let tag = sha256::Hash::hash(b"TEST_TAG");
+        let tag = ProtocolTag::new("TEST_TAG");
         let (keys, _, dummy_hashes) = gen_test_data();
         let sha_hash = sha256::Hash::hash(&"(nearly)random string".as_bytes());
 
@@ -314,7 +408,7 @@ mod test {
 
     #[test]
     fn test_unknown_key() {
-        let tag = sha256::Hash::hash(b"TEST_TAG");
+        let tag = ProtocolTag::new("TEST_TAG");
         let (keys, _, _) = gen_test_data();
 
         let mut uncompressed = keys[5];
@@ -348,7 +442,7 @@ mod test {
 
     #[test]
     fn test_unknown_hash() {
-        let tag = sha256::Hash::hash(b"TEST_TAG");
+        let tag = ProtocolTag::new("TEST_TAG");
         let (keys, _, _) = gen_test_data();
 
         let ms = vec![
@@ -379,7 +473,7 @@ mod test {
 
     #[test]
     fn test_known_key() {
-        let tag = sha256::Hash::hash(b"TEST_TAG");
+        let tag = ProtocolTag::new("TEST_TAG");
         let (keys, _, _) = gen_test_data();
 
         let mut uncompressed = keys[5];
@@ -414,7 +508,7 @@ mod test {
 
     #[test]
     fn test_known_hash() {
-        let tag = sha256::Hash::hash(b"TEST_TAG");
+        let tag = ProtocolTag::new("TEST_TAG");
         let (keys, _, _) = gen_test_data();
 
         let ms = vec![
@@ -446,7 +540,7 @@ mod test {
 
     #[test]
     fn test_multisig() {
-        let tag = sha256::Hash::hash(b"TEST_TAG");
+        let tag = ProtocolTag::new("TEST_TAG");
         let (keys, _, _) = gen_test_data();
 
         let ms: Vec<Miniscript<_, Segwitv0>> = vec![
@@ -483,9 +577,85 @@ mod test {
             });
     }
 
+    #[test]
+    fn test_multisig_keyset() {
+        let tag = ProtocolTag::new("TEST_TAG");
+        let keys = pubkeys(16).into_iter().map(|pk| pk.key).collect::<Vec<_>>();
+
+        let container =
+            LockscriptContainer::multisig_keyset(2, &keys[..3], 1, tag)
+                .unwrap();
+        let mut expected = vec![0x52];
+        for key in &keys[..3] {
+            expected.push(33);
+            expected.extend(&key.serialize());
+        }
+        expected.extend(&[0x53, 0xAE]);
+        assert_eq!(container.script.as_bytes(), &expected[..]);
+        assert_eq!(container.pubkey, keys[1]);
+        assert_eq!(container.tag, tag);
+        assert_eq!(container.tweaking_factor, None);
+
+        // The same script as produced by miniscript
+        let descriptor = policy_str!(
+            "thresh(2,pk({}),pk({}),pk({}))",
+            keys[0],
+            keys[1],
+            keys[2]
+        );
+        let ms: Miniscript<_, Segwitv0> = descriptor.compile().unwrap();
+        assert_eq!(LockScript::from(ms.encode()), container.script);
+
+        let msg = "Test message";
+        for target_index in 0..3 {
+            let container = LockscriptContainer::multisig_keyset(
+                2,
+                &keys[..3],
+                target_index,
+                tag,
+            )
+            .unwrap();
+            let commitment = LockscriptCommitment::embed_commit(
+                &mut container.clone(),
+                &msg,
+            )
+            .unwrap();
+            assert!(commitment.verify(&container, &msg).unwrap());
+        }
+
+        for (m, n) in &[(1usize, 1usize), (16, 16), (1, 16)] {
+            let container =
+                LockscriptContainer::multisig_keyset(*m, &keys[..*n], 0, tag)
+                    .unwrap();
+            let commitment = LockscriptCommitment::embed_commit(
+                &mut container.clone(),
+                &msg,
+            )
+            .unwrap();
+            assert!(commitment.verify(&container, &msg).unwrap());
+        }
+
+        let mut too_many = keys.clone();
+        too_many.push(keys[0]);
+        for (m, keys) in &[(0, &keys[..3]), (4, &keys[..3]), (1, &too_many[..])]
+        {
+            assert_eq!(
+                LockscriptContainer::multisig_keyset(*m, keys, 0, tag),
+                Err(Error::InvalidMultisig {
+                    m: *m,
+                    n: keys.len()
+                })
+            );
+        }
+        assert_eq!(
+            LockscriptContainer::multisig_keyset(2, &keys[..3], 3, tag),
+            Err(Error::LockscriptKeyNotFound)
+        );
+    }
+
     #[test]
     fn test_complex_scripts_unique_key() {
-        let tag = sha256::Hash::hash(b"TEST_TAG");
+        let tag = ProtocolTag::new("TEST_TAG");
         let (keys, _, _) = gen_test_data();
 
         let ms = policy_str!(
@@ -512,4 +682,52 @@ mod test {
                 .unwrap();
         assert!(commitment.verify(&container, &msg).unwrap())
     }
+
+    #[test]
+    fn test_try_from_script() {
+        let (keys, _, _) = gen_test_data();
+        let ms = policy_str!("and(pk({}),older(10))", keys[0])
+            .compile::<Segwitv0>()
+            .unwrap();
+        let script = ms.encode();
+        assert_eq!(
+            LockScript::try_from_script(script.clone()),
+            Ok(LockScript::from(script))
+        );
+
+        // OP_RETURN is allowed only in non-initial positions
+        let script = Builder::new()
+            .push_opcode(OP_IF)
+            .push_opcode(OP_RETURN)
+            .push_opcode(OP_ENDIF)
+            .push_opcode(OP_PUSHNUM_1)
+            .into_script();
+        assert!(LockScript::try_from_script(script).is_ok());
+
+        let script = Builder::new()
+            .push_opcode(OP_RETURN)
+            .push_slice(&[0u8; 32])
+            .into_script();
+        assert_eq!(
+            LockScript::try_from_script(script),
+            Err(LockscriptError::InvalidOpcode(OP_RETURN))
+        );
+
+        let script = Builder::new()
+            .push_opcode(OP_PUSHNUM_1)
+            .push_opcode(OP_RETURN_186)
+            .into_script();
+        assert_eq!(
+            LockScript::try_from_script(script),
+            Err(LockscriptError::InvalidOpcode(OP_RETURN_186))
+        );
+        assert_eq!(
+            LockScript::try_from_script(Script::from(vec![0xff])),
+            Err(LockscriptError::InvalidOpcode(OP_RETURN_255))
+        );
+        assert_eq!(
+            LockScript::try_from_script(Script::from(vec![0x02, 0x01])),
+            Err(LockscriptError::EarlyEndOfScript)
+        );
+    }
 }
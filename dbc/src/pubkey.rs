@@ -30,10 +30,11 @@
 
 use bitcoin::hashes::{sha256, Hmac};
 use bitcoin::secp256k1;
-use commit_verify::EmbedCommitVerify;
+use commit_verify::{CommitConceal, EmbedCommitVerify};
 
-use super::{Container, Error, Proof};
-use crate::lnpbp1;
+use super::{Container, Error, Proof, ProtocolTag};
+use crate::lnpbp1::{self, TweakingFactor};
+use crate::lnpbp4::{MpcRoot, MultiCommitBlock};
 
 /// Container for LNPBP-1 commitments. In order to be constructed, commitment
 /// requires an original public key and a protocol-specific tag, which
@@ -45,15 +46,28 @@ pub struct PubkeyContainer {
     /// The original public key: host for commitment
     pub pubkey: secp256k1::PublicKey,
     /// Single SHA256 hash of the protocol-specific tag
-    pub tag: sha256::Hash,
+    pub tag: ProtocolTag,
     /// Tweaking factor stored after [`PubkeyCommitment::embed_commit`]
     /// procedure
     pub tweaking_factor: Option<Hmac<sha256::Hash>>,
 }
 
+impl PubkeyContainer {
+    /// Constructs container for the public key commitment, computing the
+    /// protocol tag as a single SHA256 hash of the provided protocol name
+    /// (see [`ProtocolTag::new`])
+    pub fn with_tag_name(pubkey: secp256k1::PublicKey, tag_name: &str) -> Self {
+        Self {
+            pubkey,
+            tag: ProtocolTag::new(tag_name),
+            tweaking_factor: None,
+        }
+    }
+}
+
 impl Container for PubkeyContainer {
     /// Out supplement is a protocol-specific tag in its hashed form
-    type Supplement = sha256::Hash;
+    type Supplement = ProtocolTag;
     /// Our proof contains the host, so we don't need host here
     type Host = Option<()>;
 
@@ -82,14 +96,56 @@ impl Container for PubkeyContainer {
 
     #[inline]
     fn into_proof(self) -> Proof { Proof::from(self.pubkey) }
+
+    #[inline]
+    fn supplement(&self) -> &Self::Supplement { &self.tag }
 }
 
 /// Public key committed to some message via LNPBP1-based tweaking procedure
 #[derive(Wrapper, Clone, PartialEq, Eq, Hash, Debug, Display, From)]
+#[derive(StrictEncode, StrictDecode)]
+#[cfg_attr(
+    feature = "serde",
+    derive(Serialize, Deserialize),
+    serde(crate = "serde_crate")
+)]
 #[display("{0}", alt = "{_0:#}*")]
 #[wrapper(FromStr, LowerHex)]
 pub struct PubkeyCommitment(secp256k1::PublicKey);
 
+impl PubkeyCommitment {
+    /// Recovers the original public key from the commitment by subtracting
+    /// tweaking factor (multiplied by the curve generator point) from the
+    /// tweaked public key.
+    pub fn recover_original(
+        &self,
+        factor: &TweakingFactor,
+    ) -> Result<secp256k1::PublicKey, lnpbp1::Error> {
+        let tweak = secp256k1::SecretKey::from_slice(&factor[..])
+            .map_err(|_| lnpbp1::Error::InvalidTweak)?;
+        let tweak_point = lnpbp1::with_secp(|secp| {
+            let mut tweak_point =
+                secp256k1::PublicKey::from_secret_key(secp, &tweak);
+            tweak_point.negate_assign(secp);
+            tweak_point
+        });
+        self.0
+            .combine(&tweak_point)
+            .map_err(|_| lnpbp1::Error::InvalidTweak)
+    }
+
+    /// Reverts the commitment, returning the original public key. This is
+    /// the same operation as [`PubkeyCommitment::recover_original`], named
+    /// consistently with [`crate::SpkCommitment::revert`].
+    #[inline]
+    pub fn revert(
+        &self,
+        factor: &TweakingFactor,
+    ) -> Result<secp256k1::PublicKey, lnpbp1::Error> {
+        self.recover_original(factor)
+    }
+}
+
 impl<MSG> EmbedCommitVerify<MSG> for PubkeyCommitment
 where
     MSG: AsRef<[u8]>,
@@ -109,7 +165,7 @@ where
         let tweaking_factor = lnpbp1::commit(
             &mut keyset,
             &mut pubkey,
-            &pubkey_container.tag,
+            &pubkey_container.tag.to_hash(),
             msg,
         )?;
 
@@ -120,13 +176,45 @@ where
     }
 }
 
+impl EmbedCommitVerify<MpcRoot> for PubkeyCommitment {
+    type Container = PubkeyContainer;
+    type Error = lnpbp1::Error;
+
+    /// Commits to the canonical message of the LNPBP-4 tree root (see
+    /// [`MpcRoot::to_commitment_message`])
+    #[inline]
+    fn embed_commit(
+        pubkey_container: &mut Self::Container,
+        msg: &MpcRoot,
+    ) -> Result<Self, Self::Error> {
+        <Self as EmbedCommitVerify<[u8; 64]>>::embed_commit(
+            pubkey_container,
+            &msg.to_commitment_message(),
+        )
+    }
+}
+
+impl EmbedCommitVerify<MultiCommitBlock> for PubkeyCommitment {
+    type Container = PubkeyContainer;
+    type Error = lnpbp1::Error;
+
+    /// Commits to the root of the LNPBP-4 tree
+    #[inline]
+    fn embed_commit(
+        pubkey_container: &mut Self::Container,
+        msg: &MultiCommitBlock,
+    ) -> Result<Self, Self::Error> {
+        Self::embed_commit(pubkey_container, &msg.commit_conceal())
+    }
+}
+
 #[cfg(test)]
 mod test {
     use std::str::FromStr;
 
     use amplify::hex::ToHex;
     use amplify::Wrapper;
-    use bitcoin::hashes::{sha256, Hash};
+    use bitcoin::hashes::Hash;
     use bitcoin::secp256k1;
 
     use super::*;
@@ -134,7 +222,7 @@ mod test {
 
     #[test]
     fn test_pubkey_commitment() {
-        let tag = sha256::Hash::hash(b"TEST_TAG");
+        let tag = ProtocolTag::new("TEST_TAG");
         gen_secp_pubkeys(9).into_iter().for_each(|pubkey| {
             embed_commit_verify_suite::<Vec<u8>, PubkeyCommitment>(
                 gen_messages(),
@@ -147,9 +235,93 @@ mod test {
         });
     }
 
+    #[test]
+    fn test_mpc_commitment() {
+        use crate::lnpbp4::MultiSource;
+
+        let mut source = MultiSource::with_entropy(1);
+        for (no, msg) in gen_messages().into_iter().enumerate() {
+            source.insert(
+                ProtocolTag::new(&format!("protocol #{}", no)),
+                sha256::Hash::hash(&msg),
+            );
+        }
+        let block = MultiCommitBlock::with(source).unwrap();
+        let root = block.commit_conceal();
+        for pubkey in gen_secp_pubkeys(4) {
+            let container = PubkeyContainer::with_tag_name(pubkey, "TEST_TAG");
+            let commitment =
+                PubkeyCommitment::embed_commit(&mut container.clone(), &block)
+                    .unwrap();
+            assert_eq!(
+                PubkeyCommitment::embed_commit(&mut container.clone(), &root),
+                Ok(commitment.clone())
+            );
+            assert_eq!(
+                PubkeyCommitment::embed_commit(
+                    &mut container.clone(),
+                    &root.to_commitment_message()
+                ),
+                Ok(commitment.clone())
+            );
+            assert_eq!(commitment.verify(&container, &root), Ok(true));
+            assert_eq!(
+                commitment.verify(&container, &block.commit()),
+                Ok(false)
+            );
+        }
+    }
+
+    #[test]
+    fn test_recover_original() {
+        let tag = ProtocolTag::new("TEST_TAG");
+        for pubkey in gen_secp_pubkeys(9) {
+            for msg in gen_messages() {
+                let mut container = PubkeyContainer {
+                    pubkey,
+                    tag,
+                    tweaking_factor: None,
+                };
+                let commitment =
+                    PubkeyCommitment::embed_commit(&mut container, &msg)
+                        .unwrap();
+                let factor = container.tweaking_factor.unwrap();
+                assert_eq!(commitment.recover_original(&factor), Ok(pubkey));
+            }
+        }
+    }
+
+    #[test]
+    fn test_revert() {
+        let tag = ProtocolTag::new("TEST_TAG");
+        let keys = gen_secp_pubkeys(2);
+        for msg in gen_messages() {
+            let original = keys[0];
+            let mut tweaked = original;
+            let factor = lnpbp1::commit(
+                &mut bset![original],
+                &mut tweaked,
+                &tag.to_hash(),
+                &msg,
+            )
+            .unwrap();
+            let commitment = PubkeyCommitment::from_inner(tweaked);
+            assert_eq!(commitment.revert(&factor), Ok(original));
+
+            let other = lnpbp1::commit(
+                &mut bset![keys[1]],
+                &mut keys[1].clone(),
+                &tag.to_hash(),
+                &msg,
+            )
+            .unwrap();
+            assert_ne!(commitment.revert(&other), Ok(original));
+        }
+    }
+
     #[test]
     fn test_tweaking_results() {
-        let tag = sha256::Hash::hash(b"TEST_TAG");
+        let tag = ProtocolTag::new("TEST_TAG");
         let msg = "test message";
         let pubkey = secp256k1::PublicKey::from_str(
             "0218845781f631c48f1c9709e23092067d06837f30aa0cd0544ac887fe91ddd166",
@@ -169,4 +341,29 @@ mod test {
             "02de6531527f7a453e0b53e4b33a78c60f9bcdb69abbf59866e33de347ceda0bdf"
         );
     }
+
+    #[test]
+    fn test_with_tag_name() {
+        let pubkey = gen_secp_pubkeys(1)[0];
+        let container = PubkeyContainer::with_tag_name(pubkey, "TEST_TAG");
+        assert_eq!(container.tag, ProtocolTag::new("TEST_TAG"));
+        assert_eq!(container.tag.to_hash(), sha256::Hash::hash(b"TEST_TAG"));
+
+        let mut other = container.clone();
+        let commitment =
+            PubkeyCommitment::embed_commit(&mut other, &"message").unwrap();
+        let mut manual = PubkeyContainer {
+            pubkey,
+            tag: ProtocolTag::new("TEST_TAG"),
+            tweaking_factor: None,
+        };
+        assert_eq!(
+            PubkeyCommitment::embed_commit(&mut manual, &"message").unwrap(),
+            commitment
+        );
+        assert_ne!(
+            PubkeyContainer::with_tag_name(pubkey, "OTHER_TAG").tag,
+            container.tag
+        );
+    }
 }
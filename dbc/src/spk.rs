@@ -14,17 +14,41 @@
 // If not, see <https://opensource.org/licenses/Apache-2.0>.
 
 use core::convert::TryFrom;
+use core::fmt::{self, Display, Formatter};
+use core::str::FromStr;
+use std::io;
 
+use amplify::hex::{self, FromHex};
 use amplify::Wrapper;
-use bitcoin::blockdata::script::Script;
+use bitcoin::blockdata::opcodes::all::{
+    OP_PUSHBYTES_32, OP_PUSHBYTES_33, OP_PUSHNUM_1,
+};
+use bitcoin::blockdata::script::{Instruction, Script};
 use bitcoin::hashes::{sha256, Hmac};
-use bitcoin::secp256k1;
+use bitcoin::util::address::Payload;
+use bitcoin::util::psbt;
+use bitcoin::util::psbt::raw::ProprietaryKey;
+use bitcoin::{secp256k1, Address, Network};
 use bitcoin_scripts::{Category, LockScript, PubkeyScript, ToPubkeyScript};
-use commit_verify::EmbedCommitVerify;
+use commit_verify::{CommitConceal, EmbedCommitVerify};
+#[cfg(feature = "miniscript")]
+use miniscript::descriptor::DescriptorType;
+#[cfg(feature = "miniscript")]
+use miniscript::{
+    BareCtx, Descriptor, DescriptorTrait, ForEach, ForEachKey, Legacy,
+    Miniscript, Segwitv0,
+};
+use strict_encoding::{StrictDecode, StrictEncode};
 
 use super::{
     Container, Error, LockscriptCommitment, LockscriptContainer, Proof,
-    PubkeyCommitment, PubkeyContainer, TaprootCommitment, TaprootContainer,
+    ProtocolTag, PubkeyCommitment, PubkeyContainer, TaprootCommitment,
+    TaprootContainer,
+};
+use crate::lnpbp4::{MpcRoot, MultiCommitBlock};
+use crate::taproot::{
+    bip341_output_key, witness_v1_script, PSBT_LNPBP_PREFIX,
+    PSBT_OUT_LNPBP2_PROOF,
 };
 
 /// Enum defining how given `scriptPubkey` is constructed from the script data
@@ -38,6 +62,11 @@ use super::{
 /// proof (it can be guessed from a given proof and `scriptPubkey` and we'd like
 /// to preserve space with client-validated data).
 #[derive(Clone, PartialEq, Eq, Hash, Debug, Display)]
+#[cfg_attr(
+    feature = "serde",
+    derive(Serialize, Deserialize),
+    serde(crate = "serde_crate")
+)]
 #[display(Debug)]
 #[non_exhaustive]
 pub enum ScriptEncodeMethod {
@@ -51,13 +80,377 @@ pub enum ScriptEncodeMethod {
     Taproot,
     OpReturn,
     Bare,
+    /// Script-based output described by a miniscript output descriptor
+    /// string (`sh`, `wsh`, `sh(wsh)` or a bare script descriptor), which
+    /// must contain the container public key in one of its `pk()` leaves.
+    ///
+    /// The commitment procedure replaces the method in the container with one
+    /// of the script-based methods and [`ScriptEncodeData::LockScript`]
+    /// source (see [`SpkContainer::resolve_miniscript`]), so the produced
+    /// commitments and proofs are the same as for the resolved method, and
+    /// the method is never deduced from the `scriptPubkey`.
+    #[cfg(feature = "miniscript")]
+    Miniscript(String),
+}
+
+// Variants are encoded by their order with a single-byte tag, followed by the
+// variant data. Tag of the `Miniscript` variant is reserved independently of
+// the `miniscript` feature, so the encoding does not depend on the features;
+// builds without the feature fail to decode such data.
+impl StrictEncode for ScriptEncodeMethod {
+    fn strict_encode<E: io::Write>(
+        &self,
+        mut e: E,
+    ) -> Result<usize, strict_encoding::Error> {
+        use ScriptEncodeMethod::*;
+        let tag: u8 = match self {
+            PublicKey => 0,
+            PubkeyHash => 1,
+            ScriptHash => 2,
+            WPubkeyHash => 3,
+            WScriptHash => 4,
+            ShWPubkeyHash => 5,
+            ShWScriptHash => 6,
+            Taproot => 7,
+            OpReturn => 8,
+            Bare => 9,
+            #[cfg(feature = "miniscript")]
+            Miniscript(_) => 10,
+        };
+        let len = tag.strict_encode(&mut e)?;
+        match self {
+            #[cfg(feature = "miniscript")]
+            Miniscript(descriptor) => Ok(len + descriptor.strict_encode(e)?),
+            _ => Ok(len),
+        }
+    }
+}
+
+impl StrictDecode for ScriptEncodeMethod {
+    fn strict_decode<D: io::Read>(
+        mut d: D,
+    ) -> Result<Self, strict_encoding::Error> {
+        use ScriptEncodeMethod::*;
+        Ok(match u8::strict_decode(&mut d)? {
+            0 => PublicKey,
+            1 => PubkeyHash,
+            2 => ScriptHash,
+            3 => WPubkeyHash,
+            4 => WScriptHash,
+            5 => ShWPubkeyHash,
+            6 => ShWScriptHash,
+            7 => Taproot,
+            8 => OpReturn,
+            9 => Bare,
+            #[cfg(feature = "miniscript")]
+            10 => Miniscript(String::strict_decode(d)?),
+            #[cfg(not(feature = "miniscript"))]
+            10 => {
+                return Err(strict_encoding::Error::UnsupportedDataStructure(
+                    "miniscript-based script encode method requires \
+                     `miniscript` feature",
+                ))
+            }
+            tag => {
+                return Err(strict_encoding::Error::EnumValueNotKnown(
+                    "ScriptEncodeMethod",
+                    tag as usize,
+                ))
+            }
+        })
+    }
+}
+
+impl ScriptEncodeMethod {
+    /// Returns the weight of the `scriptSig` (four weight units per byte) and
+    /// witness data (one weight unit per byte, including the witness stack
+    /// item count) required to spend an output committed with this method.
+    /// Signatures are assumed to be of the maximal size (72-byte DER
+    /// signatures with sighash flag and 64-byte Schnorr signatures with the
+    /// default sighash for taproot key-path spending); public keys are
+    /// assumed to be compressed. [`ScriptEncodeMethod::OpReturn`] outputs are
+    /// unspendable and give zero weight.
+    ///
+    /// For script-based methods the weight depends on the lockscript and its
+    /// satisfaction, which are not known here, so only the weight of the
+    /// nested witness program push in the `scriptSig` of
+    /// [`ScriptEncodeMethod::ShWScriptHash`] is returned, and zero for the
+    /// rest of them.
+    pub fn expected_input_weight(&self) -> u64 {
+        use ScriptEncodeMethod::*;
+        match self {
+            // <sig>
+            PublicKey => 4 * (1 + 72),
+            // <sig> <pubkey>
+            PubkeyHash => 4 * (1 + 72 + 1 + 33),
+            // [<sig> <pubkey>]
+            WPubkeyHash => 1 + 1 + 72 + 1 + 33,
+            // <0 <20-byte-key-hash>> [<sig> <pubkey>]
+            ShWPubkeyHash => 4 * (1 + 22) + 1 + 1 + 72 + 1 + 33,
+            // [<sig>]
+            Taproot => 1 + 1 + 64,
+            // <0 <32-byte-script-hash>>
+            ShWScriptHash => 4 * (1 + 34),
+            OpReturn | ScriptHash | WScriptHash | Bare => 0,
+            #[cfg(feature = "miniscript")]
+            Miniscript(ref descriptor) => parse_descriptor(descriptor)
+                .map(|(_, method)| method.expected_input_weight())
+                .unwrap_or(0),
+        }
+    }
+
+    /// Returns the size of the `scriptSig` and witness data required to spend
+    /// an output committed with this method in virtual bytes, i.e.
+    /// [`ScriptEncodeMethod::expected_input_weight`] rounded up to the whole
+    /// virtual bytes. Multiplied by the fee rate in satoshis per virtual byte
+    /// it gives the minimal fee for spending the output.
+    #[inline]
+    pub fn minimum_fee_rate_vbytes(&self) -> u64 {
+        (self.expected_input_weight() + 3) / 4
+    }
+
+    /// Renders `scriptPubkey` for a public key-based output. Fails with
+    /// [`Error::InvalidProofStructure`] if the method requires script data.
+    ///
+    /// NB: For [`ScriptEncodeMethod::OpReturn`] the function does not check
+    /// the key parity required by LNPBP-2; this is done during the commitment
+    /// procedure.
+    fn script_pubkey_from_pubkey(
+        &self,
+        pubkey: secp256k1::PublicKey,
+    ) -> Result<PubkeyScript, Error> {
+        use ScriptEncodeMethod::*;
+        Ok(match self {
+            PublicKey => pubkey.to_pubkey_script(Category::Bare),
+            PubkeyHash => pubkey.to_pubkey_script(Category::Hashed),
+            WPubkeyHash => pubkey.to_pubkey_script(Category::SegWit),
+            ShWPubkeyHash => pubkey.to_pubkey_script(Category::Nested),
+            OpReturn => Script::new_op_return(&pubkey.serialize()).into(),
+            _ => return Err(Error::InvalidProofStructure),
+        })
+    }
+
+    /// Renders `scriptPubkey` for a script-based output. Fails with
+    /// [`Error::InvalidProofStructure`] if the method requires a single public
+    /// key.
+    fn script_pubkey_from_lockscript(
+        &self,
+        lockscript: &LockScript,
+    ) -> Result<PubkeyScript, Error> {
+        use ScriptEncodeMethod::*;
+        Ok(match self {
+            Bare => lockscript.to_pubkey_script(Category::Bare),
+            ScriptHash => lockscript.to_pubkey_script(Category::Hashed),
+            WScriptHash => lockscript.to_pubkey_script(Category::SegWit),
+            ShWScriptHash => lockscript.to_pubkey_script(Category::Nested),
+            _ => return Err(Error::InvalidProofStructure),
+        })
+    }
+}
+
+/// Type of the transaction output address, used for routing commitments to
+/// the wallet derivation paths
+#[derive(Copy, Clone, PartialEq, Eq, Hash, Debug, Display)]
+#[display(Debug)]
+pub enum AddressType {
+    /// Pay-to-public-key output
+    P2PK,
+    /// Pay-to-public-key-hash output
+    P2PKH,
+    /// Pay-to-script-hash output
+    P2SH,
+    /// Pay-to-witness-public-key-hash output
+    P2WPKH,
+    /// Pay-to-witness-script-hash output
+    P2WSH,
+    /// P2WPKH output nested into P2SH
+    P2SHWrappedP2WPKH,
+    /// P2WSH output nested into P2SH
+    P2SHWrappedP2WSH,
+    /// Pay-to-taproot output
+    P2TR,
+    /// Any other output, including `OP_RETURN` and bare scripts
+    NonStandard,
+}
+
+impl AddressType {
+    /// Classifies `scriptPubkey` by its pattern. Since P2SH output does not
+    /// reveal the redeem script, P2SH-wrapped segwit outputs are reported as
+    /// [`AddressType::P2SH`].
+    pub fn classify(script: &Script) -> AddressType {
+        if script.is_p2pk() {
+            AddressType::P2PK
+        } else if script.is_p2pkh() {
+            AddressType::P2PKH
+        } else if script.is_p2sh() {
+            AddressType::P2SH
+        } else if script.is_v0_p2wpkh() {
+            AddressType::P2WPKH
+        } else if script.is_v0_p2wsh() {
+            AddressType::P2WSH
+        } else if script.len() == 34
+            && script[0] == OP_PUSHNUM_1.into_u8()
+            && script[1] == OP_PUSHBYTES_32.into_u8()
+        {
+            AddressType::P2TR
+        } else {
+            AddressType::NonStandard
+        }
+    }
+}
+
+impl ScriptEncodeMethod {
+    /// Returns type of the address produced with the method. Unlike
+    /// [`SpkCommitment::address_type`], this distinguishes P2SH-wrapped
+    /// segwit outputs from the ordinary P2SH outputs.
+    pub fn address_type(&self) -> AddressType {
+        use ScriptEncodeMethod::*;
+        match self {
+            PublicKey => AddressType::P2PK,
+            PubkeyHash => AddressType::P2PKH,
+            ScriptHash => AddressType::P2SH,
+            WPubkeyHash => AddressType::P2WPKH,
+            WScriptHash => AddressType::P2WSH,
+            ShWPubkeyHash => AddressType::P2SHWrappedP2WPKH,
+            ShWScriptHash => AddressType::P2SHWrappedP2WSH,
+            Taproot => AddressType::P2TR,
+            OpReturn | Bare => AddressType::NonStandard,
+            #[cfg(feature = "miniscript")]
+            Miniscript(ref descriptor) => parse_descriptor(descriptor)
+                .map(|(_, method)| method.address_type())
+                .unwrap_or(AddressType::NonStandard),
+        }
+    }
+}
+
+impl TryFrom<(&Proof, &PubkeyScript)> for ScriptEncodeMethod {
+    type Error = Error;
+
+    /// Deduces the method which was used to construct `scriptPubkey` with a
+    /// commitment for the given proof; see [`ScriptEncodeMethod::try_from`]
+    /// for the borrowed [`Script`] form.
+    #[inline]
+    fn try_from(
+        (proof, host): (&Proof, &PubkeyScript),
+    ) -> Result<Self, Self::Error> {
+        Self::try_from((proof, host.as_inner()))
+    }
+}
+
+impl TryFrom<(&Proof, &Script)> for ScriptEncodeMethod {
+    type Error = Error;
+
+    /// Deduces the method which was used to construct `scriptPubkey` with a
+    /// commitment for the given proof. Fails if the script can't contain
+    /// the commitment with the proof source data.
+    ///
+    /// This function runs for each of the candidate outputs when scanning
+    /// blocks, so it avoids allocations and hashing for all but P2SH
+    /// outputs with lockscript proof sources.
+    fn try_from(
+        (proof, script): (&Proof, &Script),
+    ) -> Result<Self, Self::Error> {
+        let has_lockscript = matches!(
+            proof.source,
+            ScriptEncodeData::LockScript(_)
+                | ScriptEncodeData::NestedLockScript(_)
+        );
+        let method = if script.is_p2sh() {
+            // P2SH output may contain either bare script or a nested P2WSH
+            // script. We select the method with the following rule:
+            // - if the proof has a hint (uses
+            //   `ScriptEncodeData::NestedLockScript`), the script is nested;
+            // - otherwise, if only the nested form of the script matches the
+            //   host, the script is nested;
+            // - in all other cases (including when both or none of the forms
+            //   match, which always happens for the outputs containing
+            //   commitment) the script is treated as a bare P2SH script.
+            // Since the commitment can't be matched without the message, the
+            // nested form of the hintless proofs is resolved during the
+            // verification (see `SpkContainer::resolve_nested`).
+            if let ScriptEncodeData::LockScript(ref lockscript)
+            | ScriptEncodeData::NestedLockScript(ref lockscript) =
+                proof.source
+            {
+                let hint = matches!(
+                    proof.source,
+                    ScriptEncodeData::NestedLockScript(_)
+                );
+                let hashed =
+                    *lockscript.to_pubkey_script(Category::Hashed) == *script;
+                let nested =
+                    *lockscript.to_pubkey_script(Category::Nested) == *script;
+                if hint || (nested && !hashed) {
+                    ScriptEncodeMethod::ShWScriptHash
+                } else {
+                    ScriptEncodeMethod::ScriptHash
+                }
+            } else {
+                // Host script contains hash of the tweaked public key, so we
+                // can't match it against the original key from the proof;
+                // this is done during the commitment verification
+                ScriptEncodeMethod::ShWPubkeyHash
+            }
+        } else if has_lockscript && (script.is_p2pk() || script.is_p2pkh()) {
+            // Bare lockscript may have the same form as P2PK or P2PKH
+            // output, so with the lockscript in the proof it can't be
+            // anything else than a bare script
+            ScriptEncodeMethod::Bare
+        } else if script.is_p2pk() {
+            let data = &script[1..script.len() - 1];
+            bitcoin::PublicKey::from_slice(data)
+                .map_err(|_| Self::Error::InvalidKeyData)?;
+            ScriptEncodeMethod::PublicKey
+        } else if script.is_p2pkh() {
+            ScriptEncodeMethod::PubkeyHash
+        } else if script.is_v0_p2wpkh() {
+            ScriptEncodeMethod::WPubkeyHash
+        } else if script.is_v0_p2wsh() {
+            ScriptEncodeMethod::WScriptHash
+        } else if script.is_witness_program() {
+            if script[0] != OP_PUSHNUM_1.into_u8() || script.len() != 34 {
+                return Err(Self::Error::UnsupportedWitnessVersion);
+            }
+            ScriptEncodeMethod::Taproot
+        } else if script.is_op_return() {
+            ScriptEncodeMethod::OpReturn
+        } else {
+            ScriptEncodeMethod::Bare
+        };
+
+        match (&method, &proof.source) {
+            (
+                ScriptEncodeMethod::PublicKey
+                | ScriptEncodeMethod::PubkeyHash
+                | ScriptEncodeMethod::WPubkeyHash
+                | ScriptEncodeMethod::ShWPubkeyHash
+                | ScriptEncodeMethod::OpReturn,
+                ScriptEncodeData::SinglePubkey,
+            )
+            | (
+                ScriptEncodeMethod::Bare
+                | ScriptEncodeMethod::ScriptHash
+                | ScriptEncodeMethod::WScriptHash
+                | ScriptEncodeMethod::ShWScriptHash,
+                ScriptEncodeData::LockScript(_),
+            )
+            | (
+                ScriptEncodeMethod::ShWScriptHash,
+                ScriptEncodeData::NestedLockScript(_),
+            )
+            | (
+                ScriptEncodeMethod::Taproot,
+                ScriptEncodeData::Taproot(_) | ScriptEncodeData::TaprootKeyOnly,
+            ) => Ok(method),
+            _ => Err(Self::Error::InvalidProofStructure),
+        }
+    }
 }
 
 /// Structure keeping the minimum of information (bytewise) required to verify
 /// deterministic bitcoin commitment given only the transaction source, its
 /// fee and protocol-specific constants. It is a part of the [`Proof`] data.
 #[derive(Clone, PartialEq, Eq, Hash, Debug, Display)]
-#[derive(StrictEncode, StrictDecode)]
 #[cfg_attr(
     feature = "serde",
     derive(Serialize, Deserialize),
@@ -83,20 +476,102 @@ pub enum ScriptEncodeData {
     /// Taproot-based outputs. We need to keep only the hash of the taprscript
     /// merkle tree root.
     Taproot(sha256::Hash),
+
+    /// Script nested into P2WSH-in-P2SH output. Same as
+    /// [`ScriptEncodeData::LockScript`], but with a hint that the script is
+    /// encoded with [`ScriptEncodeMethod::ShWScriptHash`] method, which can't
+    /// be distinguished from [`ScriptEncodeMethod::ScriptHash`] by looking at
+    /// the P2SH output containing the commitment.
+    ///
+    /// With [`ScriptEncodeData::LockScript`] source such outputs are
+    /// reconstructed as bare P2SH scripts, so the hint is required to
+    /// reconstruct the container for P2WSH-in-P2SH commitment. Verification
+    /// with [`SpkCommitment::verify_script_pubkey`] and
+    /// [`crate::TxoutCommitment::verify_txout`] does not require the hint,
+    /// since it checks both forms of the script against the message.
+    /// [`Container::to_proof`] of [`SpkContainer`] always produces the hint
+    /// for such outputs.
+    NestedLockScript(LockScript),
+
+    /// Taproot-based outputs without script tree, which may be spent only
+    /// with the key path. The output key is produced with BIP-341 tweak which
+    /// does not commit to any merkle root.
+    TaprootKeyOnly,
 }
 
 impl Default for ScriptEncodeData {
     fn default() -> Self { Self::SinglePubkey }
 }
 
+// Same encoding as for `ScriptEncodeMethod`: single-byte variant tag followed
+// by the variant data
+impl StrictEncode for ScriptEncodeData {
+    fn strict_encode<E: io::Write>(
+        &self,
+        mut e: E,
+    ) -> Result<usize, strict_encoding::Error> {
+        Ok(match self {
+            ScriptEncodeData::SinglePubkey => 0u8.strict_encode(e)?,
+            ScriptEncodeData::LockScript(script) => {
+                1u8.strict_encode(&mut e)? + script.strict_encode(e)?
+            }
+            ScriptEncodeData::Taproot(script_root) => {
+                2u8.strict_encode(&mut e)? + script_root.strict_encode(e)?
+            }
+            ScriptEncodeData::NestedLockScript(script) => {
+                3u8.strict_encode(&mut e)? + script.strict_encode(e)?
+            }
+            ScriptEncodeData::TaprootKeyOnly => 4u8.strict_encode(e)?,
+        })
+    }
+}
+
+impl StrictDecode for ScriptEncodeData {
+    fn strict_decode<D: io::Read>(
+        mut d: D,
+    ) -> Result<Self, strict_encoding::Error> {
+        Ok(match u8::strict_decode(&mut d)? {
+            0 => ScriptEncodeData::SinglePubkey,
+            1 => ScriptEncodeData::LockScript(LockScript::strict_decode(d)?),
+            2 => ScriptEncodeData::Taproot(sha256::Hash::strict_decode(d)?),
+            3 => ScriptEncodeData::NestedLockScript(LockScript::strict_decode(
+                d,
+            )?),
+            4 => ScriptEncodeData::TaprootKeyOnly,
+            tag => {
+                return Err(strict_encoding::Error::EnumValueNotKnown(
+                    "ScriptEncodeData",
+                    tag as usize,
+                ))
+            }
+        })
+    }
+}
+
+impl From<Option<sha256::Hash>> for ScriptEncodeData {
+    /// Constructs taproot source data from optional script tree merkle root
+    fn from(script_root: Option<sha256::Hash>) -> Self {
+        match script_root {
+            Some(script_root) => ScriptEncodeData::Taproot(script_root),
+            None => ScriptEncodeData::TaprootKeyOnly,
+        }
+    }
+}
+
 #[derive(Clone, PartialEq, Eq, Hash, Debug, Display)]
+#[derive(StrictEncode, StrictDecode)]
+#[cfg_attr(
+    feature = "serde",
+    derive(Serialize, Deserialize),
+    serde(crate = "serde_crate")
+)]
 #[display(Debug)]
 pub struct SpkContainer {
     pub pubkey: secp256k1::PublicKey,
     pub method: ScriptEncodeMethod,
     pub source: ScriptEncodeData,
     /// Single SHA256 hash of the protocol-specific tag
-    pub tag: sha256::Hash,
+    pub tag: ProtocolTag,
     /// Tweaking factor stored after [`SpkCommitment::embed_commit`]
     /// procedure
     pub tweaking_factor: Option<Hmac<sha256::Hash>>,
@@ -104,7 +579,7 @@ pub struct SpkContainer {
 
 impl SpkContainer {
     pub fn construct(
-        protocol_tag: &sha256::Hash,
+        protocol_tag: &ProtocolTag,
         pubkey: secp256k1::PublicKey,
         source: ScriptEncodeData,
         method: ScriptEncodeMethod,
@@ -117,156 +592,812 @@ impl SpkContainer {
             tweaking_factor: None,
         }
     }
-}
 
-impl Container for SpkContainer {
-    /// Out supplement is a protocol-specific tag in its hashed form
-    type Supplement = sha256::Hash;
-    type Host = PubkeyScript;
+    /// Constructs container with the protocol tag computed as a single SHA256
+    /// hash of the provided protocol name (see [`ProtocolTag::new`])
+    pub fn with_tag_name(
+        tag_name: &str,
+        pubkey: secp256k1::PublicKey,
+        source: ScriptEncodeData,
+        method: ScriptEncodeMethod,
+    ) -> Self {
+        Self::construct(&ProtocolTag::new(tag_name), pubkey, source, method)
+    }
 
-    fn reconstruct(
-        proof: &Proof,
-        supplement: &Self::Supplement,
-        host: &Self::Host,
+    /// Constructs container for OP_RETURN-based commitment, failing fast
+    /// with [`Error::InvalidOpReturnKey`] if the `pubkey` does not have an
+    /// even y coordinate (i.e. its serialization does not start with `02`).
+    ///
+    /// NB: The same requirement applies to the tweaked public key, which is
+    /// checked during the commitment procedure, so
+    /// [`SpkCommitment::embed_commit`] may still fail with the same error.
+    pub fn new_op_return(
+        pubkey: secp256k1::PublicKey,
+        protocol_tag: &ProtocolTag,
     ) -> Result<Self, Error> {
-        let (lockscript, _) = match &proof.source {
-            ScriptEncodeData::SinglePubkey => (None, None),
-            ScriptEncodeData::LockScript(script) => (Some(script), None),
-            ScriptEncodeData::Taproot(hash) => (None, Some(hash)),
+        if pubkey.serialize()[0] != 0x02 {
+            return Err(Error::InvalidOpReturnKey);
+        }
+        Ok(Self::construct(
+            protocol_tag,
+            pubkey,
+            ScriptEncodeData::SinglePubkey,
+            ScriptEncodeMethod::OpReturn,
+        ))
+    }
+
+    /// Constructs container from the address of the output before the
+    /// commitment, deriving [`ScriptEncodeMethod`] from the address payload.
+    ///
+    /// The address must be valid for the provided `network` and must be
+    /// generated from the provided `pubkey` (for [`ScriptEncodeData::
+    /// SinglePubkey`] source) or lockscript (for [`ScriptEncodeData::
+    /// LockScript`] source). Taproot addresses are accepted with
+    /// [`ScriptEncodeData::Taproot`] source only; since the address contains
+    /// tweaked output key, we can't check it against the provided data.
+    ///
+    /// Test networks share address prefixes, so the network of a parsed
+    /// address may differ from the one it was generated for (for instance,
+    /// regtest base58 addresses are parsed as testnet addresses). Because of
+    /// this, the address is checked to have the same encoding for the
+    /// provided `network`, rather than to have the same network.
+    pub fn with_address(
+        protocol_tag: &ProtocolTag,
+        pubkey: secp256k1::PublicKey,
+        source: ScriptEncodeData,
+        address: &Address,
+        network: Network,
+    ) -> Result<Self, Error> {
+        let network_address = Address {
+            payload: address.payload.clone(),
+            network,
         };
+        if network_address.to_string() != address.to_string() {
+            return Err(Error::NetworkMismatch {
+                expected: network,
+                actual: address.network,
+            });
+        }
 
-        let mut proof = proof.clone();
-        let method = match descriptors::Compact::try_from(host.clone())? {
-            descriptors::Compact::Sh(script_hash) => {
-                let script = Script::new_p2sh(&script_hash);
-                if let Some(lockscript) = lockscript {
-                    if *lockscript.to_pubkey_script(Category::Hashed) == script
-                    {
-                        ScriptEncodeMethod::ScriptHash
-                    } else if *lockscript.to_pubkey_script(Category::Nested)
-                        == script
-                    {
-                        ScriptEncodeMethod::ShWScriptHash
-                    } else {
-                        return Err(Error::InvalidProofStructure);
-                    }
-                } else if *proof.pubkey.to_pubkey_script(Category::Nested)
-                    == script
-                {
-                    ScriptEncodeMethod::ShWPubkeyHash
-                } else {
-                    return Err(Error::InvalidProofStructure);
-                }
+        let script_pubkey = address.script_pubkey();
+        let (method, expected) = match (&address.payload, &source) {
+            (Payload::PubkeyHash(_), ScriptEncodeData::SinglePubkey) => (
+                ScriptEncodeMethod::PubkeyHash,
+                Some(pubkey.to_pubkey_script(Category::Hashed)),
+            ),
+            (Payload::ScriptHash(_), ScriptEncodeData::SinglePubkey) => (
+                ScriptEncodeMethod::ShWPubkeyHash,
+                Some(pubkey.to_pubkey_script(Category::Nested)),
+            ),
+            (Payload::ScriptHash(_), ScriptEncodeData::LockScript(script))
+                if *script.to_pubkey_script(Category::Hashed)
+                    == script_pubkey =>
+            {
+                (ScriptEncodeMethod::ScriptHash, None)
             }
-            descriptors::Compact::Bare(script)
-                if script.as_inner().is_op_return() =>
+            (Payload::ScriptHash(_), ScriptEncodeData::LockScript(script)) => (
+                ScriptEncodeMethod::ShWScriptHash,
+                Some(script.to_pubkey_script(Category::Nested)),
+            ),
+            (
+                Payload::WitnessProgram { version, program },
+                ScriptEncodeData::SinglePubkey,
+            ) if version.to_u8() == 0 && program.len() == 20 => (
+                ScriptEncodeMethod::WPubkeyHash,
+                Some(pubkey.to_pubkey_script(Category::SegWit)),
+            ),
+            (
+                Payload::WitnessProgram { version, program },
+                ScriptEncodeData::LockScript(script),
+            ) if version.to_u8() == 0 && program.len() == 32 => (
+                ScriptEncodeMethod::WScriptHash,
+                Some(script.to_pubkey_script(Category::SegWit)),
+            ),
+            (
+                Payload::WitnessProgram { version, program },
+                ScriptEncodeData::Taproot(_) | ScriptEncodeData::TaprootKeyOnly,
+            ) if version.to_u8() == 1 && program.len() == 32 => {
+                (ScriptEncodeMethod::Taproot, None)
+            }
+            (Payload::WitnessProgram { version, .. }, _)
+                if version.to_u8() > 1 =>
             {
-                ScriptEncodeMethod::OpReturn
+                return Err(Error::UnsupportedWitnessVersion)
             }
-            descriptors::Compact::Bare(script) => {
-                proof.source = ScriptEncodeData::LockScript(LockScript::from(
-                    script.to_inner(),
-                ));
-                ScriptEncodeMethod::Bare
+            _ => return Err(Error::AddressMismatch),
+        };
+
+        if let Some(expected) = expected {
+            if *expected != script_pubkey {
+                return Err(Error::AddressMismatch);
             }
-            descriptors::Compact::Pk(_) => ScriptEncodeMethod::PublicKey,
-            descriptors::Compact::Pkh(_) => ScriptEncodeMethod::PubkeyHash,
-            descriptors::Compact::Wpkh(_) => ScriptEncodeMethod::WPubkeyHash,
-            descriptors::Compact::Wsh(_) => ScriptEncodeMethod::WScriptHash,
-            descriptors::Compact::Taproot(_) => ScriptEncodeMethod::Taproot,
-            _ => unimplemented!(),
+        }
+
+        Ok(Self::construct(protocol_tag, pubkey, source, method))
+    }
+
+    /// Constructs container from the `scriptPubkey` of the output before the
+    /// commitment, detecting [`ScriptEncodeMethod`] and [`ScriptEncodeData`]
+    /// source from the script pattern. The container is accepted only if its
+    /// [`SpkContainer::original_script_pubkey`] matches `spk`.
+    ///
+    /// Script-based outputs are matched against the simplest lockscript
+    /// which may be constructed from the `pubkey`, `<pubkey> OP_CHECKSIG`;
+    /// thus P2SH outputs are detected either as
+    /// [`ScriptEncodeMethod::ShWPubkeyHash`], or as
+    /// [`ScriptEncodeMethod::ScriptHash`] or
+    /// [`ScriptEncodeMethod::ShWScriptHash`] with this lockscript. Taproot
+    /// outputs are matched only without the script tree
+    /// ([`ScriptEncodeData::TaprootKeyOnly`]); non-standard scripts are
+    /// treated as bare lockscripts, which must contain the `pubkey`.
+    ///
+    /// P2PK outputs are the same as the bare lockscript `<pubkey>
+    /// OP_CHECKSIG`; they are detected as [`ScriptEncodeMethod::PublicKey`]
+    /// with [`ScriptEncodeData::SinglePubkey`] source, which has smaller
+    /// proof. Containers for the bare lockscript form must be constructed
+    /// explicitly.
+    ///
+    /// Fails with [`Error::ScriptMismatch`] if `spk` can't be constructed
+    /// from the `pubkey` and with [`Error::AmbiguousScriptType`] if it
+    /// matches multiple methods.
+    pub fn auto_detect_method(
+        protocol_tag: &ProtocolTag,
+        spk: &PubkeyScript,
+        pubkey: secp256k1::PublicKey,
+    ) -> Result<Self, Error> {
+        use ScriptEncodeMethod::*;
+
+        let script = spk.as_inner();
+        let single = ScriptEncodeData::SinglePubkey;
+        let lockscript = ScriptEncodeData::LockScript(LockScript::from(
+            pubkey.to_pubkey_script(Category::Bare).into_inner(),
+        ));
+        let candidates = if script.is_p2sh() {
+            vec![
+                (single, ShWPubkeyHash),
+                (lockscript.clone(), ScriptHash),
+                (lockscript, ShWScriptHash),
+            ]
+        } else if script.is_p2pk() {
+            vec![(single, PublicKey)]
+        } else if script.is_p2pkh() {
+            vec![(single, PubkeyHash)]
+        } else if script.is_v0_p2wpkh() {
+            vec![(single, WPubkeyHash)]
+        } else if script.is_v0_p2wsh() {
+            vec![(lockscript, WScriptHash)]
+        } else if script.is_witness_program() {
+            if script[0] != OP_PUSHNUM_1.into_u8() || script.len() != 34 {
+                return Err(Error::UnsupportedWitnessVersion);
+            }
+            vec![(ScriptEncodeData::TaprootKeyOnly, Taproot)]
+        } else if script.is_op_return() {
+            vec![(single, OpReturn)]
+        } else {
+            let key = pubkey.serialize();
+            let has_key = script.instructions().any(|instruction| {
+                matches!(
+                    instruction,
+                    Ok(Instruction::PushBytes(data)) if data == &key[..]
+                )
+            });
+            if !has_key {
+                return Err(Error::ScriptMismatch);
+            }
+            vec![(
+                ScriptEncodeData::LockScript(LockScript::from(script.clone())),
+                Bare,
+            )]
         };
-        let proof = proof;
 
-        match method {
-            ScriptEncodeMethod::PublicKey
-            | ScriptEncodeMethod::PubkeyHash
-            | ScriptEncodeMethod::WPubkeyHash
-            | ScriptEncodeMethod::ShWPubkeyHash
-            | ScriptEncodeMethod::OpReturn => {
-                if let ScriptEncodeData::SinglePubkey = proof.source {
-                } else {
-                    return Err(Error::InvalidProofStructure);
-                }
+        let mut matched = candidates
+            .into_iter()
+            .map(|(source, method)| {
+                Self::construct(protocol_tag, pubkey, source, method)
+            })
+            .filter(|container| {
+                container
+                    .original_script_pubkey()
+                    .map(|original| original == *spk)
+                    .unwrap_or(false)
+            });
+        let container = matched.next().ok_or(Error::ScriptMismatch)?;
+        if matched.next().is_some() {
+            return Err(Error::AmbiguousScriptType);
+        }
+        Ok(container)
+    }
+
+    /// Renders `scriptPubkey` which the output would have without the
+    /// commitment, i.e. using the original (non-tweaked) public key or
+    /// lockscript and the container encoding method. For taproot outputs the
+    /// original public key is used as BIP-341 internal key.
+    pub fn original_script_pubkey(&self) -> Result<PubkeyScript, Error> {
+        match self.source {
+            ScriptEncodeData::SinglePubkey => {
+                self.method.script_pubkey_from_pubkey(self.pubkey)
             }
-            ScriptEncodeMethod::Bare
-            | ScriptEncodeMethod::ScriptHash
-            | ScriptEncodeMethod::WScriptHash
-            | ScriptEncodeMethod::ShWScriptHash => {
-                if let ScriptEncodeData::LockScript(_) = proof.source {
-                } else {
-                    return Err(Error::InvalidProofStructure);
-                }
+            ScriptEncodeData::LockScript(ref lockscript)
+            | ScriptEncodeData::NestedLockScript(ref lockscript) => {
+                self.method.script_pubkey_from_lockscript(lockscript)
             }
-            ScriptEncodeMethod::Taproot => {
-                if let ScriptEncodeData::Taproot(_) = proof.source {
-                } else {
-                    return Err(Error::InvalidProofStructure);
-                }
+            ScriptEncodeData::Taproot(script_root) => {
+                let (output_key, _) =
+                    bip341_output_key(self.pubkey, Some(script_root))?;
+                Ok(witness_v1_script(&output_key))
+            }
+            ScriptEncodeData::TaprootKeyOnly => {
+                let (output_key, _) = bip341_output_key(self.pubkey, None)?;
+                Ok(witness_v1_script(&output_key))
+            }
+        }
+    }
+
+    /// Performs structural check whether the given `scriptPubkey` may contain
+    /// commitment for the provided proof. Unlike
+    /// [`SpkContainer::reconstruct`] and the following commitment
+    /// verification, the check does not do any hashing or elliptic curve
+    /// operations and does not allocate, so it may be used by block scanners
+    /// for filtering candidate outputs.
+    ///
+    /// Returns `false` only for the outputs which can't contain the
+    /// commitment; positive result must be confirmed with the full
+    /// commitment verification.
+    #[inline]
+    pub fn quick_match(proof: &Proof, spk: &PubkeyScript) -> bool {
+        Self::quick_match_script(proof, spk.as_inner())
+    }
+
+    /// Performs [`SpkContainer::quick_match`] on a raw script, avoiding the
+    /// need to wrap it into [`PubkeyScript`]
+    pub(crate) fn quick_match_script(proof: &Proof, script: &Script) -> bool {
+        match proof.source {
+            ScriptEncodeData::SinglePubkey => {
+                script.is_p2sh()
+                    || script.is_p2pk()
+                    || script.is_p2pkh()
+                    || script.is_v0_p2wpkh()
+                    // OP_RETURN commitments contain only keys with even y
+                    // coordinate
+                    || (script.is_op_return()
+                        && script.len() == 35
+                        && script[1] == OP_PUSHBYTES_33.into_u8()
+                        && script[2] == 0x02)
+            }
+            ScriptEncodeData::LockScript(_) => {
+                script.is_p2sh()
+                    || script.is_v0_p2wsh()
+                    || !(script.is_witness_program() || script.is_op_return())
             }
+            ScriptEncodeData::NestedLockScript(_) => script.is_p2sh(),
+            ScriptEncodeData::Taproot(_) | ScriptEncodeData::TaprootKeyOnly => {
+                script.is_witness_program()
+                    && script.len() == 34
+                    && script[0] == OP_PUSHNUM_1.into_u8()
+            }
+        }
+    }
+}
+
+/// Parses descriptor string of [`ScriptEncodeMethod::Miniscript`], returning
+/// the descriptor together with the script-based method matching its type.
+/// Fails with [`Error::LockscriptParseError`] if the descriptor is invalid
+/// and with [`Error::InvalidProofStructure`] if it is not script-based.
+#[cfg(feature = "miniscript")]
+fn parse_descriptor(
+    descriptor: &str,
+) -> Result<(Descriptor<bitcoin::PublicKey>, ScriptEncodeMethod), Error> {
+    let descriptor = Descriptor::<bitcoin::PublicKey>::from_str(descriptor)
+        .map_err(|_| Error::LockscriptParseError)?;
+    let method = match descriptor.desc_type() {
+        DescriptorType::Bare if !descriptor.explicit_script().is_p2pk() => {
+            ScriptEncodeMethod::Bare
         }
+        DescriptorType::Sh | DescriptorType::ShSortedMulti => {
+            ScriptEncodeMethod::ScriptHash
+        }
+        DescriptorType::Wsh | DescriptorType::WshSortedMulti => {
+            ScriptEncodeMethod::WScriptHash
+        }
+        DescriptorType::ShWsh | DescriptorType::ShWshSortedMulti => {
+            ScriptEncodeMethod::ShWScriptHash
+        }
+        _ => return Err(Error::InvalidProofStructure),
+    };
+    Ok((descriptor, method))
+}
+
+impl SpkContainer {
+    /// Reconstructs container in the same way as [`Container::reconstruct`],
+    /// but borrowing the `scriptPubkey` as a [`Script`], so the transaction
+    /// outputs may be processed without copying their scripts.
+    pub fn reconstruct_from_script(
+        proof: &Proof,
+        protocol_tag: &ProtocolTag,
+        script_pubkey: &Script,
+    ) -> Result<Self, Error> {
+        let method = ScriptEncodeMethod::try_from((proof, script_pubkey))?;
+        // Nested script hint is not needed once the method is known
+        let source = match proof.source {
+            ScriptEncodeData::NestedLockScript(ref lockscript) => {
+                ScriptEncodeData::LockScript(lockscript.clone())
+            }
+            ref source => source.clone(),
+        };
 
         Ok(Self {
             pubkey: proof.pubkey,
-            source: proof.source,
+            source,
             method,
-            tag: *supplement,
+            tag: *protocol_tag,
             tweaking_factor: None,
         })
     }
 
-    fn deconstruct(self) -> (Proof, Self::Supplement) {
-        (
-            Proof {
-                pubkey: self.pubkey,
-                source: self.source,
-            },
-            self.tag,
-        )
+    /// Switches container reconstructed from a proof without the nested
+    /// script hint (see [`ScriptEncodeData::NestedLockScript`]) to the
+    /// [`ScriptEncodeMethod::ShWScriptHash`] method if the commitment to
+    /// `msg` with this method produces `script_pubkey`. Such containers are
+    /// reconstructed with [`ScriptEncodeMethod::ScriptHash`] method, since
+    /// without the message the committed P2SH script can't be matched
+    /// against the forms of the original lockscript.
+    pub(crate) fn resolve_nested(
+        &mut self,
+        script_pubkey: &Script,
+        msg: &impl AsRef<[u8]>,
+    ) {
+        if self.method != ScriptEncodeMethod::ScriptHash
+            || !matches!(self.source, ScriptEncodeData::LockScript(_))
+            || !script_pubkey.is_p2sh()
+        {
+            return;
+        }
+        let mut nested = SpkContainer {
+            method: ScriptEncodeMethod::ShWScriptHash,
+            ..self.clone()
+        };
+        if matches!(
+            SpkCommitment::embed_commit(&mut nested, msg),
+            Ok(commitment) if *commitment.as_inner().as_inner() == *script_pubkey
+        ) {
+            self.method = ScriptEncodeMethod::ShWScriptHash;
+        }
     }
+}
 
-    fn to_proof(&self) -> Proof {
-        Proof {
-            pubkey: self.pubkey,
-            source: self.source.clone(),
+#[cfg(feature = "miniscript")]
+impl SpkContainer {
+    /// Resolves container with [`ScriptEncodeMethod::Miniscript`] method into
+    /// a container with script-based method and
+    /// [`ScriptEncodeData::LockScript`] source, as used by the commitment
+    /// procedure. Containers with other methods are returned unchanged.
+    ///
+    /// Fails with [`Error::DescriptorKeyNotFound`] if the descriptor has no
+    /// `pk()` leaf with the container public key (keys present only in form
+    /// of their hashes are not eligible), and with the errors of descriptor
+    /// parsing.
+    pub fn resolve_miniscript(&self) -> Result<SpkContainer, Error> {
+        let descriptor = match self.method {
+            ScriptEncodeMethod::Miniscript(ref descriptor) => descriptor,
+            _ => return Ok(self.clone()),
+        };
+        let (descriptor, _) = parse_descriptor(descriptor)?;
+        let found = !descriptor.for_each_key(
+            |item| !matches!(item, ForEach::Key(pk) if pk.key == self.pubkey),
+        );
+        if !found {
+            return Err(Error::DescriptorKeyNotFound);
         }
+        let mut container =
+            SpkContainer::from_descriptor(&descriptor, self.pubkey, &self.tag)?;
+        container.tweaking_factor = self.tweaking_factor;
+        Ok(container)
     }
 
-    fn into_proof(self) -> Proof {
-        Proof {
-            pubkey: self.pubkey,
-            source: self.source,
+    /// Constructs container from an output descriptor, deriving both
+    /// [`ScriptEncodeData`] and [`ScriptEncodeMethod`] from it. The
+    /// `target_key` must be present in the descriptor (either as a key or as
+    /// its hash), otherwise [`Error::DescriptorKeyNotFound`] is returned.
+    pub fn from_descriptor(
+        descriptor: &Descriptor<bitcoin::PublicKey>,
+        target_key: secp256k1::PublicKey,
+        protocol_tag: &ProtocolTag,
+    ) -> Result<Self, Error> {
+        let target_hash = bitcoin::PublicKey {
+            compressed: true,
+            key: target_key,
+        }
+        .pubkey_hash()
+        .as_hash();
+        let mut uncompressed = false;
+        let found = !descriptor.for_each_key(|item| match item {
+            ForEach::Key(pk) if pk.key == target_key => {
+                uncompressed = !pk.compressed;
+                false
+            }
+            ForEach::Hash(hash) => *hash != target_hash,
+            _ => true,
+        });
+        if uncompressed {
+            return Err(Error::UncompressedKey);
         }
+        if !found {
+            return Err(Error::DescriptorKeyNotFound);
+        }
+
+        let lockscript = LockScript::from(descriptor.explicit_script());
+        let (source, method) = match descriptor.desc_type() {
+            DescriptorType::Bare if lockscript.is_p2pk() => (
+                ScriptEncodeData::SinglePubkey,
+                ScriptEncodeMethod::PublicKey,
+            ),
+            DescriptorType::Bare => (
+                ScriptEncodeData::LockScript(lockscript),
+                ScriptEncodeMethod::Bare,
+            ),
+            DescriptorType::Pkh => (
+                ScriptEncodeData::SinglePubkey,
+                ScriptEncodeMethod::PubkeyHash,
+            ),
+            DescriptorType::Wpkh => (
+                ScriptEncodeData::SinglePubkey,
+                ScriptEncodeMethod::WPubkeyHash,
+            ),
+            DescriptorType::ShWpkh => (
+                ScriptEncodeData::SinglePubkey,
+                ScriptEncodeMethod::ShWPubkeyHash,
+            ),
+            DescriptorType::Sh | DescriptorType::ShSortedMulti => (
+                ScriptEncodeData::LockScript(lockscript),
+                ScriptEncodeMethod::ScriptHash,
+            ),
+            DescriptorType::Wsh | DescriptorType::WshSortedMulti => (
+                ScriptEncodeData::LockScript(lockscript),
+                ScriptEncodeMethod::WScriptHash,
+            ),
+            DescriptorType::ShWsh | DescriptorType::ShWshSortedMulti => (
+                ScriptEncodeData::LockScript(lockscript),
+                ScriptEncodeMethod::ShWScriptHash,
+            ),
+        };
+
+        Ok(Self::construct(protocol_tag, target_key, source, method))
     }
-}
 
-/// [`PubkeyScript`] containing LNPBP-2 commitment
-#[derive(
-    Wrapper, Clone, PartialEq, Eq, PartialOrd, Ord, Hash, Default, Debug,
-    Display, From
-)]
-#[display(inner)]
-#[wrapper(LowerHex, UpperHex)]
-pub struct SpkCommitment(PubkeyScript);
+    /// Produces descriptor for the output after the commitment, using
+    /// tweaking factor stored in the container by
+    /// [`SpkCommitment::embed_commit`]. Fails with [`Error::NoTweakingFactor`]
+    /// if the commitment was not embedded yet.
+    ///
+    /// NB: sorted multisig descriptors are returned as `multi`, since the
+    /// commitment keeps the original order of the keys in the script.
+    pub fn to_committed_descriptor(
+        &self,
+    ) -> Result<Descriptor<bitcoin::PublicKey>, Error> {
+        let tweaking_factor =
+            self.tweaking_factor.ok_or(Error::NoTweakingFactor)?;
+        let mut tweaked_key = self.pubkey;
+        crate::lnpbp1::with_secp(|secp| {
+            tweaked_key.add_exp_assign(secp, &tweaking_factor[..])
+        })
+        .map_err(|_| crate::lnpbp1::Error::InvalidTweak)?;
+        let original_pk = bitcoin::PublicKey {
+            compressed: true,
+            key: self.pubkey,
+        };
+        let tweaked_pk = bitcoin::PublicKey {
+            compressed: true,
+            key: tweaked_key,
+        };
 
-impl<MSG> EmbedCommitVerify<MSG> for SpkCommitment
-where
-    MSG: AsRef<[u8]>,
-{
-    type Container = SpkContainer;
-    type Error = super::Error;
+        let lockscript = match self.source {
+            ScriptEncodeData::SinglePubkey => {
+                return Ok(match self.method {
+                    ScriptEncodeMethod::PublicKey => {
+                        Descriptor::new_pk(tweaked_pk)
+                    }
+                    ScriptEncodeMethod::PubkeyHash => {
+                        Descriptor::new_pkh(tweaked_pk)
+                    }
+                    ScriptEncodeMethod::WPubkeyHash => {
+                        Descriptor::new_wpkh(tweaked_pk)
+                            .map_err(|_| Error::UncompressedKey)?
+                    }
+                    ScriptEncodeMethod::ShWPubkeyHash => {
+                        Descriptor::new_sh_wpkh(tweaked_pk)
+                            .map_err(|_| Error::UncompressedKey)?
+                    }
+                    _ => return Err(Error::InvalidProofStructure),
+                })
+            }
+            ScriptEncodeData::LockScript(ref lockscript)
+            | ScriptEncodeData::NestedLockScript(ref lockscript) => lockscript,
+            ScriptEncodeData::Taproot(_) | ScriptEncodeData::TaprootKeyOnly => {
+                return Err(Error::UnsupportedWitnessVersion)
+            }
+        };
 
-    fn embed_commit(
-        container: &mut Self::Container,
-        msg: &MSG,
-    ) -> Result<Self, Self::Error> {
-        use ScriptEncodeMethod::*;
-        let script_pubkey =
-            if let ScriptEncodeData::LockScript(ref lockscript) =
-                container.source
-            {
-                let mut lockscript_container = LockscriptContainer {
-                    script: lockscript.clone(),
+        let lockscript = lockscript
+            .replace_pubkeys_and_hashes::<Segwitv0, _, _>(
+                |pk| if *pk == original_pk { tweaked_pk } else { *pk },
+                |hash| {
+                    if *hash == original_pk.pubkey_hash().as_hash() {
+                        tweaked_pk.pubkey_hash().as_hash()
+                    } else {
+                        *hash
+                    }
+                },
+            )?;
+
+        match self.method {
+            ScriptEncodeMethod::Bare => Descriptor::new_bare(
+                Miniscript::<_, BareCtx>::parse_insane(lockscript.as_inner())
+                    .map_err(|_| Error::LockscriptParseError)?,
+            ),
+            ScriptEncodeMethod::ScriptHash => Descriptor::new_sh(
+                Miniscript::<_, Legacy>::parse_insane(lockscript.as_inner())
+                    .map_err(|_| Error::LockscriptParseError)?,
+            ),
+            ScriptEncodeMethod::WScriptHash => Descriptor::new_wsh(
+                Miniscript::<_, Segwitv0>::parse_insane(lockscript.as_inner())
+                    .map_err(|_| Error::LockscriptParseError)?,
+            ),
+            ScriptEncodeMethod::ShWScriptHash => Descriptor::new_sh_wsh(
+                Miniscript::<_, Segwitv0>::parse_insane(lockscript.as_inner())
+                    .map_err(|_| Error::LockscriptParseError)?,
+            ),
+            _ => return Err(Error::InvalidProofStructure),
+        }
+        .map_err(|_| Error::LockscriptParseError)
+    }
+}
+
+impl Container for SpkContainer {
+    /// Out supplement is a protocol-specific tag in its hashed form
+    type Supplement = ProtocolTag;
+    type Host = PubkeyScript;
+
+    #[inline]
+    fn reconstruct(
+        proof: &Proof,
+        supplement: &Self::Supplement,
+        host: &Self::Host,
+    ) -> Result<Self, Error> {
+        Self::reconstruct_from_script(proof, supplement, host.as_inner())
+    }
+
+    fn deconstruct(self) -> (Proof, Self::Supplement) {
+        let tag = self.tag;
+        (self.into_proof(), tag)
+    }
+
+    fn supplement(&self) -> &Self::Supplement { &self.tag }
+
+    fn to_proof(&self) -> Proof { self.clone().into_proof() }
+
+    fn into_proof(self) -> Proof {
+        // Nested scripts must be explicitly marked in the proof, since they
+        // are indistinguishable from bare P2SH scripts after the commitment
+        let source = match (self.method, self.source) {
+            (
+                ScriptEncodeMethod::ShWScriptHash,
+                ScriptEncodeData::LockScript(lockscript),
+            ) => ScriptEncodeData::NestedLockScript(lockscript),
+            (_, source) => source,
+        };
+        Proof {
+            pubkey: self.pubkey,
+            source,
+        }
+    }
+}
+
+/// [`PubkeyScript`] containing LNPBP-2 commitment.
+///
+/// The commitment is displayed as a hex string of the script, which can be
+/// parsed back with [`FromStr`]; the alternate form (`{:#}`) displays the
+/// script assembly.
+#[derive(
+    Wrapper, Clone, PartialEq, Eq, PartialOrd, Ord, Hash, Default, Debug, From
+)]
+#[derive(StrictEncode, StrictDecode)]
+#[wrapper(LowerHex, UpperHex)]
+pub struct SpkCommitment(PubkeyScript);
+
+impl Display for SpkCommitment {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        if f.alternate() {
+            self.as_inner().fmt_asm(f)
+        } else {
+            write!(f, "{:x}", self.as_inner())
+        }
+    }
+}
+
+impl FromStr for SpkCommitment {
+    type Err = hex::Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        Vec::<u8>::from_hex(s)
+            .map(|data| SpkCommitment::from_inner(Script::from(data).into()))
+    }
+}
+
+impl SpkCommitment {
+    /// Returns address for the committed `scriptPubkey`, if the script has
+    /// an address form
+    #[inline]
+    pub fn to_address(&self, network: Network) -> Option<Address> {
+        self.as_inner().address(network)
+    }
+
+    /// Checks that the committed `scriptPubkey` follows the same template as
+    /// the original `scriptPubkey` of the container (see
+    /// [`SpkContainer::original_script_pubkey`]): both scripts must consist
+    /// of the same opcodes and data pushes of the same length.
+    pub fn matches_original(&self, container: &SpkContainer) -> bool {
+        let original = match container.original_script_pubkey() {
+            Ok(original) => original,
+            Err(_) => return false,
+        };
+        let mut committed = self.as_inner().instructions();
+        let mut original = original.instructions();
+        loop {
+            match (committed.next(), original.next()) {
+                (None, None) => return true,
+                (
+                    Some(Ok(Instruction::Op(a))),
+                    Some(Ok(Instruction::Op(b))),
+                ) if a == b => {}
+                (
+                    Some(Ok(Instruction::PushBytes(a))),
+                    Some(Ok(Instruction::PushBytes(b))),
+                ) if a.len() == b.len() => {}
+                _ => return false,
+            }
+        }
+    }
+
+    /// Classifies the committed `scriptPubkey` by its pattern. Since P2SH
+    /// output does not reveal the redeem script, P2SH-wrapped segwit outputs
+    /// are reported as [`AddressType::P2SH`]; use
+    /// [`ScriptEncodeMethod::address_type`] if the container is known.
+    #[inline]
+    pub fn address_type(&self) -> AddressType {
+        AddressType::classify(self.as_inner())
+    }
+
+    /// Creates PSBT output holding the commitment `proof` for the protocol
+    /// with the given `tag`, so it can be passed to the other participants
+    /// of the PSBT workflow. The proof is strict-encoded and stored under
+    /// [`PSBT_OUT_LNPBP2_PROOF`] proprietary key with the protocol tag as the
+    /// key data.
+    ///
+    /// NB: PSBT outputs do not contain `scriptPubkey`: the committed script
+    /// must be put into the corresponding output of the unsigned transaction.
+    pub fn to_psbt_output(
+        &self,
+        proof: &Proof,
+        tag: &ProtocolTag,
+    ) -> psbt::Output {
+        let mut output = psbt::Output::default();
+        output
+            .proprietary
+            .insert(psbt_proof_key(tag), proof.to_bytes());
+        output
+    }
+
+    /// Parses commitment proof for the protocol with the given `tag` from the
+    /// PSBT output created with [`SpkCommitment::to_psbt_output`]. Since PSBT
+    /// outputs do not contain `scriptPubkey`, it must be taken from the
+    /// corresponding output of the unsigned transaction.
+    ///
+    /// Fails with [`Error::PsbtNoProof`] if the output has no proof for the
+    /// protocol, with [`Error::InvalidProofStructure`] if the proof data can't
+    /// be decoded and with [`Error::ScriptMismatch`] if the proof can't match
+    /// the `script_pubkey` (see [`SpkContainer::quick_match`]).
+    pub fn from_psbt_output(
+        output: &psbt::Output,
+        script_pubkey: PubkeyScript,
+        tag: &ProtocolTag,
+    ) -> Result<(Self, Proof), Error> {
+        let data = output
+            .proprietary
+            .get(&psbt_proof_key(tag))
+            .ok_or(Error::PsbtNoProof)?;
+        let proof = Proof::from_bytes(data)
+            .map_err(|_| Error::InvalidProofStructure)?;
+        if !SpkContainer::quick_match(&proof, &script_pubkey) {
+            return Err(Error::ScriptMismatch);
+        }
+        Ok((SpkCommitment::from_inner(script_pubkey), proof))
+    }
+
+    /// Wraps committed P2WSH `scriptPubkey` into P2SH, producing the same
+    /// script as a commitment with [`ScriptEncodeMethod::ShWScriptHash`]
+    /// method would have. The commitment itself is not changed, however to
+    /// verify the wrapped form the container method must be changed to
+    /// [`ScriptEncodeMethod::ShWScriptHash`], and the proof must be
+    /// re-generated from the container so it contains the nested script
+    /// hint (see [`ScriptEncodeData::NestedLockScript`]).
+    ///
+    /// Fails with [`Error::NotWScriptHash`] if the committed script is not
+    /// P2WSH.
+    pub fn into_p2sh_wrapped(self) -> Result<SpkCommitment, Error> {
+        if !self.as_inner().is_v0_p2wsh() {
+            return Err(Error::NotWScriptHash);
+        }
+        Ok(SpkCommitment::from_inner(self.as_inner().to_p2sh().into()))
+    }
+
+    /// Reconstructs the original (pre-commitment) `scriptPubkey` from the
+    /// commitment and its proof, which keeps the original public key or
+    /// lockscript. The encoding method is deduced from the proof and the
+    /// committed script in the same way as by [`SpkContainer::reconstruct`].
+    ///
+    /// NB: Since the message is not known, the function does not check that
+    /// the `scriptPubkey` actually contains the commitment; use
+    /// [`SpkCommitment::verify_script_pubkey`] for that.
+    pub fn revert(
+        &self,
+        proof: &Proof,
+        tag: &ProtocolTag,
+    ) -> Result<PubkeyScript, Error> {
+        SpkContainer::reconstruct(proof, tag, self.as_inner())?
+            .original_script_pubkey()
+    }
+
+    /// Verifies that the `scriptPubkey` contains commitment to the message
+    /// using the provided proof. The commitment encoding method is deduced
+    /// from the proof and the script, as it is done by
+    /// [`SpkContainer::reconstruct`]; P2SH outputs are checked against both
+    /// bare and nested forms of the lockscript if the proof has no nested
+    /// script hint.
+    pub fn verify_script_pubkey(
+        spk: &PubkeyScript,
+        proof: &Proof,
+        tag: &ProtocolTag,
+        msg: &impl AsRef<[u8]>,
+    ) -> Result<bool, Error> {
+        let mut container = SpkContainer::reconstruct(proof, tag, spk)?;
+        container.resolve_nested(spk.as_inner(), msg);
+        SpkCommitment::from_inner(spk.clone()).verify(&container, msg)
+    }
+}
+
+/// PSBT proprietary output key holding LNPBP-2 commitment proof for the
+/// protocol with the given tag
+fn psbt_proof_key(tag: &ProtocolTag) -> ProprietaryKey {
+    ProprietaryKey {
+        prefix: PSBT_LNPBP_PREFIX.to_vec(),
+        subtype: PSBT_OUT_LNPBP2_PROOF,
+        key: tag.to_raw().to_vec(),
+    }
+}
+
+impl<MSG> EmbedCommitVerify<MSG> for SpkCommitment
+where
+    MSG: AsRef<[u8]>,
+{
+    type Container = SpkContainer;
+    type Error = super::Error;
+
+    fn embed_commit(
+        container: &mut Self::Container,
+        msg: &MSG,
+    ) -> Result<Self, Self::Error> {
+        #[cfg(feature = "miniscript")]
+        if let ScriptEncodeMethod::Miniscript(_) = container.method {
+            *container = container.resolve_miniscript()?;
+        }
+
+        let script_pubkey =
+            if let ScriptEncodeData::LockScript(ref lockscript)
+            | ScriptEncodeData::NestedLockScript(ref lockscript) =
+                container.source
+            {
+                let mut lockscript_container = LockscriptContainer {
+                    script: lockscript.clone(),
                     pubkey: container.pubkey,
                     tag: container.tag,
                     tweaking_factor: None,
@@ -278,38 +1409,32 @@ where
                 .into_inner();
                 container.tweaking_factor =
                     lockscript_container.tweaking_factor;
-                match container.method {
-                    Bare => lockscript.to_pubkey_script(Category::Bare),
-                    ScriptHash => lockscript.to_pubkey_script(Category::Hashed),
-                    WScriptHash => {
-                        lockscript.to_pubkey_script(Category::SegWit)
-                    }
-                    ShWScriptHash => {
-                        lockscript.to_pubkey_script(Category::Nested)
-                    }
-                    _ => return Err(Error::InvalidProofStructure),
-                }
-            } else if let ScriptEncodeData::Taproot(taproot_hash) =
-                container.source
+                container
+                    .method
+                    .script_pubkey_from_lockscript(&lockscript)?
+            } else if let ScriptEncodeData::Taproot(_)
+            | ScriptEncodeData::TaprootKeyOnly = container.source
             {
-                if container.method != Taproot {
+                if container.method != ScriptEncodeMethod::Taproot {
                     return Err(Error::InvalidProofStructure);
                 }
+                let script_root = match container.source {
+                    ScriptEncodeData::Taproot(script_root) => Some(script_root),
+                    _ => None,
+                };
                 let mut taproot_container = TaprootContainer {
-                    script_root: taproot_hash,
+                    script_root,
                     intermediate_key: container.pubkey,
                     tag: container.tag,
                     tweaking_factor: None,
+                    merkle_tree: None,
                 };
-                let _taproot = TaprootCommitment::embed_commit(
+                let taproot = TaprootCommitment::embed_commit(
                     &mut taproot_container,
                     msg,
                 )?;
                 container.tweaking_factor = taproot_container.tweaking_factor;
-                // TODO #2: Finalize taproot commitments once taproot will be
-                //          finalized. We don't know yet how to form scripPubkey
-                //          from Taproot data
-                unimplemented!()
+                taproot.script_pubkey()?
             } else {
                 let mut pubkey_container = PubkeyContainer {
                     pubkey: container.pubkey,
@@ -321,21 +1446,1551 @@ where
                     msg,
                 )?;
                 container.tweaking_factor = pubkey_container.tweaking_factor;
-                match container.method {
-                    PublicKey => pubkey.to_pubkey_script(Category::Bare),
-                    PubkeyHash => pubkey.to_pubkey_script(Category::Hashed),
-                    WPubkeyHash => pubkey.to_pubkey_script(Category::SegWit),
-                    ShWScriptHash => pubkey.to_pubkey_script(Category::Nested),
-                    OpReturn => {
-                        let ser = pubkey.serialize();
-                        if ser[0] != 0x02 {
-                            return Err(Error::InvalidOpReturnKey);
-                        }
-                        Script::new_op_return(&ser).into()
-                    }
-                    _ => return Err(Error::InvalidProofStructure),
+                if container.method == ScriptEncodeMethod::OpReturn
+                    && pubkey.serialize()[0] != 0x02
+                {
+                    return Err(Error::InvalidOpReturnKey);
                 }
+                container.method.script_pubkey_from_pubkey(pubkey)?
             };
         Ok(SpkCommitment::from_inner(script_pubkey))
     }
 }
+
+impl EmbedCommitVerify<MpcRoot> for SpkCommitment {
+    type Container = SpkContainer;
+    type Error = super::Error;
+
+    /// Commits to the canonical message of the LNPBP-4 tree root (see
+    /// [`MpcRoot::to_commitment_message`])
+    #[inline]
+    fn embed_commit(
+        container: &mut Self::Container,
+        msg: &MpcRoot,
+    ) -> Result<Self, Self::Error> {
+        <Self as EmbedCommitVerify<[u8; 64]>>::embed_commit(
+            container,
+            &msg.to_commitment_message(),
+        )
+    }
+}
+
+impl EmbedCommitVerify<MultiCommitBlock> for SpkCommitment {
+    type Container = SpkContainer;
+    type Error = super::Error;
+
+    /// Commits to the root of the LNPBP-4 tree
+    #[inline]
+    fn embed_commit(
+        container: &mut Self::Container,
+        msg: &MultiCommitBlock,
+    ) -> Result<Self, Self::Error> {
+        Self::embed_commit(container, &msg.commit_conceal())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::str::FromStr;
+
+    use bitcoin::bech32::u5;
+    use bitcoin::hashes::Hash;
+    use bitcoin::TxOut;
+
+    use super::*;
+    use crate::lnpbp1::test_helpers::*;
+    use crate::test_helpers::*;
+    use crate::TxoutCommitment;
+
+    fn gen_lockscript(pubkey: secp256k1::PublicKey) -> LockScript {
+        LockScript::from(
+            bitcoin::blockdata::script::Builder::new()
+                .push_slice(&pubkey.serialize())
+                .push_opcode(bitcoin::blockdata::opcodes::all::OP_CHECKSIG)
+                .into_script(),
+        )
+    }
+
+    #[test]
+    fn test_with_address() {
+        let tag = ProtocolTag::new("TEST_TAG");
+        let pubkey = gen_secp_pubkeys(1)[0];
+        let pk = bitcoin::PublicKey {
+            compressed: true,
+            key: pubkey,
+        };
+        let lockscript = gen_lockscript(pubkey);
+        let taproot = ScriptEncodeData::Taproot(sha256::Hash::hash(b"root"));
+        let network = Network::Bitcoin;
+
+        let cases = vec![
+            (
+                Address::p2pkh(&pk, network),
+                ScriptEncodeData::SinglePubkey,
+                ScriptEncodeMethod::PubkeyHash,
+            ),
+            (
+                Address::p2shwpkh(&pk, network).unwrap(),
+                ScriptEncodeData::SinglePubkey,
+                ScriptEncodeMethod::ShWPubkeyHash,
+            ),
+            (
+                Address::p2wpkh(&pk, network).unwrap(),
+                ScriptEncodeData::SinglePubkey,
+                ScriptEncodeMethod::WPubkeyHash,
+            ),
+            (
+                Address::p2sh(&lockscript, network),
+                ScriptEncodeData::LockScript(lockscript.clone()),
+                ScriptEncodeMethod::ScriptHash,
+            ),
+            (
+                Address::p2shwsh(&lockscript, network),
+                ScriptEncodeData::LockScript(lockscript.clone()),
+                ScriptEncodeMethod::ShWScriptHash,
+            ),
+            (
+                Address::p2wsh(&lockscript, network),
+                ScriptEncodeData::LockScript(lockscript.clone()),
+                ScriptEncodeMethod::WScriptHash,
+            ),
+            (
+                Address {
+                    payload: Payload::WitnessProgram {
+                        version: u5::try_from_u8(1).unwrap(),
+                        program: pubkey.serialize()[1..].to_vec(),
+                    },
+                    network,
+                },
+                taproot.clone(),
+                ScriptEncodeMethod::Taproot,
+            ),
+        ];
+
+        for (address, source, method) in cases {
+            let container = SpkContainer::with_address(
+                &tag,
+                pubkey,
+                source.clone(),
+                &address,
+                network,
+            )
+            .unwrap();
+            assert_eq!(container.method, method);
+            assert_eq!(container.source, source);
+
+            if method == ScriptEncodeMethod::Taproot {
+                continue;
+            }
+
+            // Source of a wrong type must not be accepted
+            assert_eq!(
+                SpkContainer::with_address(
+                    &tag,
+                    pubkey,
+                    taproot.clone(),
+                    &address,
+                    network,
+                ),
+                Err(Error::AddressMismatch)
+            );
+
+            let commitment =
+                SpkCommitment::embed_commit(&mut container.clone(), &"message")
+                    .unwrap();
+            let committed = commitment.to_address(network).unwrap();
+            assert_eq!(committed.address_type(), address.address_type());
+            assert_ne!(committed, address);
+        }
+    }
+
+    #[cfg(feature = "miniscript")]
+    fn test_descriptor(descriptor: &str, method: ScriptEncodeMethod) {
+        let tag = ProtocolTag::new("TEST_TAG");
+        let keys = gen_secp_pubkeys(3);
+        let descriptor = Descriptor::<bitcoin::PublicKey>::from_str(
+            &descriptor
+                .replace("A", &keys[0].to_string())
+                .replace("B", &keys[1].to_string())
+                .replace("C", &keys[2].to_string()),
+        )
+        .unwrap();
+
+        let mut container =
+            SpkContainer::from_descriptor(&descriptor, keys[0], &tag).unwrap();
+        assert_eq!(container.method, method);
+        assert_eq!(
+            container.to_committed_descriptor(),
+            Err(Error::NoTweakingFactor)
+        );
+
+        let commitment =
+            SpkCommitment::embed_commit(&mut container, &"message").unwrap();
+        let committed = container.to_committed_descriptor().unwrap();
+        assert_eq!(
+            committed.script_pubkey(),
+            commitment.to_inner().into_inner()
+        );
+        assert_ne!(committed, descriptor);
+
+        let other_key = gen_secp_pubkeys(4)[3];
+        assert_eq!(
+            SpkContainer::from_descriptor(&descriptor, other_key, &tag),
+            Err(Error::DescriptorKeyNotFound)
+        );
+    }
+
+    #[test]
+    #[cfg(feature = "miniscript")]
+    fn test_descriptor_wpkh() {
+        test_descriptor("wpkh(A)", ScriptEncodeMethod::WPubkeyHash);
+    }
+
+    #[test]
+    #[cfg(feature = "miniscript")]
+    fn test_descriptor_pkh() {
+        test_descriptor("pkh(A)", ScriptEncodeMethod::PubkeyHash);
+    }
+
+    #[test]
+    #[cfg(feature = "miniscript")]
+    fn test_descriptor_wsh_multi() {
+        test_descriptor("wsh(multi(2,A,B,C))", ScriptEncodeMethod::WScriptHash);
+    }
+
+    #[test]
+    #[cfg(feature = "miniscript")]
+    fn test_descriptor_sh_wsh_multi() {
+        test_descriptor(
+            "sh(wsh(multi(2,A,B,C)))",
+            ScriptEncodeMethod::ShWScriptHash,
+        );
+    }
+
+    #[test]
+    #[cfg(feature = "miniscript")]
+    fn test_miniscript_method() {
+        let tag = ProtocolTag::new("TEST_TAG");
+        let keys = gen_secp_pubkeys(3);
+        let hash = bitcoin::PublicKey {
+            compressed: true,
+            key: keys[0],
+        }
+        .pubkey_hash();
+        let render = |descriptor: &str| {
+            descriptor
+                .replace("H", &hash.to_string())
+                .replace("A", &keys[0].to_string())
+                .replace("B", &keys[1].to_string())
+                .replace("C", &keys[2].to_string())
+        };
+
+        for (descriptor, method) in &[
+            (
+                "wsh(thresh(2,pk(A),s:pk(B)))",
+                ScriptEncodeMethod::WScriptHash,
+            ),
+            (
+                "sh(wsh(thresh(2,pk(A),s:pk(B))))",
+                ScriptEncodeMethod::ShWScriptHash,
+            ),
+        ] {
+            let descriptor = render(descriptor);
+            let mut container = SpkContainer::construct(
+                &tag,
+                keys[0],
+                ScriptEncodeData::SinglePubkey,
+                ScriptEncodeMethod::Miniscript(descriptor.clone()),
+            );
+            assert_eq!(container.method.address_type(), method.address_type());
+            let expected = SpkContainer::from_descriptor(
+                &Descriptor::from_str(&descriptor).unwrap(),
+                keys[0],
+                &tag,
+            )
+            .unwrap();
+            assert_eq!(container.resolve_miniscript(), Ok(expected.clone()));
+
+            let commitment =
+                SpkCommitment::embed_commit(&mut container, &"message")
+                    .unwrap();
+            assert_eq!(&container.method, method);
+            assert_eq!(container.source, expected.source);
+            assert_eq!(
+                SpkCommitment::embed_commit(&mut expected.clone(), &"message"),
+                Ok(commitment.clone())
+            );
+            assert_eq!(
+                SpkCommitment::verify_script_pubkey(
+                    commitment.as_inner(),
+                    &container.to_proof(),
+                    &tag,
+                    &"message"
+                ),
+                Ok(true)
+            );
+            assert_eq!(
+                container.to_committed_descriptor().unwrap().script_pubkey(),
+                commitment.to_inner().into_inner()
+            );
+        }
+
+        let miniscript_container = |descriptor: &str| {
+            SpkContainer::construct(
+                &tag,
+                keys[0],
+                ScriptEncodeData::SinglePubkey,
+                ScriptEncodeMethod::Miniscript(render(descriptor)),
+            )
+        };
+        // Commitment key is absent or present only as a hash
+        for descriptor in &[
+            "wsh(thresh(2,pk(B),s:pk(C)))",
+            "wsh(thresh(2,c:pk_h(H),s:pk(B)))",
+        ] {
+            assert_eq!(
+                SpkCommitment::embed_commit(
+                    &mut miniscript_container(descriptor),
+                    &"message"
+                ),
+                Err(Error::DescriptorKeyNotFound)
+            );
+        }
+        // Descriptor is not script-based
+        assert_eq!(
+            SpkCommitment::embed_commit(
+                &mut miniscript_container("wpkh(A)"),
+                &"message"
+            ),
+            Err(Error::InvalidProofStructure)
+        );
+        // Descriptor is invalid
+        assert_eq!(
+            SpkCommitment::embed_commit(
+                &mut miniscript_container("wsh(thresh(2,pk(A),pk(B)))"),
+                &"message"
+            ),
+            Err(Error::LockscriptParseError)
+        );
+    }
+
+    #[test]
+    fn test_display_from_str() {
+        let hex = "5120a60869f0dbcf1dc659c9cecbaf8050135ea9e8cdc487053f1dc6880949dc684c";
+        let commitment = SpkCommitment::from_str(hex).unwrap();
+        assert_eq!(commitment.address_type(), AddressType::P2TR);
+        assert_eq!(commitment.to_string(), hex);
+        assert_eq!(
+            format!("{:#}", commitment),
+            "OP_PUSHNUM_1 OP_PUSHBYTES_32 \
+             a60869f0dbcf1dc659c9cecbaf8050135ea9e8cdc487053f1dc6880949dc684c"
+        );
+        assert_eq!(
+            SpkCommitment::from_str(&commitment.to_string()),
+            Ok(commitment)
+        );
+
+        let commitment = SpkCommitment::from_str("").unwrap();
+        assert_eq!(commitment, SpkCommitment::default());
+        assert_eq!(commitment.to_string(), "");
+
+        assert!(SpkCommitment::from_str("00f").is_err());
+        assert!(SpkCommitment::from_str("OP_RETURN").is_err());
+    }
+
+    #[test]
+    fn test_original_script_pubkey() {
+        let tag = ProtocolTag::new("TEST_TAG");
+        let pubkey = gen_secp_pubkeys(1)[0];
+        let lockscript = ScriptEncodeData::LockScript(gen_lockscript(pubkey));
+        let cases = vec![
+            (
+                ScriptEncodeData::SinglePubkey,
+                ScriptEncodeMethod::PublicKey,
+            ),
+            (
+                ScriptEncodeData::SinglePubkey,
+                ScriptEncodeMethod::PubkeyHash,
+            ),
+            (
+                ScriptEncodeData::SinglePubkey,
+                ScriptEncodeMethod::WPubkeyHash,
+            ),
+            (ScriptEncodeData::SinglePubkey, ScriptEncodeMethod::OpReturn),
+            (lockscript.clone(), ScriptEncodeMethod::Bare),
+            (lockscript.clone(), ScriptEncodeMethod::ScriptHash),
+            (lockscript.clone(), ScriptEncodeMethod::WScriptHash),
+            (lockscript, ScriptEncodeMethod::ShWScriptHash),
+            (
+                ScriptEncodeData::Taproot(sha256::Hash::hash(b"root")),
+                ScriptEncodeMethod::Taproot,
+            ),
+            (
+                ScriptEncodeData::TaprootKeyOnly,
+                ScriptEncodeMethod::Taproot,
+            ),
+        ];
+
+        for (source, method) in cases {
+            let container =
+                SpkContainer::construct(&tag, pubkey, source, method);
+            let original = container.original_script_pubkey().unwrap();
+            for msg in gen_messages() {
+                let commitment = match SpkCommitment::embed_commit(
+                    &mut container.clone(),
+                    &msg,
+                ) {
+                    Err(Error::InvalidOpReturnKey) => continue,
+                    res => res.unwrap(),
+                };
+                assert_ne!(*commitment.as_inner(), original);
+                assert_eq!(commitment.as_inner().len(), original.len());
+                assert!(commitment.matches_original(&container));
+                assert_eq!(
+                    commitment.revert(&container.to_proof(), &tag),
+                    Ok(original.clone())
+                );
+            }
+        }
+    }
+
+    #[test]
+    fn test_matches_original_mismatch() {
+        let tag = ProtocolTag::new("TEST_TAG");
+        let pubkey = gen_secp_pubkeys(1)[0];
+        let mut container = SpkContainer::construct(
+            &tag,
+            pubkey,
+            ScriptEncodeData::SinglePubkey,
+            ScriptEncodeMethod::WPubkeyHash,
+        );
+        let commitment =
+            SpkCommitment::embed_commit(&mut container.clone(), &"message")
+                .unwrap();
+        container.method = ScriptEncodeMethod::PubkeyHash;
+        assert!(!commitment.matches_original(&container));
+        assert!(commitment
+            .revert(&Proof::from_taproot(pubkey, None), &tag)
+            .is_err());
+    }
+
+    /// Reference implementation of [`SpkContainer::reconstruct`] prior to
+    /// the removal of proof and host cloning, kept to ensure the behaviour
+    /// was not changed
+    fn reconstruct_reference(
+        proof: &Proof,
+        supplement: &ProtocolTag,
+        host: &PubkeyScript,
+    ) -> Result<SpkContainer, Error> {
+        use core::convert::TryFrom;
+
+        let (lockscript, _) = match &proof.source {
+            ScriptEncodeData::SinglePubkey => (None, None),
+            ScriptEncodeData::LockScript(script) => (Some(script), None),
+            ScriptEncodeData::Taproot(hash) => (None, Some(Some(hash))),
+            ScriptEncodeData::TaprootKeyOnly => (None, Some(None)),
+            ScriptEncodeData::NestedLockScript(_) => {
+                unreachable!("reference implementation does not support hints")
+            }
+        };
+
+        let proof = proof.clone();
+        let method = match descriptors::Compact::try_from(host.clone())? {
+            descriptors::Compact::Sh(script_hash) => {
+                let script = Script::new_p2sh(&script_hash);
+                if let Some(lockscript) = lockscript {
+                    if *lockscript.to_pubkey_script(Category::Nested) == script
+                        && *lockscript.to_pubkey_script(Category::Hashed)
+                            != script
+                    {
+                        ScriptEncodeMethod::ShWScriptHash
+                    } else {
+                        ScriptEncodeMethod::ScriptHash
+                    }
+                } else {
+                    ScriptEncodeMethod::ShWPubkeyHash
+                }
+            }
+            descriptors::Compact::Bare(script)
+                if script.as_inner().is_op_return() =>
+            {
+                ScriptEncodeMethod::OpReturn
+            }
+            descriptors::Compact::Bare(_) => ScriptEncodeMethod::Bare,
+            descriptors::Compact::Pk(_) | descriptors::Compact::Pkh(_)
+                if lockscript.is_some() =>
+            {
+                ScriptEncodeMethod::Bare
+            }
+            descriptors::Compact::Pk(_) => ScriptEncodeMethod::PublicKey,
+            descriptors::Compact::Pkh(_) => ScriptEncodeMethod::PubkeyHash,
+            descriptors::Compact::Wpkh(_) => ScriptEncodeMethod::WPubkeyHash,
+            descriptors::Compact::Wsh(_) => ScriptEncodeMethod::WScriptHash,
+            descriptors::Compact::Taproot(_) => ScriptEncodeMethod::Taproot,
+            other => panic!("unsupported descriptor {:?}", other),
+        };
+
+        match (&method, &proof.source) {
+            (
+                ScriptEncodeMethod::PublicKey
+                | ScriptEncodeMethod::PubkeyHash
+                | ScriptEncodeMethod::WPubkeyHash
+                | ScriptEncodeMethod::ShWPubkeyHash
+                | ScriptEncodeMethod::OpReturn,
+                ScriptEncodeData::SinglePubkey,
+            )
+            | (
+                ScriptEncodeMethod::Bare
+                | ScriptEncodeMethod::ScriptHash
+                | ScriptEncodeMethod::WScriptHash
+                | ScriptEncodeMethod::ShWScriptHash,
+                ScriptEncodeData::LockScript(_),
+            )
+            | (
+                ScriptEncodeMethod::Taproot,
+                ScriptEncodeData::Taproot(_) | ScriptEncodeData::TaprootKeyOnly,
+            ) => {}
+            _ => return Err(Error::InvalidProofStructure),
+        }
+
+        Ok(SpkContainer {
+            pubkey: proof.pubkey,
+            source: proof.source,
+            method,
+            tag: *supplement,
+            tweaking_factor: None,
+        })
+    }
+
+    #[test]
+    fn test_reconstruct_reference() {
+        let tag = ProtocolTag::new("TEST_TAG");
+        let keys = gen_secp_pubkeys(2);
+        let lockscript = gen_lockscript(keys[0]);
+        let other_lockscript = gen_lockscript(keys[1]);
+
+        let proofs = vec![
+            Proof::from(keys[0]),
+            Proof {
+                pubkey: keys[0],
+                source: ScriptEncodeData::LockScript(lockscript.clone()),
+            },
+            Proof {
+                pubkey: keys[0],
+                source: ScriptEncodeData::Taproot(sha256::Hash::hash(b"root")),
+            },
+        ];
+
+        let hosts: Vec<PubkeyScript> = vec![
+            keys[0].to_pubkey_script(Category::Bare),
+            keys[0].to_pubkey_script(Category::Hashed),
+            keys[0].to_pubkey_script(Category::SegWit),
+            keys[0].to_pubkey_script(Category::Nested),
+            keys[1].to_pubkey_script(Category::Nested),
+            lockscript.to_pubkey_script(Category::Bare),
+            lockscript.to_pubkey_script(Category::Hashed),
+            lockscript.to_pubkey_script(Category::SegWit),
+            lockscript.to_pubkey_script(Category::Nested),
+            other_lockscript.to_pubkey_script(Category::Hashed),
+            Script::new_op_return(&keys[0].serialize()).into(),
+            Script::new_witness_program(
+                bitcoin::bech32::u5::try_from_u8(2).unwrap(),
+                &keys[0].serialize()[1..],
+            )
+            .into(),
+        ];
+
+        // Witness v1 outputs are not supported by the reference
+        // implementation; they may be reconstructed with taproot proofs only
+        let taproot_host: PubkeyScript = Script::new_witness_program(
+            bitcoin::bech32::u5::try_from_u8(1).unwrap(),
+            &keys[0].serialize()[1..],
+        )
+        .into();
+
+        for proof in &proofs {
+            for host in &hosts {
+                assert_eq!(
+                    SpkContainer::reconstruct(proof, &tag, host),
+                    reconstruct_reference(proof, &tag, host)
+                );
+            }
+            assert_eq!(
+                SpkContainer::reconstruct(proof, &tag, &taproot_host)
+                    .map(|container| container.method),
+                match proof.source {
+                    ScriptEncodeData::Taproot(_) => {
+                        Ok(ScriptEncodeMethod::Taproot)
+                    }
+                    _ => Err(Error::InvalidProofStructure),
+                }
+            );
+        }
+    }
+
+    #[test]
+    fn test_nested_pubkey_roundtrip() {
+        let tag = ProtocolTag::new("TEST_TAG");
+        let msg = "message";
+        for pubkey in gen_secp_pubkeys(4) {
+            let mut container = SpkContainer::construct(
+                &tag,
+                pubkey,
+                ScriptEncodeData::SinglePubkey,
+                ScriptEncodeMethod::ShWPubkeyHash,
+            );
+            let commitment =
+                SpkCommitment::embed_commit(&mut container, &msg).unwrap();
+            assert!(commitment.as_inner().is_p2sh());
+
+            let reconstructed = SpkContainer::reconstruct(
+                &container.to_proof(),
+                &tag,
+                commitment.as_inner(),
+            )
+            .unwrap();
+            assert_eq!(reconstructed.method, ScriptEncodeMethod::ShWPubkeyHash);
+            assert!(commitment.verify(&reconstructed, &msg).unwrap());
+            assert!(!commitment.verify(&reconstructed, &"other").unwrap());
+        }
+    }
+
+    #[test]
+    fn test_p2sh_disambiguation() {
+        let tag = ProtocolTag::new("TEST_TAG");
+        let msg = "message";
+        let pubkey = gen_secp_pubkeys(1)[0];
+        let lockscript = gen_lockscript(pubkey);
+
+        // NB: We can't construct a script for which both bare and nested
+        // P2SH forms match the host, since this requires HASH160 collision.
+        // However, the output containing a commitment matches none of them,
+        // so both interpretations are structurally valid, and the proof hint
+        // is the only way to distinguish them without the message.
+        for method in &[
+            ScriptEncodeMethod::ScriptHash,
+            ScriptEncodeMethod::ShWScriptHash,
+        ] {
+            let mut container = SpkContainer::construct(
+                &tag,
+                pubkey,
+                ScriptEncodeData::LockScript(lockscript.clone()),
+                method.clone(),
+            );
+            let commitment =
+                SpkCommitment::embed_commit(&mut container, &msg).unwrap();
+            let proof = container.to_proof();
+
+            let reconstructed =
+                SpkContainer::reconstruct(&proof, &tag, commitment.as_inner())
+                    .unwrap();
+            assert_eq!(&reconstructed.method, method);
+            assert_eq!(
+                reconstructed.source,
+                ScriptEncodeData::LockScript(lockscript.clone())
+            );
+            assert_eq!(reconstructed.to_proof(), proof);
+            assert!(commitment.verify(&reconstructed, &msg).unwrap());
+
+            // Without the hint the script is reconstructed as a bare P2SH
+            // script
+            let hintless = Proof {
+                pubkey,
+                source: ScriptEncodeData::LockScript(lockscript.clone()),
+            };
+            let reconstructed = SpkContainer::reconstruct(
+                &hintless,
+                &tag,
+                commitment.as_inner(),
+            )
+            .unwrap();
+            assert_eq!(reconstructed.method, ScriptEncodeMethod::ScriptHash);
+            assert_eq!(
+                commitment.verify(&reconstructed, &msg).unwrap(),
+                *method == ScriptEncodeMethod::ScriptHash
+            );
+        }
+
+        // When the host matches the nested form only, the hint is not needed
+        let host = lockscript.to_pubkey_script(Category::Nested);
+        let hintless = Proof {
+            pubkey,
+            source: ScriptEncodeData::LockScript(lockscript),
+        };
+        assert_eq!(
+            SpkContainer::reconstruct(&hintless, &tag, &host)
+                .unwrap()
+                .method,
+            ScriptEncodeMethod::ShWScriptHash
+        );
+    }
+
+    #[test]
+    fn test_hintless_nested_verification() {
+        let tag = ProtocolTag::new("TEST_TAG");
+        let msg = "message";
+        let pubkey = gen_secp_pubkeys(1)[0];
+        let lockscript = gen_lockscript(pubkey);
+        let hintless = Proof {
+            pubkey,
+            source: ScriptEncodeData::LockScript(lockscript.clone()),
+        };
+
+        for method in &[
+            ScriptEncodeMethod::ScriptHash,
+            ScriptEncodeMethod::ShWScriptHash,
+        ] {
+            let mut container = SpkContainer::construct(
+                &tag,
+                pubkey,
+                ScriptEncodeData::LockScript(lockscript.clone()),
+                method.clone(),
+            );
+            let commitment =
+                SpkCommitment::embed_commit(&mut container, &msg).unwrap();
+            let spk = commitment.as_inner();
+
+            let mut reconstructed =
+                SpkContainer::reconstruct(&hintless, &tag, spk).unwrap();
+            reconstructed.resolve_nested(spk.as_inner(), &"other");
+            assert_eq!(reconstructed.method, ScriptEncodeMethod::ScriptHash);
+            reconstructed.resolve_nested(spk.as_inner(), &msg);
+            assert_eq!(&reconstructed.method, method);
+            assert!(commitment.verify(&reconstructed, &msg).unwrap());
+
+            assert!(SpkCommitment::verify_script_pubkey(
+                spk, &hintless, &tag, &msg
+            )
+            .unwrap());
+            assert!(!SpkCommitment::verify_script_pubkey(
+                spk, &hintless, &tag, &"other"
+            )
+            .unwrap());
+
+            let txout = TxOut {
+                value: 1000,
+                script_pubkey: spk.to_inner(),
+            };
+            assert!(TxoutCommitment::verify_txout(
+                &txout, 1000, &hintless, &tag, &msg
+            )
+            .unwrap());
+            assert!(!TxoutCommitment::verify_txout(
+                &txout, 1000, &hintless, &tag, &"other"
+            )
+            .unwrap());
+        }
+    }
+
+    #[test]
+    fn test_nested_script_method_rejects_pubkey() {
+        let tag = ProtocolTag::new("TEST_TAG");
+        let mut container = SpkContainer::construct(
+            &tag,
+            gen_secp_pubkeys(1)[0],
+            ScriptEncodeData::SinglePubkey,
+            ScriptEncodeMethod::ShWScriptHash,
+        );
+        assert_eq!(
+            SpkCommitment::embed_commit(&mut container, &"message"),
+            Err(Error::InvalidProofStructure)
+        );
+    }
+
+    #[test]
+    fn test_new_op_return() {
+        let tag = ProtocolTag::new("TEST_TAG");
+        let keys = gen_secp_pubkeys(20);
+        assert!(keys.iter().any(|key| key.serialize()[0] == 0x02));
+        assert!(keys.iter().any(|key| key.serialize()[0] == 0x03));
+        for key in keys {
+            let container = SpkContainer::new_op_return(key, &tag);
+            if key.serialize()[0] == 0x03 {
+                assert_eq!(container, Err(Error::InvalidOpReturnKey));
+                continue;
+            }
+            assert_eq!(
+                container,
+                Ok(SpkContainer::construct(
+                    &tag,
+                    key,
+                    ScriptEncodeData::SinglePubkey,
+                    ScriptEncodeMethod::OpReturn,
+                ))
+            );
+        }
+    }
+
+    #[test]
+    fn test_address_key_mismatch() {
+        let tag = ProtocolTag::new("TEST_TAG");
+        let keys = gen_secp_pubkeys(2);
+        let address = Address::p2wpkh(
+            &bitcoin::PublicKey {
+                compressed: true,
+                key: keys[1],
+            },
+            Network::Bitcoin,
+        )
+        .unwrap();
+        assert_eq!(
+            SpkContainer::with_address(
+                &tag,
+                keys[0],
+                ScriptEncodeData::SinglePubkey,
+                &address,
+                Network::Bitcoin,
+            ),
+            Err(Error::AddressMismatch)
+        );
+    }
+
+    #[test]
+    fn test_address_network_mismatch() {
+        let tag = ProtocolTag::new("TEST_TAG");
+        let pubkey = secp256k1::PublicKey::from_str(
+            "0218845781f631c48f1c9709e23092067d06837f30aa0cd0544ac887fe91ddd166",
+        )
+        .unwrap();
+        let address = Address::p2pkh(
+            &bitcoin::PublicKey {
+                compressed: true,
+                key: pubkey,
+            },
+            Network::Testnet,
+        );
+        assert_eq!(
+            SpkContainer::with_address(
+                &tag,
+                pubkey,
+                ScriptEncodeData::SinglePubkey,
+                &address,
+                Network::Bitcoin,
+            ),
+            Err(Error::NetworkMismatch {
+                expected: Network::Bitcoin,
+                actual: Network::Testnet
+            })
+        );
+
+        // Testnet and regtest share base58 address encoding, but not bech32
+        assert!(SpkContainer::with_address(
+            &tag,
+            pubkey,
+            ScriptEncodeData::SinglePubkey,
+            &address,
+            Network::Regtest,
+        )
+        .is_ok());
+        let address = Address::p2wpkh(
+            &bitcoin::PublicKey {
+                compressed: true,
+                key: pubkey,
+            },
+            Network::Testnet,
+        )
+        .unwrap();
+        assert!(SpkContainer::with_address(
+            &tag,
+            pubkey,
+            ScriptEncodeData::SinglePubkey,
+            &address,
+            Network::Signet,
+        )
+        .is_ok());
+        assert_eq!(
+            SpkContainer::with_address(
+                &tag,
+                pubkey,
+                ScriptEncodeData::SinglePubkey,
+                &address,
+                Network::Regtest,
+            ),
+            Err(Error::NetworkMismatch {
+                expected: Network::Regtest,
+                actual: Network::Testnet
+            })
+        );
+    }
+
+    #[test]
+    fn test_roundtrip_matrix() {
+        let tag = ProtocolTag::new("TEST_TAG");
+        let pubkey_methods = [
+            ScriptEncodeMethod::PublicKey,
+            ScriptEncodeMethod::PubkeyHash,
+            ScriptEncodeMethod::WPubkeyHash,
+            ScriptEncodeMethod::ShWPubkeyHash,
+            ScriptEncodeMethod::OpReturn,
+        ];
+        let script_methods = [
+            ScriptEncodeMethod::Bare,
+            ScriptEncodeMethod::ScriptHash,
+            ScriptEncodeMethod::WScriptHash,
+            ScriptEncodeMethod::ShWScriptHash,
+        ];
+
+        for pubkey in gen_secp_pubkeys(9) {
+            let lockscript = gen_lockscript(pubkey);
+            let cases = pubkey_methods
+                .iter()
+                .map(|method| (ScriptEncodeData::SinglePubkey, method.clone()))
+                .chain(script_methods.iter().map(|method| {
+                    (
+                        ScriptEncodeData::LockScript(lockscript.clone()),
+                        method.clone(),
+                    )
+                }))
+                .chain(vec![
+                    (
+                        ScriptEncodeData::Taproot(sha256::Hash::hash(b"root")),
+                        ScriptEncodeMethod::Taproot,
+                    ),
+                    (
+                        ScriptEncodeData::TaprootKeyOnly,
+                        ScriptEncodeMethod::Taproot,
+                    ),
+                ]);
+            for (source, method) in cases {
+                let container =
+                    SpkContainer::construct(&tag, pubkey, source, method);
+                for msg in gen_messages() {
+                    // OP_RETURN commitments are valid only for the tweaked
+                    // keys with even y coordinate
+                    if container.method == ScriptEncodeMethod::OpReturn
+                        && SpkCommitment::embed_commit(
+                            &mut container.clone(),
+                            &msg,
+                        ) == Err(Error::InvalidOpReturnKey)
+                    {
+                        continue;
+                    }
+                    assert_spk_roundtrip(container.clone(), &msg);
+                }
+            }
+        }
+    }
+
+    /// Generates all valid containers for the given public key
+    fn gen_containers(
+        tag: &ProtocolTag,
+        pubkey: secp256k1::PublicKey,
+    ) -> Vec<SpkContainer> {
+        let lockscript = gen_lockscript(pubkey);
+        vec![
+            ScriptEncodeMethod::PublicKey,
+            ScriptEncodeMethod::PubkeyHash,
+            ScriptEncodeMethod::WPubkeyHash,
+            ScriptEncodeMethod::ShWPubkeyHash,
+            ScriptEncodeMethod::OpReturn,
+        ]
+        .into_iter()
+        .map(|method| (ScriptEncodeData::SinglePubkey, method))
+        .chain(
+            vec![
+                ScriptEncodeMethod::Bare,
+                ScriptEncodeMethod::ScriptHash,
+                ScriptEncodeMethod::WScriptHash,
+                ScriptEncodeMethod::ShWScriptHash,
+            ]
+            .into_iter()
+            .map(|method| {
+                (ScriptEncodeData::LockScript(lockscript.clone()), method)
+            }),
+        )
+        .chain(std::iter::once((
+            ScriptEncodeData::Taproot(sha256::Hash::hash(b"root")),
+            ScriptEncodeMethod::Taproot,
+        )))
+        .map(|(source, method)| {
+            SpkContainer::construct(tag, pubkey, source, method)
+        })
+        .collect()
+    }
+
+    #[test]
+    fn test_method_try_from() {
+        let tag = ProtocolTag::new("TEST_TAG");
+        let msg = "message";
+        for pubkey in gen_secp_pubkeys(4) {
+            for mut container in gen_containers(&tag, pubkey) {
+                let commitment =
+                    match SpkCommitment::embed_commit(&mut container, &msg) {
+                        Err(Error::InvalidOpReturnKey) => continue,
+                        res => res.unwrap(),
+                    };
+                let proof = container.to_proof();
+                assert_eq!(
+                    ScriptEncodeMethod::try_from((
+                        &proof,
+                        commitment.as_inner()
+                    )),
+                    Ok(container.method.clone())
+                );
+
+                // Bare lockscripts may look exactly as P2PK outputs, so the
+                // only source which is wrong for all of the other hosts is
+                // taproot
+                let wrong_source =
+                    if let ScriptEncodeData::Taproot(_) = proof.source {
+                        ScriptEncodeData::SinglePubkey
+                    } else {
+                        ScriptEncodeData::Taproot(sha256::Hash::hash(b"root"))
+                    };
+                let wrong_proof = Proof {
+                    pubkey: proof.pubkey,
+                    source: wrong_source,
+                };
+                assert_eq!(
+                    ScriptEncodeMethod::try_from((
+                        &wrong_proof,
+                        commitment.as_inner()
+                    )),
+                    Err(Error::InvalidProofStructure)
+                );
+            }
+        }
+
+        let proof = Proof {
+            pubkey: gen_secp_pubkeys(1)[0],
+            source: ScriptEncodeData::SinglePubkey,
+        };
+        let v2 = PubkeyScript::from(
+            bitcoin::blockdata::script::Builder::new()
+                .push_int(2)
+                .push_slice(&[0u8; 32])
+                .into_script(),
+        );
+        assert_eq!(
+            ScriptEncodeMethod::try_from((&proof, &v2)),
+            Err(Error::UnsupportedWitnessVersion)
+        );
+    }
+
+    #[test]
+    fn test_address_type() {
+        let tag = ProtocolTag::new("TEST_TAG");
+        let pubkey = gen_secp_pubkeys(1)[0];
+        let lockscript = ScriptEncodeData::LockScript(gen_lockscript(pubkey));
+        let cases = vec![
+            (
+                ScriptEncodeData::SinglePubkey,
+                ScriptEncodeMethod::PublicKey,
+                AddressType::P2PK,
+            ),
+            (
+                ScriptEncodeData::SinglePubkey,
+                ScriptEncodeMethod::PubkeyHash,
+                AddressType::P2PKH,
+            ),
+            (
+                ScriptEncodeData::SinglePubkey,
+                ScriptEncodeMethod::WPubkeyHash,
+                AddressType::P2WPKH,
+            ),
+            (
+                ScriptEncodeData::SinglePubkey,
+                ScriptEncodeMethod::ShWPubkeyHash,
+                AddressType::P2SHWrappedP2WPKH,
+            ),
+            (
+                ScriptEncodeData::SinglePubkey,
+                ScriptEncodeMethod::OpReturn,
+                AddressType::NonStandard,
+            ),
+            (
+                lockscript.clone(),
+                ScriptEncodeMethod::Bare,
+                AddressType::NonStandard,
+            ),
+            (
+                lockscript.clone(),
+                ScriptEncodeMethod::ScriptHash,
+                AddressType::P2SH,
+            ),
+            (
+                lockscript.clone(),
+                ScriptEncodeMethod::WScriptHash,
+                AddressType::P2WSH,
+            ),
+            (
+                lockscript,
+                ScriptEncodeMethod::ShWScriptHash,
+                AddressType::P2SHWrappedP2WSH,
+            ),
+            (
+                ScriptEncodeData::TaprootKeyOnly,
+                ScriptEncodeMethod::Taproot,
+                AddressType::P2TR,
+            ),
+        ];
+        for (source, method, address_type) in cases {
+            assert_eq!(method.address_type(), address_type);
+            let bare = method == ScriptEncodeMethod::Bare;
+            let mut container =
+                SpkContainer::construct(&tag, pubkey, source, method);
+            let commitment =
+                match SpkCommitment::embed_commit(&mut container, &"message") {
+                    Err(Error::InvalidOpReturnKey) => continue,
+                    res => res.unwrap(),
+                };
+            let expected = match address_type {
+                // Test lockscript has the same form as P2PK output
+                _ if bare => AddressType::P2PK,
+                AddressType::P2SHWrappedP2WPKH
+                | AddressType::P2SHWrappedP2WSH => AddressType::P2SH,
+                address_type => address_type,
+            };
+            assert_eq!(commitment.address_type(), expected);
+        }
+
+        let v2 = SpkCommitment::from_inner(
+            bitcoin::blockdata::script::Builder::new()
+                .push_int(2)
+                .push_slice(&[0u8; 32])
+                .into_script()
+                .into(),
+        );
+        assert_eq!(v2.address_type(), AddressType::NonStandard);
+    }
+
+    #[test]
+    fn test_quick_match() {
+        let tag = ProtocolTag::new("TEST_TAG");
+        let msg = "message";
+        let mut containers = vec![];
+        let mut hosts = vec![];
+        for pubkey in gen_secp_pubkeys(4) {
+            for mut container in gen_containers(&tag, pubkey) {
+                match SpkCommitment::embed_commit(&mut container, &msg) {
+                    Err(Error::InvalidOpReturnKey) => continue,
+                    res => hosts.push(res.unwrap()),
+                };
+                containers.push(container);
+            }
+        }
+        hosts.push(SpkCommitment::from_inner(
+            Script::new_op_return(&[0u8; 33]).into(),
+        ));
+
+        // Making sure that allocations are really counted
+        assert_eq!(count_allocations(|| Vec::<u8>::with_capacity(1)).1, 1);
+
+        for container in &containers {
+            let proof = container.to_proof();
+            for host in &hosts {
+                let verified =
+                    SpkContainer::reconstruct(&proof, &tag, host.as_inner())
+                        .map(|reconstructed| host.verify(&reconstructed, &msg))
+                        .unwrap_or(Ok(false))
+                        .unwrap_or(false);
+                let (matched, allocations) = count_allocations(|| {
+                    SpkContainer::quick_match(&proof, host.as_inner())
+                });
+                // Quick match must never reject valid commitments
+                assert!(matched || !verified);
+                assert_eq!(allocations, 0);
+            }
+        }
+    }
+
+    #[test]
+    fn test_auto_detect_method() {
+        use bitcoin::blockdata::opcodes::all::{
+            OP_CHECKSIGVERIFY, OP_PUSHNUM_2,
+        };
+        use bitcoin::blockdata::script::Builder;
+
+        let tag = ProtocolTag::new("TEST_TAG");
+        let keys = gen_secp_pubkeys(2);
+        let pubkey = keys[0];
+        let checksig = ScriptEncodeData::LockScript(LockScript::from(
+            pubkey.to_pubkey_script(Category::Bare).into_inner(),
+        ));
+        let bare = Builder::new()
+            .push_slice(&pubkey.serialize())
+            .push_opcode(OP_CHECKSIGVERIFY)
+            .push_opcode(OP_PUSHNUM_1)
+            .into_script();
+        let cases = vec![
+            (
+                ScriptEncodeData::SinglePubkey,
+                ScriptEncodeMethod::PubkeyHash,
+            ),
+            (
+                ScriptEncodeData::SinglePubkey,
+                ScriptEncodeMethod::WPubkeyHash,
+            ),
+            (
+                ScriptEncodeData::SinglePubkey,
+                ScriptEncodeMethod::ShWPubkeyHash,
+            ),
+            (ScriptEncodeData::SinglePubkey, ScriptEncodeMethod::OpReturn),
+            (checksig.clone(), ScriptEncodeMethod::ScriptHash),
+            (checksig.clone(), ScriptEncodeMethod::WScriptHash),
+            (checksig, ScriptEncodeMethod::ShWScriptHash),
+            (
+                ScriptEncodeData::LockScript(LockScript::from(bare.clone())),
+                ScriptEncodeMethod::Bare,
+            ),
+            (
+                ScriptEncodeData::TaprootKeyOnly,
+                ScriptEncodeMethod::Taproot,
+            ),
+        ];
+        for (source, method) in cases {
+            let container =
+                SpkContainer::construct(&tag, pubkey, source, method);
+            let spk = container.original_script_pubkey().unwrap();
+            assert_eq!(
+                SpkContainer::auto_detect_method(&tag, &spk, pubkey),
+                Ok(container)
+            );
+            // Script constructed from another key
+            assert_eq!(
+                SpkContainer::auto_detect_method(&tag, &spk, keys[1]),
+                Err(Error::ScriptMismatch)
+            );
+        }
+
+        // P2PK output is the same as a bare lockscript, but is always
+        // detected as a public key output
+        let p2pk = pubkey.to_pubkey_script(Category::Bare);
+        assert_eq!(
+            SpkContainer::auto_detect_method(&tag, &p2pk, pubkey),
+            Ok(SpkContainer::construct(
+                &tag,
+                pubkey,
+                ScriptEncodeData::SinglePubkey,
+                ScriptEncodeMethod::PublicKey
+            ))
+        );
+        assert_eq!(
+            SpkContainer::auto_detect_method(&tag, &p2pk, keys[1]),
+            Err(Error::ScriptMismatch)
+        );
+
+        // P2SH of an unknown script
+        let lockscript = LockScript::from(bare);
+        assert_eq!(
+            SpkContainer::auto_detect_method(
+                &tag,
+                &lockscript.to_pubkey_script(Category::Hashed),
+                pubkey
+            ),
+            Err(Error::ScriptMismatch)
+        );
+
+        let future_witness = Builder::new()
+            .push_opcode(OP_PUSHNUM_2)
+            .push_slice(&[0u8; 32])
+            .into_script();
+        assert_eq!(
+            SpkContainer::auto_detect_method(
+                &tag,
+                &future_witness.into(),
+                pubkey
+            ),
+            Err(Error::UnsupportedWitnessVersion)
+        );
+    }
+
+    #[test]
+    fn test_psbt_output() {
+        let tag = ProtocolTag::new("TEST_TAG");
+        let other_tag = ProtocolTag::new("OTHER_TAG");
+        let msg = "message";
+        for pubkey in gen_secp_pubkeys(2) {
+            for mut container in gen_containers(&tag, pubkey) {
+                let commitment =
+                    match SpkCommitment::embed_commit(&mut container, &msg) {
+                        Err(Error::InvalidOpReturnKey) => continue,
+                        res => res.unwrap(),
+                    };
+                let proof = container.to_proof();
+                let output = commitment.to_psbt_output(&proof, &tag);
+                assert_eq!(
+                    output.proprietary.get(&ProprietaryKey {
+                        prefix: vec![0x4C, 0x4E, 0x50, 0x42],
+                        subtype: 0x02,
+                        key: tag.to_raw().to_vec(),
+                    }),
+                    Some(&proof.to_bytes())
+                );
+
+                let (parsed, parsed_proof) = SpkCommitment::from_psbt_output(
+                    &output,
+                    commitment.as_inner().clone(),
+                    &tag,
+                )
+                .unwrap();
+                assert_eq!(parsed, commitment);
+                assert_eq!(parsed_proof, proof);
+                let reconstructed =
+                    SpkContainer::reconstruct(&parsed_proof, &tag, &parsed)
+                        .unwrap();
+                assert!(parsed.verify(&reconstructed, &msg).unwrap());
+
+                assert_eq!(
+                    SpkCommitment::from_psbt_output(
+                        &output,
+                        commitment.as_inner().clone(),
+                        &other_tag,
+                    ),
+                    Err(Error::PsbtNoProof)
+                );
+                assert_eq!(
+                    SpkCommitment::from_psbt_output(
+                        &output,
+                        Script::new_op_return(&[0u8; 33]).into(),
+                        &tag,
+                    ),
+                    Err(Error::ScriptMismatch)
+                );
+            }
+        }
+
+        let mut output = psbt::Output::default();
+        output.proprietary.insert(psbt_proof_key(&tag), vec![0xFF]);
+        assert_eq!(
+            SpkCommitment::from_psbt_output(
+                &output,
+                Script::new().into(),
+                &tag
+            ),
+            Err(Error::InvalidProofStructure)
+        );
+    }
+
+    #[test]
+    fn test_expected_input_weight() {
+        use bitcoin::blockdata::script::Builder;
+        use bitcoin::consensus::serialize;
+
+        let sig = [0x30u8; 72];
+        let pubkey = [0x02u8; 33];
+        let weight = |script_sig: Script, witness: Vec<Vec<u8>>| {
+            let witness_len = if witness.is_empty() {
+                0
+            } else {
+                serialize(&witness).len()
+            };
+            4 * script_sig.len() as u64 + witness_len as u64
+        };
+        let witness_program = |len: usize| {
+            Builder::new()
+                .push_slice(
+                    Builder::new()
+                        .push_int(0)
+                        .push_slice(&vec![0u8; len])
+                        .into_script()
+                        .as_bytes(),
+                )
+                .into_script()
+        };
+
+        assert_eq!(
+            ScriptEncodeMethod::PublicKey.expected_input_weight(),
+            weight(Builder::new().push_slice(&sig).into_script(), vec![])
+        );
+        assert_eq!(
+            ScriptEncodeMethod::PubkeyHash.expected_input_weight(),
+            weight(
+                Builder::new()
+                    .push_slice(&sig)
+                    .push_slice(&pubkey)
+                    .into_script(),
+                vec![]
+            )
+        );
+        assert_eq!(
+            ScriptEncodeMethod::WPubkeyHash.expected_input_weight(),
+            108
+        );
+        assert_eq!(
+            ScriptEncodeMethod::WPubkeyHash.expected_input_weight(),
+            weight(Script::new(), vec![sig.to_vec(), pubkey.to_vec()])
+        );
+        assert_eq!(
+            ScriptEncodeMethod::ShWPubkeyHash.expected_input_weight(),
+            weight(witness_program(20), vec![sig.to_vec(), pubkey.to_vec()])
+        );
+        assert_eq!(
+            ScriptEncodeMethod::ShWScriptHash.expected_input_weight(),
+            weight(witness_program(32), vec![])
+        );
+        assert_eq!(
+            ScriptEncodeMethod::Taproot.expected_input_weight(),
+            weight(Script::new(), vec![vec![0u8; 64]])
+        );
+        for method in &[
+            ScriptEncodeMethod::OpReturn,
+            ScriptEncodeMethod::ScriptHash,
+            ScriptEncodeMethod::WScriptHash,
+            ScriptEncodeMethod::Bare,
+        ] {
+            assert_eq!(method.expected_input_weight(), 0);
+            assert_eq!(method.minimum_fee_rate_vbytes(), 0);
+        }
+    }
+
+    #[test]
+    fn test_minimum_fee_rate_vbytes() {
+        use ScriptEncodeMethod::*;
+
+        for (method, vbytes) in &[
+            (PublicKey, 73),
+            (PubkeyHash, 107),
+            (WPubkeyHash, 27),
+            (ShWPubkeyHash, 50),
+            (ShWScriptHash, 35),
+            (Taproot, 17),
+        ] {
+            assert_eq!(method.minimum_fee_rate_vbytes(), *vbytes);
+            assert!(
+                method.minimum_fee_rate_vbytes() * 4
+                    >= method.expected_input_weight()
+            );
+            assert!(
+                (method.minimum_fee_rate_vbytes() - 1) * 4
+                    < method.expected_input_weight()
+            );
+        }
+    }
+
+    #[test]
+    fn test_p2sh_wrapping() {
+        let tag = ProtocolTag::new("TEST_TAG");
+        let msg = "message";
+        for pubkey in gen_secp_pubkeys(4) {
+            let mut container = SpkContainer::construct(
+                &tag,
+                pubkey,
+                ScriptEncodeData::LockScript(gen_lockscript(pubkey)),
+                ScriptEncodeMethod::WScriptHash,
+            );
+            let commitment =
+                SpkCommitment::embed_commit(&mut container, &msg).unwrap();
+            let wrapped = commitment.clone().into_p2sh_wrapped().unwrap();
+            assert!(wrapped.as_inner().is_p2sh());
+
+            // Wrapped commitment is the same as the one produced with the
+            // nested method
+            let mut nested_container = container.clone();
+            nested_container.method = ScriptEncodeMethod::ShWScriptHash;
+            assert_eq!(
+                SpkCommitment::embed_commit(&mut nested_container, &msg)
+                    .unwrap(),
+                wrapped
+            );
+
+            // The original proof still verifies unwrapped commitment
+            let reconstructed = SpkContainer::reconstruct(
+                &container.to_proof(),
+                &tag,
+                commitment.as_inner(),
+            )
+            .unwrap();
+            assert!(commitment.verify(&reconstructed, &msg).unwrap());
+
+            // The proof from the container with the updated method verifies
+            // wrapped commitment
+            let reconstructed = SpkContainer::reconstruct(
+                &nested_container.to_proof(),
+                &tag,
+                wrapped.as_inner(),
+            )
+            .unwrap();
+            assert!(wrapped.verify(&reconstructed, &msg).unwrap());
+            assert!(!wrapped.verify(&reconstructed, &"other").unwrap());
+
+            assert_eq!(wrapped.into_p2sh_wrapped(), Err(Error::NotWScriptHash));
+        }
+    }
+
+    #[test]
+    fn test_method_strict_encoding() {
+        use ScriptEncodeMethod::*;
+        // Variants are encoded by their order; the encoding must not change
+        let methods = vec![
+            PublicKey,
+            PubkeyHash,
+            ScriptHash,
+            WPubkeyHash,
+            WScriptHash,
+            ShWPubkeyHash,
+            ShWScriptHash,
+            Taproot,
+            OpReturn,
+            Bare,
+        ];
+        for (tag, method) in methods.into_iter().enumerate() {
+            let data = method.strict_serialize().unwrap();
+            assert_eq!(data, vec![tag as u8]);
+            assert_eq!(
+                ScriptEncodeMethod::strict_deserialize(&data),
+                Ok(method)
+            );
+        }
+
+        let mut data = vec![10u8, 10, 0];
+        data.extend(b"wsh(pk(A))");
+        #[cfg(feature = "miniscript")]
+        {
+            let method = Miniscript(s!("wsh(pk(A))"));
+            assert_eq!(method.strict_serialize().unwrap(), data);
+            assert_eq!(
+                ScriptEncodeMethod::strict_deserialize(&data),
+                Ok(method)
+            );
+        }
+        #[cfg(not(feature = "miniscript"))]
+        assert!(matches!(
+            ScriptEncodeMethod::strict_deserialize(&data),
+            Err(strict_encoding::Error::UnsupportedDataStructure(_))
+        ));
+
+        assert_eq!(
+            ScriptEncodeMethod::strict_deserialize([11]),
+            Err(strict_encoding::Error::EnumValueNotKnown(
+                "ScriptEncodeMethod",
+                11
+            ))
+        );
+    }
+
+    #[test]
+    fn test_source_strict_encoding() {
+        let script = LockScript::from(Script::from(vec![0x51, 0x52]));
+        let root = sha256::Hash::hash(b"root");
+        let cases = vec![
+            (ScriptEncodeData::SinglePubkey, vec![0u8]),
+            (ScriptEncodeData::LockScript(script.clone()), vec![
+                1, 2, 0, 0x51, 0x52,
+            ]),
+            (
+                ScriptEncodeData::Taproot(root),
+                [&[2u8][..], &root[..]].concat(),
+            ),
+            (ScriptEncodeData::NestedLockScript(script), vec![
+                3, 2, 0, 0x51, 0x52,
+            ]),
+            (ScriptEncodeData::TaprootKeyOnly, vec![4]),
+        ];
+        for (source, data) in cases {
+            assert_eq!(source.strict_serialize().unwrap(), data);
+            assert_eq!(ScriptEncodeData::strict_deserialize(&data), Ok(source));
+        }
+        assert_eq!(
+            ScriptEncodeData::strict_deserialize([5]),
+            Err(strict_encoding::Error::EnumValueNotKnown(
+                "ScriptEncodeData",
+                5
+            ))
+        );
+        assert!(matches!(
+            ScriptEncodeData::strict_deserialize([1]),
+            Err(strict_encoding::Error::Io(_))
+        ));
+    }
+}
@@ -13,30 +13,59 @@
 // along with this software.
 // If not, see <https://opensource.org/licenses/Apache-2.0>.
 
-use bitcoin::hashes::{sha256, Hmac};
-use bitcoin::secp256k1;
+use bitcoin::blockdata::opcodes::all::{OP_PUSHNUM_1, OP_RETURN};
+use bitcoin::blockdata::script::{Builder, Script};
+use bitcoin::consensus::Encodable;
+use bitcoin::hashes::{sha256, Hash, HashEngine, Hmac};
+use bitcoin::util::psbt;
+use bitcoin::util::psbt::raw::{Key, ProprietaryKey};
+use bitcoin::util::taproot::{TapBranchHash, TapLeafHash, TapTweakHash};
+use bitcoin::{schnorr, secp256k1};
+use bitcoin_scripts::PubkeyScript;
 use commit_verify::EmbedCommitVerify;
 
 use super::{
-    Container, Error, Proof, PubkeyCommitment, PubkeyContainer,
-    ScriptEncodeData,
+    lnpbp1, Container, Error, Proof, ProtocolTag, PubkeyCommitment,
+    PubkeyContainer, ScriptEncodeData,
 };
+use crate::lnpbp1::TweakingFactor;
+use crate::taptree::{ControlBlock, TapTree};
 
+/// Container for taproot-based commitments.
+///
+/// Both the container and [`TaprootCommitment`] support strict encoding and
+/// serde serialization, so they can be persisted between the commitment
+/// construction and the transaction broadcast. The intermediate key is always
+/// encoded as a full 33-byte compressed public key: while taproot outputs
+/// contain only x-only keys, the LNPBP-1 tweak is applied to the full key.
+/// Optional fields are strict-encoded with a single-byte presence prefix.
 #[derive(Clone, PartialEq, Eq, Hash, Debug, Display)]
+#[derive(StrictEncode, StrictDecode)]
+#[cfg_attr(
+    feature = "serde",
+    derive(Serialize, Deserialize),
+    serde(crate = "serde_crate")
+)]
 #[display(Debug)]
 pub struct TaprootContainer {
-    pub script_root: sha256::Hash,
+    /// Merkle root of the script tree, or `None` for the outputs which may be
+    /// spent only with the key path
+    pub script_root: Option<sha256::Hash>,
     pub intermediate_key: secp256k1::PublicKey,
     /// Single SHA256 hash of the protocol-specific tag
-    pub tag: sha256::Hash,
+    pub tag: ProtocolTag,
     /// Tweaking factor stored after [`TaprootCommitment::embed_commit`]
     /// procedure
     pub tweaking_factor: Option<Hmac<sha256::Hash>>,
+    /// Full script tree with the `script_root`, if known, which is required
+    /// for constructing control blocks. The tree is not a part of the
+    /// [`Proof`], which keeps only the root.
+    pub merkle_tree: Option<TapTree>,
 }
 
 impl Container for TaprootContainer {
     /// Out supplement is a protocol-specific tag in its hashed form
-    type Supplement = sha256::Hash;
+    type Supplement = ProtocolTag;
     /// Our proof contains the host, so we don't need host here
     type Host = Option<()>;
 
@@ -45,48 +74,522 @@ impl Container for TaprootContainer {
         supplement: &Self::Supplement,
         _: &Self::Host,
     ) -> Result<Self, Error> {
-        if let ScriptEncodeData::Taproot(ref tapscript_root) = proof.source {
-            Ok(Self {
-                script_root: *tapscript_root,
-                intermediate_key: proof.pubkey,
-                tag: *supplement,
-                tweaking_factor: None,
-            })
-        } else {
-            Err(Error::InvalidProofStructure)
-        }
+        let script_root = match proof.source {
+            ScriptEncodeData::Taproot(script_root) => Some(script_root),
+            ScriptEncodeData::TaprootKeyOnly => None,
+            _ => return Err(Error::InvalidProofStructure),
+        };
+        Ok(Self {
+            script_root,
+            intermediate_key: proof.pubkey,
+            tag: *supplement,
+            tweaking_factor: None,
+            merkle_tree: None,
+        })
     }
 
     fn deconstruct(self) -> (Proof, Self::Supplement) {
         (
-            Proof {
-                pubkey: self.intermediate_key,
-                source: ScriptEncodeData::Taproot(self.script_root),
-            },
+            Proof::from_taproot(self.intermediate_key, self.script_root),
             self.tag,
         )
     }
 
+    fn supplement(&self) -> &Self::Supplement { &self.tag }
+
     fn to_proof(&self) -> Proof {
-        Proof {
-            pubkey: self.intermediate_key,
-            source: ScriptEncodeData::Taproot(self.script_root),
-        }
+        Proof::from_taproot(self.intermediate_key, self.script_root)
     }
 
     fn into_proof(self) -> Proof {
-        Proof {
-            pubkey: self.intermediate_key,
-            source: ScriptEncodeData::Taproot(self.script_root),
+        Proof::from_taproot(self.intermediate_key, self.script_root)
+    }
+}
+
+/// PSBT output key type for the x-only taproot internal key (BIP-371)
+pub const PSBT_OUT_TAP_INTERNAL_KEY: u8 = 0x05;
+/// PSBT output key type for the taproot script tree (BIP-371)
+pub const PSBT_OUT_TAP_TREE: u8 = 0x06;
+/// Prefix of PSBT proprietary keys used by LNPBP standards
+pub const PSBT_LNPBP_PREFIX: &[u8] = b"LNPB";
+/// Subtype of PSBT LNPBP proprietary output key holding LNPBP-1 tweaking
+/// factor applied to the original public key, which is serialized as the key
+/// data
+pub const PSBT_OUT_LNPBP1_TWEAK: u8 = 0x01;
+/// Subtype of PSBT LNPBP proprietary output key holding strict-encoded
+/// LNPBP-2 commitment [`Proof`]; the protocol tag is serialized as the key
+/// data
+pub const PSBT_OUT_LNPBP2_PROOF: u8 = 0x02;
+
+impl TaprootContainer {
+    /// Constructs container from the x-only internal key, as it is provided
+    /// by descriptors, PSBTs and taproot APIs. The key is lifted into a full
+    /// public key with an even y coordinate (see [`lift_xonly_key`]), which
+    /// is also the form in which the key is stored in the [`Proof`]: the
+    /// proof keeps 33-byte serialization with `02` prefix, so it remains
+    /// compatible with the proofs made from full public keys.
+    pub fn with_xonly_key(
+        internal_key: schnorr::PublicKey,
+        script_root: Option<sha256::Hash>,
+        tag: ProtocolTag,
+    ) -> Self {
+        TaprootContainer {
+            script_root,
+            intermediate_key: lift_xonly_key(internal_key),
+            tag,
+            tweaking_factor: None,
+            merkle_tree: None,
+        }
+    }
+
+    /// Constructs container with the protocol tag computed as a single SHA256
+    /// hash of the provided protocol name (see [`ProtocolTag::new`])
+    pub fn with_tag_name(
+        intermediate_key: secp256k1::PublicKey,
+        script_root: Option<sha256::Hash>,
+        tag_name: &str,
+    ) -> Self {
+        TaprootContainer {
+            script_root,
+            intermediate_key,
+            tag: ProtocolTag::new(tag_name),
+            tweaking_factor: None,
+            merkle_tree: None,
         }
     }
+
+    /// Returns x-only form of the intermediate key
+    pub fn xonly_intermediate_key(&self) -> schnorr::PublicKey {
+        to_xonly(&self.intermediate_key)
+    }
+
+    /// Constructs container from the taproot internal key and script tree
+    /// contained in PSBT output `PSBT_OUT_TAP_INTERNAL_KEY` and
+    /// `PSBT_OUT_TAP_TREE` fields. Since the PSBT library does not parse
+    /// taproot fields yet, they are read from the unknown PSBT output keys.
+    pub fn from_psbt_output(
+        output: &psbt::Output,
+        tag: ProtocolTag,
+    ) -> Result<Self, Error> {
+        let internal_key = output
+            .unknown
+            .get(&Key {
+                type_value: PSBT_OUT_TAP_INTERNAL_KEY,
+                key: vec![],
+            })
+            .and_then(|data| schnorr::PublicKey::from_slice(data).ok())
+            .ok_or(Error::PsbtNoTapInternalKey)?;
+        let tap_tree = output
+            .unknown
+            .get(&Key {
+                type_value: PSBT_OUT_TAP_TREE,
+                key: vec![],
+            })
+            .map(|data| TapTree::from_psbt_data(data))
+            .transpose()?;
+
+        Ok(TaprootContainer {
+            script_root: tap_tree.as_ref().map(TapTree::merkle_root),
+            intermediate_key: lift_xonly_key(internal_key),
+            tag,
+            tweaking_factor: None,
+            merkle_tree: tap_tree,
+        })
+    }
+
+    /// Constructs control block for spending the output via script path
+    /// with the leaf at `leaf_index` of the container `merkle_tree`. If the
+    /// commitment was already embedded with
+    /// [`TaprootCommitment::embed_commit`], the intermediate key tweaked with
+    /// the commitment is used as the internal key, producing control block
+    /// for spending the output containing the commitment.
+    ///
+    /// Fails with [`Error::InvalidTapTree`] if the container has no script
+    /// tree or its merkle root does not match `script_root`, and with
+    /// [`Error::TapLeafNotFound`] if the tree has no leaf with such index.
+    pub fn control_block(
+        &self,
+        leaf_index: usize,
+    ) -> Result<ControlBlock, Error> {
+        let tree = self
+            .merkle_tree
+            .as_ref()
+            .filter(|tree| Some(tree.merkle_root()) == self.script_root)
+            .ok_or(Error::InvalidTapTree)?;
+        let leaf =
+            tree.leafs().get(leaf_index).ok_or(Error::TapLeafNotFound)?;
+
+        let mut internal_key = self.intermediate_key;
+        if let Some(tweaking_factor) = self.tweaking_factor {
+            lnpbp1::with_secp(|secp| {
+                internal_key.add_exp_assign(secp, &tweaking_factor[..])
+            })
+            .map_err(|_| lnpbp1::Error::InvalidTweak)?;
+        }
+        let (_, output_key_parity) =
+            bip341_output_key(internal_key, self.script_root)?;
+
+        Ok(ControlBlock {
+            leaf_version: leaf.leaf_version,
+            output_key_parity,
+            internal_key: to_xonly(&internal_key),
+            merkle_branch: tree
+                .merkle_branch(leaf_index)
+                .expect("leaf presence is checked above"),
+        })
+    }
+
+    /// Reconstructs container from the data revealed on-chain by a script
+    /// path spending of the taproot output: the merkle root is computed from
+    /// the `leaf_script` and the merkle branch of the `control_block`, and the
+    /// internal key is taken from the control block.
+    ///
+    /// NB: The internal key revealed in the control block is the key which
+    /// already contains LNPBP-1 commitment, and it is lifted into a full
+    /// public key with an even y coordinate. Thus, the returned container
+    /// must not be used for embedding commitments; it serves as a trusted
+    /// source for cross-checking the proof provided by the prover with
+    /// [`TaprootCommitment::verify_spend`].
+    pub fn reconstruct_from_spend(
+        control_block: &ControlBlock,
+        leaf_script: &Script,
+        tag: ProtocolTag,
+    ) -> Result<Self, Error> {
+        Ok(TaprootContainer {
+            script_root: Some(control_block.merkle_root(leaf_script)),
+            intermediate_key: lift_xonly_key(control_block.internal_key),
+            tag,
+            tweaking_factor: None,
+            merkle_tree: None,
+        })
+    }
+
+    /// Updates PSBT output after the commitment was embedded into the
+    /// container with [`TaprootCommitment::embed_commit`]: replaces the
+    /// taproot internal key with the intermediate key containing LNPBP-1
+    /// commitment and records the tweaking factor under
+    /// [`PSBT_OUT_LNPBP1_TWEAK`] proprietary key, so the signers can adjust
+    /// their secret keys and produce valid key-path signatures.
+    ///
+    /// Fails with [`Error::NoTweakingFactor`] if the commitment was not
+    /// embedded yet.
+    pub fn apply_to_psbt_output(
+        &self,
+        output: &mut psbt::Output,
+    ) -> Result<(), Error> {
+        let tweaking_factor =
+            self.tweaking_factor.ok_or(Error::NoTweakingFactor)?;
+        let mut tweaked_key = self.intermediate_key;
+        lnpbp1::with_secp(|secp| {
+            tweaked_key.add_exp_assign(secp, &tweaking_factor[..])
+        })
+        .map_err(|_| lnpbp1::Error::InvalidTweak)?;
+
+        output.unknown.insert(
+            Key {
+                type_value: PSBT_OUT_TAP_INTERNAL_KEY,
+                key: vec![],
+            },
+            tweaked_key.serialize()[1..].to_vec(),
+        );
+        output.proprietary.insert(
+            ProprietaryKey {
+                prefix: PSBT_LNPBP_PREFIX.to_vec(),
+                subtype: PSBT_OUT_LNPBP1_TWEAK,
+                key: self.intermediate_key.serialize().to_vec(),
+            },
+            tweaking_factor[..].to_vec(),
+        );
+        Ok(())
+    }
 }
 
 #[derive(Clone, PartialEq, Eq, Hash, Debug, Display)]
+#[derive(StrictEncode, StrictDecode)]
+#[cfg_attr(
+    feature = "serde",
+    derive(Serialize, Deserialize),
+    serde(crate = "serde_crate")
+)]
 #[display(Debug)]
 pub struct TaprootCommitment {
-    pub script_root: sha256::Hash,
+    pub script_root: Option<sha256::Hash>,
     pub intermediate_key_commitment: PubkeyCommitment,
+    /// Parity of the y coordinate of the BIP-341 output key, which is lost
+    /// in the x-only form of the key put into the `scriptPubkey`
+    pub parity: Parity,
+}
+
+impl TaprootCommitment {
+    /// Produces BIP-341 output key by applying taproot tweak on top of the
+    /// intermediate key containing LNPBP-1 commitment. Returns the x-only
+    /// output key and parity of its y coordinate.
+    pub fn output_key(&self) -> Result<(schnorr::PublicKey, Parity), Error> {
+        bip341_output_key(*self.intermediate_key_commitment, self.script_root)
+    }
+
+    /// Returns x-only form of the intermediate key containing LNPBP-1
+    /// commitment, which is used as BIP-341 internal key
+    pub fn xonly_intermediate_key(&self) -> schnorr::PublicKey {
+        to_xonly(&self.intermediate_key_commitment)
+    }
+
+    /// Computes adjustment which must be applied to the secret key of the
+    /// original intermediate key in order to produce the secret key for the
+    /// key-path spending of the output, given the LNPBP-1 `tweaking_factor`
+    /// stored in the [`TaprootContainer`] after the commitment.
+    ///
+    /// BIP-340 requires the internal key to have an even y coordinate, so if
+    /// the intermediate key with the LNPBP-1 commitment is odd, its secret
+    /// key has to be negated before adding the taproot tweak.
+    pub fn spend_tweak(
+        &self,
+        tweaking_factor: &TweakingFactor,
+    ) -> Result<SpendTweak, Error> {
+        let negate = self.intermediate_key_commitment.serialize()[0] == 0x03;
+        let mut tweak = secp256k1::SecretKey::from_slice(&tweaking_factor[..])
+            .map_err(|_| lnpbp1::Error::InvalidTweak)?;
+        if negate {
+            tweak.negate_assign();
+        }
+        tweak
+            .add_assign(
+                &tap_tweak(
+                    &self.intermediate_key_commitment.serialize()[1..],
+                    self.script_root,
+                )[..],
+            )
+            .map_err(|_| Error::InvalidTaprootTweak)?;
+        Ok(SpendTweak { negate, tweak })
+    }
+
+    /// Produces witness version 1 `scriptPubkey` (`OP_1 <output_key>`) for
+    /// the commitment
+    pub fn script_pubkey(&self) -> Result<PubkeyScript, Error> {
+        let (output_key, _) = self.output_key()?;
+        Ok(witness_v1_script(&output_key))
+    }
+
+    /// Verifies that witness version 1 `scriptPubkey` contains commitment to
+    /// the message, reconstructing taproot container from the proof and
+    /// repeating both LNPBP-1 and BIP-341 tweaks. Since the output contains
+    /// only x-only key, the output key parity is not taken into account.
+    ///
+    /// Fails with [`Error::InvalidProofStructure`] if the proof is not a
+    /// taproot proof.
+    pub fn verify_script_pubkey(
+        spk: &PubkeyScript,
+        proof: &Proof,
+        tag: &ProtocolTag,
+        msg: &impl AsRef<[u8]>,
+    ) -> Result<bool, Error> {
+        let container = TaprootContainer::reconstruct(proof, tag, &None)?;
+        let chain = combined_tweak(
+            container.intermediate_key,
+            container.script_root,
+            tag,
+            msg,
+        )?;
+        Ok(witness_v1_script(&chain.output_key) == *spk)
+    }
+
+    /// Verifies commitment to the message against the data revealed on-chain
+    /// by a script path spending of the taproot output, without trusting the
+    /// script root from the proof. The container is reconstructed with
+    /// [`TaprootContainer::reconstruct_from_spend`] and cross-checked with the
+    /// commitment produced from the `proof`.
+    ///
+    /// Fails with [`Error::ScriptRootMismatch`] if the proof script root does
+    /// not match the one from the control block, and with
+    /// [`Error::InternalKeyMismatch`] if the intermediate key from the proof
+    /// does not produce the internal key revealed in the control block.
+    pub fn verify_spend(
+        control_block: &ControlBlock,
+        leaf_script: &Script,
+        proof: &Proof,
+        tag: &ProtocolTag,
+        msg: &impl AsRef<[u8]>,
+    ) -> Result<(), Error> {
+        let spent = TaprootContainer::reconstruct_from_spend(
+            control_block,
+            leaf_script,
+            *tag,
+        )?;
+        let mut container = TaprootContainer::reconstruct(proof, tag, &None)?;
+        if container.script_root != spent.script_root {
+            return Err(Error::ScriptRootMismatch);
+        }
+        let commitment = TaprootCommitment::embed_commit(&mut container, msg)?;
+        if commitment.intermediate_key_commitment.serialize()[1..]
+            != spent.intermediate_key.serialize()[1..]
+        {
+            return Err(Error::InternalKeyMismatch);
+        }
+        Ok(())
+    }
+}
+
+/// Adjustment of the secret key required for the key-path spending of the
+/// taproot output with [`TaprootCommitment`], produced by
+/// [`TaprootCommitment::spend_tweak`]
+#[derive(Copy, Clone, PartialEq, Eq, Debug)]
+pub struct SpendTweak {
+    /// Whether the original secret key must be negated before adding the
+    /// tweak
+    pub negate: bool,
+    /// Scalar which must be added to the (possibly negated) original secret
+    /// key
+    pub tweak: secp256k1::SecretKey,
+}
+
+impl SpendTweak {
+    /// Applies the adjustment to the secret key of the original intermediate
+    /// key, producing the secret key for the output key
+    pub fn apply(
+        &self,
+        secret_key: &secp256k1::SecretKey,
+    ) -> Result<secp256k1::SecretKey, Error> {
+        let mut secret_key = *secret_key;
+        if self.negate {
+            secret_key.negate_assign();
+        }
+        secret_key
+            .add_assign(&self.tweak[..])
+            .map_err(|_| Error::InvalidTaprootTweak)?;
+        Ok(secret_key)
+    }
+}
+
+/// Parity of the y coordinate of a taproot output key
+#[derive(Copy, Clone, PartialEq, Eq, Hash, Debug, Display)]
+#[derive(StrictEncode, StrictDecode)]
+#[cfg_attr(
+    feature = "serde",
+    derive(Serialize, Deserialize),
+    serde(crate = "serde_crate")
+)]
+pub enum Parity {
+    /// Even y coordinate
+    #[display("even")]
+    Even,
+
+    /// Odd y coordinate
+    #[display("odd")]
+    Odd,
+}
+
+/// Chain of tweaks producing taproot output key containing LNPBP-1
+/// commitment, as computed by [`combined_tweak`]
+#[derive(Clone, PartialEq, Eq, Debug)]
+pub struct TweakChain {
+    /// LNPBP-1 tweaking factor applied to the original intermediate key
+    pub lnpbp1_tweak: TweakingFactor,
+    /// Intermediate key containing LNPBP-1 commitment, which is used as
+    /// BIP-341 internal key
+    pub intermediate_key: secp256k1::PublicKey,
+    /// BIP-341 tweak applied to the x-only form of the intermediate key
+    /// containing the commitment
+    pub tap_tweak: TapTweakHash,
+    /// X-only taproot output key
+    pub output_key: schnorr::PublicKey,
+    /// Parity of the y coordinate of the output key
+    pub parity: Parity,
+}
+
+/// Produces taproot output key committing to the message, applying both
+/// LNPBP-1 and BIP-341 tweaks to the `internal_key`.
+///
+/// The order of the tweaks is consensus-critical: LNPBP-1 tweak with the
+/// message is applied to the original internal key first, and the resulting
+/// key is used as BIP-341 internal key, which is tweaked with the
+/// `script_root`. Both [`TaprootCommitment::embed_commit`] and the
+/// commitment verification use this function, so the order can't diverge.
+pub fn combined_tweak(
+    internal_key: secp256k1::PublicKey,
+    script_root: Option<sha256::Hash>,
+    tag: &ProtocolTag,
+    msg: &impl AsRef<[u8]>,
+) -> Result<TweakChain, Error> {
+    // ! [CONSENSUS-CRITICAL]: LNPBP-1 tweak goes first
+    let mut pubkey_container = PubkeyContainer {
+        pubkey: internal_key,
+        tag: *tag,
+        tweaking_factor: None,
+    };
+    let cmt = PubkeyCommitment::embed_commit(&mut pubkey_container, msg)?;
+    let lnpbp1_tweak = pubkey_container
+        .tweaking_factor
+        .expect("PubkeyCommitment::embed_commit always sets tweaking factor");
+
+    // ! [CONSENSUS-CRITICAL]: BIP-341 tweak is applied to the key containing
+    //                         LNPBP-1 commitment
+    let (output_key, parity) = bip341_output_key(*cmt, script_root)?;
+
+    Ok(TweakChain {
+        lnpbp1_tweak,
+        intermediate_key: *cmt,
+        tap_tweak: tap_tweak(&cmt.serialize()[1..], script_root),
+        output_key,
+        parity,
+    })
+}
+
+/// Constructs witness version 1 `scriptPubkey` (`OP_1 <output_key>`)
+pub(crate) fn witness_v1_script(
+    output_key: &schnorr::PublicKey,
+) -> PubkeyScript {
+    Builder::new()
+        .push_opcode(OP_PUSHNUM_1)
+        .push_slice(&output_key.serialize())
+        .into_script()
+        .into()
+}
+
+/// Lifts x-only public key into a full public key with an even y
+/// coordinate, as it is defined by BIP-340
+pub fn lift_xonly_key(key: schnorr::PublicKey) -> secp256k1::PublicKey {
+    let mut data = [0x02u8; 33];
+    data[1..].copy_from_slice(&key.serialize());
+    secp256k1::PublicKey::from_slice(&data)
+        .expect("x-only key with even y coordinate is a valid key")
+}
+
+/// Drops y coordinate of the public key
+fn to_xonly(key: &secp256k1::PublicKey) -> schnorr::PublicKey {
+    schnorr::PublicKey::from_slice(&key.serialize()[1..])
+        .expect("x coordinate of a valid public key is a valid x-only key")
+}
+
+/// Applies BIP-341 taproot tweak `t = H_TapTweak(P || merkle_root)` to the
+/// x-only form of the `internal_key` `P`, returning the output key with the
+/// parity of its y coordinate. If there is no script tree (`merkle_root` is
+/// `None`), the key-only tweak `t = H_TapTweak(P)` is used.
+pub fn bip341_output_key(
+    internal_key: secp256k1::PublicKey,
+    merkle_root: Option<sha256::Hash>,
+) -> Result<(schnorr::PublicKey, Parity), Error> {
+    let mut output_key = to_xonly(&internal_key);
+    let tweak = tap_tweak(&output_key.serialize(), merkle_root);
+
+    let odd =
+        lnpbp1::with_secp(|secp| output_key.tweak_add_assign(secp, &tweak[..]))
+            .map_err(|_| Error::InvalidTaprootTweak)?;
+    let parity = if odd { Parity::Odd } else { Parity::Even };
+    Ok((output_key, parity))
+}
+
+/// Computes BIP-341 tagged hash of the x-only internal key and optional
+/// merkle root of the script tree, which is used as the taproot tweak
+fn tap_tweak(
+    internal_key: &[u8],
+    merkle_root: Option<sha256::Hash>,
+) -> TapTweakHash {
+    let mut engine = TapTweakHash::engine();
+    engine.input(internal_key);
+    if let Some(merkle_root) = merkle_root {
+        engine.input(&merkle_root[..]);
+    }
+    TapTweakHash::from_engine(engine)
 }
 
 impl<MSG> EmbedCommitVerify<MSG> for TaprootCommitment
@@ -100,19 +603,1323 @@ where
         container: &mut Self::Container,
         msg: &MSG,
     ) -> Result<Self, Self::Error> {
-        let mut pubkey_container = PubkeyContainer {
-            pubkey: container.intermediate_key,
-            tag: container.tag,
-            tweaking_factor: None,
-        };
-
-        let cmt = PubkeyCommitment::embed_commit(&mut pubkey_container, msg)?;
+        let chain = combined_tweak(
+            container.intermediate_key,
+            container.script_root,
+            &container.tag,
+            msg,
+        )?;
 
-        container.tweaking_factor = pubkey_container.tweaking_factor;
+        container.tweaking_factor = Some(chain.lnpbp1_tweak);
 
         Ok(Self {
             script_root: container.script_root,
-            intermediate_key_commitment: cmt,
+            intermediate_key_commitment: chain.intermediate_key.into(),
+            parity: chain.parity,
+        })
+    }
+}
+
+/// Leaf version of tapscript leafs (BIP-342)
+pub const TAPSCRIPT_LEAF_VERSION: u8 = 0xc0;
+
+/// Computes hash of a tapscript leaf with [`TAPSCRIPT_LEAF_VERSION`]
+pub fn tapleaf_hash(script: &Script) -> TapLeafHash {
+    TapLeafHash::from_inner(tapleaf_hash_ver(TAPSCRIPT_LEAF_VERSION, script))
+}
+
+pub(crate) fn tapleaf_hash_ver(leaf_version: u8, script: &Script) -> [u8; 32] {
+    let mut engine = TapLeafHash::engine();
+    engine.input(&[leaf_version]);
+    script
+        .consensus_encode(&mut engine)
+        .expect("engines don't error");
+    TapLeafHash::from_engine(engine).into_inner()
+}
+
+/// Computes hash of a taproot script tree branch from the hashes of its two
+/// child nodes, which are sorted lexicographically according to BIP-341
+pub fn tapbranch_hash(a: &[u8], b: &[u8]) -> TapBranchHash {
+    let mut engine = TapBranchHash::engine();
+    if a < b {
+        engine.input(a);
+        engine.input(b);
+    } else {
+        engine.input(b);
+        engine.input(a);
+    }
+    TapBranchHash::from_engine(engine)
+}
+
+/// Commitment embedded into a taproot output as a dedicated script tree
+/// leaf, instead of the intermediate key tweak used by
+/// [`TaprootCommitment`]. Unlike key tweak, such commitment can be revealed
+/// to third parties with just a leaf script and its merkle path from the
+/// control block.
+///
+/// The commitment leaf is always added at the top of the tree, as a sibling
+/// to the original script tree root from the [`TaprootContainer`], so the
+/// merkle paths of the original leafs are extended with the hash of the
+/// commitment leaf.
+#[derive(Clone, PartialEq, Eq, Hash, Debug, Display)]
+#[display(Debug)]
+pub struct TapleafCommitment {
+    /// Merkle root of the script tree including the commitment leaf
+    pub script_root: sha256::Hash,
+    /// Leaf script containing the commitment
+    pub leaf_script: Script,
+    /// Merkle path from the commitment leaf to the script tree root
+    pub merkle_path: Vec<TapBranchHash>,
+    /// Internal key of the taproot output, which is not tweaked with the
+    /// commitment
+    pub internal_key: secp256k1::PublicKey,
+}
+
+impl TapleafCommitment {
+    /// Constructs leaf script committing to the message: `OP_RETURN <hash>`,
+    /// where the hash is a single SHA256 of the protocol tag followed by a
+    /// single SHA256 hash of the message
+    pub fn leaf_script(tag: &ProtocolTag, msg: &impl AsRef<[u8]>) -> Script {
+        let mut engine = sha256::Hash::engine();
+        engine.input(tag.as_ref());
+        engine.input(&sha256::Hash::hash(msg.as_ref())[..]);
+        Builder::new()
+            .push_opcode(OP_RETURN)
+            .push_slice(&sha256::Hash::from_engine(engine)[..])
+            .into_script()
+    }
+
+    /// Verifies that the script tree with `script_root` includes the leaf
+    /// committing to the message under the given protocol tag. The
+    /// `merkle_path` is the path from the leaf to the root, as it is given in
+    /// the control block.
+    pub fn verify_inclusion(
+        script_root: sha256::Hash,
+        merkle_path: &[TapBranchHash],
+        tag: &ProtocolTag,
+        msg: &impl AsRef<[u8]>,
+    ) -> bool {
+        let leaf_hash = tapleaf_hash(&Self::leaf_script(tag, msg));
+        let root = merkle_path
+            .iter()
+            .fold(leaf_hash.into_inner(), |node, sibling| {
+                tapbranch_hash(&node, &sibling[..]).into_inner()
+            });
+        root == script_root.into_inner()
+    }
+
+    /// Produces BIP-341 output key for the internal key and the script tree
+    /// including the commitment leaf
+    pub fn output_key(&self) -> Result<(schnorr::PublicKey, Parity), Error> {
+        bip341_output_key(self.internal_key, Some(self.script_root))
+    }
+}
+
+impl<MSG> EmbedCommitVerify<MSG> for TapleafCommitment
+where
+    MSG: AsRef<[u8]>,
+{
+    type Container = TaprootContainer;
+    type Error = Error;
+
+    fn embed_commit(
+        container: &mut Self::Container,
+        msg: &MSG,
+    ) -> Result<Self, Self::Error> {
+        let leaf_script = Self::leaf_script(&container.tag, msg);
+        let leaf_hash = tapleaf_hash(&leaf_script);
+        let (script_root, merkle_path) = match container.script_root {
+            Some(original_root) => (
+                tapbranch_hash(&leaf_hash[..], &original_root[..]).into_inner(),
+                vec![TapBranchHash::from_inner(original_root.into_inner())],
+            ),
+            // Without original script tree the commitment leaf becomes the
+            // only leaf of the tree
+            None => (leaf_hash.into_inner(), vec![]),
+        };
+
+        Ok(Self {
+            script_root: sha256::Hash::from_inner(script_root),
+            leaf_script,
+            merkle_path,
+            internal_key: container.intermediate_key,
         })
     }
 }
+
+/// Known-answer test vectors for taproot commitments, covering both LNPBP-1
+/// and BIP-341 tweaks. The vectors may be used for checking compatibility of
+/// other implementations.
+#[cfg(any(test, feature = "diagnostics"))]
+pub mod test_vectors {
+    use super::Parity;
+
+    /// Name of the protocol tag used by all of the [`TAPROOT_VECTORS`]
+    pub const TAPROOT_VECTORS_TAG: &str = "LNPBP-TAPROOT-TEST";
+
+    /// Test vector for taproot commitment. All keys and hashes are
+    /// hex-encoded.
+    #[derive(Copy, Clone, PartialEq, Eq, Debug)]
+    pub struct TaprootVector {
+        /// X-only internal key, which is lifted to a full key with even y
+        /// coordinate
+        pub internal_key: &'static str,
+        /// Merkle root of the script tree, if any
+        pub script_root: Option<&'static str>,
+        /// Committed message
+        pub message: &'static str,
+        /// Compressed intermediate key containing LNPBP-1 commitment
+        pub intermediate_key: &'static str,
+        /// X-only BIP-341 output key
+        pub output_key: &'static str,
+        /// Parity of the output key y coordinate
+        pub parity: Parity,
+        /// Witness version 1 `scriptPubkey`
+        pub script_pubkey: &'static str,
+    }
+
+    /// Taproot commitment test vectors
+    pub const TAPROOT_VECTORS: [TaprootVector; 5] = [
+        TaprootVector {
+            internal_key: "d6889cb081036e0faefa3a35157ad71086b123b2b144b649798b494c300a961d",
+            script_root: None,
+            message: "message",
+            intermediate_key: "0242be9cb4101419cc7021d2d155a5e3d29ea72b0a75eb84e6913d4580f4a4b980",
+            output_key: "5a1f886d2ed4181c439527688cdba3371fbf713d080271aca51a86c2ec1d280f",
+            parity: Parity::Odd,
+            script_pubkey: "51205a1f886d2ed4181c439527688cdba3371fbf713d080271aca51a86c2ec1d280f",
+        },
+        TaprootVector {
+            internal_key: "187791b6f712a8ea41c8ecdd0ee77fab3e85263b37e1ec18a3651926b3a6cf27",
+            script_root: Some(
+                "5b75adecf53548f3ec6ad7d78383bf84cc57b55a3127c72b9a2481752dd88b21",
+            ),
+            message: "",
+            intermediate_key: "03a3c04fa02895f81d5733dd1b9dbd8a4fd9aad26ef26f338445e784850f1ca0c1",
+            output_key: "fb3dadc7704221331d60c34410dbe94273a34eeb8953ca6a9684e5ee64ca2d18",
+            parity: Parity::Odd,
+            script_pubkey: "5120fb3dadc7704221331d60c34410dbe94273a34eeb8953ca6a9684e5ee64ca2d18",
+        },
+        TaprootVector {
+            internal_key: "93478e9488f956df2396be2ce6c5cced75f900dfa18e7dabd2428aae78451820",
+            script_root: Some(
+                "c525714a7f49c28aedbbba78c005931a81c234b2f6c99a73e4d06082adc8bf2b",
+            ),
+            message: "message",
+            intermediate_key: "03876630ac770f20ce4ecf592cde18ee000f810009d7717094ff6d0e03f080b6df",
+            output_key: "e4a6814524e9ecabf8ade253f7029b7907a6fc1a4ebd7f82f8075f139d7887ea",
+            parity: Parity::Even,
+            script_pubkey: "5120e4a6814524e9ecabf8ade253f7029b7907a6fc1a4ebd7f82f8075f139d7887ea",
+        },
+        TaprootVector {
+            internal_key: "ee4fe085983462a184015d1f782d6a5f8b9c2b60130aff050ce221ecf3786592",
+            script_root: None,
+            message: "",
+            intermediate_key: "0326f03f6c550ca563cb536b48c7af92495ff25bbdeedf067ad8fbd15ff858f3ff",
+            output_key: "5fed832c3f17055d3d95730a19f4903da92b196e5a538fef8543f06b13c6bfa4",
+            parity: Parity::Even,
+            script_pubkey: "51205fed832c3f17055d3d95730a19f4903da92b196e5a538fef8543f06b13c6bfa4",
+        },
+        TaprootVector {
+            internal_key: "f9f400803e683727b14f463836e1e78e1c64417638aa066919291a225f0e8dd8",
+            script_root: Some(
+                "5b75adecf53548f3ec6ad7d78383bf84cc57b55a3127c72b9a2481752dd88b21",
+            ),
+            message: "LNPBP",
+            intermediate_key: "0372c1fbb16d7f9fb09e96c33336d4d79442d80fcc5ecf56e19d0b24a7dbda7697",
+            output_key: "9ab9d176c39d57a9d99d57c046c6033095dc9b9eaa8859e5b6d8b0d08eaff49a",
+            parity: Parity::Even,
+            script_pubkey: "51209ab9d176c39d57a9d99d57c046c6033095dc9b9eaa8859e5b6d8b0d08eaff49a",
+        },
+    ];
+}
+
+#[cfg(test)]
+mod test {
+    use std::str::FromStr;
+
+    use amplify::hex::{FromHex, ToHex};
+    use amplify::Wrapper;
+    use strict_encoding::{StrictDecode, StrictEncode};
+
+    use super::*;
+    use crate::lnpbp1::test_helpers::gen_messages;
+    use crate::taptree::TapLeaf;
+    use crate::SpkCommitment;
+
+    const CONTAINER_HEX: &str = "0115526cd6108b4765640abe555e75f4bd11d9b1453b9db4cd36cf4189577a6f630218845781f631c48f1c9709e23092067d06837f30aa0cd0544ac887fe91ddd166013113300fd425c4880faf45e5d3196a833e7b36ea5222310388f4f0c43f9c5901b5b75893388b288e686279bc3c64b2a1b437d48fbc309619971712cc3894864f01020001c001000001c0010051";
+    const COMMITMENT_HEX: &str = "0115526cd6108b4765640abe555e75f4bd11d9b1453b9db4cd36cf4189577a6f6303db3289f5e13774ef6d579305b6502b316e90d0416b1a5a3e4d8676d94cf0f67d01";
+
+    fn lift_xonly(hex: &str) -> secp256k1::PublicKey {
+        secp256k1::PublicKey::from_str(&format!("02{}", hex)).unwrap()
+    }
+
+    #[test]
+    fn test_bip341_vectors() {
+        // Test vectors from BIP-341 `wallet-test-vectors.json`
+        let vectors = [
+            (
+                "d6889cb081036e0faefa3a35157ad71086b123b2b144b649798b494c300a961d",
+                None,
+                "53a1f6e454df1aa2776a2814a721372d6258050de330b3c6d10ee8f4e0dda343",
+            ),
+            (
+                "187791b6f712a8ea41c8ecdd0ee77fab3e85263b37e1ec18a3651926b3a6cf27",
+                Some("5b75adecf53548f3ec6ad7d78383bf84cc57b55a3127c72b9a2481752dd88b21"),
+                "147c9c57132f6e7ecddba9800bb0c4449251c92a1e60371ee77557b6620f3ea3",
+            ),
+            (
+                "93478e9488f956df2396be2ce6c5cced75f900dfa18e7dabd2428aae78451820",
+                Some("c525714a7f49c28aedbbba78c005931a81c234b2f6c99a73e4d06082adc8bf2b"),
+                "e4d810fd50586274face62b8a807eb9719cef49c04177cc6b76a9a4251d5450e",
+            ),
+        ];
+        for (internal_key, merkle_root, output_key) in &vectors {
+            let merkle_root =
+                merkle_root.map(|root| sha256::Hash::from_str(root).unwrap());
+            let (key, parity) =
+                bip341_output_key(lift_xonly(internal_key), merkle_root)
+                    .unwrap();
+            assert_eq!(key.to_string(), *output_key);
+
+            let mut engine = TapTweakHash::engine();
+            engine.input(&Vec::<u8>::from_hex(internal_key).unwrap());
+            if let Some(merkle_root) = merkle_root {
+                engine.input(&merkle_root[..]);
+            }
+            let tweak = TapTweakHash::from_engine(engine).into_inner();
+            let internal_key =
+                schnorr::PublicKey::from_str(internal_key).unwrap();
+            assert!(crate::lnpbp1::with_secp(|secp| {
+                internal_key.tweak_add_check(
+                    secp,
+                    &key,
+                    parity == Parity::Odd,
+                    tweak,
+                )
+            }));
+        }
+    }
+
+    #[test]
+    fn test_commitment_output_key() {
+        let mut container = TaprootContainer {
+            script_root: Some(sha256::Hash::hash(b"root")),
+            intermediate_key: secp256k1::PublicKey::from_str(
+                "0218845781f631c48f1c9709e23092067d06837f30aa0cd0544ac887fe91ddd166",
+            )
+            .unwrap(),
+            tag: ProtocolTag::new("TEST_TAG"),
+            tweaking_factor: None,
+            merkle_tree: None,
+        };
+        let commitment =
+            TaprootCommitment::embed_commit(&mut container, b"message")
+                .unwrap();
+
+        let (output_key, parity) = commitment.output_key().unwrap();
+        assert_eq!(
+            output_key.to_string(),
+            "dd825247aa2ec9972f48f9faa34c5e3d1c5c4d7f9edc6d6a9f5a20a568831b77"
+        );
+        assert_eq!(parity, Parity::Even);
+        assert_eq!(
+            bip341_output_key(
+                *commitment.intermediate_key_commitment.as_inner(),
+                container.script_root
+            )
+            .unwrap(),
+            (output_key, parity)
+        );
+
+        let script_pubkey = commitment.script_pubkey().unwrap();
+        assert!(script_pubkey.is_witness_program());
+        assert_eq!(script_pubkey[0], OP_PUSHNUM_1.into_u8());
+        assert_eq!(&script_pubkey[2..], &output_key.serialize()[..]);
+
+        // Commitment to a different message changes the output key
+        let other =
+            TaprootCommitment::embed_commit(&mut container, b"other message")
+                .unwrap();
+        assert_ne!(other.output_key().unwrap().0, output_key);
+    }
+
+    fn tap_tree_root(data: &[u8]) -> Result<sha256::Hash, Error> {
+        TapTree::from_psbt_data(data).map(|tree| tree.merkle_root())
+    }
+
+    fn psbt_tap_tree(leafs: &[(u8, &Script)]) -> Vec<u8> {
+        let mut data = vec![];
+        for (depth, script) in leafs {
+            data.push(*depth);
+            data.push(TAPSCRIPT_LEAF_VERSION);
+            script.consensus_encode(&mut data).unwrap();
+        }
+        data
+    }
+
+    #[test]
+    fn test_psbt_tap_tree() {
+        let scripts = (0u8..3)
+            .map(|i| Builder::new().push_int(i as i64).into_script())
+            .collect::<Vec<_>>();
+        let hashes = scripts.iter().map(tapleaf_hash).collect::<Vec<_>>();
+
+        let single = psbt_tap_tree(&[(0, &scripts[0])]);
+        assert_eq!(tap_tree_root(&single).unwrap()[..], hashes[0][..]);
+
+        let root = tapbranch_hash(
+            &hashes[0][..],
+            &tapbranch_hash(&hashes[1][..], &hashes[2][..])[..],
+        );
+        let tree = psbt_tap_tree(&[
+            (1, &scripts[0]),
+            (2, &scripts[1]),
+            (2, &scripts[2]),
+        ]);
+        assert_eq!(tap_tree_root(&tree).unwrap()[..], root[..]);
+        let tree = psbt_tap_tree(&[
+            (2, &scripts[1]),
+            (2, &scripts[2]),
+            (1, &scripts[0]),
+        ]);
+        assert_eq!(tap_tree_root(&tree).unwrap()[..], root[..]);
+
+        for invalid in &[
+            vec![],
+            vec![0u8],
+            psbt_tap_tree(&[(1, &scripts[0])]),
+            psbt_tap_tree(&[(0, &scripts[0]), (0, &scripts[1])]),
+            psbt_tap_tree(&[(1, &scripts[0]), (2, &scripts[1])]),
+            psbt_tap_tree(&[
+                (2, &scripts[0]),
+                (1, &scripts[1]),
+                (2, &scripts[2]),
+            ]),
+            psbt_tap_tree(&[(0, &scripts[0])])[..2].to_vec(),
+        ] {
+            assert_eq!(tap_tree_root(invalid), Err(Error::InvalidTapTree));
+        }
+    }
+
+    #[test]
+    fn test_psbt_output_roundtrip() {
+        let scripts = (0u8..2)
+            .map(|i| Builder::new().push_int(i as i64).into_script())
+            .collect::<Vec<_>>();
+        let internal_key = schnorr::PublicKey::from_str(
+            "18845781f631c48f1c9709e23092067d06837f30aa0cd0544ac887fe91ddd166",
+        )
+        .unwrap();
+        let tag = ProtocolTag::new("TEST_TAG");
+
+        let mut output = psbt::Output::default();
+        assert_eq!(
+            TaprootContainer::from_psbt_output(&output, tag),
+            Err(Error::PsbtNoTapInternalKey)
+        );
+        output.unknown.insert(
+            Key {
+                type_value: PSBT_OUT_TAP_INTERNAL_KEY,
+                key: vec![],
+            },
+            internal_key.serialize().to_vec(),
+        );
+        // Outputs without script tree produce key-only containers
+        let key_only =
+            TaprootContainer::from_psbt_output(&output, tag).unwrap();
+        assert_eq!(key_only.script_root, None);
+        assert_eq!(key_only.merkle_tree, None);
+        output.unknown.insert(
+            Key {
+                type_value: PSBT_OUT_TAP_TREE,
+                key: vec![],
+            },
+            vec![0x01],
+        );
+        assert_eq!(
+            TaprootContainer::from_psbt_output(&output, tag),
+            Err(Error::InvalidTapTree)
+        );
+        output.unknown.insert(
+            Key {
+                type_value: PSBT_OUT_TAP_TREE,
+                key: vec![],
+            },
+            psbt_tap_tree(&[(1, &scripts[0]), (1, &scripts[1])]),
+        );
+
+        let mut container =
+            TaprootContainer::from_psbt_output(&output, tag).unwrap();
+        assert_eq!(
+            container.script_root.unwrap()[..],
+            tapbranch_hash(
+                &tapleaf_hash(&scripts[0])[..],
+                &tapleaf_hash(&scripts[1])[..]
+            )[..]
+        );
+        assert_eq!(
+            &container.intermediate_key.serialize()[1..],
+            &internal_key.serialize()[..]
+        );
+        assert_eq!(
+            container.apply_to_psbt_output(&mut output),
+            Err(Error::NoTweakingFactor)
+        );
+
+        let commitment =
+            TaprootCommitment::embed_commit(&mut container, b"message")
+                .unwrap();
+        container.apply_to_psbt_output(&mut output).unwrap();
+
+        let tweak = output
+            .proprietary
+            .get(&ProprietaryKey {
+                prefix: PSBT_LNPBP_PREFIX.to_vec(),
+                subtype: PSBT_OUT_LNPBP1_TWEAK,
+                key: container.intermediate_key.serialize().to_vec(),
+            })
+            .unwrap();
+        assert_eq!(&tweak[..], &container.tweaking_factor.unwrap()[..]);
+
+        // Output key derived by signers from the updated PSBT matches the
+        // committed one
+        let updated = TaprootContainer::from_psbt_output(&output, tag).unwrap();
+        assert_eq!(updated.script_root, container.script_root);
+        assert_eq!(
+            bip341_output_key(updated.intermediate_key, updated.script_root)
+                .unwrap()
+                .0,
+            commitment.output_key().unwrap().0
+        );
+    }
+
+    #[test]
+    fn test_control_block() {
+        let leafs = [2u8, 2, 1]
+            .iter()
+            .enumerate()
+            .map(|(no, depth)| TapLeaf {
+                depth: *depth,
+                leaf_version: TAPSCRIPT_LEAF_VERSION,
+                script: Builder::new().push_int(no as i64).into_script(),
+            })
+            .collect::<Vec<_>>();
+        let tree = TapTree::with_leafs(leafs.clone()).unwrap();
+        let mut container = TaprootContainer {
+            script_root: Some(tree.merkle_root()),
+            intermediate_key: secp256k1::PublicKey::from_str(
+                "0218845781f631c48f1c9709e23092067d06837f30aa0cd0544ac887fe91ddd166",
+            )
+            .unwrap(),
+            tag: ProtocolTag::new("TEST_TAG"),
+            tweaking_factor: None,
+            merkle_tree: Some(tree),
+        };
+        let proof = container.to_proof();
+        let before = (0..leafs.len())
+            .map(|index| container.control_block(index).unwrap())
+            .collect::<Vec<_>>();
+        assert_eq!(
+            container.control_block(leafs.len()),
+            Err(Error::TapLeafNotFound)
+        );
+
+        let commitment =
+            TaprootCommitment::embed_commit(&mut container, b"message")
+                .unwrap();
+        // The script tree is not a part of the proof
+        assert_eq!(container.to_proof(), proof);
+        let (output_key, parity) = commitment.output_key().unwrap();
+
+        for (index, leaf) in leafs.iter().enumerate() {
+            let after = container.control_block(index).unwrap();
+            assert_eq!(after.leaf_version, before[index].leaf_version);
+            assert_eq!(after.merkle_branch, before[index].merkle_branch);
+            assert_ne!(after.internal_key, before[index].internal_key);
+            assert_eq!(
+                &after.internal_key.serialize()[..],
+                &commitment.intermediate_key_commitment.serialize()[1..]
+            );
+            assert_eq!(after.output_key_parity, parity);
+
+            let root = after.merkle_root(&leaf.script);
+            assert_eq!(Some(root), container.script_root);
+            assert_eq!(
+                bip341_output_key(
+                    *commitment.intermediate_key_commitment,
+                    Some(root)
+                )
+                .unwrap()
+                .0,
+                output_key
+            );
+        }
+
+        container.script_root = Some(sha256::Hash::hash(b"other root"));
+        assert_eq!(container.control_block(0), Err(Error::InvalidTapTree));
+        container.merkle_tree = None;
+        assert_eq!(container.control_block(0), Err(Error::InvalidTapTree));
+    }
+
+    #[test]
+    fn test_reconstruct_from_spend() {
+        let leafs = [1u8, 2, 2]
+            .iter()
+            .enumerate()
+            .map(|(no, depth)| TapLeaf {
+                depth: *depth,
+                leaf_version: TAPSCRIPT_LEAF_VERSION,
+                script: Builder::new().push_int(no as i64).into_script(),
+            })
+            .collect::<Vec<_>>();
+        let tree = TapTree::with_leafs(leafs.clone()).unwrap();
+        let tag = ProtocolTag::new("TEST_TAG");
+        let mut container = TaprootContainer {
+            script_root: Some(tree.merkle_root()),
+            intermediate_key: secp256k1::PublicKey::from_str(
+                "0218845781f631c48f1c9709e23092067d06837f30aa0cd0544ac887fe91ddd166",
+            )
+            .unwrap(),
+            tag,
+            tweaking_factor: None,
+            merkle_tree: Some(tree),
+        };
+        let commitment =
+            TaprootCommitment::embed_commit(&mut container, b"message")
+                .unwrap();
+        let proof = container.to_proof();
+
+        for (index, leaf) in leafs.iter().enumerate() {
+            let data = container.control_block(index).unwrap().serialize();
+            let control_block = ControlBlock::from_slice(&data).unwrap();
+            assert_eq!(control_block, container.control_block(index).unwrap());
+
+            let spent = TaprootContainer::reconstruct_from_spend(
+                &control_block,
+                &leaf.script,
+                tag,
+            )
+            .unwrap();
+            assert_eq!(spent.script_root, container.script_root);
+            assert_eq!(
+                spent.intermediate_key.serialize()[1..],
+                commitment.intermediate_key_commitment.serialize()[1..]
+            );
+            assert_eq!(
+                TaprootCommitment::verify_spend(
+                    &control_block,
+                    &leaf.script,
+                    &proof,
+                    &tag,
+                    b"message"
+                ),
+                Ok(())
+            );
+            assert_eq!(
+                TaprootCommitment::verify_spend(
+                    &control_block,
+                    &leaf.script,
+                    &proof,
+                    &tag,
+                    b"other message"
+                ),
+                Err(Error::InternalKeyMismatch)
+            );
+            assert_eq!(
+                TaprootCommitment::verify_spend(
+                    &control_block,
+                    &leafs[(index + 1) % leafs.len()].script,
+                    &proof,
+                    &tag,
+                    b"message"
+                ),
+                Err(Error::ScriptRootMismatch)
+            );
+        }
+
+        // Prover lies about the internal key
+        let forged = Proof {
+            pubkey: lift_xonly(
+                "d6889cb081036e0faefa3a35157ad71086b123b2b144b649798b494c300a961d",
+            ),
+            source: proof.source.clone(),
+        };
+        let control_block = container.control_block(0).unwrap();
+        assert_eq!(
+            TaprootCommitment::verify_spend(
+                &control_block,
+                &leafs[0].script,
+                &forged,
+                &tag,
+                b"message"
+            ),
+            Err(Error::InternalKeyMismatch)
+        );
+
+        // Control block with forged internal key
+        let mut data = control_block.serialize();
+        data[1..33].copy_from_slice(&forged.pubkey.serialize()[1..]);
+        let control_block = ControlBlock::from_slice(&data).unwrap();
+        assert_eq!(
+            TaprootCommitment::verify_spend(
+                &control_block,
+                &leafs[0].script,
+                &proof,
+                &tag,
+                b"message"
+            ),
+            Err(Error::InternalKeyMismatch)
+        );
+
+        // Malformed control blocks
+        assert_eq!(
+            ControlBlock::from_slice(&data[..32]),
+            Err(Error::InvalidControlBlock)
+        );
+        assert_eq!(
+            ControlBlock::from_slice(&data[..data.len() - 1]),
+            Err(Error::InvalidControlBlock)
+        );
+        data[1..33].copy_from_slice(&[0xFFu8; 32]);
+        assert_eq!(
+            ControlBlock::from_slice(&data),
+            Err(Error::InvalidControlBlock)
+        );
+    }
+
+    #[test]
+    fn test_key_only_commitment() {
+        let tag = ProtocolTag::new("TEST_TAG");
+        let internal_key = lift_xonly(
+            "d6889cb081036e0faefa3a35157ad71086b123b2b144b649798b494c300a961d",
+        );
+        let mut container = TaprootContainer {
+            script_root: None,
+            intermediate_key: internal_key,
+            tag,
+            tweaking_factor: None,
+            merkle_tree: None,
+        };
+        let proof = container.to_proof();
+        assert_eq!(proof.source, ScriptEncodeData::TaprootKeyOnly);
+        assert_eq!(
+            TaprootContainer::reconstruct(&proof, &tag, &None).unwrap(),
+            container
+        );
+
+        let commitment =
+            TaprootCommitment::embed_commit(&mut container, b"message")
+                .unwrap();
+        assert_eq!(commitment.script_root, None);
+        let (output_key, parity) = commitment.output_key().unwrap();
+        assert_eq!(
+            bip341_output_key(*commitment.intermediate_key_commitment, None)
+                .unwrap(),
+            (output_key, parity)
+        );
+        // Key-only tweak differs from the tweak with any script root
+        let mut with_root = container.clone();
+        with_root.script_root = Some(sha256::Hash::hash(b"root"));
+        let other = TaprootCommitment::embed_commit(&mut with_root, b"message")
+            .unwrap();
+        assert_eq!(
+            other.intermediate_key_commitment,
+            commitment.intermediate_key_commitment
+        );
+        assert_ne!(other.output_key().unwrap().0, output_key);
+
+        let spk = commitment.script_pubkey().unwrap();
+        assert!(TaprootCommitment::verify_script_pubkey(
+            &spk, &proof, &tag, b"message"
+        )
+        .unwrap());
+        assert!(!TaprootCommitment::verify_script_pubkey(
+            &spk, &proof, &tag, b"other"
+        )
+        .unwrap());
+        assert!(SpkCommitment::verify_script_pubkey(
+            &spk, &proof, &tag, b"message"
+        )
+        .unwrap());
+
+        // Tapleaf commitment without original script tree becomes the only
+        // leaf of the tree
+        let leaf_commitment =
+            TapleafCommitment::embed_commit(&mut container, b"message")
+                .unwrap();
+        assert_eq!(leaf_commitment.merkle_path, vec![]);
+        assert_eq!(
+            leaf_commitment.script_root[..],
+            tapleaf_hash(&leaf_commitment.leaf_script)[..]
+        );
+        assert!(TapleafCommitment::verify_inclusion(
+            leaf_commitment.script_root,
+            &leaf_commitment.merkle_path,
+            &tag,
+            b"message"
+        ));
+    }
+
+    fn gen_encoding_container() -> TaprootContainer {
+        let leafs = [1u8, 1]
+            .iter()
+            .enumerate()
+            .map(|(no, depth)| TapLeaf {
+                depth: *depth,
+                leaf_version: TAPSCRIPT_LEAF_VERSION,
+                script: Builder::new().push_int(no as i64).into_script(),
+            })
+            .collect();
+        let tree = TapTree::with_leafs(leafs).unwrap();
+        TaprootContainer {
+            script_root: Some(tree.merkle_root()),
+            intermediate_key: secp256k1::PublicKey::from_str(
+                "0218845781f631c48f1c9709e23092067d06837f30aa0cd0544ac887fe91ddd166",
+            )
+            .unwrap(),
+            tag: ProtocolTag::new("TEST_TAG"),
+            tweaking_factor: None,
+            merkle_tree: Some(tree),
+        }
+    }
+
+    #[test]
+    fn test_strict_encoding() {
+        let mut container = gen_encoding_container();
+        let commitment =
+            TaprootCommitment::embed_commit(&mut container, b"message")
+                .unwrap();
+
+        let data = container.strict_serialize().unwrap();
+        assert_eq!(data.to_hex(), CONTAINER_HEX);
+        assert_eq!(
+            TaprootContainer::strict_deserialize(&data).unwrap(),
+            container
+        );
+        let data = commitment.strict_serialize().unwrap();
+        assert_eq!(data.to_hex(), COMMITMENT_HEX);
+        assert_eq!(
+            TaprootCommitment::strict_deserialize(&data).unwrap(),
+            commitment
+        );
+
+        let mut key_only = container.clone();
+        key_only.script_root = None;
+        key_only.merkle_tree = None;
+        key_only.tweaking_factor = None;
+        let data = key_only.strict_serialize().unwrap();
+        assert_eq!(data.len(), 1 + 33 + 32 + 1 + 1);
+        assert_eq!(
+            TaprootContainer::strict_deserialize(&data).unwrap(),
+            key_only
+        );
+    }
+
+    #[test]
+    fn test_strict_encoding_roundtrip() {
+        for msg in gen_messages() {
+            for root in &[true, false] {
+                let mut container = gen_encoding_container();
+                if !root {
+                    container.script_root = None;
+                    container.merkle_tree = None;
+                }
+                let commitment =
+                    TaprootCommitment::embed_commit(&mut container, &msg)
+                        .unwrap();
+
+                let data = container.strict_serialize().unwrap();
+                let decoded = TaprootContainer::strict_deserialize(&data);
+                assert_eq!(decoded.unwrap(), container);
+
+                let data = commitment.strict_serialize().unwrap();
+                let decoded = TaprootCommitment::strict_deserialize(&data);
+                assert_eq!(decoded.unwrap(), commitment);
+
+                let proof = container.to_proof();
+                let data = proof.strict_serialize().unwrap();
+                assert_eq!(Proof::strict_deserialize(&data).unwrap(), proof);
+            }
+        }
+    }
+
+    #[test]
+    fn test_strict_decoding_errors() {
+        let container = gen_encoding_container();
+        let data = container.strict_serialize().unwrap();
+
+        // Truncated script root hash
+        assert!(matches!(
+            TaprootContainer::strict_deserialize(&data[..20]),
+            Err(strict_encoding::Error::Io(_))
+        ));
+        // Intermediate key which is not a valid curve point
+        let mut invalid = data.clone();
+        invalid[1 + 32 + 1..1 + 32 + 33].copy_from_slice(&[0xff; 32]);
+        assert!(matches!(
+            TaprootContainer::strict_deserialize(&invalid),
+            Err(strict_encoding::Error::DataIntegrityError(_))
+        ));
+        // Invalid script tree structure: depth of the first leaf is changed
+        // from 1 to 0
+        let mut invalid = data;
+        let depth_offset = 1 + 32 + 33 + 32 + 1 + 1 + 2;
+        assert_eq!(invalid[depth_offset], 1);
+        invalid[depth_offset] = 0;
+        assert!(matches!(
+            TaprootContainer::strict_deserialize(&invalid),
+            Err(strict_encoding::Error::DataIntegrityError(_))
+        ));
+
+        let mut container = gen_encoding_container();
+        let commitment =
+            TaprootCommitment::embed_commit(&mut container, b"message")
+                .unwrap();
+        let data = commitment.strict_serialize().unwrap();
+        assert!(matches!(
+            TaprootCommitment::strict_deserialize(&data[..data.len() - 1]),
+            Err(strict_encoding::Error::Io(_))
+        ));
+    }
+
+    #[test]
+    #[cfg(feature = "serde")]
+    fn test_serde() {
+        let mut container = gen_encoding_container();
+        let commitment =
+            TaprootCommitment::embed_commit(&mut container, b"message")
+                .unwrap();
+
+        let json = serde_json::to_string(&container).unwrap();
+        assert_eq!(
+            serde_json::from_str::<TaprootContainer>(&json).unwrap(),
+            container
+        );
+        let json = serde_json::to_string(&commitment).unwrap();
+        assert_eq!(
+            serde_json::from_str::<TaprootCommitment>(&json).unwrap(),
+            commitment
+        );
+
+        // Script tree structure is validated on deserialization
+        let mut value = serde_json::to_value(&container).unwrap();
+        value["merkle_tree"].as_array_mut().unwrap().pop();
+        assert!(serde_json::from_value::<TaprootContainer>(value).is_err());
+    }
+
+    #[test]
+    fn test_known_answer_vectors() {
+        use super::test_vectors::*;
+
+        let tag = ProtocolTag::new(TAPROOT_VECTORS_TAG);
+        for vector in &TAPROOT_VECTORS {
+            let mut container = TaprootContainer {
+                script_root: vector
+                    .script_root
+                    .map(|root| sha256::Hash::from_str(root).unwrap()),
+                intermediate_key: lift_xonly(vector.internal_key),
+                tag,
+                tweaking_factor: None,
+                merkle_tree: None,
+            };
+            let commitment = TaprootCommitment::embed_commit(
+                &mut container,
+                &vector.message,
+            )
+            .unwrap();
+            assert_eq!(
+                commitment.intermediate_key_commitment.serialize().to_hex(),
+                vector.intermediate_key
+            );
+            let (output_key, parity) = commitment.output_key().unwrap();
+            assert_eq!(output_key.to_string(), vector.output_key);
+            assert_eq!(parity, vector.parity);
+            assert_eq!(commitment.parity, vector.parity);
+            assert_eq!(
+                commitment.script_pubkey().unwrap().as_bytes().to_hex(),
+                vector.script_pubkey
+            );
+        }
+
+        // Vectors must cover key-only outputs and odd intermediate keys
+        assert!(TAPROOT_VECTORS.iter().any(|v| v.script_root.is_none()));
+        assert!(TAPROOT_VECTORS
+            .iter()
+            .any(|v| v.intermediate_key.starts_with("03")));
+    }
+
+    #[test]
+    fn test_xonly_key() {
+        let tag = ProtocolTag::new("TEST_TAG");
+        for vector in &test_vectors::TAPROOT_VECTORS {
+            let xonly =
+                schnorr::PublicKey::from_str(vector.internal_key).unwrap();
+            let script_root = vector
+                .script_root
+                .map(|root| sha256::Hash::from_str(root).unwrap());
+            let mut xonly_container =
+                TaprootContainer::with_xonly_key(xonly, script_root, tag);
+            let mut full_container = TaprootContainer {
+                script_root,
+                intermediate_key: lift_xonly(vector.internal_key),
+                tag,
+                tweaking_factor: None,
+                merkle_tree: None,
+            };
+            assert_eq!(xonly_container, full_container);
+            assert_eq!(xonly_container.xonly_intermediate_key(), xonly);
+            assert_eq!(xonly_container.to_proof().pubkey.serialize()[0], 0x02);
+
+            let xonly_commitment =
+                TaprootCommitment::embed_commit(&mut xonly_container, &"msg")
+                    .unwrap();
+            let full_commitment =
+                TaprootCommitment::embed_commit(&mut full_container, &"msg")
+                    .unwrap();
+            assert_eq!(xonly_commitment, full_commitment);
+            assert_eq!(xonly_container.to_proof(), full_container.to_proof());
+            assert_eq!(
+                xonly_commitment.xonly_intermediate_key().serialize()[..],
+                xonly_commitment.intermediate_key_commitment.serialize()[1..]
+            );
+        }
+
+        // Lifting always produces a key with even y coordinate
+        let odd = secp256k1::PublicKey::from_str(
+            "03a3c04fa02895f81d5733dd1b9dbd8a4fd9aad26ef26f338445e784850f1ca0c1",
+        )
+        .unwrap();
+        let lifted = lift_xonly_key(to_xonly(&odd));
+        assert_eq!(lifted.serialize()[0], 0x02);
+        assert_eq!(lifted.serialize()[1..], odd.serialize()[1..]);
+    }
+
+    #[test]
+    fn test_combined_tweak() {
+        let tag = ProtocolTag::new("TEST_TAG");
+        let script_root = Some(sha256::Hash::hash(b"script root"));
+        let internal_key = lift_xonly(
+            "d6889cb081036e0faefa3a35157ad71086b123b2b144b649798b494c300a961d",
+        );
+        for root in &[script_root, None] {
+            let chain =
+                combined_tweak(internal_key, *root, &tag, b"message").unwrap();
+
+            let mut container = TaprootContainer {
+                script_root: *root,
+                intermediate_key: internal_key,
+                tag,
+                tweaking_factor: None,
+                merkle_tree: None,
+            };
+            let commitment =
+                TaprootCommitment::embed_commit(&mut container, b"message")
+                    .unwrap();
+            assert_eq!(container.tweaking_factor, Some(chain.lnpbp1_tweak));
+            assert_eq!(
+                *commitment.intermediate_key_commitment,
+                chain.intermediate_key
+            );
+            assert_eq!(
+                commitment.output_key().unwrap(),
+                (chain.output_key, chain.parity)
+            );
+            assert!(lnpbp1::verify_with_factor(
+                chain.intermediate_key,
+                internal_key,
+                &chain.lnpbp1_tweak
+            ));
+            let mut xonly = schnorr::PublicKey::from_slice(
+                &chain.intermediate_key.serialize()[1..],
+            )
+            .unwrap();
+            lnpbp1::with_secp(|secp| {
+                xonly.tweak_add_assign(secp, &chain.tap_tweak[..])
+            })
+            .unwrap();
+            assert_eq!(xonly, chain.output_key);
+
+            let proof = container.to_proof();
+            let spk = commitment.script_pubkey().unwrap();
+            assert!(TaprootCommitment::verify_script_pubkey(
+                &spk, &proof, &tag, b"message"
+            )
+            .unwrap());
+
+            // Applying BIP-341 tweak before LNPBP-1 tweak must produce a
+            // different output key, which does not verify
+            let (tap_first, _) =
+                bip341_output_key(internal_key, *root).unwrap();
+            let mut swapped = PubkeyContainer {
+                pubkey: lift_xonly_key(tap_first),
+                tag,
+                tweaking_factor: None,
+            };
+            let swapped =
+                PubkeyCommitment::embed_commit(&mut swapped, b"message")
+                    .unwrap();
+            let swapped_key =
+                schnorr::PublicKey::from_slice(&swapped.serialize()[1..])
+                    .unwrap();
+            assert_ne!(swapped_key, chain.output_key);
+            assert!(!TaprootCommitment::verify_script_pubkey(
+                &witness_v1_script(&swapped_key),
+                &proof,
+                &tag,
+                b"message"
+            )
+            .unwrap());
+        }
+    }
+
+    #[test]
+    fn test_spend_tweak() {
+        let secp = secp256k1::Secp256k1::new();
+        let msg = secp256k1::Message::from_slice(&[0x55; 32]).unwrap();
+        let mut negated = [false; 2];
+        let mut parities = [false; 2];
+        for i in 1u8..=16 {
+            let secret_key =
+                secp256k1::SecretKey::from_slice(&[i; 32]).unwrap();
+            let mut container = TaprootContainer {
+                script_root: if i % 3 == 0 {
+                    None
+                } else {
+                    Some(sha256::Hash::hash(&[i]))
+                },
+                intermediate_key: secp256k1::PublicKey::from_secret_key(
+                    &secp,
+                    &secret_key,
+                ),
+                tag: ProtocolTag::new("TEST_TAG"),
+                tweaking_factor: None,
+                merkle_tree: None,
+            };
+            let commitment =
+                TaprootCommitment::embed_commit(&mut container, b"message")
+                    .unwrap();
+            let (output_key, parity) = commitment.output_key().unwrap();
+            assert_eq!(commitment.parity, parity);
+            parities[(parity == Parity::Odd) as usize] = true;
+
+            let spend_tweak = commitment
+                .spend_tweak(&container.tweaking_factor.unwrap())
+                .unwrap();
+            negated[spend_tweak.negate as usize] = true;
+
+            let output_secret = spend_tweak.apply(&secret_key).unwrap();
+            let keypair = secp256k1::schnorrsig::KeyPair::from_secret_key(
+                &secp,
+                output_secret,
+            );
+            assert_eq!(
+                secp256k1::schnorrsig::PublicKey::from_keypair(&secp, &keypair),
+                output_key
+            );
+            let sig = secp.schnorrsig_sign_no_aux_rand(&msg, &keypair);
+            secp.schnorrsig_verify(&sig, &msg, &output_key).unwrap();
+
+            let data = commitment.strict_serialize().unwrap();
+            assert_eq!(
+                TaprootCommitment::strict_deserialize(&data).unwrap(),
+                commitment
+            );
+        }
+        assert_eq!(negated, [true, true]);
+        assert_eq!(parities, [true, true]);
+    }
+
+    /// Builds a balanced script tree of the given depth, returning its root
+    /// and the leaf scripts with their merkle paths
+    fn gen_tree(
+        depth: u32,
+    ) -> (sha256::Hash, Vec<(Script, Vec<TapBranchHash>)>) {
+        let mut leafs = (0..2u8.pow(depth))
+            .map(|no| {
+                let script = Builder::new().push_int(no as i64).into_script();
+                (script, vec![])
+            })
+            .collect::<Vec<(Script, Vec<TapBranchHash>)>>();
+        let mut nodes = leafs
+            .iter()
+            .map(|(script, _)| tapleaf_hash(script).into_inner())
+            .collect::<Vec<_>>();
+        while nodes.len() > 1 {
+            let level_size = leafs.len() / nodes.len();
+            for (no, (_, path)) in leafs.iter_mut().enumerate() {
+                let sibling = nodes[(no / level_size) ^ 1];
+                path.push(TapBranchHash::from_inner(sibling));
+            }
+            nodes = nodes
+                .chunks(2)
+                .map(|pair| tapbranch_hash(&pair[0], &pair[1]).into_inner())
+                .collect();
+        }
+        (sha256::Hash::from_inner(nodes[0]), leafs)
+    }
+
+    fn merkle_root(script: &Script, path: &[TapBranchHash]) -> [u8; 32] {
+        path.iter()
+            .fold(tapleaf_hash(script).into_inner(), |node, sibling| {
+                tapbranch_hash(&node, &sibling[..]).into_inner()
+            })
+    }
+
+    #[test]
+    fn test_tapleaf_hash() {
+        // Single-leaf script tree from BIP-341 `wallet-test-vectors.json`
+        let script = Script::from(
+            Vec::<u8>::from_hex(
+                "20d85a959b0290bf19bb89ed43c916be835475d013da4b362117393e25a48229b8ac",
+            )
+            .unwrap(),
+        );
+        assert_eq!(
+            tapleaf_hash(&script).into_inner(),
+            sha256::Hash::from_str(
+                "5b75adecf53548f3ec6ad7d78383bf84cc57b55a3127c72b9a2481752dd88b21"
+            )
+            .unwrap()
+            .into_inner()
+        );
+    }
+
+    #[test]
+    fn test_tapleaf_commitment() {
+        let tag = ProtocolTag::new("TEST_TAG");
+        let internal_key = lift_xonly(
+            "187791b6f712a8ea41c8ecdd0ee77fab3e85263b37e1ec18a3651926b3a6cf27",
+        );
+        for depth in &[0, 1, 3] {
+            let (script_root, leafs) = gen_tree(*depth);
+            for (script, path) in &leafs {
+                assert_eq!(merkle_root(script, path), script_root.into_inner());
+            }
+
+            let mut container = TaprootContainer {
+                script_root: Some(script_root),
+                intermediate_key: internal_key,
+                tag,
+                tweaking_factor: None,
+                merkle_tree: None,
+            };
+            let commitment =
+                TapleafCommitment::embed_commit(&mut container, b"message")
+                    .unwrap();
+            assert_ne!(commitment.script_root, script_root);
+            assert_eq!(commitment.internal_key, internal_key);
+            assert!(commitment.verify(&container, b"message").unwrap());
+            assert!(!commitment.verify(&container, b"other").unwrap());
+
+            assert!(TapleafCommitment::verify_inclusion(
+                commitment.script_root,
+                &commitment.merkle_path,
+                &tag,
+                b"message"
+            ));
+            assert!(!TapleafCommitment::verify_inclusion(
+                commitment.script_root,
+                &commitment.merkle_path,
+                &tag,
+                b"other"
+            ));
+            assert!(!TapleafCommitment::verify_inclusion(
+                commitment.script_root,
+                &commitment.merkle_path,
+                &ProtocolTag::new("OTHER_TAG"),
+                b"message"
+            ));
+            assert!(!TapleafCommitment::verify_inclusion(
+                script_root,
+                &commitment.merkle_path,
+                &tag,
+                b"message"
+            ));
+
+            // Original leafs remain spendable with the merkle paths extended
+            // by the commitment leaf hash
+            let leaf_hash = TapBranchHash::from_inner(
+                tapleaf_hash(&commitment.leaf_script).into_inner(),
+            );
+            for (script, path) in &leafs {
+                let mut path = path.clone();
+                path.push(leaf_hash);
+                assert_eq!(
+                    merkle_root(script, &path),
+                    commitment.script_root.into_inner()
+                );
+            }
+
+            assert_eq!(
+                commitment.output_key().unwrap(),
+                bip341_output_key(internal_key, Some(commitment.script_root))
+                    .unwrap()
+            );
+        }
+    }
+
+    #[test]
+    fn test_verify_script_pubkey() {
+        let tag = ProtocolTag::new("TEST_TAG");
+        let mut container = TaprootContainer {
+            script_root: Some(sha256::Hash::hash(b"root")),
+            intermediate_key: lift_xonly(
+                "187791b6f712a8ea41c8ecdd0ee77fab3e85263b37e1ec18a3651926b3a6cf27",
+            ),
+            tag,
+            tweaking_factor: None,
+            merkle_tree: None,
+        };
+        let spk = TaprootCommitment::embed_commit(&mut container, b"message")
+            .unwrap()
+            .script_pubkey()
+            .unwrap();
+        let proof = container.to_proof();
+        let wrong_root = Proof {
+            pubkey: proof.pubkey,
+            source: ScriptEncodeData::Taproot(sha256::Hash::hash(b"other")),
+        };
+
+        assert!(TaprootCommitment::verify_script_pubkey(
+            &spk, &proof, &tag, b"message"
+        )
+        .unwrap());
+        assert!(!TaprootCommitment::verify_script_pubkey(
+            &spk,
+            &wrong_root,
+            &tag,
+            b"message"
+        )
+        .unwrap());
+        assert!(!TaprootCommitment::verify_script_pubkey(
+            &spk, &proof, &tag, b"other"
+        )
+        .unwrap());
+        assert_eq!(
+            TaprootCommitment::verify_script_pubkey(
+                &spk,
+                &Proof::from(proof.pubkey),
+                &tag,
+                b"message"
+            ),
+            Err(Error::InvalidProofStructure)
+        );
+
+        assert!(SpkCommitment::verify_script_pubkey(
+            &spk, &proof, &tag, b"message"
+        )
+        .unwrap());
+        assert!(!SpkCommitment::verify_script_pubkey(
+            &spk,
+            &wrong_root,
+            &tag,
+            b"message"
+        )
+        .unwrap());
+        assert!(!SpkCommitment::verify_script_pubkey(
+            &spk, &proof, &tag, b"other"
+        )
+        .unwrap());
+    }
+}
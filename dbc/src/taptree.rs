@@ -0,0 +1,434 @@
+// BP Core Library implementing LNP/BP specifications & standards related to
+// bitcoin protocol
+//
+// Written in 2020-2021 by
+//     Dr. Maxim Orlovsky <orlovsky@pandoracore.com>
+//
+// To the extent possible under law, the author(s) have dedicated all
+// copyright and related and neighboring rights to this software to
+// the public domain worldwide. This software is distributed without
+// any warranty.
+//
+// You should have received a copy of the Apache 2.0 License
+// along with this software.
+// If not, see <https://opensource.org/licenses/Apache-2.0>.
+
+//! Taproot script trees and control blocks (BIP-341), which are required for
+//! spending taproot outputs with commitments via script path.
+
+use core::convert::TryFrom;
+use std::io;
+
+use bitcoin::blockdata::script::Script;
+use bitcoin::consensus::{Decodable, Encodable};
+use bitcoin::hashes::{sha256, Hash};
+use bitcoin::schnorr;
+use bitcoin::util::taproot::{TapBranchHash, TapLeafHash};
+use strict_encoding::{StrictDecode, StrictEncode};
+
+use super::taproot::{tapbranch_hash, tapleaf_hash_ver, Parity};
+use super::Error;
+
+/// Maximal depth of a taproot script tree leaf (BIP-341)
+pub const TAPROOT_MAX_DEPTH: u8 = 128;
+
+/// Leaf of a taproot script tree
+#[derive(Clone, PartialEq, Eq, Hash, Debug)]
+#[derive(StrictEncode, StrictDecode)]
+#[cfg_attr(
+    feature = "serde",
+    derive(Serialize, Deserialize),
+    serde(crate = "serde_crate")
+)]
+pub struct TapLeaf {
+    /// Depth of the leaf in the tree; zero for a tree consisting of a single
+    /// leaf
+    pub depth: u8,
+    /// Leaf version, which is
+    /// [`TAPSCRIPT_LEAF_VERSION`](super::taproot::TAPSCRIPT_LEAF_VERSION)
+    /// for tapscript leafs
+    pub leaf_version: u8,
+    /// Leaf script
+    pub script: Script,
+}
+
+impl TapLeaf {
+    /// Computes hash of the leaf
+    #[inline]
+    pub fn leaf_hash(&self) -> TapLeafHash {
+        TapLeafHash::from_inner(tapleaf_hash_ver(
+            self.leaf_version,
+            &self.script,
+        ))
+    }
+}
+
+/// Taproot script tree represented by a list of its leafs with their depths
+/// in depth-first search order, like in BIP-371 `PSBT_OUT_TAP_TREE` field
+#[derive(Clone, PartialEq, Eq, Hash, Debug)]
+#[cfg_attr(
+    feature = "serde",
+    derive(Serialize, Deserialize),
+    serde(
+        crate = "serde_crate",
+        try_from = "Vec<TapLeaf>",
+        into = "Vec<TapLeaf>"
+    )
+)]
+pub struct TapTree(Vec<TapLeaf>);
+
+impl TryFrom<Vec<TapLeaf>> for TapTree {
+    type Error = Error;
+
+    #[inline]
+    fn try_from(leafs: Vec<TapLeaf>) -> Result<Self, Self::Error> {
+        TapTree::with_leafs(leafs)
+    }
+}
+
+impl From<TapTree> for Vec<TapLeaf> {
+    #[inline]
+    fn from(tree: TapTree) -> Self { tree.0 }
+}
+
+impl StrictEncode for TapTree {
+    #[inline]
+    fn strict_encode<E: io::Write>(
+        &self,
+        e: E,
+    ) -> Result<usize, strict_encoding::Error> {
+        self.0.strict_encode(e)
+    }
+}
+
+impl StrictDecode for TapTree {
+    fn strict_decode<D: io::Read>(
+        d: D,
+    ) -> Result<Self, strict_encoding::Error> {
+        TapTree::with_leafs(Vec::strict_decode(d)?).map_err(|_| {
+            strict_encoding::Error::DataIntegrityError(s!("invalid taproot \
+                                                           script tree \
+                                                           structure"))
+        })
+    }
+}
+
+impl TapTree {
+    /// Constructs script tree from the list of leafs in depth-first search
+    /// order. Fails with [`Error::InvalidTapTree`] if the leafs do not form a
+    /// complete binary tree.
+    pub fn with_leafs(leafs: Vec<TapLeaf>) -> Result<Self, Error> {
+        Self::walk(&leafs, None)?;
+        Ok(TapTree(leafs))
+    }
+
+    /// Parses script tree serialized according to BIP-371 `PSBT_OUT_TAP_TREE`
+    /// format: a sequence of leafs, each consisting of its depth, leaf version
+    /// and script
+    pub fn from_psbt_data(data: &[u8]) -> Result<Self, Error> {
+        let mut cursor = data;
+        let mut leafs = vec![];
+        while !cursor.is_empty() {
+            let depth = cursor[0];
+            let leaf_version = *cursor.get(1).ok_or(Error::InvalidTapTree)?;
+            cursor = &cursor[2..];
+            let script = Script::consensus_decode(&mut cursor)
+                .map_err(|_| Error::InvalidTapTree)?;
+            leafs.push(TapLeaf {
+                depth,
+                leaf_version,
+                script,
+            });
+        }
+        Self::with_leafs(leafs)
+    }
+
+    /// Serializes script tree according to BIP-371 `PSBT_OUT_TAP_TREE`
+    /// format
+    pub fn to_psbt_data(&self) -> Vec<u8> {
+        let mut data = vec![];
+        for leaf in &self.0 {
+            data.push(leaf.depth);
+            data.push(leaf.leaf_version);
+            leaf.script
+                .consensus_encode(&mut data)
+                .expect("in-memory encoders don't error");
+        }
+        data
+    }
+
+    /// Returns leafs of the tree in depth-first search order
+    #[inline]
+    pub fn leafs(&self) -> &[TapLeaf] { &self.0 }
+
+    /// Computes merkle root of the tree
+    pub fn merkle_root(&self) -> sha256::Hash {
+        Self::walk(&self.0, None)
+            .expect("tree structure is checked during construction")
+            .0
+    }
+
+    /// Computes merkle branch for the leaf with a given index, ordered from
+    /// the leaf to the root, as it is put into the control block. Returns
+    /// `None` if there is no leaf with such index.
+    pub fn merkle_branch(
+        &self,
+        leaf_index: usize,
+    ) -> Option<Vec<TapBranchHash>> {
+        if leaf_index >= self.0.len() {
+            return None;
+        }
+        Some(
+            Self::walk(&self.0, Some(leaf_index))
+                .expect("tree structure is checked during construction")
+                .1,
+        )
+    }
+
+    /// Walks the leafs in depth-first search order, computing merkle root and
+    /// collecting merkle branch for the `target` leaf
+    fn walk(
+        leafs: &[TapLeaf],
+        target: Option<usize>,
+    ) -> Result<(sha256::Hash, Vec<TapBranchHash>), Error> {
+        struct Node {
+            depth: u8,
+            hash: [u8; 32],
+            has_target: bool,
+        }
+
+        let mut branch = vec![];
+        // Nodes which siblings are not processed yet
+        let mut stack: Vec<Node> = vec![];
+        for (index, leaf) in leafs.iter().enumerate() {
+            if leaf.depth > TAPROOT_MAX_DEPTH || leaf.leaf_version & 0x01 != 0 {
+                return Err(Error::InvalidTapTree);
+            }
+
+            let mut node = Node {
+                depth: leaf.depth,
+                hash: leaf.leaf_hash().into_inner(),
+                has_target: target == Some(index),
+            };
+            while let Some(sibling) = stack.last() {
+                if sibling.depth != node.depth {
+                    break;
+                }
+                if node.depth == 0 {
+                    return Err(Error::InvalidTapTree);
+                }
+                let sibling = stack.pop().expect("stack is not empty");
+                if node.has_target {
+                    branch.push(TapBranchHash::from_inner(sibling.hash));
+                } else if sibling.has_target {
+                    branch.push(TapBranchHash::from_inner(node.hash));
+                }
+                node = Node {
+                    depth: node.depth - 1,
+                    hash: tapbranch_hash(&sibling.hash, &node.hash)
+                        .into_inner(),
+                    has_target: node.has_target || sibling.has_target,
+                };
+            }
+            if stack.last().map(|sibling| sibling.depth > node.depth)
+                == Some(true)
+            {
+                return Err(Error::InvalidTapTree);
+            }
+            stack.push(node);
+        }
+
+        match stack.as_slice() {
+            [Node { depth: 0, hash, .. }] => {
+                Ok((sha256::Hash::from_inner(*hash), branch))
+            }
+            _ => Err(Error::InvalidTapTree),
+        }
+    }
+}
+
+/// Control block for spending taproot output via script path (BIP-341)
+#[derive(Clone, PartialEq, Eq, Debug)]
+pub struct ControlBlock {
+    /// Version of the spent leaf
+    pub leaf_version: u8,
+    /// Parity of the y coordinate of the output key
+    pub output_key_parity: Parity,
+    /// Taproot internal key
+    pub internal_key: schnorr::PublicKey,
+    /// Merkle branch of the spent leaf, ordered from the leaf to the root
+    pub merkle_branch: Vec<TapBranchHash>,
+}
+
+impl ControlBlock {
+    /// Serializes control block for putting it into the witness
+    pub fn serialize(&self) -> Vec<u8> {
+        let mut data = Vec::with_capacity(33 + 32 * self.merkle_branch.len());
+        data.push(
+            self.leaf_version | (self.output_key_parity == Parity::Odd) as u8,
+        );
+        data.extend(&self.internal_key.serialize());
+        for hash in &self.merkle_branch {
+            data.extend(&hash[..]);
+        }
+        data
+    }
+
+    /// Parses control block from its witness serialization. Fails with
+    /// [`Error::InvalidControlBlock`] if the data length does not match a
+    /// merkle branch of at most [`TAPROOT_MAX_DEPTH`] hashes, or if the data
+    /// do not contain a valid x-only internal key.
+    pub fn from_slice(data: &[u8]) -> Result<Self, Error> {
+        if data.len() < 33 || data.len() > 33 + 32 * TAPROOT_MAX_DEPTH as usize
+        {
+            return Err(Error::InvalidControlBlock);
+        }
+        let internal_key = schnorr::PublicKey::from_slice(&data[1..33])
+            .map_err(|_| Error::InvalidControlBlock)?;
+        let chunks = data[33..].chunks_exact(32);
+        if !chunks.remainder().is_empty() {
+            return Err(Error::InvalidControlBlock);
+        }
+        let merkle_branch = chunks
+            .map(|chunk| {
+                TapBranchHash::from_slice(chunk)
+                    .expect("chunk length is always 32 bytes")
+            })
+            .collect();
+        Ok(ControlBlock {
+            leaf_version: data[0] & 0xfe,
+            output_key_parity: if data[0] & 0x01 == 0x01 {
+                Parity::Odd
+            } else {
+                Parity::Even
+            },
+            internal_key,
+            merkle_branch,
+        })
+    }
+
+    /// Computes merkle root of the script tree from the leaf script and the
+    /// merkle branch of the control block
+    pub fn merkle_root(&self, leaf_script: &Script) -> sha256::Hash {
+        let leaf_hash = tapleaf_hash_ver(self.leaf_version, leaf_script);
+        let root =
+            self.merkle_branch.iter().fold(leaf_hash, |node, sibling| {
+                tapbranch_hash(&node, &sibling[..]).into_inner()
+            });
+        sha256::Hash::from_inner(root)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::str::FromStr;
+
+    use bitcoin::blockdata::script::Builder;
+
+    use super::*;
+    use crate::taproot::{tapleaf_hash, TAPSCRIPT_LEAF_VERSION};
+
+    fn gen_leafs(depths: &[u8]) -> Vec<TapLeaf> {
+        depths
+            .iter()
+            .enumerate()
+            .map(|(no, depth)| TapLeaf {
+                depth: *depth,
+                leaf_version: TAPSCRIPT_LEAF_VERSION,
+                script: Builder::new().push_int(no as i64).into_script(),
+            })
+            .collect()
+    }
+
+    #[test]
+    fn test_tree_root() {
+        let leafs = gen_leafs(&[0]);
+        let hashes = leafs
+            .iter()
+            .map(|leaf| tapleaf_hash(&leaf.script))
+            .collect::<Vec<_>>();
+        let tree = TapTree::with_leafs(leafs).unwrap();
+        assert_eq!(tree.merkle_root()[..], hashes[0][..]);
+        assert_eq!(tree.merkle_branch(0), Some(vec![]));
+        assert_eq!(tree.merkle_branch(1), None);
+
+        for depths in &[[1, 2, 2], [2, 2, 1]] {
+            let leafs = gen_leafs(&depths[..]);
+            let hashes = leafs
+                .iter()
+                .map(|leaf| tapleaf_hash(&leaf.script))
+                .collect::<Vec<_>>();
+            let tree = TapTree::with_leafs(leafs).unwrap();
+            let (single, pair) = if depths[0] == 1 {
+                (0, (1, 2))
+            } else {
+                (2, (0, 1))
+            };
+            let pair_hash =
+                tapbranch_hash(&hashes[pair.0][..], &hashes[pair.1][..]);
+            let root = tapbranch_hash(&hashes[single][..], &pair_hash[..]);
+            assert_eq!(tree.merkle_root()[..], root[..]);
+            assert_eq!(tree.merkle_branch(single), Some(vec![pair_hash]));
+            assert_eq!(
+                tree.merkle_branch(pair.0),
+                Some(vec![
+                    TapBranchHash::from_inner(hashes[pair.1].into_inner()),
+                    TapBranchHash::from_inner(hashes[single].into_inner())
+                ])
+            );
+        }
+    }
+
+    #[test]
+    fn test_invalid_tree() {
+        for depths in
+            &[&[][..], &[1], &[0, 0], &[1, 2], &[2, 1, 2], &[129, 129]]
+        {
+            assert_eq!(
+                TapTree::with_leafs(gen_leafs(depths)),
+                Err(Error::InvalidTapTree)
+            );
+        }
+        let mut leafs = gen_leafs(&[0]);
+        leafs[0].leaf_version = 0xc1;
+        assert_eq!(TapTree::with_leafs(leafs), Err(Error::InvalidTapTree));
+    }
+
+    #[test]
+    fn test_psbt_data() {
+        let tree = TapTree::with_leafs(gen_leafs(&[2, 2, 2, 3, 3])).unwrap();
+        let data = tree.to_psbt_data();
+        assert_eq!(TapTree::from_psbt_data(&data), Ok(tree));
+        assert_eq!(
+            TapTree::from_psbt_data(&data[..1]),
+            Err(Error::InvalidTapTree)
+        );
+        assert_eq!(
+            TapTree::from_psbt_data(&data[..data.len() - 1]),
+            Err(Error::InvalidTapTree)
+        );
+    }
+
+    #[test]
+    fn test_control_block_root() {
+        let tree = TapTree::with_leafs(gen_leafs(&[2, 2, 2, 3, 3])).unwrap();
+        for (index, leaf) in tree.leafs().iter().enumerate() {
+            let control_block = ControlBlock {
+                leaf_version: leaf.leaf_version,
+                output_key_parity: Parity::Odd,
+                internal_key: schnorr::PublicKey::from_str(
+                    "18845781f631c48f1c9709e23092067d06837f30aa0cd0544ac887fe91ddd166",
+                )
+                .unwrap(),
+                merkle_branch: tree.merkle_branch(index).unwrap(),
+            };
+            assert_eq!(
+                control_block.merkle_root(&leaf.script),
+                tree.merkle_root()
+            );
+            let data = control_block.serialize();
+            assert_eq!(data.len(), 33 + 32 * control_block.merkle_branch.len());
+            assert_eq!(data[0], 0xc1);
+        }
+    }
+}
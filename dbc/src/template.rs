@@ -0,0 +1,481 @@
+// BP Core Library implementing LNP/BP specifications & standards related to
+// bitcoin protocol
+//
+// Written in 2020-2021 by
+//     Dr. Maxim Orlovsky <orlovsky@pandoracore.com>
+//
+// To the extent possible under law, the author(s) have dedicated all
+// copyright and related and neighboring rights to this software to
+// the public domain worldwide. This software is distributed without
+// any warranty.
+//
+// You should have received a copy of the Apache 2.0 License
+// along with this software.
+// If not, see <https://opensource.org/licenses/Apache-2.0>.
+
+//! Builder of transactions containing LNPBP-3 commitment, which places the
+//! commitment output according to the final transaction fee.
+
+use std::collections::BTreeSet;
+
+use amplify::Wrapper;
+use bitcoin::{secp256k1, Transaction, TxIn, TxOut};
+
+use crate::lnpbp1::TweakingFactor;
+use crate::{
+    lnpbp3, Container, Error, Proof, ProtocolTag, ScriptEncodeData,
+    ScriptEncodeMethod, TxoutCommitment, TxoutContainer,
+};
+
+/// Template of a transaction committing to a message according to LNPBP-3.
+///
+/// The commitment output receives the change: the value of the inputs left
+/// after paying the recipient outputs and the fee, so it can't use
+/// [`ScriptEncodeMethod::OpReturn`] method. Since the fee defines the
+/// position of the commitment output (see
+/// [`lnpbp3::commitment_output_index`]) while the transaction size (and, thus,
+/// the fee) depends on the commitment output, [`TxTemplate::finalize`]
+/// iterates the fee computation until it reaches a fixed point.
+///
+/// Positions of the transaction outputs may be reserved with
+/// [`TxTemplate::avoid_commitment_on`], for instance for the wallet change
+/// output, which must not be tweaked. If LNPBP-3 designates a reserved
+/// position to the commitment, the fee is increased by the minimal number of
+/// satoshis moving the commitment to the next unreserved position, and the
+/// increase is reported in [`FinalizedTx::fee_adjustment`].
+#[derive(Clone, PartialEq, Eq, Debug)]
+pub struct TxTemplate<MSG> {
+    /// Transaction version
+    pub version: i32,
+    /// Transaction lock time
+    pub lock_time: u32,
+    /// Transaction inputs with the values of the spent outputs and the
+    /// weight of their satisfaction (see [`TxTemplate::add_input`])
+    pub inputs: Vec<(TxIn, u64, usize)>,
+    /// Recipient outputs, which are kept in the same order in the final
+    /// transaction
+    pub outputs: Vec<TxOut>,
+    /// Fee rate in satoshis per virtual byte
+    pub fee_rate: u64,
+    /// Positions of the final transaction outputs which must not contain the
+    /// commitment
+    pub reserved: BTreeSet<usize>,
+    /// Protocol factor used in LNPBP-3 output selection
+    pub protocol_factor: u32,
+    /// Container for the commitment output
+    pub container: TxoutContainer,
+    /// Message to commit to
+    pub msg: MSG,
+}
+
+impl<MSG> TxTemplate<MSG>
+where
+    MSG: AsRef<[u8]>,
+{
+    /// Constructs template of version 2 transaction with no inputs and no
+    /// recipient outputs, committing to `msg` with fee rate of 1 satoshi per
+    /// virtual byte
+    pub fn new(
+        protocol_tag: &ProtocolTag,
+        protocol_factor: u32,
+        pubkey: secp256k1::PublicKey,
+        source: ScriptEncodeData,
+        method: ScriptEncodeMethod,
+        msg: MSG,
+    ) -> Self {
+        TxTemplate {
+            version: 2,
+            lock_time: 0,
+            inputs: vec![],
+            outputs: vec![],
+            fee_rate: 1,
+            reserved: empty!(),
+            protocol_factor,
+            container: TxoutContainer::construct(
+                protocol_tag,
+                0,
+                pubkey,
+                source,
+                method,
+            ),
+            msg,
+        }
+    }
+
+    /// Adds input spending output of the given `value`. Since the input is
+    /// not signed yet, `satisfaction_weight` must provide weight (in weight
+    /// units) of its future signature script and witness, including segwit
+    /// marker and flag, for the fee estimation.
+    pub fn add_input(
+        &mut self,
+        txin: TxIn,
+        value: u64,
+        satisfaction_weight: usize,
+    ) -> &mut Self {
+        self.inputs.push((txin, value, satisfaction_weight));
+        self
+    }
+
+    /// Adds recipient output
+    pub fn add_output(&mut self, txout: TxOut) -> &mut Self {
+        self.outputs.push(txout);
+        self
+    }
+
+    /// Sets fee rate in satoshis per virtual byte
+    pub fn set_fee_rate(&mut self, fee_rate: u64) -> &mut Self {
+        self.fee_rate = fee_rate;
+        self
+    }
+
+    /// Reserves positions of the final transaction outputs which must not
+    /// contain the commitment. Since the commitment output is inserted among
+    /// the recipient outputs, the positions of the recipient outputs in the
+    /// final transaction depend on the position of the commitment output;
+    /// the reserved positions are always counted in the final transaction.
+    pub fn avoid_commitment_on(
+        &mut self,
+        indexes: impl IntoIterator<Item = usize>,
+    ) -> &mut Self {
+        self.reserved.extend(indexes);
+        self
+    }
+
+    /// Reserves position of the final transaction output for the wallet
+    /// change (see [`TxTemplate::avoid_commitment_on`])
+    #[inline]
+    pub fn reserve_change(&mut self, index: usize) -> &mut Self {
+        self.avoid_commitment_on(Some(index))
+    }
+
+    /// Constructs transaction with the commitment output placed according to
+    /// LNPBP-3 and holding the change, avoiding the reserved output positions.
+    ///
+    /// Fails with [`Error::OpReturnChange`] if the commitment output uses
+    /// [`ScriptEncodeMethod::OpReturn`] method, with
+    /// [`Error::InsufficientFunds`] if the inputs can't pay for the outputs
+    /// and the fee, with [`Error::DustOutput`] if the change is below the
+    /// dust limit of the commitment output and with
+    /// [`Error::AllOutputsReserved`] if none of the transaction outputs may
+    /// contain the commitment.
+    pub fn finalize(&self) -> Result<FinalizedTx, Error> {
+        // OP_RETURN output is unspendable, so the change would be burned
+        if self.container.script_container.method
+            == ScriptEncodeMethod::OpReturn
+        {
+            return Err(Error::OpReturnChange);
+        }
+
+        let outputs = self.outputs.len() + 1;
+        if (0..outputs).all(|index| self.reserved.contains(&index)) {
+            return Err(Error::AllOutputsReserved(outputs));
+        }
+
+        // Values exceeding 64-bit range are invalid anyway, so saturation
+        // results in failure with insufficient funds error or in invalid
+        // transaction
+        let available = self
+            .inputs
+            .iter()
+            .fold(0u64, |sum, (_, value, _)| sum.saturating_add(*value));
+        let spent = self
+            .outputs
+            .iter()
+            .fold(0u64, |sum, txout| sum.saturating_add(txout.value));
+        let satisfaction_weight = self
+            .inputs
+            .iter()
+            .map(|(_, _, weight)| weight)
+            .sum::<usize>();
+        let original_script = self
+            .container
+            .script_container
+            .original_script_pubkey()?
+            .into_inner();
+
+        // Fee never decreases between the iterations, so the loop terminates
+        // either when the fee covers the transaction size or when the inputs
+        // can't cover the fee
+        let mut fee = 0u64;
+        loop {
+            let index = loop {
+                let index = lnpbp3::commitment_output_index(
+                    fee,
+                    self.protocol_factor,
+                    outputs,
+                )
+                .expect("template always has the commitment output");
+                if !self.reserved.contains(&index) {
+                    break index;
+                }
+                // Each satoshi of the fee moves the commitment to the next
+                // output, so the unreserved output is reached in less than
+                // `outputs` steps
+                fee += 1;
+            };
+            let required = spent.saturating_add(fee);
+            let change = available.checked_sub(required).ok_or(
+                Error::InsufficientFunds {
+                    available,
+                    required,
+                },
+            )?;
+
+            let mut output = self.outputs.clone();
+            output.insert(index, TxOut {
+                value: change,
+                script_pubkey: original_script.clone(),
+            });
+            let mut tx = Transaction {
+                version: self.version,
+                lock_time: self.lock_time,
+                input: self
+                    .inputs
+                    .iter()
+                    .map(|(txin, ..)| txin.clone())
+                    .collect(),
+                output,
+            };
+
+            let mut container = self.container.clone();
+            container.value = change;
+            let dust_limit = container.dust_limit();
+            if change < dust_limit {
+                return Err(Error::DustOutput {
+                    value: change,
+                    required: dust_limit,
+                });
+            }
+            let (_, tweaking_factor) = TxoutCommitment::embed_commit_into(
+                &mut tx.output,
+                index,
+                &mut container,
+                &self.msg,
+            )?;
+
+            let weight = tx.get_weight() + satisfaction_weight;
+            let min_fee = (weight as u64 + 3) / 4 * self.fee_rate;
+            if min_fee <= fee {
+                return Ok(FinalizedTx {
+                    tx,
+                    proof: container.into_proof(),
+                    tweaking_factor,
+                    output_index: index,
+                    fee,
+                    fee_adjustment: fee - min_fee,
+                });
+            }
+            fee = min_fee;
+        }
+    }
+}
+
+/// Transaction produced by [`TxTemplate::finalize`] together with the data
+/// required for the commitment verification
+#[derive(Clone, PartialEq, Eq, Debug)]
+pub struct FinalizedTx {
+    /// Transaction containing the commitment
+    pub tx: Transaction,
+    /// Proof for the commitment verification
+    pub proof: Proof,
+    /// Tweaking factor applied to the commitment public key
+    pub tweaking_factor: TweakingFactor,
+    /// Index of the commitment output
+    pub output_index: usize,
+    /// Transaction fee
+    pub fee: u64,
+    /// Satoshis added to the fee required by the fee rate in order to move
+    /// the commitment from the reserved outputs
+    pub fee_adjustment: u64,
+}
+
+#[cfg(test)]
+mod test {
+    use bitcoin::hashes::Hash;
+    use bitcoin::{OutPoint, Txid};
+    use bitcoin_scripts::{Category, ToPubkeyScript};
+
+    use super::*;
+    use crate::lnpbp1::test_helpers::*;
+    use crate::tx_fee;
+
+    // Weight of P2WPKH input satisfaction: segwit marker and flag, witness
+    // items count, signature and public key
+    const P2WPKH_SATISFACTION: usize = 2 + 1 + 73 + 34;
+
+    fn gen_template(fee_rate: u64) -> TxTemplate<&'static str> {
+        let keys = gen_secp_pubkeys(4);
+        let mut template = TxTemplate::new(
+            &ProtocolTag::new("TEST_TAG"),
+            5,
+            keys[0],
+            ScriptEncodeData::SinglePubkey,
+            ScriptEncodeMethod::WPubkeyHash,
+            "message to commit to",
+        );
+        for (vout, value) in [40_000u64, 25_000].iter().enumerate() {
+            template.add_input(
+                TxIn {
+                    previous_output: OutPoint::new(
+                        Txid::hash(&[vout as u8]),
+                        vout as u32,
+                    ),
+                    ..Default::default()
+                },
+                *value,
+                P2WPKH_SATISFACTION,
+            );
+        }
+        for (key, value) in keys[1..].iter().zip([10_000u64, 20_000].iter()) {
+            template.add_output(TxOut {
+                value: *value,
+                script_pubkey: bitcoin::PublicKey {
+                    compressed: true,
+                    key: *key,
+                }
+                .to_pubkey_script(Category::SegWit)
+                .into_inner(),
+            });
+        }
+        template.set_fee_rate(fee_rate);
+        template
+    }
+
+    #[test]
+    fn test_finalize() {
+        for fee_rate in 1..20 {
+            let template = gen_template(fee_rate);
+            let FinalizedTx {
+                tx,
+                proof,
+                output_index: index,
+                fee_adjustment,
+                ..
+            } = template.finalize().unwrap();
+            assert_eq!(fee_adjustment, 0);
+
+            let fee = tx_fee(&tx, &|outpoint| {
+                template
+                    .inputs
+                    .iter()
+                    .find(|(txin, ..)| txin.previous_output == *outpoint)
+                    .map(|(_, value, _)| TxOut {
+                        value: *value,
+                        script_pubkey: empty!(),
+                    })
+            })
+            .unwrap();
+            let vsize = (tx.get_weight() + 2 * P2WPKH_SATISFACTION + 3) / 4;
+            assert_eq!(fee, vsize as u64 * fee_rate);
+
+            // Fixed point: the commitment output is placed at the index
+            // derived from the final fee
+            assert_eq!(tx.output.len(), 3);
+            assert_eq!(
+                lnpbp3::commitment_output_index(fee, 5, tx.output.len()),
+                Some(index)
+            );
+            assert_eq!(tx.output[index].value, 65_000 - 30_000 - fee);
+            let recipients = tx
+                .output
+                .iter()
+                .enumerate()
+                .filter(|(vout, _)| *vout != index)
+                .map(|(_, txout)| txout.clone())
+                .collect::<Vec<_>>();
+            assert_eq!(recipients, template.outputs);
+
+            assert_eq!(
+                proof.pubkey,
+                template.container.script_container.pubkey
+            );
+            assert_eq!(
+                TxoutCommitment::verify_txout_script_only(
+                    &tx.output[index],
+                    &proof,
+                    &ProtocolTag::new("TEST_TAG"),
+                    &template.msg,
+                ),
+                Ok(true)
+            );
+            assert!(!TxoutCommitment::verify_txout_script_only(
+                &tx.output[index],
+                &proof,
+                &ProtocolTag::new("TEST_TAG"),
+                &"other message",
+            )
+            .unwrap());
+        }
+    }
+
+    #[test]
+    fn test_finalize_failures() {
+        let mut template = gen_template(1000);
+        assert!(matches!(
+            template.finalize(),
+            Err(Error::InsufficientFunds {
+                available: 65_000,
+                ..
+            })
+        ));
+
+        template.set_fee_rate(1);
+        template.outputs[0].value = 44_700;
+        assert!(matches!(template.finalize(), Err(Error::DustOutput { .. })));
+
+        let mut template = gen_template(1);
+        template.container.script_container.method =
+            ScriptEncodeMethod::OpReturn;
+        assert_eq!(template.finalize(), Err(Error::OpReturnChange));
+    }
+
+    #[test]
+    fn test_reserved_outputs() {
+        let tag = ProtocolTag::new("TEST_TAG");
+        let mut template = gen_template(3);
+        let unreserved = template.finalize().unwrap();
+        let index = unreserved.output_index;
+
+        // Commitment designated to the reserved output is moved to the next
+        // one by the minimal fee increase
+        template.reserve_change(index);
+        let finalized = template.finalize().unwrap();
+        assert_ne!(finalized.output_index, index);
+        assert_eq!(finalized.output_index, (index + 1) % 3);
+        assert_eq!(finalized.fee_adjustment, 1);
+        assert_eq!(finalized.fee, unreserved.fee + 1);
+        assert_eq!(
+            lnpbp3::commitment_output_index(finalized.fee, 5, 3),
+            Some(finalized.output_index)
+        );
+        assert_eq!(
+            finalized.tx.output[finalized.output_index].value,
+            unreserved.tx.output[index].value - 1
+        );
+        assert_eq!(
+            TxoutCommitment::verify_txout(
+                &finalized.tx.output[finalized.output_index],
+                unreserved.tx.output[index].value - 1,
+                &finalized.proof,
+                &tag,
+                &template.msg,
+            ),
+            Ok(true)
+        );
+
+        // Two reserved outputs require two satoshis
+        template.avoid_commitment_on(vec![(index + 1) % 3]);
+        let finalized = template.finalize().unwrap();
+        assert_eq!(finalized.output_index, (index + 2) % 3);
+        assert_eq!(finalized.fee_adjustment, 2);
+        assert_eq!(finalized.fee, unreserved.fee + 2);
+
+        // Reserved positions out of the transaction range do not matter
+        let mut template = gen_template(3);
+        template.avoid_commitment_on(vec![3, 4, 100]);
+        assert_eq!(template.finalize(), Ok(unreserved));
+
+        template.avoid_commitment_on(vec![0, 1, 2]);
+        assert_eq!(template.finalize(), Err(Error::AllOutputsReserved(3)));
+    }
+}
@@ -0,0 +1,108 @@
+// BP Core Library implementing LNP/BP specifications & standards related to
+// bitcoin protocol
+//
+// Written in 2020-2021 by
+//     Dr. Maxim Orlovsky <orlovsky@pandoracore.com>
+//
+// To the extent possible under law, the author(s) have dedicated all
+// copyright and related and neighboring rights to this software to
+// the public domain worldwide. This software is distributed without
+// any warranty.
+//
+// You should have received a copy of the Apache 2.0 License
+// along with this software.
+// If not, see <https://opensource.org/licenses/Apache-2.0>.
+
+//! Helpers for checking invariants which must hold for all of the
+//! deterministic bitcoin commitment containers
+
+use std::alloc::{GlobalAlloc, Layout, System};
+use std::cell::Cell;
+use std::fmt::Debug;
+
+use amplify::Wrapper;
+use commit_verify::EmbedCommitVerify;
+
+use crate::{Container, SpkCommitment, SpkContainer};
+
+/// Checks that [`SpkContainer::reconstruct`] is consistent with
+/// [`SpkCommitment::embed_commit`]: the container reconstructed from the
+/// proof and the committed `scriptPubkey` must produce exactly the same
+/// commitment as the original container.
+///
+/// Panics if the original container can't be committed to, so it must be
+/// called only for the valid containers. Returns the produced commitment.
+pub fn assert_spk_roundtrip<MSG>(
+    mut container: SpkContainer,
+    msg: &MSG,
+) -> SpkCommitment
+where
+    MSG: AsRef<[u8]> + Debug,
+{
+    let commitment = SpkCommitment::embed_commit(&mut container, msg)
+        .unwrap_or_else(|err| {
+            panic!("container {:?} can't commit: {}", container, err)
+        });
+
+    let mut reconstructed = SpkContainer::reconstruct(
+        &container.to_proof(),
+        &container.tag,
+        commitment.as_inner(),
+    )
+    .unwrap_or_else(|err| {
+        panic!(
+            "container {:?} can't be reconstructed from {}: {}",
+            container, commitment, err
+        )
+    });
+    assert_eq!(reconstructed.pubkey, container.pubkey);
+    assert_eq!(reconstructed.method, container.method);
+    assert_eq!(reconstructed.source, container.source);
+    assert_eq!(reconstructed.tag, container.tag);
+
+    let recommitment = SpkCommitment::embed_commit(&mut reconstructed, msg)
+        .unwrap_or_else(|err| {
+            panic!(
+                "reconstructed container {:?} can't commit: {}",
+                reconstructed, err
+            )
+        });
+    assert_eq!(
+        recommitment, commitment,
+        "commitment to {:?} is changed after reconstruction",
+        msg
+    );
+    assert_eq!(reconstructed.tweaking_factor, container.tweaking_factor);
+
+    commitment
+}
+
+/// Allocator counting heap allocations made by each of the threads, used to
+/// check that performance-critical functions do not allocate
+struct CountingAllocator;
+
+thread_local! {
+    static ALLOCATIONS: Cell<usize> = Cell::new(0);
+}
+
+unsafe impl GlobalAlloc for CountingAllocator {
+    unsafe fn alloc(&self, layout: Layout) -> *mut u8 {
+        let _ = ALLOCATIONS.try_with(|count| count.set(count.get() + 1));
+        System.alloc(layout)
+    }
+
+    unsafe fn dealloc(&self, ptr: *mut u8, layout: Layout) {
+        System.dealloc(ptr, layout)
+    }
+}
+
+#[global_allocator]
+static ALLOCATOR: CountingAllocator = CountingAllocator;
+
+/// Runs `f` and returns its result together with the number of heap
+/// allocations it has performed
+pub fn count_allocations<R>(f: impl FnOnce() -> R) -> (R, usize) {
+    let before = ALLOCATIONS.with(Cell::get);
+    let res = f();
+    (res, ALLOCATIONS.with(Cell::get) - before)
+}
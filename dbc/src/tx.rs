@@ -13,21 +13,25 @@
 // along with this software.
 // If not, see <https://opensource.org/licenses/Apache-2.0>.
 
+use std::collections::BTreeMap;
+
 use amplify::Wrapper;
 use bitcoin::hashes::{sha256, Hmac};
-use bitcoin::{secp256k1, Transaction, TxOut};
+use bitcoin::{secp256k1, OutPoint, Transaction, TxOut};
 use commit_verify::EmbedCommitVerify;
 
 use super::{
-    Container, Error, Proof, ScriptEncodeData, ScriptEncodeMethod,
-    TxoutCommitment, TxoutContainer,
+    lnpbp3, Container, Error, Proof, ProtocolTag, ScriptEncodeData,
+    ScriptEncodeMethod, SpkContainer, TxoutCommitment, TxoutContainer,
+    ValueSource,
 };
 
 #[derive(Clone, PartialEq, Eq, Debug, Display)]
 #[display(Debug)]
 pub struct TxContainer {
-    pub protocol_factor: u32,
-    pub fee: u64,
+    /// Protocol factor, fee and tag used for the commitment; the tag is the
+    /// same as the one kept by the `txout_container`
+    pub supplement: TxSupplement,
     pub txout_container: TxoutContainer,
     pub tx: Transaction,
     /// Tweaking factor stored after [`TxCommitment::embed_commit`] procedure
@@ -40,13 +44,100 @@ pub struct TxSupplement {
     pub protocol_factor: u32,
     pub fee: u64,
     /// Single SHA256 hash of the protocol-specific tag
-    pub tag: sha256::Hash,
+    pub tag: ProtocolTag,
+}
+
+impl TxSupplement {
+    /// Constructs supplement for the transaction with the fee computed from
+    /// the outputs spent by the transaction, which are provided by the
+    /// `prevouts` resolver (see [`tx_fee`]), so the container may be
+    /// reconstructed when the fee is not known to the verifier.
+    pub fn with_prevouts(
+        protocol_factor: u32,
+        tag: ProtocolTag,
+        tx: &Transaction,
+        prevouts: &impl Fn(&OutPoint) -> Option<TxOut>,
+    ) -> Result<Self, FeeError> {
+        Ok(TxSupplement {
+            protocol_factor,
+            fee: tx_fee(tx, prevouts)?,
+            tag,
+        })
+    }
+
+    /// Constructs supplement for the coinbase transaction, which does not
+    /// pay fees, so the commitment output is selected with zero fee (see
+    /// [`TxContainer::for_coinbase`])
+    #[inline]
+    pub fn for_coinbase(protocol_factor: u32, tag: ProtocolTag) -> Self {
+        TxSupplement {
+            protocol_factor,
+            fee: 0,
+            tag,
+        }
+    }
+}
+
+/// Errors happening during computation of the transaction fee with
+/// [`tx_fee`]
+#[derive(Copy, Clone, PartialEq, Eq, Hash, Debug, Display, Error)]
+#[display(doc_comments)]
+pub enum FeeError {
+    /// output {0} spent by the transaction is unknown
+    MissingPrevout(OutPoint),
+
+    /// value of the transaction outputs ({outputs} satoshis) exceeds the value
+    /// of the spent outputs ({inputs} satoshis)
+    ValueUnderflow {
+        /// Total value of the spent outputs
+        inputs: u64,
+        /// Total value of the transaction outputs
+        outputs: u64,
+    },
+
+    /// total value of the transaction inputs or outputs overflows 64-bit
+    /// integer
+    ValueOverflow,
+}
+
+/// Computes fee of the transaction as a difference between the value of the
+/// outputs spent by the transaction and the value of its outputs. The spent
+/// outputs are provided by the `prevouts` resolver, so any source of UTXO
+/// data may be used.
+///
+/// Coinbase transactions do not spend any outputs and do not pay fees, so
+/// zero fee is returned for them without calling the resolver.
+///
+/// # Errors
+///
+/// Fails with [`FeeError::MissingPrevout`] if the resolver can't provide one
+/// of the spent outputs and with [`FeeError::ValueUnderflow`] if the
+/// transaction outputs value exceeds the value of the spent outputs.
+pub fn tx_fee(
+    tx: &Transaction,
+    prevouts: &impl Fn(&OutPoint) -> Option<TxOut>,
+) -> Result<u64, FeeError> {
+    if tx.is_coin_base() {
+        return Ok(0);
+    }
+    let inputs = tx.input.iter().try_fold(0u64, |sum, txin| {
+        let prevout = prevouts(&txin.previous_output)
+            .ok_or(FeeError::MissingPrevout(txin.previous_output))?;
+        sum.checked_add(prevout.value)
+            .ok_or(FeeError::ValueOverflow)
+    })?;
+    let outputs = tx.output.iter().try_fold(0u64, |sum, txout| {
+        sum.checked_add(txout.value).ok_or(FeeError::ValueOverflow)
+    })?;
+    inputs
+        .checked_sub(outputs)
+        .ok_or(FeeError::ValueUnderflow { inputs, outputs })
 }
 
 impl TxContainer {
     pub fn construct(
         protocol_factor: u32,
-        protocol_tag: &sha256::Hash,
+        protocol_tag: &ProtocolTag,
         fee: u64,
         tx: Transaction,
         pubkey: secp256k1::PublicKey,
@@ -55,8 +146,11 @@ impl TxContainer {
     ) -> Self {
         let mut me = Self {
             tx,
-            fee,
-            protocol_factor,
+            supplement: TxSupplement {
+                protocol_factor,
+                fee,
+                tag: *protocol_tag,
+            },
             txout_container: TxoutContainer::construct(
                 protocol_tag,
                 0,
@@ -67,14 +161,109 @@ impl TxContainer {
             tweaking_factor: None,
         };
         me.txout_container.value = me.tx.output[me.vout()].value;
+        me.txout_container.value_source = ValueSource::Host;
         me
     }
 
+    /// Constructs container for the commitment in a coinbase transaction,
+    /// for instance by a miner of a merge-mined protocol.
+    ///
+    /// Coinbase transactions do not spend any outputs and do not pay fees,
+    /// so the commitment output is selected according to LNPBP-3 with zero
+    /// fee, i.e. it is defined by the protocol factor only. Containers for
+    /// the coinbase transactions are detected automatically during the
+    /// reconstruction and fail with [`Error::CoinbaseFee`] if the supplement
+    /// specifies non-zero fee (see [`TxSupplement::for_coinbase`]).
+    ///
+    /// Fails with [`Error::NotCoinbase`] if `tx` is not a coinbase
+    /// transaction and with [`Error::OutputIndexOutOfRange`] if it has no
+    /// outputs.
+    pub fn for_coinbase(
+        protocol_factor: u32,
+        protocol_tag: &ProtocolTag,
+        tx: Transaction,
+        pubkey: secp256k1::PublicKey,
+        source: ScriptEncodeData,
+        method: ScriptEncodeMethod,
+    ) -> Result<Self, Error> {
+        if !tx.is_coin_base() {
+            return Err(Error::NotCoinbase);
+        }
+        Self::lnpbp3_vout(0, protocol_factor, &tx)?;
+        Ok(Self::construct(
+            protocol_factor,
+            protocol_tag,
+            0,
+            tx,
+            pubkey,
+            source,
+            method,
+        ))
+    }
+
+    /// Returns index of the transaction output which contains the commitment,
+    /// computed according to LNPBP-3 from the transaction fee and the
+    /// protocol factor.
+    ///
+    /// # Panics
+    ///
+    /// If the transaction has no outputs, or if it is a coinbase transaction
+    /// and the fee is not zero
     pub fn vout(&self) -> usize {
-        let nouts = self.tx.output.len() as u16;
-        let vout = ((self.fee + (self.protocol_factor as u64)) % (nouts as u64))
-            as u16;
-        vout as usize
+        Self::lnpbp3_vout(
+            self.supplement.fee,
+            self.supplement.protocol_factor,
+            &self.tx,
+        )
+        .expect(
+            "transaction without outputs or coinbase transaction with \
+             non-zero fee can't contain a commitment",
+        )
+    }
+
+    /// Reconstructs container from the consensus-serialized transaction
+    /// received from an untrusted party, deserializing it only if its size
+    /// does not exceed `max_size` bytes. The commitment output is derived
+    /// according to LNPBP-3 from the `supplement` in the same way as in
+    /// [`Container::reconstruct`].
+    ///
+    /// Fails with [`Error::TxTooLarge`] if the data exceed the size limit,
+    /// with [`Error::TxDecode`] if they are not a valid transaction
+    /// serialization (including the case of extra data after the
+    /// transaction), or with the errors of [`Container::reconstruct`].
+    pub fn reconstruct_from_raw(
+        raw_tx: &[u8],
+        proof: &Proof,
+        supplement: &TxSupplement,
+        max_size: usize,
+    ) -> Result<Self, Error> {
+        if raw_tx.len() > max_size {
+            return Err(Error::TxTooLarge {
+                size: raw_tx.len(),
+                max_size,
+            });
+        }
+        let tx: Transaction = bitcoin::consensus::deserialize(raw_tx)
+            .map_err(|_| Error::TxDecode)?;
+        Self::reconstruct(proof, supplement, &tx)
+    }
+
+    /// Computes LNPBP-3 index of the output containing commitment (see
+    /// [`lnpbp3::commitment_output_index`]), failing if the transaction has
+    /// no outputs or if it is a coinbase transaction and the fee is not zero
+    fn lnpbp3_vout(
+        fee: u64,
+        protocol_factor: u32,
+        tx: &Transaction,
+    ) -> Result<usize, Error> {
+        if fee != 0 && tx.is_coin_base() {
+            return Err(Error::CoinbaseFee(fee));
+        }
+        lnpbp3::commitment_output_index(fee, protocol_factor, tx.output.len())
+            .ok_or(Error::OutputIndexOutOfRange {
+                index: 0,
+                outputs: 0,
+            })
     }
 }
 
@@ -87,33 +276,30 @@ impl Container for TxContainer {
         supplement: &Self::Supplement,
         host: &Self::Host,
     ) -> Result<Self, Error> {
-        let mut me = Self {
-            protocol_factor: supplement.protocol_factor,
-            fee: supplement.fee,
+        let vout = Self::lnpbp3_vout(
+            supplement.fee,
+            supplement.protocol_factor,
+            host,
+        )?;
+        Ok(Self {
+            supplement: supplement.clone(),
             txout_container: TxoutContainer::reconstruct(
                 proof,
                 &supplement.tag,
-                &TxOut::default(),
+                &host.output[vout],
             )?,
             tx: host.clone(),
             tweaking_factor: None,
-        };
-        me.txout_container = TxoutContainer::reconstruct(
-            proof,
-            &supplement.tag,
-            &host.output[me.vout()],
-        )?;
-        Ok(me)
+        })
     }
 
     fn deconstruct(self) -> (Proof, Self::Supplement) {
-        (self.txout_container.clone().into_proof(), TxSupplement {
-            protocol_factor: self.protocol_factor,
-            fee: self.fee,
-            tag: self.txout_container.script_container.tag,
-        })
+        (self.txout_container.into_proof(), self.supplement)
     }
 
+    #[inline]
+    fn supplement(&self) -> &Self::Supplement { &self.supplement }
+
     fn to_proof(&self) -> Proof { self.txout_container.to_proof() }
 
     fn into_proof(self) -> Proof { self.txout_container.into_proof() }
@@ -124,6 +310,98 @@ impl Container for TxContainer {
 #[display(Debug)]
 pub struct TxCommitment(Transaction);
 
+impl TxCommitment {
+    /// Moves the commitment to the output designated by LNPBP-3 for the
+    /// `new_fee`, which is required when the transaction fee is bumped (for
+    /// instance, with RBF) after the commitment was embedded. The `container`
+    /// must be the one used to embed the commitment, keeping the tweaking
+    /// factor; the transaction may already have the output values changed by
+    /// the fee bump.
+    ///
+    /// The commitment is stripped from the output designated for the old fee
+    /// by restoring its original `scriptPubkey` (see
+    /// [`SpkContainer::original_script_pubkey`]). This output is then swapped
+    /// with the output designated for the `new_fee`, keeping their values, so
+    /// the outputs of other parties are moved but never modified, and the
+    /// commitment is embedded into it at the new position. On success the
+    /// `container` is updated to the new fee and the tweaking factor, and the
+    /// new transaction is returned together with the proof for its
+    /// verification.
+    ///
+    /// Fails with [`Error::NoTweakingFactor`] if the container does not keep
+    /// the tweaking factor and with [`Error::ScriptMismatch`] if the
+    /// transaction does not contain the commitment to `msg` made with the
+    /// container and its tweaking factor.
+    pub fn rebase(
+        &self,
+        container: &mut TxContainer,
+        new_fee: u64,
+        msg: &impl AsRef<[u8]>,
+    ) -> Result<(Transaction, Proof), Error> {
+        let tweaking_factor =
+            container.tweaking_factor.ok_or(Error::NoTweakingFactor)?;
+        let tx = self.as_inner();
+        let old_vout = TxContainer::lnpbp3_vout(
+            container.supplement.fee,
+            container.supplement.protocol_factor,
+            tx,
+        )?;
+        let new_vout = TxContainer::lnpbp3_vout(
+            new_fee,
+            container.supplement.protocol_factor,
+            tx,
+        )?;
+        let mut stripped = tx.clone();
+        stripped.output[old_vout].script_pubkey = container
+            .txout_container
+            .script_container
+            .original_script_pubkey()?
+            .into_inner();
+
+        let recommit = |fee,
+                        stripped: Transaction|
+         -> Result<(TxContainer, Transaction), Error> {
+            let vout = TxContainer::lnpbp3_vout(
+                fee,
+                container.supplement.protocol_factor,
+                &stripped,
+            )?;
+            let mut txout_container = container.txout_container.clone();
+            txout_container.value = stripped.output[vout].value;
+            txout_container.value_source = ValueSource::Host;
+            txout_container.tweaking_factor = None;
+            txout_container.script_container.tweaking_factor = None;
+            let mut rebased = TxContainer {
+                supplement: TxSupplement {
+                    fee,
+                    ..container.supplement.clone()
+                },
+                txout_container,
+                tx: stripped,
+                tweaking_factor: None,
+            };
+            let commitment = TxCommitment::embed_commit(&mut rebased, msg)?;
+            Ok((rebased, commitment.into_inner()))
+        };
+
+        // Stripping must be reversible with the same container and message,
+        // otherwise the transaction does not contain the commitment
+        let (recommitted, original) =
+            recommit(container.supplement.fee, stripped.clone())?;
+        if original != *tx
+            || recommitted.tweaking_factor != Some(tweaking_factor)
+        {
+            return Err(Error::ScriptMismatch);
+        }
+
+        stripped.output.swap(old_vout, new_vout);
+        let (rebased, tx) = recommit(new_fee, stripped)?;
+        let proof = rebased.to_proof();
+        *container = rebased;
+        Ok((tx, proof))
+    }
+}
+
 impl<MSG> EmbedCommitVerify<MSG> for TxCommitment
 where
     MSG: AsRef<[u8]>,
@@ -135,11 +413,16 @@ where
         container: &mut Self::Container,
         msg: &MSG,
     ) -> Result<Self, Self::Error> {
+        let vout = TxContainer::lnpbp3_vout(
+            container.supplement.fee,
+            container.supplement.protocol_factor,
+            &container.tx,
+        )?;
         let mut tx = container.tx.clone();
 
         let txout_commitment =
             TxoutCommitment::embed_commit(&mut container.txout_container, msg)?;
-        tx.output[container.vout()] = txout_commitment.into_inner();
+        tx.output[vout] = txout_commitment.into_inner();
 
         container.tweaking_factor = container.txout_container.tweaking_factor;
 
@@ -147,14 +430,187 @@ where
     }
 }
 
+/// Builder for the commitments of multiple protocols into a single
+/// transaction. Each of the protocols commits into the output selected
+/// according to LNPBP-3 from the transaction fee and the protocol factor (see
+/// [`lnpbp3::commitment_output_index`]), so the protocols can't occupy the
+/// outputs designated to the other protocols.
+///
+/// Since LNPBP-3 does not define rules for resolving the case when two
+/// protocols are designated to the same output, such transactions are
+/// rejected with [`Error::OutputCollision`].
+#[derive(Clone, PartialEq, Eq, Debug)]
+pub struct TxMultiCommitment<MSG> {
+    /// Transaction fee used in LNPBP-3 output selection
+    pub fee: u64,
+    /// Protocol factor, container and message for each of the protocols;
+    /// the containers keep tweaking factors after the commitment is embedded
+    pub protocols: BTreeMap<ProtocolTag, (u32, TxoutContainer, MSG)>,
+}
+
+impl<MSG> TxMultiCommitment<MSG>
+where
+    MSG: AsRef<[u8]>,
+{
+    /// Constructs builder with no protocols for a transaction with the given
+    /// fee
+    pub fn new(fee: u64) -> Self {
+        TxMultiCommitment {
+            fee,
+            protocols: empty!(),
+        }
+    }
+
+    /// Adds commitment to `msg` for the protocol with the given tag,
+    /// replacing the previously added commitment for the same protocol. The
+    /// value of the output is taken from the transaction during the
+    /// commitment.
+    pub fn add_protocol(
+        &mut self,
+        protocol_tag: &ProtocolTag,
+        protocol_factor: u32,
+        pubkey: secp256k1::PublicKey,
+        source: ScriptEncodeData,
+        method: ScriptEncodeMethod,
+        msg: MSG,
+    ) -> &mut Self {
+        let mut container =
+            TxoutContainer::construct(protocol_tag, 0, pubkey, source, method);
+        container.value_source = ValueSource::Host;
+        self.protocols
+            .insert(*protocol_tag, (protocol_factor, container, msg));
+        self
+    }
+
+    /// Computes indexes of the transaction outputs designated to each of the
+    /// protocols. Fails with [`Error::OutputCollision`] if two protocols are
+    /// designated to the same output and with
+    /// [`Error::OutputIndexOutOfRange`] if the transaction has no outputs.
+    pub fn output_indexes(
+        &self,
+        tx: &Transaction,
+    ) -> Result<BTreeMap<ProtocolTag, usize>, Error> {
+        let mut occupied = BTreeMap::<usize, ProtocolTag>::new();
+        let mut indexes = BTreeMap::new();
+        for (tag, (protocol_factor, ..)) in &self.protocols {
+            let index =
+                TxContainer::lnpbp3_vout(self.fee, *protocol_factor, tx)?;
+            if let Some(other) = occupied.insert(index, *tag) {
+                return Err(Error::OutputCollision {
+                    tag_a: other,
+                    tag_b: *tag,
+                    index,
+                });
+            }
+            indexes.insert(*tag, index);
+        }
+        Ok(indexes)
+    }
+
+    /// Embeds commitments of all protocols into the designated outputs of the
+    /// transaction, returning the transaction with the commitments. The
+    /// operation is atomic: if any of the commitments fails, the error is
+    /// returned and the containers are left unchanged.
+    pub fn embed_commit(
+        &mut self,
+        tx: &Transaction,
+    ) -> Result<Transaction, Error> {
+        let indexes = self.output_indexes(tx)?;
+        let mut tx = tx.clone();
+        let mut containers = Vec::with_capacity(self.protocols.len());
+        for (tag, (_, container, msg)) in &self.protocols {
+            let index = indexes[tag];
+            let mut container = container.clone();
+            container.value = tx.output[index].value;
+            let commitment =
+                TxoutCommitment::embed_commit(&mut container, msg)?;
+            tx.output[index] = commitment.into_inner();
+            containers.push(container);
+        }
+        for ((_, container, _), updated) in
+            self.protocols.values_mut().zip(containers)
+        {
+            *container = updated;
+        }
+        Ok(tx)
+    }
+
+    /// Verifies that the transaction output designated according to LNPBP-3
+    /// to the protocol from the `supplement` contains commitment to the
+    /// message with the given proof (see
+    /// [`TxoutCommitment::verify_txout_script_only`]).
+    ///
+    /// Fails with [`Error::OutputIndexOutOfRange`] if the transaction has no
+    /// outputs, or if the proof is structurally incompatible with the output.
+    pub fn verify_protocol(
+        tx: &Transaction,
+        supplement: &TxSupplement,
+        proof: &Proof,
+        msg: &MSG,
+    ) -> Result<bool, Error> {
+        let index = TxContainer::lnpbp3_vout(
+            supplement.fee,
+            supplement.protocol_factor,
+            tx,
+        )?;
+        TxoutCommitment::verify_txout_script_only(
+            &tx.output[index],
+            proof,
+            &supplement.tag,
+            msg,
+        )
+    }
+}
+
+/// Finds the output of the transaction containing commitment to the message
+/// for the given proof and protocol tag, which is useful when the output index
+/// is not known to the verifier. Outputs are filtered with the structural
+/// check of [`SpkContainer::quick_match`] before running the full
+/// verification with [`TxoutCommitment::verify_txout_script_only`].
+///
+/// Returns `Ok(None)` if none of the outputs contains the commitment. Fails
+/// with [`Error::MultipleMatches`] if more than one output contains the
+/// commitment, which indicates malformed transaction.
+pub fn find_commitment_output(
+    tx: &Transaction,
+    proof: &Proof,
+    tag: &ProtocolTag,
+    msg: &impl AsRef<[u8]>,
+) -> Result<Option<usize>, Error> {
+    let mut found = None;
+    for (index, txout) in tx.output.iter().enumerate() {
+        if !SpkContainer::quick_match_script(proof, &txout.script_pubkey) {
+            continue;
+        }
+        // Outputs structurally incompatible with the proof can't contain the
+        // commitment
+        if !TxoutCommitment::verify_txout_script_only(txout, proof, tag, msg)
+            .unwrap_or(false)
+        {
+            continue;
+        }
+        if let Some(first) = found {
+            return Err(Error::MultipleMatches {
+                first,
+                second: index,
+            });
+        }
+        found = Some(index);
+    }
+    Ok(found)
+}
+
 #[cfg(test)]
 mod test {
     use std::str::FromStr;
 
-    use bitcoin::consensus::encode::deserialize;
+    use bitcoin::consensus::encode::{deserialize, serialize};
     use bitcoin::hashes::hex::FromHex;
+    use bitcoin::TxIn;
+    use bitcoin_scripts::{Category, ToPubkeyScript};
 
     use super::*;
+    use crate::lnpbp1::test_helpers::*;
     use crate::{ScriptEncodeData, ScriptEncodeMethod, SpkContainer};
 
     #[test]
@@ -181,10 +637,14 @@ mod test {
 
         let mut container = TxContainer {
             tx,
-            fee: 0,
-            protocol_factor: 0,
+            supplement: TxSupplement {
+                protocol_factor: 0,
+                fee: 0,
+                tag: Default::default(),
+            },
             txout_container: TxoutContainer {
                 value: 0,
+                value_source: ValueSource::Provided,
                 script_container: SpkContainer {
                     pubkey: secp256k1::PublicKey::from_str(
                         "0218845781f631c48f1c9709e23092067d06837f30aa0cd0544ac887fe91ddd166",
@@ -206,4 +666,765 @@ mod test {
             TxCommitment::embed_commit(&mut container, &msg).unwrap();
         assert_eq!(commitment.verify(&container, &msg).unwrap(), true);
     }
+
+    fn gen_tx(outputs: usize) -> Transaction {
+        Transaction {
+            version: 2,
+            lock_time: 0,
+            input: vec![],
+            output: gen_secp_pubkeys(outputs)
+                .into_iter()
+                .enumerate()
+                .map(|(index, key)| TxOut {
+                    value: 1000 * (index as u64 + 1),
+                    script_pubkey: bitcoin::PublicKey {
+                        compressed: true,
+                        key,
+                    }
+                    .to_pubkey_script(Category::SegWit)
+                    .into_inner(),
+                })
+                .collect(),
+        }
+    }
+
+    #[test]
+    fn test_vout() {
+        let tag = ProtocolTag::new("TEST_TAG");
+        let pubkey = gen_secp_pubkeys(1)[0];
+        let construct = |fee, protocol_factor, tx| {
+            TxContainer::construct(
+                protocol_factor,
+                &tag,
+                fee,
+                tx,
+                pubkey,
+                ScriptEncodeData::SinglePubkey,
+                ScriptEncodeMethod::WPubkeyHash,
+            )
+        };
+
+        for fee in 0..10 {
+            assert_eq!(construct(fee, 7, gen_tx(1)).vout(), 0);
+        }
+        let vouts = (0..5)
+            .map(|fee| construct(fee, 3, gen_tx(5)).vout())
+            .collect::<Vec<_>>();
+        assert_eq!(vouts, vec![3, 4, 0, 1, 2]);
+        let container = construct(11, 3, gen_tx(5));
+        assert_eq!(container.vout(), 4);
+        assert_eq!(container.txout_container.value, 5000);
+        assert_eq!(container.txout_container.value_source, ValueSource::Host);
+        assert_eq!(container.supplement(), &TxSupplement {
+            protocol_factor: 3,
+            fee: 11,
+            tag,
+        });
+
+        let mut container = construct(0, 0, gen_tx(1));
+        container.tx.output.clear();
+        assert_eq!(
+            TxCommitment::embed_commit(&mut container, &"message"),
+            Err(Error::OutputIndexOutOfRange {
+                index: 0,
+                outputs: 0
+            })
+        );
+    }
+
+    #[test]
+    fn test_embed_reconstruct() {
+        let tag = ProtocolTag::new("TEST_TAG");
+        let msg = "message to commit to";
+        for (outputs, fee) in [(1usize, 1000u64), (5, 1000), (5, 1001)].iter() {
+            let tx = gen_tx(*outputs);
+            let pubkey = gen_secp_pubkeys(*outputs)[0];
+            let mut container = TxContainer::construct(
+                2,
+                &tag,
+                *fee,
+                tx.clone(),
+                pubkey,
+                ScriptEncodeData::SinglePubkey,
+                ScriptEncodeMethod::WPubkeyHash,
+            );
+            let vout = container.vout();
+            let commitment =
+                TxCommitment::embed_commit(&mut container, &msg).unwrap();
+            assert!(container.tweaking_factor.is_some());
+
+            // Only the designated output is changed
+            for (index, txout) in commitment.output.iter().enumerate() {
+                assert_eq!(index == vout, *txout != tx.output[index]);
+            }
+            assert_eq!(commitment.output[vout].value, tx.output[vout].value);
+
+            // Verification with the data from the on-chain transaction
+            let (proof, supplement) = container.clone().deconstruct();
+            let reconstructed =
+                TxContainer::reconstruct(&proof, &supplement, &commitment)
+                    .unwrap();
+            assert_eq!(reconstructed.vout(), vout);
+            assert_eq!(
+                reconstructed.txout_container.value,
+                commitment.output[vout].value
+            );
+            assert_eq!(commitment.verify(&container, &msg), Ok(true));
+            assert_eq!(commitment.verify(&container, &"other"), Ok(false));
+
+            // Fee change moves the commitment to another output
+            if *outputs > 1 {
+                let supplement = TxSupplement {
+                    fee: fee + 1,
+                    ..supplement
+                };
+                let reconstructed =
+                    TxContainer::reconstruct(&proof, &supplement, &commitment)
+                        .unwrap();
+                assert_ne!(reconstructed.vout(), vout);
+                assert_eq!(
+                    TxCommitment::from_inner(commitment.to_inner())
+                        .verify(&reconstructed, &msg),
+                    Ok(false)
+                );
+            }
+        }
+    }
+
+    #[test]
+    fn test_coinbase() {
+        let tag = ProtocolTag::new("TEST_TAG");
+        let keys = gen_secp_pubkeys(2);
+        let coinbase = Transaction {
+            version: 1,
+            lock_time: 0,
+            input: vec![TxIn {
+                previous_output: OutPoint::null(),
+                // BIP-34 block height
+                script_sig: bitcoin::blockdata::script::Builder::new()
+                    .push_int(700_000)
+                    .into_script(),
+                sequence: 0xFFFFFFFF,
+                witness: vec![],
+            }],
+            output: vec![
+                TxOut {
+                    value: 625_000_000,
+                    script_pubkey: bitcoin::PublicKey {
+                        compressed: true,
+                        key: keys[1],
+                    }
+                    .to_pubkey_script(Category::SegWit)
+                    .into_inner(),
+                },
+                TxOut::default(),
+            ],
+        };
+        assert!(coinbase.is_coin_base());
+
+        let mut committed = 0;
+        for msg in gen_messages() {
+            let mut container = TxContainer::for_coinbase(
+                1,
+                &tag,
+                coinbase.clone(),
+                keys[0],
+                ScriptEncodeData::SinglePubkey,
+                ScriptEncodeMethod::OpReturn,
+            )
+            .unwrap();
+            assert_eq!(container.supplement.fee, 0);
+            assert_eq!(container.vout(), 1);
+            let commitment =
+                match TxCommitment::embed_commit(&mut container, &msg) {
+                    Err(Error::InvalidOpReturnKey) => continue,
+                    res => res.unwrap(),
+                };
+            committed += 1;
+            assert!(commitment.output[1].script_pubkey.is_op_return());
+            assert_eq!(commitment.output[0], coinbase.output[0]);
+
+            let proof = container.to_proof();
+            let supplement = TxSupplement::for_coinbase(1, tag);
+            assert_eq!(container.clone().deconstruct().1, supplement);
+            let reconstructed =
+                TxContainer::reconstruct(&proof, &supplement, &commitment)
+                    .unwrap();
+            assert_eq!(commitment.verify(&reconstructed, &msg), Ok(true));
+            assert_eq!(
+                TxMultiCommitment::verify_protocol(
+                    &commitment,
+                    &supplement,
+                    &proof,
+                    &msg
+                ),
+                Ok(true)
+            );
+            assert_eq!(
+                TxSupplement::with_prevouts(1, tag, &commitment, &|_| None),
+                Ok(supplement.clone())
+            );
+
+            // Fee-based output selection is not applicable to coinbase
+            let supplement = TxSupplement {
+                fee: 1000,
+                ..supplement
+            };
+            assert_eq!(
+                TxContainer::reconstruct(&proof, &supplement, &commitment),
+                Err(Error::CoinbaseFee(1000))
+            );
+            assert_eq!(
+                TxMultiCommitment::verify_protocol(
+                    &commitment,
+                    &supplement,
+                    &proof,
+                    &msg
+                ),
+                Err(Error::CoinbaseFee(1000))
+            );
+        }
+        assert!(committed > 0);
+
+        assert_eq!(
+            TxContainer::for_coinbase(
+                1,
+                &tag,
+                gen_tx(2),
+                keys[0],
+                ScriptEncodeData::SinglePubkey,
+                ScriptEncodeMethod::OpReturn,
+            ),
+            Err(Error::NotCoinbase)
+        );
+        let mut empty = coinbase;
+        empty.output.clear();
+        assert_eq!(
+            TxContainer::for_coinbase(
+                1,
+                &tag,
+                empty,
+                keys[0],
+                ScriptEncodeData::SinglePubkey,
+                ScriptEncodeMethod::OpReturn,
+            ),
+            Err(Error::OutputIndexOutOfRange {
+                index: 0,
+                outputs: 0
+            })
+        );
+    }
+
+    #[test]
+    fn test_rebase() {
+        let tag = ProtocolTag::new("TEST_TAG");
+        let msg = "message to commit to";
+        // Output #2 designated for the commitment has the original script
+        let pubkey = gen_secp_pubkeys(5)[2];
+        let mut container = TxContainer::construct(
+            2,
+            &tag,
+            1000,
+            gen_tx(5),
+            pubkey,
+            ScriptEncodeData::SinglePubkey,
+            ScriptEncodeMethod::WPubkeyHash,
+        );
+        let commitment =
+            TxCommitment::embed_commit(&mut container, &msg).unwrap();
+        let (old_proof, old_supplement) = container.clone().deconstruct();
+        let old_vout = container.vout();
+
+        // Fee bump reduces value of the last output
+        let mut bumped = commitment.clone();
+        bumped.as_inner_mut().output[4].value -= 1;
+        let mut rebased_container = container.clone();
+        let (rebased, proof) =
+            bumped.rebase(&mut rebased_container, 1001, &msg).unwrap();
+        let new_vout = rebased_container.vout();
+        assert_ne!(new_vout, old_vout);
+        assert_eq!(rebased_container.supplement.fee, 1001);
+        // Tweak depends only on the key and message, not the output
+        assert_eq!(
+            rebased_container.tweaking_factor,
+            container.tweaking_factor
+        );
+        // Committer's output with its value is moved to the new position,
+        // while all other outputs are kept unchanged
+        assert_eq!(rebased.output[old_vout], bumped.output[new_vout]);
+        assert_eq!(
+            rebased.output[new_vout].value,
+            bumped.output[old_vout].value
+        );
+        for (no, output) in bumped.output.iter().enumerate() {
+            if no != old_vout && no != new_vout {
+                assert_eq!(&rebased.output[no], output);
+            }
+        }
+        assert_eq!(
+            rebased.output.iter().map(|txout| txout.value).sum::<u64>(),
+            bumped.output.iter().map(|txout| txout.value).sum::<u64>()
+        );
+
+        let new_supplement = TxSupplement {
+            fee: 1001,
+            ..old_supplement.clone()
+        };
+        let verify = |tx: &Transaction, proof, supplement| {
+            TxMultiCommitment::verify_protocol(tx, supplement, proof, &msg)
+        };
+        assert_eq!(verify(&rebased, &proof, &new_supplement), Ok(true));
+        assert_eq!(verify(&rebased, &old_proof, &old_supplement), Ok(false));
+        assert_eq!(verify(&bumped, &old_proof, &new_supplement), Ok(false));
+        assert_eq!(
+            TxCommitment::from_inner(rebased.clone())
+                .verify(&rebased_container, &msg),
+            Ok(true)
+        );
+
+        // Rebasing to the same fee is no-op
+        assert_eq!(
+            commitment.rebase(&mut container.clone(), 1000, &msg),
+            Ok((commitment.to_inner(), old_proof))
+        );
+
+        // Commitment to other message or with missing factor can't be rebased
+        assert_eq!(
+            bumped.rebase(&mut container.clone(), 1001, &"other"),
+            Err(Error::ScriptMismatch)
+        );
+        let mut wrong_factor = container.clone();
+        TxCommitment::embed_commit(&mut wrong_factor, &"other").unwrap();
+        wrong_factor.supplement.fee = 1000;
+        assert_eq!(
+            bumped.rebase(&mut wrong_factor, 1001, &msg),
+            Err(Error::ScriptMismatch)
+        );
+        let mut no_factor = container;
+        no_factor.tweaking_factor = None;
+        assert_eq!(
+            bumped.rebase(&mut no_factor, 1001, &msg),
+            Err(Error::NoTweakingFactor)
+        );
+    }
+
+    #[test]
+    fn test_reconstruct_from_raw() {
+        let tag = ProtocolTag::new("TEST_TAG");
+        let msg = "message to commit to";
+        let pubkey = gen_secp_pubkeys(3)[0];
+        let mut container = TxContainer::construct(
+            2,
+            &tag,
+            1000,
+            gen_tx(3),
+            pubkey,
+            ScriptEncodeData::SinglePubkey,
+            ScriptEncodeMethod::WPubkeyHash,
+        );
+        let commitment =
+            TxCommitment::embed_commit(&mut container, &msg).unwrap();
+        let (proof, supplement) = container.clone().deconstruct();
+        let raw_tx = serialize(commitment.as_inner());
+
+        let reconstructed = TxContainer::reconstruct_from_raw(
+            &raw_tx,
+            &proof,
+            &supplement,
+            raw_tx.len(),
+        )
+        .unwrap();
+        assert_eq!(
+            reconstructed,
+            TxContainer::reconstruct(&proof, &supplement, &commitment).unwrap()
+        );
+        assert_eq!(commitment.verify(&reconstructed, &msg), Ok(true));
+
+        assert_eq!(
+            TxContainer::reconstruct_from_raw(
+                &raw_tx,
+                &proof,
+                &supplement,
+                raw_tx.len() - 1
+            ),
+            Err(Error::TxTooLarge {
+                size: raw_tx.len(),
+                max_size: raw_tx.len() - 1
+            })
+        );
+
+        let mut extended = raw_tx.clone();
+        extended.push(0);
+        assert_eq!(
+            TxContainer::reconstruct_from_raw(
+                &extended,
+                &proof,
+                &supplement,
+                100_000
+            ),
+            Err(Error::TxDecode)
+        );
+
+        // Truncated data must fail without panicking
+        for len in 0..raw_tx.len() {
+            assert!(TxContainer::reconstruct_from_raw(
+                &raw_tx[..len],
+                &proof,
+                &supplement,
+                100_000
+            )
+            .is_err());
+        }
+
+        // Garbage data, including length prefixes for huge vectors, must fail
+        // without panicking
+        let mut seed = 0x2545_f491_4f6c_dd1du64;
+        for len in 0..512usize {
+            let garbage = (0..len)
+                .map(|_| {
+                    seed ^= seed << 13;
+                    seed ^= seed >> 7;
+                    seed ^= seed << 17;
+                    seed as u8
+                })
+                .collect::<Vec<_>>();
+            let _ = TxContainer::reconstruct_from_raw(
+                &garbage,
+                &proof,
+                &supplement,
+                100_000,
+            );
+        }
+        for prefix in [
+            &[
+                0x02, 0, 0, 0, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff,
+                0xff,
+            ][..],
+            &[0x02, 0, 0, 0, 0x00, 0x01, 0xfe, 0xff, 0xff, 0xff, 0x7f][..],
+        ]
+        .iter()
+        {
+            assert_eq!(
+                TxContainer::reconstruct_from_raw(
+                    prefix,
+                    &proof,
+                    &supplement,
+                    100_000
+                ),
+                Err(Error::TxDecode)
+            );
+        }
+    }
+
+    #[test]
+    fn test_find_commitment_output() {
+        let tag = ProtocolTag::new("TEST_TAG");
+        let msg = "message to commit to";
+        let pubkey = gen_secp_pubkeys(6)[5];
+        let mut container = TxoutContainer::construct(
+            &tag,
+            1000,
+            pubkey,
+            ScriptEncodeData::SinglePubkey,
+            ScriptEncodeMethod::WPubkeyHash,
+        );
+        let commitment =
+            TxoutCommitment::embed_commit(&mut container, &msg).unwrap();
+        let proof = container.to_proof();
+
+        let tx = gen_tx(5);
+        assert_eq!(find_commitment_output(&tx, &proof, &tag, &msg), Ok(None));
+        for vout in [0usize, 2, 4].iter() {
+            let mut tx = tx.clone();
+            tx.output[*vout] = commitment.as_inner().clone();
+            assert_eq!(
+                find_commitment_output(&tx, &proof, &tag, &msg),
+                Ok(Some(*vout))
+            );
+            assert_eq!(
+                find_commitment_output(&tx, &proof, &tag, &"other"),
+                Ok(None)
+            );
+            assert_eq!(
+                find_commitment_output(
+                    &tx,
+                    &proof,
+                    &ProtocolTag::new("OTHER_TAG"),
+                    &msg
+                ),
+                Ok(None)
+            );
+        }
+
+        let mut tx = tx;
+        tx.output[1] = commitment.as_inner().clone();
+        tx.output[3] = commitment.as_inner().clone();
+        assert_eq!(
+            find_commitment_output(&tx, &proof, &tag, &msg),
+            Err(Error::MultipleMatches {
+                first: 1,
+                second: 3
+            })
+        );
+    }
+
+    #[test]
+    fn test_multi_commitment() {
+        let tags = [
+            ProtocolTag::new("FIRST"),
+            ProtocolTag::new("SECOND"),
+            ProtocolTag::new("THIRD"),
+        ];
+        let keys = gen_secp_pubkeys(3);
+        let tx = gen_tx(5);
+        let fee = 1000;
+
+        for (count, factors) in [(2usize, [1u32, 2, 0]), (3, [7, 3, 4])].iter()
+        {
+            let mut multi = TxMultiCommitment::new(fee);
+            for no in 0..*count {
+                multi.add_protocol(
+                    &tags[no],
+                    factors[no],
+                    keys[no],
+                    ScriptEncodeData::SinglePubkey,
+                    ScriptEncodeMethod::WPubkeyHash,
+                    format!("message {}", no),
+                );
+            }
+            let indexes = multi.output_indexes(&tx).unwrap();
+            assert_eq!(indexes.len(), *count);
+            for no in 0..*count {
+                assert_eq!(
+                    indexes[&tags[no]],
+                    lnpbp3::commitment_output_index(fee, factors[no], 5)
+                        .unwrap()
+                );
+            }
+
+            let commitment = multi.embed_commit(&tx).unwrap();
+            for (index, txout) in commitment.output.iter().enumerate() {
+                assert_eq!(txout.value, tx.output[index].value);
+                assert_eq!(
+                    indexes.values().any(|vout| *vout == index),
+                    *txout != tx.output[index]
+                );
+            }
+
+            for no in 0..*count {
+                let (_, container, _) = &multi.protocols[&tags[no]];
+                assert!(container.tweaking_factor.is_some());
+                let proof = container.to_proof();
+                let msg = format!("message {}", no);
+                let supplement = TxSupplement {
+                    protocol_factor: factors[no],
+                    fee,
+                    tag: tags[no],
+                };
+                assert_eq!(
+                    TxMultiCommitment::verify_protocol(
+                        &commitment,
+                        &supplement,
+                        &proof,
+                        &msg
+                    ),
+                    Ok(true)
+                );
+                assert_eq!(
+                    TxMultiCommitment::verify_protocol(
+                        &commitment,
+                        &supplement,
+                        &proof,
+                        &s!("other")
+                    ),
+                    Ok(false)
+                );
+                // Commitment must be found in the designated output only
+                let other = TxSupplement {
+                    protocol_factor: factors[no] + 1,
+                    ..supplement
+                };
+                assert_eq!(
+                    TxMultiCommitment::verify_protocol(
+                        &commitment,
+                        &other,
+                        &proof,
+                        &msg
+                    ),
+                    Ok(false)
+                );
+            }
+        }
+
+        // Forced collision: 1000 + 0 and 1000 + 5 modulo 5 give the same
+        // output
+        let mut multi = TxMultiCommitment::new(fee);
+        for (no, factor) in [0u32, 5].iter().enumerate() {
+            multi.add_protocol(
+                &tags[no],
+                *factor,
+                keys[no],
+                ScriptEncodeData::SinglePubkey,
+                ScriptEncodeMethod::WPubkeyHash,
+                "message",
+            );
+        }
+        let (tag_a, tag_b) = if tags[0] < tags[1] {
+            (tags[0], tags[1])
+        } else {
+            (tags[1], tags[0])
+        };
+        assert_eq!(
+            multi.embed_commit(&tx),
+            Err(Error::OutputCollision {
+                tag_a,
+                tag_b,
+                index: 0
+            })
+        );
+
+        // Failure of a single commitment does not change any of containers
+        let mut multi = TxMultiCommitment::new(fee);
+        multi.add_protocol(
+            &tags[0],
+            1,
+            keys[0],
+            ScriptEncodeData::SinglePubkey,
+            ScriptEncodeMethod::WPubkeyHash,
+            "message",
+        );
+        multi.add_protocol(
+            &tags[1],
+            2,
+            keys[1],
+            ScriptEncodeData::TaprootKeyOnly,
+            ScriptEncodeMethod::WPubkeyHash,
+            "message",
+        );
+        assert!(multi.embed_commit(&tx).is_err());
+        assert!(multi
+            .protocols
+            .values()
+            .all(|(_, container, _)| container.tweaking_factor.is_none()));
+
+        let mut empty = tx;
+        empty.output.clear();
+        assert_eq!(
+            multi.output_indexes(&empty),
+            Err(Error::OutputIndexOutOfRange {
+                index: 0,
+                outputs: 0
+            })
+        );
+    }
+
+    #[test]
+    fn test_tx_fee() {
+        let prev_tx = gen_tx(3);
+        let prevouts = |outpoint: &OutPoint| {
+            if outpoint.txid != prev_tx.txid() {
+                return None;
+            }
+            prev_tx.output.get(outpoint.vout as usize).cloned()
+        };
+        let txin = |vout| TxIn {
+            previous_output: OutPoint::new(prev_tx.txid(), vout),
+            script_sig: Default::default(),
+            sequence: 0,
+            witness: vec![],
+        };
+
+        // Spends 1000 + 3000 sat, outputs are 1000 + 2000 sat
+        let mut tx = gen_tx(2);
+        tx.input = vec![txin(0), txin(2)];
+        assert_eq!(tx_fee(&tx, &prevouts), Ok(1000));
+
+        let tag = ProtocolTag::new("TEST_TAG");
+        assert_eq!(
+            TxSupplement::with_prevouts(7, tag, &tx, &prevouts),
+            Ok(TxSupplement {
+                protocol_factor: 7,
+                fee: 1000,
+                tag
+            })
+        );
+
+        tx.input = vec![txin(0), txin(1)];
+        assert_eq!(tx_fee(&tx, &prevouts), Ok(0));
+
+        tx.input = vec![txin(1)];
+        assert_eq!(
+            tx_fee(&tx, &prevouts),
+            Err(FeeError::ValueUnderflow {
+                inputs: 2000,
+                outputs: 3000
+            })
+        );
+
+        tx.input = vec![txin(0), txin(3)];
+        assert_eq!(
+            tx_fee(&tx, &prevouts),
+            Err(FeeError::MissingPrevout(OutPoint::new(prev_tx.txid(), 3)))
+        );
+        assert_eq!(
+            TxSupplement::with_prevouts(7, tag, &tx, &prevouts),
+            Err(FeeError::MissingPrevout(OutPoint::new(prev_tx.txid(), 3)))
+        );
+
+        // Coinbase transaction does not spend outputs
+        let mut coinbase = gen_tx(2);
+        coinbase.input = vec![TxIn {
+            previous_output: OutPoint::null(),
+            script_sig: Default::default(),
+            sequence: 0xFFFFFFFF,
+            witness: vec![],
+        }];
+        assert_eq!(
+            tx_fee(&coinbase, &|_: &OutPoint| -> Option<TxOut> {
+                panic!("coinbase has no prevouts")
+            }),
+            Ok(0)
+        );
+    }
+
+    #[test]
+    fn test_reconstruct_with_prevouts() {
+        let tag = ProtocolTag::new("TEST_TAG");
+        let msg = "message to commit to";
+        let prev_tx = gen_tx(3);
+        let prevouts = |outpoint: &OutPoint| {
+            prev_tx.output.get(outpoint.vout as usize).cloned()
+        };
+        // Spends 3000 sat, outputs are 100 + 2000 sat
+        let mut tx = gen_tx(2);
+        tx.output[0].value = 100;
+        tx.input = vec![TxIn {
+            previous_output: OutPoint::new(prev_tx.txid(), 2),
+            script_sig: Default::default(),
+            sequence: 0,
+            witness: vec![],
+        }];
+        let fee = tx_fee(&tx, &prevouts).unwrap();
+        assert_eq!(fee, 900);
+
+        let mut container = TxContainer::construct(
+            3,
+            &tag,
+            fee,
+            tx,
+            gen_secp_pubkeys(1)[0],
+            ScriptEncodeData::SinglePubkey,
+            ScriptEncodeMethod::WPubkeyHash,
+        );
+        let commitment =
+            TxCommitment::embed_commit(&mut container, &msg).unwrap();
+        let (proof, _) = container.clone().deconstruct();
+        let supplement =
+            TxSupplement::with_prevouts(3, tag, &commitment, &prevouts)
+                .unwrap();
+        let reconstructed =
+            TxContainer::reconstruct(&proof, &supplement, &commitment).unwrap();
+        assert_eq!(reconstructed.vout(), container.vout());
+        assert_eq!(commitment.verify(&reconstructed, &msg), Ok(true));
+    }
 }
@@ -13,21 +13,46 @@
 // along with this software.
 // If not, see <https://opensource.org/licenses/Apache-2.0>.
 
+use core::convert::TryFrom;
+use core::fmt::{self, Display, Formatter};
+use std::io;
+
 use amplify::Wrapper;
-use bitcoin::hashes::{sha256, Hmac};
-use bitcoin::{secp256k1, TxOut};
+use bitcoin::consensus::encode::{self, VarInt};
+use bitcoin::consensus::{Decodable, Encodable};
+use bitcoin::hashes::{sha256, sha256d, Hash, Hmac};
+use bitcoin::{secp256k1, Transaction, TxOut};
 use bitcoin_scripts::PubkeyScript;
-use commit_verify::EmbedCommitVerify;
+use commit_verify::{CommitConceal, EmbedCommitVerify};
+use miniscript::Segwitv0;
+use strict_encoding::{StrictDecode, StrictEncode};
 
 use super::{
-    Container, Error, Proof, ScriptEncodeData, ScriptEncodeMethod,
-    SpkCommitment, SpkContainer,
+    AddressType, Container, Error, Proof, ProtocolTag, ScriptEncodeData,
+    ScriptEncodeMethod, SpkCommitment, SpkContainer,
 };
+use crate::lnpbp1::TweakingFactor;
+use crate::lnpbp4::{MpcProof, MpcRoot, MultiCommitBlock, ProtocolId};
 
+/// Container for the commitments into transaction outputs.
+///
+/// Both the container and [`TxoutCommitment`] support strict encoding and
+/// serde serialization. Strict decoding fails if the output value exceeds
+/// [`MAX_MONEY`].
 #[derive(Clone, PartialEq, Eq, Debug, Display)]
+#[derive(StrictEncode)]
+#[cfg_attr(
+    feature = "serde",
+    derive(Serialize, Deserialize),
+    serde(crate = "serde_crate")
+)]
 #[display(Debug)]
 pub struct TxoutContainer {
     pub value: u64,
+    /// Origin of the `value`, which allows to distinguish containers
+    /// reconstructed from the transaction outputs from the ones created with
+    /// the value known in advance
+    pub value_source: ValueSource,
     pub script_container: SpkContainer,
     /// Tweaking factor stored after [`TxoutCommitment::embed_commit`]
     /// procedure
@@ -36,7 +61,7 @@ pub struct TxoutContainer {
 
 impl TxoutContainer {
     pub fn construct(
-        protocol_tag: &sha256::Hash,
+        protocol_tag: &ProtocolTag,
         value: u64,
         pubkey: secp256k1::PublicKey,
         source: ScriptEncodeData,
@@ -44,6 +69,7 @@ impl TxoutContainer {
     ) -> Self {
         Self {
             value,
+            value_source: ValueSource::Provided,
             script_container: SpkContainer::construct(
                 protocol_tag,
                 pubkey,
@@ -53,11 +79,229 @@ impl TxoutContainer {
             tweaking_factor: None,
         }
     }
+
+    /// Constructs container for OP_RETURN-based commitment output, which
+    /// always has zero value, as required by the standardness rules. Fails
+    /// with [`Error::InvalidOpReturnKey`] under the same conditions as
+    /// [`SpkContainer::new_op_return`].
+    pub fn op_return(
+        protocol_tag: &ProtocolTag,
+        pubkey: secp256k1::PublicKey,
+    ) -> Result<Self, Error> {
+        Ok(Self {
+            value: 0,
+            value_source: ValueSource::Provided,
+            script_container: SpkContainer::new_op_return(
+                pubkey,
+                protocol_tag,
+            )?,
+            tweaking_factor: None,
+        })
+    }
+
+    /// Constructs container with the protocol tag computed as a single SHA256
+    /// hash of the provided protocol name (see [`ProtocolTag::new`])
+    pub fn with_tag_name(
+        tag_name: &str,
+        value: u64,
+        pubkey: secp256k1::PublicKey,
+        source: ScriptEncodeData,
+        method: ScriptEncodeMethod,
+    ) -> Self {
+        Self::construct(
+            &ProtocolTag::new(tag_name),
+            value,
+            pubkey,
+            source,
+            method,
+        )
+    }
+
+    /// Constructs container from an existing transaction output before the
+    /// commitment. The [`ScriptEncodeMethod`] is deduced from the output
+    /// `scriptPubkey` in the same way as during the commitment verification,
+    /// and the `pubkey` with the `source` must render exactly the same
+    /// `scriptPubkey` (see [`SpkContainer::original_script_pubkey`]).
+    ///
+    /// Fails with [`Error::ScriptMismatch`] if the script rendered from the
+    /// provided data differs from the output script, with
+    /// [`Error::LockscriptKeyNotFound`] if the lockscript does not contain
+    /// the `pubkey`, or with the error of the method deduction if the output
+    /// can't contain commitment with the provided source data.
+    pub fn from_txout(
+        protocol_tag: &ProtocolTag,
+        txout: &TxOut,
+        pubkey: secp256k1::PublicKey,
+        source: ScriptEncodeData,
+    ) -> Result<Self, Error> {
+        let script_pubkey =
+            PubkeyScript::from_inner(txout.script_pubkey.clone());
+        let proof = Proof { pubkey, source };
+        let method = ScriptEncodeMethod::try_from((&proof, &script_pubkey))?;
+        let script_container =
+            SpkContainer::construct(protocol_tag, pubkey, proof.source, method);
+        if script_container.original_script_pubkey()? != script_pubkey {
+            return Err(Error::ScriptMismatch);
+        }
+        if let ScriptEncodeData::LockScript(ref lockscript) =
+            script_container.source
+        {
+            let (keys, hashes) =
+                lockscript.extract_pubkey_hash_set::<Segwitv0>()?;
+            let pubkey_hash = bitcoin::PublicKey {
+                compressed: true,
+                key: pubkey,
+            }
+            .pubkey_hash();
+            if !keys.iter().any(|pk| pk.key == pubkey)
+                && !hashes.contains(&pubkey_hash)
+            {
+                return Err(Error::LockscriptKeyNotFound);
+            }
+        }
+        Ok(Self {
+            value: txout.value,
+            value_source: ValueSource::Host,
+            script_container,
+            tweaking_factor: None,
+        })
+    }
+
+    /// Reconstructs container from the output `vout` of the transaction
+    /// containing commitment, using the provided proof and protocol tag (see
+    /// [`TxoutContainer::reconstruct`]).
+    ///
+    /// Fails with [`Error::OutputIndexOutOfRange`] if the transaction has no
+    /// output with index `vout`.
+    pub fn from_transaction(
+        tx: &Transaction,
+        vout: u32,
+        proof: &Proof,
+        protocol_tag: &ProtocolTag,
+    ) -> Result<Self, Error> {
+        let txout = tx.output.get(vout as usize).ok_or(
+            Error::OutputIndexOutOfRange {
+                index: vout as usize,
+                outputs: tx.output.len(),
+            },
+        )?;
+        Self::reconstruct(proof, protocol_tag, txout)
+    }
+
+    /// Returns dust limit for the output, computed in the same way as Bitcoin
+    /// Core does it with the default dust relay fee of 3 sat/vbyte.
+    /// Unspendable `OP_RETURN` outputs have zero dust limit.
+    pub fn dust_limit(&self) -> u64 {
+        let script_len = match self.script_container.method {
+            ScriptEncodeMethod::PublicKey => 35,
+            ScriptEncodeMethod::PubkeyHash => 25,
+            ScriptEncodeMethod::ScriptHash
+            | ScriptEncodeMethod::ShWPubkeyHash
+            | ScriptEncodeMethod::ShWScriptHash => 23,
+            ScriptEncodeMethod::WPubkeyHash => 22,
+            ScriptEncodeMethod::WScriptHash | ScriptEncodeMethod::Taproot => 34,
+            ScriptEncodeMethod::OpReturn => return 0,
+            ScriptEncodeMethod::Bare => match self.script_container.source {
+                ScriptEncodeData::LockScript(ref script)
+                | ScriptEncodeData::NestedLockScript(ref script) => {
+                    script.len()
+                }
+                _ => 0,
+            },
+            #[cfg(feature = "miniscript")]
+            ScriptEncodeMethod::Miniscript(_) => {
+                return match self.script_container.resolve_miniscript() {
+                    Ok(script_container) => TxoutContainer {
+                        script_container,
+                        ..self.clone()
+                    }
+                    .dust_limit(),
+                    Err(_) => 0,
+                }
+            }
+        };
+        let witness = matches!(
+            self.script_container.method,
+            ScriptEncodeMethod::WPubkeyHash
+                | ScriptEncodeMethod::WScriptHash
+                | ScriptEncodeMethod::Taproot
+        );
+        // Serialized output size: value, script length and script itself
+        let output_size = 8 + VarInt(script_len as u64).len() + script_len;
+        // Size of the input spending the output: outpoint, script length,
+        // sequence number and 107 bytes of signature script (or its witness
+        // equivalent)
+        let input_size = if witness {
+            32 + 4 + 1 + 107 / 4 + 4
+        } else {
+            148
+        };
+        (output_size + input_size) as u64 * 3
+    }
+
+    /// Updates output value, checking it against the output dust limit (see
+    /// [`TxoutContainer::dust_limit`]) unless [`DustPolicy::Allow`] is used.
+    pub fn set_value(
+        &mut self,
+        value: u64,
+        policy: DustPolicy,
+    ) -> Result<(), DustError> {
+        let threshold = self.dust_limit();
+        if policy == DustPolicy::Enforce && value < threshold {
+            return Err(DustError { value, threshold });
+        }
+        self.value = value;
+        self.value_source = ValueSource::Provided;
+        Ok(())
+    }
+}
+
+/// Origin of the output value kept in [`TxoutContainer`]
+#[derive(Copy, Clone, PartialEq, Eq, Hash, Debug, Display)]
+#[derive(StrictEncode, StrictDecode)]
+#[cfg_attr(
+    feature = "serde",
+    derive(Serialize, Deserialize),
+    serde(crate = "serde_crate")
+)]
+#[display(Debug)]
+pub enum ValueSource {
+    /// Value is provided on the container construction or updated with
+    /// [`TxoutContainer::set_value`]
+    Provided,
+
+    /// Value is taken from the host transaction output, as it happens during
+    /// [`TxoutContainer::reconstruct`]
+    Host,
+}
+
+/// Policy for checking output values against the dust limit
+#[derive(Copy, Clone, PartialEq, Eq, Hash, Debug, Display)]
+#[display(Debug)]
+pub enum DustPolicy {
+    /// Values below the dust limit are rejected
+    Enforce,
+
+    /// Values below the dust limit are allowed
+    Allow,
+}
+
+/// Error returned when output value is below the dust limit for its script
+/// type
+#[derive(Copy, Clone, PartialEq, Eq, Hash, Debug, Display, Error)]
+#[display(
+    "output value {value} is below the dust limit of {threshold} satoshis"
+)]
+pub struct DustError {
+    /// Rejected output value
+    pub value: u64,
+    /// Dust limit for the output script type
+    pub threshold: u64,
 }
 
 impl Container for TxoutContainer {
     /// Out supplement is a protocol-specific tag in its hashed form
-    type Supplement = sha256::Hash;
+    type Supplement = ProtocolTag;
     type Host = TxOut;
 
     fn reconstruct(
@@ -67,10 +311,11 @@ impl Container for TxoutContainer {
     ) -> Result<Self, Error> {
         Ok(Self {
             value: host.value,
-            script_container: SpkContainer::reconstruct(
+            value_source: ValueSource::Host,
+            script_container: SpkContainer::reconstruct_from_script(
                 proof,
                 supplement,
-                &PubkeyScript::from_inner(host.clone().script_pubkey),
+                &host.script_pubkey,
             )?,
             tweaking_factor: None,
         })
@@ -80,16 +325,310 @@ impl Container for TxoutContainer {
         self.script_container.deconstruct()
     }
 
+    fn supplement(&self) -> &Self::Supplement { &self.script_container.tag }
+
     fn to_proof(&self) -> Proof { self.script_container.to_proof() }
 
     fn into_proof(self) -> Proof { self.script_container.into_proof() }
 }
 
-/// [`bitcoin::TxOut`] containing LNPBP-2 commitment
-#[derive(Wrapper, Clone, PartialEq, Eq, Hash, Default, Debug, Display, From)]
-#[display(Debug)]
+/// [`bitcoin::TxOut`] containing LNPBP-2 commitment.
+///
+/// The output is strict-encoded with bitcoin consensus serialization, which
+/// is prefixed with its length. It is displayed as the output value followed
+/// by the [`AddressType`] of the `scriptPubkey` and the script in hex form
+/// (or script assembly for the alternate `{:#}` form).
+#[derive(Wrapper, Clone, PartialEq, Eq, Hash, Default, Debug, From)]
+#[cfg_attr(
+    feature = "serde",
+    derive(Serialize, Deserialize),
+    serde(crate = "serde_crate")
+)]
 pub struct TxoutCommitment(TxOut);
 
+impl Display for TxoutCommitment {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        let script = &self.as_inner().script_pubkey;
+        write!(
+            f,
+            "{} sat → {}:",
+            self.as_inner().value,
+            AddressType::classify(script)
+        )?;
+        if f.alternate() {
+            script.fmt_asm(f)
+        } else {
+            write!(f, "{:x}", script)
+        }
+    }
+}
+
+/// Maximal amount of satoshis which may be present in a transaction output
+pub const MAX_MONEY: u64 = 21_000_000 * 100_000_000;
+
+/// Checks that the output value does not exceed [`MAX_MONEY`]
+fn check_value(value: u64) -> Result<u64, strict_encoding::Error> {
+    if value > MAX_MONEY {
+        return Err(strict_encoding::Error::DataIntegrityError(format!(
+            "output value {} exceeds the maximal amount of money",
+            value
+        )));
+    }
+    Ok(value)
+}
+
+impl StrictDecode for TxoutContainer {
+    fn strict_decode<D: io::Read>(
+        mut d: D,
+    ) -> Result<Self, strict_encoding::Error> {
+        Ok(Self {
+            value: check_value(u64::strict_decode(&mut d)?)?,
+            value_source: ValueSource::strict_decode(&mut d)?,
+            script_container: SpkContainer::strict_decode(&mut d)?,
+            tweaking_factor: Option::strict_decode(&mut d)?,
+        })
+    }
+}
+
+impl StrictEncode for TxoutCommitment {
+    fn strict_encode<E: io::Write>(
+        &self,
+        e: E,
+    ) -> Result<usize, strict_encoding::Error> {
+        self.consensus_serialize().strict_encode(e)
+    }
+}
+
+impl StrictDecode for TxoutCommitment {
+    fn strict_decode<D: io::Read>(
+        d: D,
+    ) -> Result<Self, strict_encoding::Error> {
+        let data = Vec::<u8>::strict_decode(d)?;
+        let commitment = TxoutCommitment::consensus_deserialize(&data)
+            .map_err(|err| {
+                strict_encoding::Error::DataIntegrityError(err.to_string())
+            })?;
+        check_value(commitment.value)?;
+        Ok(commitment)
+    }
+}
+
+impl Encodable for TxoutCommitment {
+    #[inline]
+    fn consensus_encode<W: io::Write>(
+        &self,
+        writer: W,
+    ) -> Result<usize, io::Error> {
+        self.as_inner().consensus_encode(writer)
+    }
+}
+
+impl Decodable for TxoutCommitment {
+    #[inline]
+    fn consensus_decode<D: io::Read>(d: D) -> Result<Self, encode::Error> {
+        TxOut::consensus_decode(d).map(TxoutCommitment)
+    }
+}
+
+impl TxoutCommitment {
+    /// Serializes committed output in the same way as it is serialized in
+    /// the transaction
+    #[inline]
+    pub fn consensus_serialize(&self) -> Vec<u8> {
+        bitcoin::consensus::serialize(self.as_inner())
+    }
+
+    /// Deserializes committed output from its consensus serialization,
+    /// failing if the data contain any bytes after the output
+    #[inline]
+    pub fn consensus_deserialize(data: &[u8]) -> Result<Self, encode::Error> {
+        bitcoin::consensus::deserialize(data)
+    }
+
+    /// Returns double SHA256 hash of the consensus serialization of the
+    /// committed output
+    #[inline]
+    pub fn txout_hash(&self) -> sha256d::Hash {
+        sha256d::Hash::hash(&self.consensus_serialize())
+    }
+
+    /// Embeds commitment into the output like [`TxoutCommitment::
+    /// embed_commit`], but first checks the output value against the dust
+    /// limit of the output script type (see [`TxoutContainer::dust_limit`]),
+    /// failing with [`Error::DustOutput`] if the produced output would not be
+    /// relayed by the network. For the same reason OP_RETURN outputs with
+    /// non-zero value are rejected with [`Error::NonZeroOpReturnValue`].
+    ///
+    /// NB: Commitment verification does not apply these checks, since
+    /// non-standard transactions still may be mined.
+    pub fn embed_commit_checked(
+        container: &mut TxoutContainer,
+        msg: &impl AsRef<[u8]>,
+    ) -> Result<Self, Error> {
+        if container.script_container.method == ScriptEncodeMethod::OpReturn
+            && container.value != 0
+        {
+            return Err(Error::NonZeroOpReturnValue(container.value));
+        }
+        let required = container.dust_limit();
+        if container.value < required {
+            return Err(Error::DustOutput {
+                value: container.value,
+                required,
+            });
+        }
+        Self::embed_commit(container, msg)
+    }
+
+    /// Embeds commitment into the output at `index` of the transaction
+    /// `outputs`, replacing it with the committed version and leaving all
+    /// other outputs untouched. The designated output must have the
+    /// `scriptPubkey` which is rendered by the container before the
+    /// commitment (see [`SpkContainer::original_script_pubkey`]), which
+    /// protects from committing into a wrong (for instance, change) output.
+    ///
+    /// The committed output keeps its original value, which is stored in the
+    /// container with [`ValueSource::Host`]. Returns the commitment together
+    /// with the tweaking factor applied to the container public key.
+    ///
+    /// Fails with [`Error::OutputIndexOutOfRange`] if there is no output at
+    /// the `index`, or with [`Error::ScriptMismatch`] if the output script
+    /// does not match the container. The outputs are not modified on
+    /// failure.
+    pub fn embed_commit_into(
+        outputs: &mut [TxOut],
+        index: usize,
+        container: &mut TxoutContainer,
+        msg: &impl AsRef<[u8]>,
+    ) -> Result<(Self, TweakingFactor), Error> {
+        let count = outputs.len();
+        let output =
+            outputs.get_mut(index).ok_or(Error::OutputIndexOutOfRange {
+                index,
+                outputs: count,
+            })?;
+        if container
+            .script_container
+            .original_script_pubkey()?
+            .as_inner()
+            != &output.script_pubkey
+        {
+            return Err(Error::ScriptMismatch);
+        }
+
+        let mut committed = container.clone();
+        committed.value = output.value;
+        committed.value_source = ValueSource::Host;
+        let commitment = Self::embed_commit(&mut committed, msg)?;
+        let factor = committed.tweaking_factor.expect(
+            "TxoutCommitment::embed_commit always sets tweaking factor",
+        );
+
+        *container = committed;
+        *output = commitment.as_inner().clone();
+        Ok((commitment, factor))
+    }
+
+    /// Verifies commitment to the message checking only the `scriptPubkey`
+    /// of the output and ignoring its value, which may change when the
+    /// transaction is fee-bumped (with RBF or CPFP-adjusted outputs).
+    ///
+    /// NB: This verification is weaker than the default
+    /// [`EmbedCommitVerify::verify`], which compares both the script and the
+    /// value with the container: any output with the committed script passes
+    /// it. The caller may compare the values themselves, taking into account
+    /// [`TxoutContainer::value_source`].
+    pub fn verify_script_only(
+        &self,
+        container: &TxoutContainer,
+        msg: &impl AsRef<[u8]>,
+    ) -> Result<bool, Error> {
+        let mut container = container.clone();
+        Ok(match Self::embed_commit(&mut container, msg) {
+            Ok(commitment) => {
+                commitment.script_pubkey == self.as_inner().script_pubkey
+            }
+            Err(_) => false,
+        })
+    }
+
+    /// Verifies that the transaction output contains commitment to the
+    /// message using the provided proof and has the expected `value`. The
+    /// container is reconstructed from the output with
+    /// [`TxoutContainer::reconstruct`], re-embedded and compared with the
+    /// output. As in [`SpkCommitment::verify_script_pubkey`], P2SH outputs
+    /// are checked against both bare and nested forms of the lockscript if
+    /// the proof has no nested script hint.
+    ///
+    /// Returns `Ok(false)` if the output does not commit to the message or
+    /// if its value differs from `value`, and fails with an error if the
+    /// proof is structurally incompatible with the output `scriptPubkey`.
+    pub fn verify_txout(
+        txout: &TxOut,
+        value: u64,
+        proof: &Proof,
+        tag: &ProtocolTag,
+        msg: &impl AsRef<[u8]>,
+    ) -> Result<bool, Error> {
+        if txout.value != value {
+            return Ok(false);
+        }
+        Self::verify_txout_script_only(txout, proof, tag, msg)
+    }
+
+    /// Verifies that the transaction output contains commitment to the
+    /// message using the provided proof, like
+    /// [`TxoutCommitment::verify_txout`], but without checking the output
+    /// value, which is not a part of the commitment and may be unknown to the
+    /// verifier (for instance, when the commitment is verified with an
+    /// [`crate::Anchor`]).
+    ///
+    /// Returns `Ok(false)` if the output does not commit to the message, and
+    /// fails with an error if the proof is structurally incompatible with the
+    /// output `scriptPubkey`.
+    pub fn verify_txout_script_only(
+        txout: &TxOut,
+        proof: &Proof,
+        tag: &ProtocolTag,
+        msg: &impl AsRef<[u8]>,
+    ) -> Result<bool, Error> {
+        let mut container = TxoutContainer::reconstruct(proof, tag, txout)?;
+        container
+            .script_container
+            .resolve_nested(&txout.script_pubkey, msg);
+        TxoutCommitment::from_inner(txout.clone()).verify(&container, msg)
+    }
+
+    /// Verifies that the transaction output commits to the LNPBP-4 tree
+    /// containing `message` of the protocol with `protocol_id`: checks the
+    /// inclusion of the message into the tree with `mpc_proof` and the
+    /// commitment of the output to the resulting tree root in one call. The
+    /// output is verified as described in
+    /// [`TxoutCommitment::verify_txout_script_only`].
+    ///
+    /// Returns `Ok(false)` if the proof slot does not belong to the protocol
+    /// or if the output does not commit to the tree root. Fails with
+    /// [`crate::lnpbp4::Error::InvalidProof`] if the proof path does not
+    /// match the tree width, or with other errors if the proof is
+    /// structurally incompatible with the output `scriptPubkey`.
+    pub fn verify_protocol_in_txout(
+        txout: &TxOut,
+        proof: &Proof,
+        tag: &ProtocolTag,
+        protocol_id: &ProtocolId,
+        message: &sha256::Hash,
+        mpc_proof: &MpcProof,
+    ) -> Result<bool, Error> {
+        if !mpc_proof.matches_slot(protocol_id) {
+            return Ok(false);
+        }
+        let root =
+            MpcRoot::from_inner(mpc_proof.merkle_root(protocol_id, message)?);
+        let container = TxoutContainer::reconstruct(proof, tag, txout)?;
+        TxoutCommitment::from_inner(txout.clone()).verify(&container, &root)
+    }
+}
+
 impl<MSG> EmbedCommitVerify<MSG> for TxoutCommitment
 where
     MSG: AsRef<[u8]>,
@@ -115,3 +654,1219 @@ where
         Ok(commitment.into())
     }
 }
+
+impl EmbedCommitVerify<MpcRoot> for TxoutCommitment {
+    type Container = TxoutContainer;
+    type Error = Error;
+
+    /// Commits to the canonical message of the LNPBP-4 tree root (see
+    /// [`MpcRoot::to_commitment_message`])
+    #[inline]
+    fn embed_commit(
+        container: &mut Self::Container,
+        msg: &MpcRoot,
+    ) -> Result<Self, Self::Error> {
+        <Self as EmbedCommitVerify<[u8; 64]>>::embed_commit(
+            container,
+            &msg.to_commitment_message(),
+        )
+    }
+}
+
+impl EmbedCommitVerify<MultiCommitBlock> for TxoutCommitment {
+    type Container = TxoutContainer;
+    type Error = Error;
+
+    /// Commits to the root of the LNPBP-4 tree
+    #[inline]
+    fn embed_commit(
+        container: &mut Self::Container,
+        msg: &MultiCommitBlock,
+    ) -> Result<Self, Self::Error> {
+        Self::embed_commit(container, &msg.commit_conceal())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::str::FromStr;
+
+    use bitcoin::blockdata::script::{Builder, Script};
+    use bitcoin::hashes::hex::{FromHex, ToHex};
+    use bitcoin::hashes::Hash;
+    use bitcoin_scripts::{LockScript, ToPubkeyScript};
+    use strict_encoding::{StrictDecode, StrictEncode};
+
+    use super::*;
+
+    #[test]
+    fn test_dust_limit() {
+        let tag = ProtocolTag::new("TEST_TAG");
+        let pubkey = secp256k1::PublicKey::from_str(
+            "0218845781f631c48f1c9709e23092067d06837f30aa0cd0544ac887fe91ddd166",
+        )
+        .unwrap();
+        let lockscript = LockScript::from(
+            Builder::new()
+                .push_slice(&pubkey.serialize())
+                .push_opcode(bitcoin::blockdata::opcodes::all::OP_CHECKSIG)
+                .into_script(),
+        );
+        let cases = vec![
+            (
+                ScriptEncodeData::SinglePubkey,
+                ScriptEncodeMethod::PublicKey,
+                576,
+            ),
+            (
+                ScriptEncodeData::SinglePubkey,
+                ScriptEncodeMethod::PubkeyHash,
+                546,
+            ),
+            (
+                ScriptEncodeData::SinglePubkey,
+                ScriptEncodeMethod::ShWPubkeyHash,
+                540,
+            ),
+            (
+                ScriptEncodeData::SinglePubkey,
+                ScriptEncodeMethod::WPubkeyHash,
+                294,
+            ),
+            (
+                ScriptEncodeData::SinglePubkey,
+                ScriptEncodeMethod::OpReturn,
+                0,
+            ),
+            (
+                ScriptEncodeData::LockScript(lockscript.clone()),
+                ScriptEncodeMethod::Bare,
+                576,
+            ),
+            (
+                ScriptEncodeData::LockScript(lockscript.clone()),
+                ScriptEncodeMethod::ScriptHash,
+                540,
+            ),
+            (
+                ScriptEncodeData::LockScript(lockscript),
+                ScriptEncodeMethod::WScriptHash,
+                330,
+            ),
+            (
+                ScriptEncodeData::Taproot(sha256::Hash::hash(b"root")),
+                ScriptEncodeMethod::Taproot,
+                330,
+            ),
+        ];
+        for (source, method, threshold) in cases {
+            let mut container =
+                TxoutContainer::construct(&tag, 0, pubkey, source, method);
+            assert_eq!(container.dust_limit(), threshold);
+
+            assert_eq!(
+                container.set_value(threshold, DustPolicy::Enforce),
+                Ok(())
+            );
+            assert_eq!(container.value, threshold);
+            if threshold > 0 {
+                assert_eq!(
+                    container.set_value(threshold - 1, DustPolicy::Enforce),
+                    Err(DustError {
+                        value: threshold - 1,
+                        threshold
+                    })
+                );
+                assert_eq!(container.value, threshold);
+            }
+            assert_eq!(container.set_value(1, DustPolicy::Allow), Ok(()));
+            assert_eq!(container.value, 1);
+        }
+    }
+
+    #[test]
+    fn test_embed_commit_checked() {
+        let tag = ProtocolTag::new("TEST_TAG");
+        let pubkey = secp256k1::PublicKey::from_str(
+            "0218845781f631c48f1c9709e23092067d06837f30aa0cd0544ac887fe91ddd166",
+        )
+        .unwrap();
+        let cases = vec![
+            (
+                ScriptEncodeData::SinglePubkey,
+                ScriptEncodeMethod::PubkeyHash,
+                546,
+            ),
+            (
+                ScriptEncodeData::SinglePubkey,
+                ScriptEncodeMethod::WPubkeyHash,
+                294,
+            ),
+            (
+                ScriptEncodeData::Taproot(sha256::Hash::hash(b"root")),
+                ScriptEncodeMethod::Taproot,
+                330,
+            ),
+            (
+                ScriptEncodeData::TaprootKeyOnly,
+                ScriptEncodeMethod::Taproot,
+                330,
+            ),
+        ];
+        for (source, method, required) in cases {
+            let mut container = TxoutContainer::construct(
+                &tag,
+                required - 1,
+                pubkey,
+                source,
+                method,
+            );
+            assert_eq!(
+                TxoutCommitment::embed_commit_checked(
+                    &mut container,
+                    &"message"
+                ),
+                Err(Error::DustOutput {
+                    value: required - 1,
+                    required
+                })
+            );
+            assert_eq!(container.tweaking_factor, None);
+            // Unchecked commitment preserves the original behaviour
+            assert!(TxoutCommitment::embed_commit(
+                &mut container.clone(),
+                &"message"
+            )
+            .is_ok());
+
+            container.value = required;
+            let commitment = TxoutCommitment::embed_commit_checked(
+                &mut container,
+                &"message",
+            )
+            .unwrap();
+            assert_eq!(commitment.value, required);
+        }
+
+        let container = TxoutContainer::construct(
+            &tag,
+            0,
+            pubkey,
+            ScriptEncodeData::SinglePubkey,
+            ScriptEncodeMethod::OpReturn,
+        );
+        let committed = ["message", "other", "LNPBP"].iter().any(|msg| {
+            match TxoutCommitment::embed_commit_checked(
+                &mut container.clone(),
+                msg,
+            ) {
+                Err(Error::InvalidOpReturnKey) => false,
+                res => res.unwrap().value == 0,
+            }
+        });
+        assert!(committed);
+    }
+
+    #[test]
+    fn test_from_txout() {
+        let tag = ProtocolTag::new("TEST_TAG");
+        let pubkey = secp256k1::PublicKey::from_str(
+            "0218845781f631c48f1c9709e23092067d06837f30aa0cd0544ac887fe91ddd166",
+        )
+        .unwrap();
+        let other = secp256k1::PublicKey::from_str(
+            "03db3289f5e13774ef6d579305b6502b316e90d0416b1a5a3e4d8676d94cf0f67d",
+        )
+        .unwrap();
+        let lockscript = LockScript::from(
+            Builder::new()
+                .push_slice(&pubkey.serialize())
+                .push_opcode(bitcoin::blockdata::opcodes::all::OP_CHECKSIG)
+                .into_script(),
+        );
+        let cases = vec![
+            (
+                ScriptEncodeData::SinglePubkey,
+                ScriptEncodeMethod::WPubkeyHash,
+            ),
+            (
+                ScriptEncodeData::LockScript(lockscript.clone()),
+                ScriptEncodeMethod::WScriptHash,
+            ),
+            (
+                ScriptEncodeData::Taproot(sha256::Hash::hash(b"root")),
+                ScriptEncodeMethod::Taproot,
+            ),
+        ];
+        for (source, method) in cases {
+            let mut expected = TxoutContainer::construct(
+                &tag,
+                1000,
+                pubkey,
+                source.clone(),
+                method,
+            );
+            let txout = TxOut {
+                value: 1000,
+                script_pubkey: expected
+                    .script_container
+                    .original_script_pubkey()
+                    .unwrap()
+                    .into_inner(),
+            };
+            let container = TxoutContainer::from_txout(
+                &tag,
+                &txout,
+                pubkey,
+                source.clone(),
+            )
+            .unwrap();
+            expected.value_source = ValueSource::Host;
+            assert_eq!(container, expected);
+            let err = match source {
+                ScriptEncodeData::LockScript(_) => Error::LockscriptKeyNotFound,
+                _ => Error::ScriptMismatch,
+            };
+            assert_eq!(
+                TxoutContainer::from_txout(&tag, &txout, other, source),
+                Err(err)
+            );
+        }
+
+        // Source data type does not match the output script type
+        let txout = TxOut {
+            value: 1000,
+            script_pubkey: lockscript
+                .to_pubkey_script(bitcoin_scripts::Category::SegWit)
+                .into_inner(),
+        };
+        assert_eq!(
+            TxoutContainer::from_txout(
+                &tag,
+                &txout,
+                pubkey,
+                ScriptEncodeData::SinglePubkey
+            ),
+            Err(Error::InvalidProofStructure)
+        );
+    }
+
+    #[test]
+    fn test_verify_script_only() {
+        let tag = ProtocolTag::new("TEST_TAG");
+        let pubkey = secp256k1::PublicKey::from_str(
+            "0218845781f631c48f1c9709e23092067d06837f30aa0cd0544ac887fe91ddd166",
+        )
+        .unwrap();
+        let mut container = TxoutContainer::construct(
+            &tag,
+            1000,
+            pubkey,
+            ScriptEncodeData::SinglePubkey,
+            ScriptEncodeMethod::WPubkeyHash,
+        );
+        assert_eq!(container.value_source, ValueSource::Provided);
+        let commitment =
+            TxoutCommitment::embed_commit(&mut container, &"message").unwrap();
+        let original = container.clone();
+
+        assert_eq!(commitment.verify(&original, &"message"), Ok(true));
+        assert_eq!(
+            commitment.verify_script_only(&original, &"message"),
+            Ok(true)
+        );
+
+        // Fee-bumped transaction with a smaller output value
+        let bumped = TxoutCommitment::from_inner(TxOut {
+            value: 900,
+            script_pubkey: commitment.script_pubkey.clone(),
+        });
+        assert_eq!(bumped.verify(&original, &"message"), Ok(false));
+        assert_eq!(bumped.verify_script_only(&original, &"message"), Ok(true));
+        assert_eq!(
+            bumped.verify_script_only(&original, &"other message"),
+            Ok(false)
+        );
+
+        let reconstructed = TxoutContainer::reconstruct(
+            &original.to_proof(),
+            &tag,
+            bumped.as_inner(),
+        )
+        .unwrap();
+        assert_eq!(reconstructed.value_source, ValueSource::Host);
+        assert_eq!(reconstructed.value, 900);
+        assert_ne!(reconstructed.value, original.value);
+        assert_eq!(bumped.verify(&reconstructed, &"message"), Ok(true));
+
+        let mut updated = reconstructed;
+        updated.set_value(1000, DustPolicy::Enforce).unwrap();
+        assert_eq!(updated.value_source, ValueSource::Provided);
+    }
+
+    #[test]
+    fn test_verify_txout() {
+        let tag = ProtocolTag::new("TEST_TAG");
+        let pubkey = secp256k1::PublicKey::from_str(
+            "0218845781f631c48f1c9709e23092067d06837f30aa0cd0544ac887fe91ddd166",
+        )
+        .unwrap();
+        let lockscript = LockScript::from(
+            Builder::new()
+                .push_slice(&pubkey.serialize())
+                .push_opcode(bitcoin::blockdata::opcodes::all::OP_CHECKSIG)
+                .into_script(),
+        );
+        let cases = vec![
+            (
+                ScriptEncodeData::SinglePubkey,
+                ScriptEncodeMethod::PublicKey,
+            ),
+            (
+                ScriptEncodeData::SinglePubkey,
+                ScriptEncodeMethod::PubkeyHash,
+            ),
+            (
+                ScriptEncodeData::SinglePubkey,
+                ScriptEncodeMethod::ShWPubkeyHash,
+            ),
+            (
+                ScriptEncodeData::SinglePubkey,
+                ScriptEncodeMethod::WPubkeyHash,
+            ),
+            (ScriptEncodeData::SinglePubkey, ScriptEncodeMethod::OpReturn),
+            (
+                ScriptEncodeData::LockScript(lockscript.clone()),
+                ScriptEncodeMethod::Bare,
+            ),
+            (
+                ScriptEncodeData::LockScript(lockscript.clone()),
+                ScriptEncodeMethod::ScriptHash,
+            ),
+            (
+                ScriptEncodeData::LockScript(lockscript.clone()),
+                ScriptEncodeMethod::WScriptHash,
+            ),
+            (
+                ScriptEncodeData::LockScript(lockscript),
+                ScriptEncodeMethod::ShWScriptHash,
+            ),
+            (
+                ScriptEncodeData::Taproot(sha256::Hash::hash(b"root")),
+                ScriptEncodeMethod::Taproot,
+            ),
+            (
+                ScriptEncodeData::TaprootKeyOnly,
+                ScriptEncodeMethod::Taproot,
+            ),
+        ];
+        let mut verified = 0;
+        for (source, method) in cases {
+            for msg in &["message", "other", "LNPBP"] {
+                let mut container = TxoutContainer::construct(
+                    &tag,
+                    1000,
+                    pubkey,
+                    source.clone(),
+                    method.clone(),
+                );
+                let txout =
+                    match TxoutCommitment::embed_commit(&mut container, msg) {
+                        Err(Error::InvalidOpReturnKey) => continue,
+                        res => res.unwrap().into_inner(),
+                    };
+                let proof = container.to_proof();
+                let value = container.value;
+                assert_eq!(
+                    TxoutCommitment::verify_txout(
+                        &txout, value, &proof, &tag, msg
+                    ),
+                    Ok(true)
+                );
+                assert_eq!(
+                    TxoutCommitment::verify_txout(
+                        &txout,
+                        value,
+                        &proof,
+                        &tag,
+                        &"wrong message"
+                    ),
+                    Ok(false)
+                );
+                assert_eq!(
+                    TxoutCommitment::verify_txout(
+                        &txout,
+                        value,
+                        &proof,
+                        &ProtocolTag::new("OTHER_TAG"),
+                        msg
+                    ),
+                    Ok(false)
+                );
+
+                // Output with the same script and a different value does not
+                // pass the verification, unless the value is ignored
+                let other_value = TxOut {
+                    value: 2000,
+                    script_pubkey: txout.script_pubkey.clone(),
+                };
+                assert_eq!(
+                    TxoutCommitment::verify_txout(
+                        &other_value,
+                        value,
+                        &proof,
+                        &tag,
+                        msg
+                    ),
+                    Ok(false)
+                );
+                assert_eq!(
+                    TxoutCommitment::verify_txout(
+                        &txout, 2000, &proof, &tag, msg
+                    ),
+                    Ok(false)
+                );
+                assert_eq!(
+                    TxoutCommitment::verify_txout_script_only(
+                        &other_value,
+                        &proof,
+                        &tag,
+                        msg
+                    ),
+                    Ok(true)
+                );
+                verified += 1;
+            }
+        }
+        assert!(verified >= 30);
+
+        // Proof is incompatible with the output script type
+        let mut container = TxoutContainer::construct(
+            &tag,
+            1000,
+            pubkey,
+            ScriptEncodeData::Taproot(sha256::Hash::hash(b"root")),
+            ScriptEncodeMethod::Taproot,
+        );
+        TxoutCommitment::embed_commit(&mut container, &"message").unwrap();
+        let txout = TxOut {
+            value: 1000,
+            script_pubkey: Builder::new()
+                .push_int(0)
+                .push_slice(&[0u8; 20])
+                .into_script(),
+        };
+        assert_eq!(
+            TxoutCommitment::verify_txout(
+                &txout,
+                1000,
+                &container.to_proof(),
+                &tag,
+                &"message"
+            ),
+            Err(Error::InvalidProofStructure)
+        );
+    }
+
+    #[test]
+    fn test_op_return() {
+        let tag = ProtocolTag::new("TEST_TAG");
+        let pubkey = secp256k1::PublicKey::from_str(
+            "0218845781f631c48f1c9709e23092067d06837f30aa0cd0544ac887fe91ddd166",
+        )
+        .unwrap();
+        let odd_pubkey = secp256k1::PublicKey::from_str(
+            "0318845781f631c48f1c9709e23092067d06837f30aa0cd0544ac887fe91ddd166",
+        )
+        .unwrap();
+
+        let mut container = TxoutContainer::op_return(&tag, pubkey).unwrap();
+        // Tweaked key must also have even y coordinate, so we select the
+        // message producing such a key
+        let msg = (0..)
+            .map(|i| format!("message {}", i))
+            .find(|msg| {
+                TxoutCommitment::embed_commit(&mut container.clone(), msg)
+                    .is_ok()
+            })
+            .unwrap();
+        assert_eq!(container.value, 0);
+        assert_eq!(
+            container.script_container.method,
+            ScriptEncodeMethod::OpReturn
+        );
+        assert_eq!(
+            container.script_container.source,
+            ScriptEncodeData::SinglePubkey
+        );
+        assert_eq!(
+            TxoutContainer::op_return(&tag, odd_pubkey),
+            Err(Error::InvalidOpReturnKey)
+        );
+
+        let commitment =
+            TxoutCommitment::embed_commit_checked(&mut container.clone(), &msg)
+                .unwrap();
+        assert_eq!(commitment.value, 0);
+        assert!(commitment.script_pubkey.is_op_return());
+
+        // Non-zero value is rejected for the new commitments
+        container.value = 1000;
+        assert_eq!(
+            TxoutCommitment::embed_commit_checked(&mut container.clone(), &msg),
+            Err(Error::NonZeroOpReturnValue(1000))
+        );
+
+        // ... but confirmed non-standard outputs can still be verified
+        let nonstandard =
+            TxoutCommitment::embed_commit(&mut container, &msg).unwrap();
+        assert_eq!(nonstandard.value, 1000);
+        assert_eq!(nonstandard.script_pubkey, commitment.script_pubkey);
+        let reconstructed = TxoutContainer::reconstruct(
+            &container.to_proof(),
+            &tag,
+            nonstandard.as_inner(),
+        )
+        .unwrap();
+        assert_eq!(reconstructed.value, 1000);
+        assert_eq!(nonstandard.verify(&reconstructed, &msg), Ok(true));
+        assert_eq!(
+            TxoutCommitment::verify_txout(
+                nonstandard.as_inner(),
+                1000,
+                &container.to_proof(),
+                &tag,
+                &msg
+            ),
+            Ok(true)
+        );
+    }
+
+    /// Reconstructs containers for a large number of synthetic outputs of
+    /// different types, as it happens when scanning blocks; can be used for
+    /// profiling allocations made during reconstruction
+    #[test]
+    fn test_reconstruct_many() {
+        let tag = ProtocolTag::new("TEST_TAG");
+        let pubkey = secp256k1::PublicKey::from_str(
+            "0218845781f631c48f1c9709e23092067d06837f30aa0cd0544ac887fe91ddd166",
+        )
+        .unwrap();
+        let lockscript = LockScript::from(
+            Builder::new()
+                .push_slice(&pubkey.serialize())
+                .push_opcode(bitcoin::blockdata::opcodes::all::OP_CHECKSIG)
+                .into_script(),
+        );
+        let cases = [
+            (
+                ScriptEncodeData::SinglePubkey,
+                ScriptEncodeMethod::WPubkeyHash,
+            ),
+            (
+                ScriptEncodeData::SinglePubkey,
+                ScriptEncodeMethod::PubkeyHash,
+            ),
+            (
+                ScriptEncodeData::LockScript(lockscript.clone()),
+                ScriptEncodeMethod::WScriptHash,
+            ),
+            (
+                ScriptEncodeData::LockScript(lockscript),
+                ScriptEncodeMethod::ScriptHash,
+            ),
+            (
+                ScriptEncodeData::Taproot(sha256::Hash::hash(b"root")),
+                ScriptEncodeMethod::Taproot,
+            ),
+        ];
+        let templates = cases
+            .iter()
+            .map(|(source, method)| {
+                let container = TxoutContainer::construct(
+                    &tag,
+                    0,
+                    pubkey,
+                    source.clone(),
+                    method.clone(),
+                );
+                let commitment =
+                    TxoutCommitment::embed_commit(&mut container.clone(), &"")
+                        .unwrap();
+                (container.to_proof(), commitment.into_inner().script_pubkey)
+            })
+            .collect::<Vec<_>>();
+
+        let outputs = (0..10_000u64)
+            .map(|value| {
+                let (proof, script_pubkey) =
+                    &templates[value as usize % templates.len()];
+                (proof, TxOut {
+                    value,
+                    script_pubkey: script_pubkey.clone(),
+                })
+            })
+            .collect::<Vec<_>>();
+
+        for (index, (proof, txout)) in outputs.iter().enumerate() {
+            let container =
+                TxoutContainer::reconstruct(proof, &tag, txout).unwrap();
+            assert_eq!(container.value, txout.value);
+            assert_eq!(
+                container.script_container.method,
+                cases[index % cases.len()].1
+            );
+            if index < cases.len() {
+                assert_eq!(
+                    container.script_container,
+                    SpkContainer::reconstruct(
+                        proof,
+                        &tag,
+                        &PubkeyScript::from_inner(txout.script_pubkey.clone())
+                    )
+                    .unwrap()
+                );
+            }
+        }
+    }
+
+    #[test]
+    fn test_with_tag_name() {
+        let pubkey = secp256k1::PublicKey::from_str(
+            "0218845781f631c48f1c9709e23092067d06837f30aa0cd0544ac887fe91ddd166",
+        )
+        .unwrap();
+        let container = TxoutContainer::with_tag_name(
+            "TEST_TAG",
+            1000,
+            pubkey,
+            ScriptEncodeData::SinglePubkey,
+            ScriptEncodeMethod::WPubkeyHash,
+        );
+        assert_eq!(
+            container,
+            TxoutContainer::construct(
+                &ProtocolTag::new("TEST_TAG"),
+                1000,
+                pubkey,
+                ScriptEncodeData::SinglePubkey,
+                ScriptEncodeMethod::WPubkeyHash,
+            )
+        );
+        assert_eq!(
+            container.script_container,
+            SpkContainer::with_tag_name(
+                "TEST_TAG",
+                pubkey,
+                ScriptEncodeData::SinglePubkey,
+                ScriptEncodeMethod::WPubkeyHash,
+            )
+        );
+    }
+
+    #[test]
+    fn test_embed_commit_into() {
+        let tag = ProtocolTag::new("TEST_TAG");
+        let pubkey = secp256k1::PublicKey::from_str(
+            "0218845781f631c48f1c9709e23092067d06837f30aa0cd0544ac887fe91ddd166",
+        )
+        .unwrap();
+        let change_pubkey = secp256k1::PublicKey::from_str(
+            "02c6047f9441ed7d6d3045406e95c07cd85c778e4b8cef3ca7abac09b95c709ee5",
+        )
+        .unwrap();
+        let container = TxoutContainer::construct(
+            &tag,
+            1000,
+            pubkey,
+            ScriptEncodeData::SinglePubkey,
+            ScriptEncodeMethod::WPubkeyHash,
+        );
+        let change = TxoutContainer::construct(
+            &tag,
+            5000,
+            change_pubkey,
+            ScriptEncodeData::SinglePubkey,
+            ScriptEncodeMethod::WPubkeyHash,
+        );
+        let outputs = vec![
+            TxOut {
+                value: 5000,
+                script_pubkey: change
+                    .script_container
+                    .original_script_pubkey()
+                    .unwrap()
+                    .into_inner(),
+            },
+            TxOut {
+                value: 1500,
+                script_pubkey: container
+                    .script_container
+                    .original_script_pubkey()
+                    .unwrap()
+                    .into_inner(),
+            },
+            TxOut {
+                value: 0,
+                script_pubkey: Builder::new()
+                    .push_opcode(bitcoin::blockdata::opcodes::all::OP_RETURN)
+                    .into_script(),
+            },
+        ];
+
+        let mut committed = outputs.clone();
+        let mut designated = container.clone();
+        let (commitment, factor) = TxoutCommitment::embed_commit_into(
+            &mut committed,
+            1,
+            &mut designated,
+            &"message",
+        )
+        .unwrap();
+        assert_eq!(committed.len(), 3);
+        for index in [0usize, 2].iter() {
+            assert_eq!(
+                bitcoin::consensus::serialize(&committed[*index]),
+                bitcoin::consensus::serialize(&outputs[*index])
+            );
+        }
+        assert_eq!(&committed[1], commitment.as_inner());
+        assert_ne!(committed[1].script_pubkey, outputs[1].script_pubkey);
+        assert_eq!(committed[1].value, 1500);
+        assert_eq!(designated.value, 1500);
+        assert_eq!(designated.value_source, ValueSource::Host);
+        assert_eq!(designated.tweaking_factor, Some(factor));
+        assert_eq!(commitment.verify(&designated, &"message"), Ok(true));
+        assert_eq!(
+            TxoutCommitment::verify_txout(
+                &committed[1],
+                1500,
+                &designated.to_proof(),
+                &tag,
+                &"message"
+            ),
+            Ok(true)
+        );
+
+        // Designated output does not match the container
+        for index in [0usize, 2].iter() {
+            let mut committed = outputs.clone();
+            let mut designated = container.clone();
+            assert_eq!(
+                TxoutCommitment::embed_commit_into(
+                    &mut committed,
+                    *index,
+                    &mut designated,
+                    &"message",
+                ),
+                Err(Error::ScriptMismatch)
+            );
+            assert_eq!(committed, outputs);
+            assert_eq!(designated, container);
+        }
+
+        // Index is out of range
+        let mut committed = outputs.clone();
+        let mut designated = container.clone();
+        assert_eq!(
+            TxoutCommitment::embed_commit_into(
+                &mut committed,
+                3,
+                &mut designated,
+                &"message",
+            ),
+            Err(Error::OutputIndexOutOfRange {
+                index: 3,
+                outputs: 3
+            })
+        );
+        assert_eq!(committed, outputs);
+    }
+
+    #[test]
+    fn test_strict_encoding() {
+        let tag = ProtocolTag::new("TEST_TAG");
+        let pubkey = secp256k1::PublicKey::from_str(
+            "0218845781f631c48f1c9709e23092067d06837f30aa0cd0544ac887fe91ddd166",
+        )
+        .unwrap();
+        let mut container = TxoutContainer::construct(
+            &tag,
+            1000,
+            pubkey,
+            ScriptEncodeData::SinglePubkey,
+            ScriptEncodeMethod::WPubkeyHash,
+        );
+        let commitment =
+            TxoutCommitment::embed_commit(&mut container, &"message").unwrap();
+
+        let data = container.strict_serialize().unwrap();
+        assert_eq!(
+            data.to_hex(),
+            concat!(
+            "e803000000000000000218845781f631c48f1c9709e23092067d06837f30aa0c",
+            "d0544ac887fe91ddd1660300013113300fd425c4880faf45e5d3196a833e7b36",
+            "ea5222310388f4f0c43f9c5901b5b75893388b288e686279bc3c64b2a1b437d4",
+            "8fbc309619971712cc3894864f01b5b75893388b288e686279bc3c64b2a1b437",
+            "d48fbc309619971712cc3894864f",
+            )
+        );
+        assert_eq!(
+            TxoutContainer::strict_deserialize(&data),
+            Ok(container.clone())
+        );
+
+        let data = commitment.strict_serialize().unwrap();
+        assert_eq!(
+            data.to_hex(),
+            "1f00e803000000000000160014bcf98ae3320df5729f87541432d8b2bd148d1b9e"
+        );
+        assert_eq!(
+            TxoutCommitment::strict_deserialize(&data),
+            Ok(commitment.clone())
+        );
+
+        // Values which exceed the amount of money in existence, including
+        // the ones which look negative when read as a signed integer
+        for value in [MAX_MONEY + 1, u64::MAX, 1 << 63].iter() {
+            let mut malformed = container.clone();
+            malformed.value = *value;
+            let data = malformed.strict_serialize().unwrap();
+            assert!(matches!(
+                TxoutContainer::strict_deserialize(&data),
+                Err(strict_encoding::Error::DataIntegrityError(_))
+            ));
+
+            let malformed = TxoutCommitment::from_inner(TxOut {
+                value: *value,
+                script_pubkey: commitment.script_pubkey.clone(),
+            });
+            let data = malformed.strict_serialize().unwrap();
+            assert!(matches!(
+                TxoutCommitment::strict_deserialize(&data),
+                Err(strict_encoding::Error::DataIntegrityError(_))
+            ));
+        }
+
+        // Script length exceeds the amount of the provided data
+        let data = Vec::<u8>::from_hex(
+            "1f00e803000000000000170014bcf98ae3320df5729f87541432d8b2bd148d1b9e",
+        )
+        .unwrap();
+        assert!(matches!(
+            TxoutCommitment::strict_deserialize(&data),
+            Err(strict_encoding::Error::DataIntegrityError(_))
+        ));
+        // Trailing data after the script
+        let data = Vec::<u8>::from_hex(
+            "1f00e803000000000000150014bcf98ae3320df5729f87541432d8b2bd148d1b9e",
+        )
+        .unwrap();
+        assert!(matches!(
+            TxoutCommitment::strict_deserialize(&data),
+            Err(strict_encoding::Error::DataIntegrityError(_))
+        ));
+    }
+
+    #[test]
+    fn test_display() {
+        let commitment = TxoutCommitment::from_inner(TxOut {
+            value: 1000,
+            script_pubkey: Script::from(
+                Vec::<u8>::from_hex(
+                    "0014bcf98ae3320df5729f87541432d8b2bd148d1b9e",
+                )
+                .unwrap(),
+            ),
+        });
+        assert_eq!(
+            commitment.to_string(),
+            "1000 sat → P2WPKH:0014bcf98ae3320df5729f87541432d8b2bd148d1b9e"
+        );
+        assert_eq!(
+            format!("{:#}", commitment),
+            "1000 sat → P2WPKH:OP_0 OP_PUSHBYTES_20 \
+             bcf98ae3320df5729f87541432d8b2bd148d1b9e"
+        );
+
+        let commitment = TxoutCommitment::from_inner(TxOut {
+            value: 0,
+            script_pubkey: Builder::new()
+                .push_opcode(bitcoin::blockdata::opcodes::all::OP_RETURN)
+                .push_slice(&[0xde, 0xad])
+                .into_script(),
+        });
+        assert_eq!(commitment.to_string(), "0 sat → NonStandard:6a02dead");
+        assert_eq!(
+            format!("{:#}", commitment),
+            "0 sat → NonStandard:OP_RETURN OP_PUSHBYTES_2 dead"
+        );
+    }
+
+    #[test]
+    fn test_consensus_encoding() {
+        let tag = ProtocolTag::new("TEST_TAG");
+        let pubkey = secp256k1::PublicKey::from_str(
+            "0218845781f631c48f1c9709e23092067d06837f30aa0cd0544ac887fe91ddd166",
+        )
+        .unwrap();
+        let mut container = TxoutContainer::construct(
+            &tag,
+            1000,
+            pubkey,
+            ScriptEncodeData::SinglePubkey,
+            ScriptEncodeMethod::WPubkeyHash,
+        );
+        let commitment =
+            TxoutCommitment::embed_commit(&mut container, &"message").unwrap();
+        let txout = commitment.as_inner().clone();
+
+        let data = commitment.consensus_serialize();
+        assert_eq!(data, bitcoin::consensus::serialize(&txout));
+        assert_eq!(data, bitcoin::consensus::serialize(&commitment));
+        assert_eq!(
+            data.to_hex(),
+            "e803000000000000160014bcf98ae3320df5729f87541432d8b2bd148d1b9e"
+        );
+        assert_eq!(
+            TxoutCommitment::consensus_deserialize(&data).unwrap(),
+            commitment
+        );
+        assert_eq!(
+            bitcoin::consensus::deserialize::<TxoutCommitment>(&data).unwrap(),
+            commitment
+        );
+        assert_eq!(
+            bitcoin::consensus::deserialize::<TxOut>(&data).unwrap(),
+            txout
+        );
+        assert_eq!(commitment.txout_hash(), sha256d::Hash::hash(&data));
+
+        // Strict encoding wraps the same data with the length prefix
+        assert_eq!(&commitment.strict_serialize().unwrap()[2..], &data[..]);
+
+        let mut extended = data.clone();
+        extended.push(0);
+        assert!(TxoutCommitment::consensus_deserialize(&extended).is_err());
+        assert!(TxoutCommitment::consensus_deserialize(
+            &data[..data.len() - 1]
+        )
+        .is_err());
+    }
+
+    #[test]
+    #[cfg(feature = "serde")]
+    fn test_serde() {
+        let tag = ProtocolTag::new("TEST_TAG");
+        let pubkey = secp256k1::PublicKey::from_str(
+            "0218845781f631c48f1c9709e23092067d06837f30aa0cd0544ac887fe91ddd166",
+        )
+        .unwrap();
+        let mut container = TxoutContainer::construct(
+            &tag,
+            1000,
+            pubkey,
+            ScriptEncodeData::SinglePubkey,
+            ScriptEncodeMethod::WPubkeyHash,
+        );
+        let commitment =
+            TxoutCommitment::embed_commit(&mut container, &"message").unwrap();
+
+        let json = serde_json::to_value(&commitment).unwrap();
+        assert_eq!(
+            json["script_pubkey"],
+            "0014bcf98ae3320df5729f87541432d8b2bd148d1b9e"
+        );
+        assert_eq!(
+            serde_json::from_value::<TxoutCommitment>(json).unwrap(),
+            commitment
+        );
+
+        let json = serde_json::to_string(&container).unwrap();
+        assert_eq!(
+            serde_json::from_str::<TxoutContainer>(&json).unwrap(),
+            container
+        );
+    }
+
+    #[test]
+    fn test_supplement() {
+        let tag = ProtocolTag::new("TEST_TAG");
+        let pubkey = secp256k1::PublicKey::from_str(
+            "0218845781f631c48f1c9709e23092067d06837f30aa0cd0544ac887fe91ddd166",
+        )
+        .unwrap();
+        let container = TxoutContainer::construct(
+            &tag,
+            1000,
+            pubkey,
+            ScriptEncodeData::SinglePubkey,
+            ScriptEncodeMethod::WPubkeyHash,
+        );
+        assert_eq!(container.supplement(), &tag);
+        assert_eq!(container.script_container.supplement(), &tag);
+        let dry_run = crate::DryRunContainer::new(container.clone());
+        assert_eq!(dry_run.supplement(), &tag);
+        assert_eq!(container.deconstruct().1, tag);
+    }
+
+    #[test]
+    fn test_from_transaction() {
+        let tag = ProtocolTag::new("TEST_TAG");
+        let pubkey = secp256k1::PublicKey::from_str(
+            "0218845781f631c48f1c9709e23092067d06837f30aa0cd0544ac887fe91ddd166",
+        )
+        .unwrap();
+        let mut container = TxoutContainer::construct(
+            &tag,
+            1000,
+            pubkey,
+            ScriptEncodeData::SinglePubkey,
+            ScriptEncodeMethod::WPubkeyHash,
+        );
+        let commitment =
+            TxoutCommitment::embed_commit(&mut container, &"message").unwrap();
+        let proof = container.to_proof();
+        let tx = Transaction {
+            version: 2,
+            lock_time: 0,
+            input: vec![],
+            output: vec![TxOut::default(), commitment.as_inner().clone()],
+        };
+
+        let reconstructed =
+            TxoutContainer::from_transaction(&tx, 1, &proof, &tag).unwrap();
+        assert_eq!(
+            reconstructed,
+            TxoutContainer::reconstruct(&proof, &tag, &tx.output[1]).unwrap()
+        );
+        assert_eq!(commitment.verify(&reconstructed, &"message"), Ok(true));
+        assert_eq!(
+            TxoutContainer::from_transaction(&tx, 2, &proof, &tag),
+            Err(Error::OutputIndexOutOfRange {
+                index: 2,
+                outputs: 2
+            })
+        );
+    }
+
+    #[test]
+    fn test_mpc_commitment() {
+        use crate::lnpbp4::{MultiSource, LNPBP4_HASHED_TAG};
+
+        let tag = ProtocolTag::new("TEST_TAG");
+        let pubkey = secp256k1::PublicKey::from_str(
+            "0218845781f631c48f1c9709e23092067d06837f30aa0cd0544ac887fe91ddd166",
+        )
+        .unwrap();
+        let mut source = MultiSource::with_entropy(0xdead_beef);
+        for no in 0u8..6 {
+            source.insert(
+                ProtocolId::new(&format!("protocol #{}", no)),
+                sha256::Hash::hash(&[no]),
+            );
+        }
+        let block = MultiCommitBlock::with(source).unwrap();
+        let root = block.commit_conceal();
+
+        let container = TxoutContainer::construct(
+            &tag,
+            1000,
+            pubkey,
+            ScriptEncodeData::SinglePubkey,
+            ScriptEncodeMethod::WPubkeyHash,
+        );
+        let commitment =
+            TxoutCommitment::embed_commit(&mut container.clone(), &block)
+                .unwrap();
+        assert!(commitment.script_pubkey.is_v0_p2wpkh());
+        assert_eq!(commitment.value, 1000);
+
+        // The same commitment is produced from the concealed root and from
+        // its canonical message with LNPBP-4 prefix
+        assert_eq!(
+            TxoutCommitment::embed_commit(&mut container.clone(), &root),
+            Ok(commitment.clone())
+        );
+        let msg = root.to_commitment_message();
+        assert_eq!(&msg[..32], &LNPBP4_HASHED_TAG[..]);
+        assert_eq!(&msg[32..], &block.commit()[..]);
+        assert_eq!(
+            TxoutCommitment::embed_commit(&mut container.clone(), &msg),
+            Ok(commitment.clone())
+        );
+        assert_ne!(
+            TxoutCommitment::embed_commit(
+                &mut container.clone(),
+                &block.commit()
+            ),
+            Ok(commitment.clone())
+        );
+        assert_eq!(commitment.verify(&container, &block), Ok(true));
+        assert_eq!(commitment.verify(&container, &root), Ok(true));
+
+        // Verification of a single protocol message
+        let proof = container.to_proof();
+        let id = ProtocolId::new("protocol #3");
+        let message = sha256::Hash::hash(&[3]);
+        let mpc_proof = block.proof_for(&id).unwrap();
+        assert_eq!(
+            TxoutCommitment::verify_protocol_in_txout(
+                &commitment,
+                &proof,
+                &tag,
+                &id,
+                &message,
+                &mpc_proof
+            ),
+            Ok(true)
+        );
+        assert_eq!(
+            TxoutCommitment::verify_protocol_in_txout(
+                &commitment,
+                &proof,
+                &tag,
+                &id,
+                &sha256::Hash::hash(&[4]),
+                &mpc_proof
+            ),
+            Ok(false)
+        );
+        assert_eq!(
+            TxoutCommitment::verify_protocol_in_txout(
+                &commitment,
+                &proof,
+                &ProtocolTag::new("OTHER_TAG"),
+                &id,
+                &message,
+                &mpc_proof
+            ),
+            Ok(false)
+        );
+        let other = ProtocolId::new("protocol #4");
+        assert_eq!(
+            TxoutCommitment::verify_protocol_in_txout(
+                &commitment,
+                &proof,
+                &tag,
+                &other,
+                &sha256::Hash::hash(&[4]),
+                &mpc_proof
+            ),
+            Ok(false)
+        );
+        let mut malformed = mpc_proof;
+        malformed.path.pop();
+        assert_eq!(
+            TxoutCommitment::verify_protocol_in_txout(
+                &commitment,
+                &proof,
+                &tag,
+                &id,
+                &message,
+                &malformed
+            ),
+            Err(Error::Lnpbp4(crate::lnpbp4::Error::InvalidProof))
+        );
+    }
+}
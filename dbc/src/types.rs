@@ -13,8 +13,15 @@
 // along with this software.
 // If not, see <https://opensource.org/licenses/Apache-2.0>.
 
-use amplify::DumbDefault;
+use std::fmt::{self, Debug, Display, Formatter};
+
+use amplify::hex::{FromHex, ToHex};
+use amplify::{DumbDefault, Wrapper};
+use bitcoin::hashes::{sha256, sha256t, Hash, HashEngine};
 use bitcoin::secp256k1;
+use bitcoin_scripts::LockScript;
+use commit_verify::EmbedCommitVerify;
+use strict_encoding::{StrictDecode, StrictEncode};
 
 use super::{Error, ScriptEncodeData};
 
@@ -30,10 +37,51 @@ pub trait Container: Sized {
 
     fn deconstruct(self) -> (Proof, Self::Supplement);
 
+    /// Returns reference to the supplement stored in the container, which
+    /// allows to access it without consuming the container with
+    /// [`Container::deconstruct`].
+    fn supplement(&self) -> &Self::Supplement;
+
     fn to_proof(&self) -> Proof;
     fn into_proof(self) -> Proof;
 }
 
+/// Extension to [`EmbedCommitVerify`] for the commitments supporting strict
+/// encoding, which allows to verify a commitment provided in a serialized
+/// form (for instance, read by a chain scanner) without decoding it first.
+/// The trait is implemented for all such commitments.
+pub trait VerifyEncoded<MSG>: EmbedCommitVerify<MSG> + StrictDecode
+where
+    Error: From<Self::Error>,
+{
+    /// Decodes commitment from `commitment_bytes` with [`StrictDecode`] and
+    /// verifies it against the `container` and the `msg` with
+    /// [`EmbedCommitVerify::verify`]. Fails with
+    /// [`Error::InvalidCommitmentEncoding`] if the bytes are not a valid
+    /// strict encoding of the commitment, including the case when they
+    /// contain extra data after the commitment.
+    fn verify_encoded(
+        commitment_bytes: &[u8],
+        container: &Self::Container,
+        msg: &MSG,
+    ) -> Result<bool, Error> {
+        let mut cursor = std::io::Cursor::new(commitment_bytes);
+        let commitment = Self::strict_decode(&mut cursor)
+            .map_err(|_| Error::InvalidCommitmentEncoding)?;
+        if cursor.position() as usize != commitment_bytes.len() {
+            return Err(Error::InvalidCommitmentEncoding);
+        }
+        Ok(commitment.verify(container, msg)?)
+    }
+}
+
+impl<MSG, T> VerifyEncoded<MSG> for T
+where
+    T: EmbedCommitVerify<MSG> + StrictDecode,
+    Error: From<T::Error>,
+{
+}
+
 #[derive(Clone, PartialEq, Eq, Hash, Debug, Display)]
 #[derive(StrictEncode, StrictDecode)]
 #[cfg_attr(
@@ -47,23 +95,458 @@ pub struct Proof {
     pub source: ScriptEncodeData,
 }
 
+/// Tag used for [`ProofId`] hash type
+pub struct ProofIdTag;
+
+/// Name of the BIP-340-style tag used in [`ProofId`] computation
+pub const PROOF_ID_TAG: &str = "bp:dbc:proof:v1";
+
+impl sha256t::Tag for ProofIdTag {
+    #[inline]
+    fn engine() -> sha256::HashEngine {
+        let tag = sha256::Hash::hash(PROOF_ID_TAG.as_bytes());
+        let mut engine = sha256::HashEngine::default();
+        engine.input(&tag[..]);
+        engine.input(&tag[..]);
+        engine
+    }
+}
+
+/// Unique proof identifier, computed as BIP-340-style tagged hash (with
+/// [`PROOF_ID_TAG`]) of the strict-encoded [`Proof`] data. The identifier is
+/// displayed and parsed as a hexadecimal string in the reverse byte order,
+/// like bitcoin transaction ids.
+///
+/// NB: The identifiers are used as database keys, so all implementations must
+/// compute them identically. The preimage layout must never change without
+/// changing the version in the tag.
+#[derive(
+    Wrapper, Copy, Clone, PartialEq, Eq, PartialOrd, Ord, Hash, Default,
+    Display, From
+)]
+#[wrapper(
+    Debug, LowerHex, FromStr, Index, IndexRange, IndexFrom, IndexTo, IndexFull
+)]
+#[display(LowerHex)]
+pub struct ProofId(sha256t::Hash<ProofIdTag>);
+
+impl strict_encoding::Strategy for ProofId {
+    type Strategy = strict_encoding::strategies::Wrapped;
+}
+
+/// Errors happening during parsing of the hexadecimal [`Proof`] representation
+#[derive(Clone, PartialEq, Eq, Debug, Display, Error, From)]
+#[display(doc_comments)]
+pub enum ProofParseError {
+    /// proof data must be a hexadecimal string – {0}
+    #[from]
+    Hex(amplify::hex::Error),
+
+    /// wrong proof data encoding – {0}
+    #[from]
+    Encoding(strict_encoding::Error),
+}
+
+impl Proof {
+    /// Constructs proof for the commitments which keep no data besides the
+    /// public key (see [`ScriptEncodeData::SinglePubkey`])
+    #[inline]
+    pub fn from_pubkey_only(pubkey: secp256k1::PublicKey) -> Self {
+        Proof {
+            pubkey,
+            source: ScriptEncodeData::SinglePubkey,
+        }
+    }
+
+    /// Constructs proof for the commitment into the `pubkey` contained in the
+    /// lockscript (see [`ScriptEncodeData::LockScript`])
+    #[inline]
+    pub fn from_lockscript(
+        pubkey: secp256k1::PublicKey,
+        script: LockScript,
+    ) -> Self {
+        Proof {
+            pubkey,
+            source: ScriptEncodeData::LockScript(script),
+        }
+    }
+
+    /// Constructs proof for the taproot commitment into the intermediate
+    /// key, with optional merkle root of the script tree (see
+    /// [`ScriptEncodeData::Taproot`] and
+    /// [`ScriptEncodeData::TaprootKeyOnly`])
+    #[inline]
+    pub fn from_taproot(
+        intermediate_key: secp256k1::PublicKey,
+        script_root: Option<sha256::Hash>,
+    ) -> Self {
+        Proof {
+            pubkey: intermediate_key,
+            source: script_root.into(),
+        }
+    }
+
+    /// Computes unique proof identifier
+    pub fn proof_id(&self) -> ProofId {
+        let mut engine = sha256t::Hash::<ProofIdTag>::engine();
+        self.strict_encode(&mut engine)
+            .expect("memory encoders do not fail");
+        ProofId::from_inner(sha256t::Hash::from_engine(engine))
+    }
+
+    /// Serializes proof with strict encoding
+    #[inline]
+    pub fn to_bytes(&self) -> Vec<u8> {
+        self.strict_serialize()
+            .expect("in-memory strict encoding of proof data can't fail")
+    }
+
+    /// Deserializes strict-encoded proof
+    #[inline]
+    pub fn from_bytes(bytes: &[u8]) -> Result<Self, strict_encoding::Error> {
+        Self::strict_deserialize(bytes)
+    }
+
+    /// Returns hexadecimal string representing strict-encoded proof data
+    #[inline]
+    pub fn hex(&self) -> String { self.to_bytes().to_hex() }
+
+    /// Parses proof from hexadecimal string representing strict-encoded proof
+    /// data
+    pub fn from_hex(s: &str) -> Result<Self, ProofParseError> {
+        Ok(Self::from_bytes(&Vec::<u8>::from_hex(s)?)?)
+    }
+}
+
 impl DumbDefault for Proof {
     fn dumb_default() -> Self {
         Proof {
-            pubkey: secp256k1::PublicKey::from_secret_key(
-                secp256k1::SECP256K1,
-                &secp256k1::key::ONE_KEY,
-            ),
+            pubkey: crate::lnpbp1::with_secp(|secp| {
+                secp256k1::PublicKey::from_secret_key(
+                    secp,
+                    &secp256k1::key::ONE_KEY,
+                )
+            }),
             source: Default::default(),
         }
     }
 }
 
 impl From<secp256k1::PublicKey> for Proof {
+    #[inline]
     fn from(pubkey: secp256k1::PublicKey) -> Self {
-        Self {
-            pubkey,
-            source: ScriptEncodeData::SinglePubkey,
+        Proof::from_pubkey_only(pubkey)
+    }
+}
+
+/// Protocol-specific tag in its hashed form (single SHA256 hash of the
+/// protocol name) used by all of the commitment containers.
+///
+/// The tag keeps raw hash bytes, so it can be constructed at compile time
+/// with [`ProtocolTag::from_raw`] for the well-known protocols.
+#[derive(Copy, Clone, PartialEq, Eq, PartialOrd, Ord, Hash, Default)]
+#[cfg_attr(
+    feature = "serde",
+    derive(Serialize, Deserialize),
+    serde(crate = "serde_crate", from = "sha256::Hash", into = "sha256::Hash")
+)]
+pub struct ProtocolTag([u8; 32]);
+
+impl ProtocolTag {
+    /// Constructs tag from already hashed protocol name
+    #[inline]
+    pub const fn from_raw(bytes: [u8; 32]) -> Self { Self(bytes) }
+
+    /// Constructs tag by hashing protocol name
+    #[inline]
+    pub fn new(name: &str) -> Self {
+        Self(sha256::Hash::hash(name.as_bytes()).into_inner())
+    }
+
+    /// Returns raw hash bytes of the tag
+    #[inline]
+    pub fn to_raw(&self) -> [u8; 32] { self.0 }
+
+    /// Returns tag in form of SHA256 hash, as used by [`crate::lnpbp1`]
+    /// commitment procedures
+    #[inline]
+    pub fn to_hash(&self) -> sha256::Hash { sha256::Hash::from_inner(self.0) }
+}
+
+impl StrictEncode for ProtocolTag {
+    #[inline]
+    fn strict_encode<E: std::io::Write>(
+        &self,
+        e: E,
+    ) -> Result<usize, strict_encoding::Error> {
+        self.to_hash().strict_encode(e)
+    }
+}
+
+impl StrictDecode for ProtocolTag {
+    #[inline]
+    fn strict_decode<D: std::io::Read>(
+        d: D,
+    ) -> Result<Self, strict_encoding::Error> {
+        sha256::Hash::strict_decode(d).map(Self::from)
+    }
+}
+
+impl From<sha256::Hash> for ProtocolTag {
+    #[inline]
+    fn from(hash: sha256::Hash) -> Self { Self(hash.into_inner()) }
+}
+
+impl From<ProtocolTag> for sha256::Hash {
+    #[inline]
+    fn from(tag: ProtocolTag) -> Self { tag.to_hash() }
+}
+
+impl AsRef<[u8]> for ProtocolTag {
+    #[inline]
+    fn as_ref(&self) -> &[u8] { &self.0[..] }
+}
+
+impl Display for ProtocolTag {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        Display::fmt(&self.to_hash(), f)
+    }
+}
+
+impl Debug for ProtocolTag {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        write!(f, "ProtocolTag({})", self.to_hash())
+    }
+}
+
+/// Tag of LNPBP-1 protocol: single SHA256 hash of "LNPBP1" string
+pub const LNPBP1_TAG: ProtocolTag = ProtocolTag::from_raw([
+    245, 8, 242, 142, 252, 192, 113, 82, 108, 168, 134, 200, 224, 124, 105,
+    212, 149, 78, 46, 201, 252, 82, 171, 140, 204, 209, 41, 17, 12, 0, 64, 175,
+]);
+
+/// Tag of LNPBP-2 protocol: single SHA256 hash of "LNPBP2" string
+pub const LNPBP2_TAG: ProtocolTag = ProtocolTag::from_raw([
+    99, 153, 92, 193, 123, 137, 108, 224, 26, 233, 162, 48, 3, 199, 79, 172,
+    103, 145, 147, 152, 96, 7, 79, 160, 111, 66, 18, 22, 32, 76, 224, 104,
+]);
+
+#[cfg(test)]
+mod test {
+    use std::str::FromStr;
+
+    use bitcoin::blockdata::opcodes;
+    use bitcoin::blockdata::script::Builder;
+
+    use super::*;
+    use crate::lnpbp1::test_helpers::*;
+
+    #[test]
+    fn test_proof_roundtrip() {
+        let pubkey = gen_secp_pubkeys(1)[0];
+        let lockscript = LockScript::from(
+            Builder::new()
+                .push_slice(&pubkey.serialize())
+                .push_opcode(opcodes::all::OP_CHECKSIG)
+                .into_script(),
+        );
+        let proofs = vec![
+            Proof::from_pubkey_only(pubkey),
+            Proof::from_lockscript(pubkey, lockscript),
+            Proof::from_taproot(pubkey, Some(sha256::Hash::hash(b"root"))),
+            Proof::from_taproot(pubkey, None),
+        ];
+        for proof in proofs {
+            assert_eq!(Proof::from_bytes(&proof.to_bytes()), Ok(proof.clone()));
+            assert_eq!(Proof::from_hex(&proof.hex()), Ok(proof.clone()));
         }
     }
+
+    #[test]
+    fn test_proof_constructors() {
+        let pubkey = gen_secp_pubkeys(1)[0];
+        let lockscript = LockScript::from(
+            Builder::new()
+                .push_slice(&pubkey.serialize())
+                .push_opcode(opcodes::all::OP_CHECKSIG)
+                .into_script(),
+        );
+        let root = sha256::Hash::hash(b"root");
+
+        assert_eq!(Proof::from_pubkey_only(pubkey), Proof {
+            pubkey,
+            source: ScriptEncodeData::SinglePubkey
+        });
+        assert_eq!(Proof::from(pubkey), Proof::from_pubkey_only(pubkey));
+        assert_eq!(Proof::from_lockscript(pubkey, lockscript.clone()), Proof {
+            pubkey,
+            source: ScriptEncodeData::LockScript(lockscript)
+        });
+        assert_eq!(Proof::from_taproot(pubkey, Some(root)), Proof {
+            pubkey,
+            source: ScriptEncodeData::Taproot(root)
+        });
+        assert_eq!(Proof::from_taproot(pubkey, None), Proof {
+            pubkey,
+            source: ScriptEncodeData::TaprootKeyOnly
+        });
+    }
+
+    #[test]
+    fn test_proof_id_golden() {
+        // Any change of these values breaks compatibility of the identifiers
+        // and requires change of the tag version
+        let proof = Proof::from_pubkey_only(
+            secp256k1::PublicKey::from_str(
+                "0218845781f631c48f1c9709e23092067d06837f30aa0cd0544ac887fe91ddd166",
+            )
+            .unwrap(),
+        );
+        let id = proof.proof_id();
+        assert_eq!(
+            id.as_inner().into_inner().to_hex(),
+            "4d1d8567ea2c256dfab003e480da2f65eb714146c006ed3aa6cd82367c16233f"
+        );
+        assert_eq!(
+            id.to_string(),
+            "3f23167c3682cda63aed06c0464171eb652fda80e403b0fa6d252cea67851d4d"
+        );
+        assert_eq!(ProofId::from_str(&id.to_string()), Ok(id));
+        assert_eq!(
+            ProofId::strict_deserialize(id.strict_serialize().unwrap()),
+            Ok(id)
+        );
+        assert_ne!(
+            Proof::from_taproot(proof.pubkey, None).proof_id(),
+            proof.proof_id()
+        );
+    }
+
+    #[test]
+    fn test_taproot_proof_encoding() {
+        // Encoding of taproot proofs with script root must not change with
+        // addition of key-only taproot proofs
+        let pubkey = gen_secp_pubkeys(1)[0];
+        let root = sha256::Hash::hash(b"root");
+        let proof = Proof {
+            pubkey,
+            source: ScriptEncodeData::Taproot(root),
+        };
+        let mut data = pubkey.serialize().to_vec();
+        data.push(0x02);
+        data.extend(&root[..]);
+        assert_eq!(proof.to_bytes(), data);
+
+        let proof = Proof {
+            pubkey,
+            source: ScriptEncodeData::TaprootKeyOnly,
+        };
+        let mut data = pubkey.serialize().to_vec();
+        data.push(0x04);
+        assert_eq!(proof.to_bytes(), data);
+    }
+
+    #[test]
+    fn test_proof_parse_errors() {
+        assert!(matches!(
+            Proof::from_hex("zz"),
+            Err(ProofParseError::Hex(_))
+        ));
+        assert!(matches!(
+            Proof::from_hex("00"),
+            Err(ProofParseError::Encoding(_))
+        ));
+        let mut data = Proof::from(gen_secp_pubkeys(1)[0]).to_bytes();
+        data.pop();
+        assert!(Proof::from_bytes(&data).is_err());
+    }
+
+    #[test]
+    fn test_protocol_tag() {
+        assert_eq!(ProtocolTag::new("LNPBP1"), LNPBP1_TAG);
+        assert_eq!(ProtocolTag::new("LNPBP2"), LNPBP2_TAG);
+        assert_eq!(LNPBP1_TAG.to_raw(), crate::lnpbp1::LNPBP1_HASHED_TAG);
+        assert_ne!(ProtocolTag::new("lnpbp2"), LNPBP2_TAG);
+
+        let hash = sha256::Hash::hash(b"TEST_TAG");
+        let tag = ProtocolTag::new("TEST_TAG");
+        assert_eq!(ProtocolTag::from(hash), tag);
+        assert_eq!(sha256::Hash::from(tag), hash);
+        assert_eq!(tag.to_hash(), hash);
+        assert_eq!(tag.to_string(), hash.to_string());
+    }
+
+    #[test]
+    fn test_verify_encoded() {
+        use crate::{
+            PubkeyCommitment, PubkeyContainer, ScriptEncodeMethod,
+            SpkCommitment, SpkContainer, TxoutCommitment, TxoutContainer,
+        };
+
+        let tag = ProtocolTag::new("TEST_TAG");
+        let pubkey = gen_secp_pubkeys(1)[0];
+
+        let mut container = PubkeyContainer::with_tag_name(pubkey, "TEST_TAG");
+        let original = container.clone();
+        let commitment =
+            PubkeyCommitment::embed_commit(&mut container, &"message").unwrap();
+        let data = commitment.strict_serialize().unwrap();
+        assert_eq!(
+            PubkeyCommitment::verify_encoded(&data, &original, &"message"),
+            Ok(true)
+        );
+        assert_eq!(
+            PubkeyCommitment::verify_encoded(&data, &original, &"other"),
+            Ok(false)
+        );
+
+        let mut container = SpkContainer::construct(
+            &tag,
+            pubkey,
+            ScriptEncodeData::SinglePubkey,
+            ScriptEncodeMethod::WPubkeyHash,
+        );
+        let original = container.clone();
+        let commitment =
+            SpkCommitment::embed_commit(&mut container, &"message").unwrap();
+        let data = commitment.strict_serialize().unwrap();
+        assert_eq!(
+            SpkCommitment::verify_encoded(&data, &original, &"message"),
+            Ok(true)
+        );
+        let mut extended = data.clone();
+        extended.push(0);
+        assert_eq!(
+            SpkCommitment::verify_encoded(&extended, &original, &"message"),
+            Err(Error::InvalidCommitmentEncoding)
+        );
+        assert_eq!(
+            SpkCommitment::verify_encoded(
+                &data[..data.len() - 1],
+                &original,
+                &"message"
+            ),
+            Err(Error::InvalidCommitmentEncoding)
+        );
+
+        let mut container = TxoutContainer::construct(
+            &tag,
+            1000,
+            pubkey,
+            ScriptEncodeData::SinglePubkey,
+            ScriptEncodeMethod::WPubkeyHash,
+        );
+        let original = container.clone();
+        let commitment =
+            TxoutCommitment::embed_commit(&mut container, &"message").unwrap();
+        let data = commitment.strict_serialize().unwrap();
+        assert_eq!(
+            TxoutCommitment::verify_encoded(&data, &original, &"message"),
+            Ok(true)
+        );
+        assert_eq!(
+            TxoutCommitment::verify_encoded(&[], &original, &"message"),
+            Err(Error::InvalidCommitmentEncoding)
+        );
+    }
 }
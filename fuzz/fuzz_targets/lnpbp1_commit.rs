@@ -0,0 +1,61 @@
+// BP Core Library implementing LNP/BP specifications & standards related to
+// bitcoin protocol
+//
+// Written in 2020-2021 by
+//     Dr. Maxim Orlovsky <orlovsky@pandoracore.com>
+//
+// To the extent possible under law, the author(s) have dedicated all
+// copyright and related and neighboring rights to this software to
+// the public domain worldwide. This software is distributed without
+// any warranty.
+//
+// You should have received a copy of the Apache 2.0 License
+// along with this software.
+// If not, see <https://opensource.org/licenses/Apache-2.0>.
+
+//! Fuzzing target for LNPBP-1 commitment procedure.
+//!
+//! Input data layout:
+//! - 32 bytes: seed of the secret key for the target public key;
+//! - 32 bytes: seed of the secret key for the second public key in the keyset;
+//! - 32 bytes: protocol tag;
+//! - the rest: message to commit to.
+//!
+//! The commitment must never panic, and each successful commitment must pass
+//! verification.
+
+#![no_main]
+
+use std::collections::BTreeSet;
+
+use bitcoin::hashes::{sha256, Hash};
+use bitcoin::secp256k1;
+use dbc::lnpbp1;
+use libfuzzer_sys::fuzz_target;
+
+fn pubkey(seed: &[u8]) -> Option<secp256k1::PublicKey> {
+    let secret_key = secp256k1::SecretKey::from_slice(seed).ok()?;
+    Some(secp256k1::PublicKey::from_secret_key(
+        &secp256k1::SECP256K1,
+        &secret_key,
+    ))
+}
+
+fuzz_target!(|data: &[u8]| {
+    if data.len() < 96 {
+        return;
+    }
+    let (target, other) = match (pubkey(&data[..32]), pubkey(&data[32..64])) {
+        (Some(target), Some(other)) => (target, other),
+        _ => return,
+    };
+    let tag = sha256::Hash::from_slice(&data[64..96])
+        .expect("slice length is 32 bytes");
+    let msg = &data[96..];
+
+    let keyset: BTreeSet<_> = vec![target, other].into_iter().collect();
+    let mut tweaked = target;
+    if lnpbp1::commit(&mut keyset.clone(), &mut tweaked, &tag, &msg).is_ok() {
+        assert!(lnpbp1::verify(tweaked, &keyset, target, &tag, &msg));
+    }
+});